@@ -0,0 +1,159 @@
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::PathBuf;
+
+/// Size past which the audit log is rotated
+pub const AUDIT_LOG_MAX_BYTES: u64 = 1024 * 1024;
+
+/// A line of the audit log
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AuditEntry {
+    /// Local time, RFC 3339
+    pub timestamp: String,
+    /// Operation name (e.g. "add", "import")
+    pub operation: String,
+    /// Affected domains or a short summary
+    pub detail: String,
+}
+
+/// Append-only, human-readable log of mutating operations
+///
+/// Each line is `timestamp<TAB>operation<TAB>detail`. When the log grows
+/// past its size limit it is moved to `<name>.1` (replacing the previous
+/// rotation) and a new log is started.
+#[derive(Debug, Clone)]
+pub struct AuditLog {
+    path: PathBuf,
+    max_bytes: u64,
+}
+
+impl AuditLog {
+    pub fn new(path: PathBuf) -> Self {
+        AuditLog {
+            path,
+            max_bytes: AUDIT_LOG_MAX_BYTES,
+        }
+    }
+
+    fn rotated_path(&self) -> PathBuf {
+        let mut name = self.path.file_name().unwrap_or_default().to_os_string();
+        name.push(".1");
+        self.path.with_file_name(name)
+    }
+
+    /// Append an entry, rotating the log first if it is too large
+    pub fn append(&self, operation: &str, detail: &str) -> Result<(), anyhow::Error> {
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        if fs::metadata(&self.path).is_ok_and(|metadata| metadata.len() >= self.max_bytes) {
+            fs::rename(&self.path, self.rotated_path())?;
+        }
+
+        let timestamp = chrono::Local::now().to_rfc3339_opts(chrono::SecondsFormat::Secs, false);
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        writeln!(
+            file,
+            "{}\t{}\t{}",
+            timestamp,
+            single_line(operation),
+            single_line(detail)
+        )?;
+        Ok(())
+    }
+
+    /// Read the last `limit` entries, newest first (including the rotated log)
+    pub fn tail(&self, limit: usize) -> Result<Vec<AuditEntry>, anyhow::Error> {
+        let mut entries = Vec::new();
+
+        for path in [self.path.clone(), self.rotated_path()] {
+            let content = match fs::read_to_string(&path) {
+                Ok(content) => content,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e.into()),
+            };
+            for line in content.lines().rev() {
+                if entries.len() == limit {
+                    return Ok(entries);
+                }
+                if let Some(entry) = parse_line(line) {
+                    entries.push(entry);
+                }
+            }
+        }
+
+        Ok(entries)
+    }
+}
+
+/// Keep an entry on one line with exactly three fields
+#[inline]
+fn single_line(text: &str) -> String {
+    text.replace(['\t', '\n', '\r'], " ")
+}
+
+fn parse_line(line: &str) -> Option<AuditEntry> {
+    let mut fields = line.splitn(3, '\t');
+    Some(AuditEntry {
+        timestamp: fields.next()?.to_string(),
+        operation: fields.next()?.to_string(),
+        detail: fields.next().unwrap_or_default().to_string(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_append_and_tail() {
+        let temp_dir = TempDir::new().unwrap();
+        let log = AuditLog::new(temp_dir.path().join("audit.log"));
+
+        log.append("add", "ads.example.com").unwrap();
+        log.append("remove", "tracker.example.com").unwrap();
+        log.append("import", "hosts.txt\n(2 domains)").unwrap();
+
+        let entries = log.tail(2).unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].operation, "import");
+        assert_eq!(entries[0].detail, "hosts.txt (2 domains)");
+        assert_eq!(entries[1].operation, "remove");
+        assert_eq!(entries[1].detail, "tracker.example.com");
+    }
+
+    #[test]
+    fn test_rotation() {
+        let temp_dir = TempDir::new().unwrap();
+        let log = AuditLog {
+            path: temp_dir.path().join("audit.log"),
+            max_bytes: 100,
+        };
+
+        for i in 0..10 {
+            log.append("add", &format!("ads{}.example.com", i)).unwrap();
+        }
+
+        assert!(fs::metadata(&log.path).unwrap().len() < 200);
+        assert!(temp_dir.path().join("audit.log.1").exists());
+
+        // The tail reads across the rotation, newest first
+        let entries = log.tail(3).unwrap();
+        let details: Vec<&str> = entries.iter().map(|e| e.detail.as_str()).collect();
+        assert_eq!(
+            details,
+            vec!["ads9.example.com", "ads8.example.com", "ads7.example.com"]
+        );
+    }
+
+    #[test]
+    fn test_tail_of_missing_log() {
+        let temp_dir = TempDir::new().unwrap();
+        let log = AuditLog::new(temp_dir.path().join("audit.log"));
+        assert!(log.tail(10).unwrap().is_empty());
+    }
+}
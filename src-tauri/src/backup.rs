@@ -0,0 +1,365 @@
+use crate::history::check_snapshot_name;
+use crate::profiles::validate_profile_name;
+use crate::state::DB_VERSION;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{Read, Write};
+use std::path::{Component, Path, PathBuf};
+
+/// Marks a file as a backup of this app
+pub const BACKUP_FORMAT: &str = "adguard-backup";
+
+/// Version of the backup layout; newer backups are refused
+pub const BACKUP_VERSION: u64 = 1;
+
+/// Files of a backup keyed by their path inside the archive, always with `/`
+pub type BackupFiles = BTreeMap<String, String>;
+
+/// What a backup holds and which versions wrote it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BackupManifest {
+    pub version: u64,
+    pub app_version: String,
+    pub db_version: u64,
+    /// RFC 3339 local time the backup was written
+    pub created_at: String,
+    pub includes_history: bool,
+}
+
+impl BackupManifest {
+    /// Manifest for a backup written now by this build
+    pub fn new(includes_history: bool) -> Self {
+        BackupManifest {
+            version: BACKUP_VERSION,
+            app_version: env!("CARGO_PKG_VERSION").to_string(),
+            db_version: DB_VERSION,
+            created_at: chrono::Local::now().to_rfc3339(),
+            includes_history,
+        }
+    }
+
+    fn to_json(&self, files: &BackupFiles) -> serde_json::Value {
+        serde_json::json!({
+            "format": BACKUP_FORMAT,
+            "version": self.version,
+            "app_version": self.app_version,
+            "db_version": self.db_version,
+            "created_at": self.created_at,
+            "includes_history": self.includes_history,
+            "files": files.keys().collect::<Vec<_>>(),
+        })
+    }
+
+    /// Read a manifest, refusing other formats and versions newer than this
+    /// build understands
+    fn from_json(manifest: &serde_json::Value) -> Result<Self, anyhow::Error> {
+        if manifest.get("format").and_then(|v| v.as_str()) != Some(BACKUP_FORMAT) {
+            return Err(anyhow::anyhow!("Not an adguard backup"));
+        }
+        let version = manifest
+            .get("version")
+            .and_then(|v| v.as_u64())
+            .ok_or_else(|| anyhow::anyhow!("Backup manifest is missing its version"))?;
+        if version > BACKUP_VERSION {
+            return Err(anyhow::anyhow!(
+                "Unsupported backup version {} (expected at most {})",
+                version,
+                BACKUP_VERSION
+            ));
+        }
+        let db_version = manifest
+            .get("db_version")
+            .and_then(|v| v.as_u64())
+            .ok_or_else(|| anyhow::anyhow!("Backup manifest is missing the database version"))?;
+        if db_version > DB_VERSION {
+            return Err(anyhow::anyhow!(
+                "Backup database version {} is newer than this app supports ({})",
+                db_version,
+                DB_VERSION
+            ));
+        }
+        let text = |key: &str| {
+            manifest
+                .get(key)
+                .and_then(|v| v.as_str())
+                .unwrap_or_default()
+                .to_string()
+        };
+        Ok(BackupManifest {
+            version,
+            app_version: text("app_version"),
+            db_version,
+            created_at: text("created_at"),
+            includes_history: manifest
+                .get("includes_history")
+                .and_then(|v| v.as_bool())
+                .unwrap_or(false),
+        })
+    }
+}
+
+/// Where a file of a backup belongs once restored
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BackupEntry<'a> {
+    Config,
+    Db,
+    /// A file of a named profile's directory
+    Profile {
+        name: &'a str,
+        file: &'a str,
+    },
+    /// A history snapshot, by filename
+    History(&'a str),
+}
+
+impl<'a> BackupEntry<'a> {
+    /// Classify a path inside a backup; anything unexpected, including paths
+    /// that could escape the restore directories, is an error
+    pub fn parse(path: &'a str) -> Result<Self, anyhow::Error> {
+        let invalid = || anyhow::anyhow!("Unexpected file in backup: {}", path);
+        let safe = Path::new(path)
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)));
+        if !safe || path.contains('\\') {
+            return Err(invalid());
+        }
+        let parts: Vec<&str> = path.split('/').collect();
+        match parts.as_slice() {
+            ["config.ini"] => Ok(BackupEntry::Config),
+            ["db.json"] => Ok(BackupEntry::Db),
+            ["profiles", name, file @ ("profile.ini" | "db.json")] => {
+                validate_profile_name(name)?;
+                Ok(BackupEntry::Profile { name, file })
+            }
+            ["history", filename] => {
+                check_snapshot_name(filename)?;
+                Ok(BackupEntry::History(filename))
+            }
+            _ => Err(invalid()),
+        }
+    }
+}
+
+/// Write files and their manifest to `dest` as gzip-compressed JSON
+pub fn write_backup(
+    dest: &Path,
+    manifest: &BackupManifest,
+    files: &BackupFiles,
+) -> Result<(), anyhow::Error> {
+    let archive = serde_json::json!({
+        "manifest": manifest.to_json(files),
+        "files": files,
+    });
+    if let Some(parent) = dest.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let mut encoder = GzEncoder::new(fs::File::create(dest)?, Compression::default());
+    encoder.write_all(serde_json::to_string(&archive)?.as_bytes())?;
+    encoder.finish()?.sync_all()?;
+    Ok(())
+}
+
+/// Suffix of a restored file written next to its target before the swap
+const STAGED_SUFFIX: &str = ".restoring";
+
+/// Suffix the replaced file is kept under until the whole restore is in place
+const REPLACED_SUFFIX: &str = ".replaced";
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(suffix);
+    path.with_file_name(name)
+}
+
+/// Write the files of a restore, all of them or none
+///
+/// Each file is first written next to its target, then the targets are
+/// swapped in by rename. If anything fails, the files replaced so far are
+/// put back and the directories the restore created are removed.
+pub fn write_restored_files(files: &BTreeMap<PathBuf, String>) -> Result<(), anyhow::Error> {
+    let mut created_dirs: Vec<PathBuf> = Vec::new();
+    let mut swapped: Vec<(&Path, bool)> = Vec::new();
+    let result = (|| -> Result<(), anyhow::Error> {
+        for (path, content) in files {
+            let parent = path.parent().unwrap_or(Path::new("."));
+            if let Some(outermost) = parent.ancestors().take_while(|dir| !dir.exists()).last() {
+                created_dirs.push(outermost.to_path_buf());
+                fs::create_dir_all(parent)?;
+            }
+            fs::write(with_suffix(path, STAGED_SUFFIX), content)?;
+        }
+        for path in files.keys() {
+            let replacing = path.exists();
+            if replacing {
+                fs::rename(path, with_suffix(path, REPLACED_SUFFIX))?;
+            }
+            swapped.push((path, replacing));
+            fs::rename(with_suffix(path, STAGED_SUFFIX), path)?;
+        }
+        Ok(())
+    })();
+
+    if let Err(e) = result {
+        for (path, replacing) in swapped.into_iter().rev() {
+            let undone = if replacing {
+                fs::rename(with_suffix(path, REPLACED_SUFFIX), path)
+            } else {
+                fs::remove_file(path)
+            };
+            if let Err(undo_error) = undone {
+                tracing::warn!(
+                    "Failed to roll back restored file {:?}: {}",
+                    path,
+                    undo_error
+                );
+            }
+        }
+        for path in files.keys() {
+            let _ = fs::remove_file(with_suffix(path, STAGED_SUFFIX));
+        }
+        for dir in created_dirs.iter().rev() {
+            let _ = fs::remove_dir_all(dir);
+        }
+        return Err(e);
+    }
+    for path in files.keys() {
+        let _ = fs::remove_file(with_suffix(path, REPLACED_SUFFIX));
+    }
+    Ok(())
+}
+
+/// Read a backup written by `write_backup`, validating its manifest and the
+/// path of every file before anything is restored
+pub fn read_backup(src: &Path) -> Result<(BackupManifest, BackupFiles), anyhow::Error> {
+    let mut content = String::new();
+    GzDecoder::new(fs::File::open(src)?)
+        .read_to_string(&mut content)
+        .map_err(|e| anyhow::anyhow!("Not a readable backup archive: {}", e))?;
+    let archive: serde_json::Value = serde_json::from_str(&content)?;
+
+    let manifest = archive
+        .get("manifest")
+        .ok_or_else(|| anyhow::anyhow!("Backup is missing its manifest"))?;
+    let parsed = BackupManifest::from_json(manifest)?;
+
+    let files: BackupFiles = archive
+        .get("files")
+        .and_then(|v| v.as_object())
+        .ok_or_else(|| anyhow::anyhow!("Backup is missing its files"))?
+        .iter()
+        .map(|(path, content)| {
+            let content = content
+                .as_str()
+                .ok_or_else(|| anyhow::anyhow!("Backup file {} is not text", path))?;
+            Ok((path.clone(), content.to_string()))
+        })
+        .collect::<Result<_, anyhow::Error>>()?;
+
+    // The manifest lists every file, so a truncated or edited archive shows up here
+    let listed: Vec<&str> = manifest
+        .get("files")
+        .and_then(|v| v.as_array())
+        .map(|listed| listed.iter().filter_map(|v| v.as_str()).collect())
+        .unwrap_or_default();
+    if listed.len() != files.len() || listed.iter().any(|path| !files.contains_key(*path)) {
+        return Err(anyhow::anyhow!("Backup files don't match its manifest"));
+    }
+    for path in files.keys() {
+        BackupEntry::parse(path)?;
+    }
+    for required in ["config.ini", "db.json"] {
+        if !files.contains_key(required) {
+            return Err(anyhow::anyhow!("Backup is missing {}", required));
+        }
+    }
+    Ok((parsed, files))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_backup_manifest_validation() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("backup.gz");
+        let files: BackupFiles = [
+            ("config.ini".to_string(), "[blocking]\n".to_string()),
+            ("db.json".to_string(), "{}".to_string()),
+        ]
+        .into();
+        write_backup(&path, &BackupManifest::new(false), &files).unwrap();
+        let (manifest, read) = read_backup(&path).unwrap();
+        assert_eq!(manifest.version, BACKUP_VERSION);
+        assert_eq!(read, files);
+
+        let newer = BackupManifest {
+            version: BACKUP_VERSION + 1,
+            ..BackupManifest::new(false)
+        };
+        write_backup(&path, &newer, &files).unwrap();
+        assert!(read_backup(&path).is_err());
+
+        let newer_db = BackupManifest {
+            db_version: DB_VERSION + 1,
+            ..BackupManifest::new(false)
+        };
+        write_backup(&path, &newer_db, &files).unwrap();
+        assert!(read_backup(&path).is_err());
+
+        for path in [
+            "../config.ini",
+            "/etc/hosts",
+            "profiles/../../x/db.json",
+            "history/a/b",
+            "history/",
+        ] {
+            assert!(BackupEntry::parse(path).is_err(), "{}", path);
+        }
+        assert_eq!(
+            BackupEntry::parse("profiles/work/db.json").unwrap(),
+            BackupEntry::Profile {
+                name: "work",
+                file: "db.json"
+            }
+        );
+    }
+
+    #[test]
+    fn test_restored_files_are_all_or_nothing() {
+        let temp_dir = TempDir::new().unwrap();
+        let config = temp_dir.path().join("config.ini");
+        let profile_db = temp_dir.path().join("profiles/work/db.json");
+        fs::write(&config, "[paths]\n").unwrap();
+        fs::create_dir_all(with_suffix(&profile_db, REPLACED_SUFFIX).join("stale")).unwrap();
+        fs::write(&profile_db, "{\"work\": true}").unwrap();
+        let files: BTreeMap<PathBuf, String> = [
+            (config.clone(), "[paths]\nrestored\n".to_string()),
+            (temp_dir.path().join("db.json"), "{}".to_string()),
+            (profile_db.clone(), "{}".to_string()),
+        ]
+        .into();
+
+        // The profile database can't be moved aside, after the others were swapped in
+        assert!(write_restored_files(&files).is_err());
+        assert_eq!(fs::read_to_string(&config).unwrap(), "[paths]\n");
+        assert!(!temp_dir.path().join("db.json").exists());
+        assert_eq!(fs::read_to_string(&profile_db).unwrap(), "{\"work\": true}");
+        let names: Vec<String> = fs::read_dir(profile_db.parent().unwrap())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .filter(|name| name.ends_with(STAGED_SUFFIX))
+            .collect();
+        assert!(names.is_empty());
+
+        fs::remove_dir_all(with_suffix(&profile_db, REPLACED_SUFFIX)).unwrap();
+        write_restored_files(&files).unwrap();
+        assert_eq!(fs::read_to_string(&config).unwrap(), "[paths]\nrestored\n");
+        assert_eq!(fs::read_to_string(&profile_db).unwrap(), "{}");
+        assert!(!with_suffix(&config, REPLACED_SUFFIX).exists());
+    }
+}
@@ -0,0 +1,153 @@
+use futures_util::stream::{self, StreamExt};
+use std::future::Future;
+use std::net::{IpAddr, ToSocketAddrs};
+use std::time::Duration;
+
+/// Lookups run at once when checking a batch
+pub const BLOCK_CHECK_CONCURRENCY: usize = 8;
+
+/// Time allowed for a whole batch; lookups not done by then are reported as errors
+pub const BLOCK_CHECK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Whether a domain resolves to a sink
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BlockCheck {
+    /// Every address the name resolved to is a sink
+    Blocked,
+    /// The name resolved to at least one address that isn't a sink
+    NotBlocked(Vec<IpAddr>),
+    /// The lookup failed or ran out of time
+    Error(String),
+}
+
+/// Addresses of a name as the system resolver, which reads the hosts file, answers
+pub fn resolve_system(domain: &str) -> Result<Vec<IpAddr>, anyhow::Error> {
+    Ok((domain, 0)
+        .to_socket_addrs()?
+        .map(|address| address.ip())
+        .collect())
+}
+
+/// Resolve each domain through the system resolver, at most
+/// `BLOCK_CHECK_CONCURRENCY` at once and within `BLOCK_CHECK_TIMEOUT` overall
+pub async fn check_blocking_batch(
+    domains: &[String],
+    is_sink: impl Fn(&IpAddr) -> bool,
+) -> Vec<BlockCheck> {
+    check_blocking_with(
+        domains,
+        BLOCK_CHECK_CONCURRENCY,
+        BLOCK_CHECK_TIMEOUT,
+        is_sink,
+        |domain| async move {
+            tauri::async_runtime::spawn_blocking(move || resolve_system(&domain))
+                .await
+                .map_err(|e| anyhow::anyhow!("Lookup task failed: {}", e))?
+        },
+    )
+    .await
+}
+
+/// `check_blocking_batch` with the limits and the lookup itself supplied
+/// Results are in the order of `domains`
+pub async fn check_blocking_with<S, F, Fut>(
+    domains: &[String],
+    concurrency: usize,
+    timeout: Duration,
+    is_sink: S,
+    resolve: F,
+) -> Vec<BlockCheck>
+where
+    S: Fn(&IpAddr) -> bool,
+    F: Fn(String) -> Fut,
+    Fut: Future<Output = Result<Vec<IpAddr>, anyhow::Error>>,
+{
+    // One deadline for the batch, so queued lookups don't each get the full timeout
+    let deadline = tokio::time::Instant::now() + timeout;
+    let lookups: Vec<_> = domains
+        .iter()
+        .enumerate()
+        .map(|(index, domain)| {
+            let lookup = tokio::time::timeout_at(deadline, resolve(domain.clone()));
+            async move { (index, lookup.await) }
+        })
+        .collect();
+    let mut results: Vec<_> = stream::iter(lookups)
+        .buffer_unordered(concurrency.max(1))
+        .collect()
+        .await;
+    results.sort_by_key(|(index, _)| *index);
+
+    results
+        .into_iter()
+        .map(|(_, result)| match result {
+            Err(_) => {
+                BlockCheck::Error(format!("Timed out after {} seconds", timeout.as_secs_f32()))
+            }
+            Ok(Err(e)) => BlockCheck::Error(e.to_string()),
+            Ok(Ok(addresses)) if addresses.is_empty() => {
+                BlockCheck::Error("No addresses returned".to_string())
+            }
+            Ok(Ok(addresses)) if addresses.iter().all(&is_sink) => BlockCheck::Blocked,
+            Ok(Ok(addresses)) => BlockCheck::NotBlocked(addresses),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::ParseOptions;
+
+    #[test]
+    fn test_check_blocking_with_mock_resolver() {
+        let domains: Vec<String> = [
+            "ads.example.com",
+            "v6.example.com",
+            "real.example.com",
+            "missing.example.invalid",
+            "slow.example.com",
+        ]
+        .iter()
+        .map(|domain| domain.to_string())
+        .collect();
+        let options = ParseOptions::default();
+
+        let results = tauri::async_runtime::block_on(check_blocking_with(
+            &domains,
+            2,
+            Duration::from_millis(200),
+            |ip| options.is_sink(ip),
+            |domain| async move {
+                match domain.as_str() {
+                    "ads.example.com" => Ok(vec!["0.0.0.0".parse().unwrap()]),
+                    "v6.example.com" => Ok(vec!["::".parse().unwrap()]),
+                    "real.example.com" => Ok(vec![
+                        "127.0.0.1".parse().unwrap(),
+                        "93.184.216.34".parse().unwrap(),
+                    ]),
+                    "slow.example.com" => {
+                        tokio::time::sleep(Duration::from_secs(5)).await;
+                        Ok(Vec::new())
+                    }
+                    _ => Err(anyhow::anyhow!("Name or service not known")),
+                }
+            },
+        ));
+
+        assert_eq!(results[0], BlockCheck::Blocked);
+        assert_eq!(results[1], BlockCheck::Blocked);
+        assert_eq!(
+            results[2],
+            BlockCheck::NotBlocked(vec![
+                "127.0.0.1".parse().unwrap(),
+                "93.184.216.34".parse().unwrap()
+            ])
+        );
+        assert_eq!(
+            results[3],
+            BlockCheck::Error("Name or service not known".to_string())
+        );
+        assert!(matches!(&results[4], BlockCheck::Error(e) if e.starts_with("Timed out")));
+    }
+}
@@ -0,0 +1,158 @@
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+
+/// Number of bits allocated per expected item (about 1% false positives)
+const BITS_PER_ITEM: usize = 10;
+
+/// Number of hash functions
+const HASHES: u64 = 7;
+
+/// Smallest filter allocated, in bits
+const MIN_BITS: usize = 1024;
+
+/// Bloom filter for fast negative membership answers
+///
+/// Removing is not supported: removed items keep their bits until the next
+/// rebuild, which only costs extra false positives.
+#[derive(Debug, Clone)]
+pub struct BloomFilter {
+    bits: Vec<u64>,
+    /// Number of items the filter was sized for
+    capacity: usize,
+    /// Number of items inserted
+    len: usize,
+}
+
+impl BloomFilter {
+    /// Create an empty filter sized for `capacity` items
+    pub fn with_capacity(capacity: usize) -> Self {
+        let bits = (capacity * BITS_PER_ITEM).max(MIN_BITS).next_power_of_two();
+        BloomFilter {
+            bits: vec![0; bits / 64],
+            capacity,
+            len: 0,
+        }
+    }
+
+    /// Build a filter holding every item
+    pub fn from_items<'a>(items: impl ExactSizeIterator<Item = &'a str>) -> Self {
+        let mut filter = Self::with_capacity(items.len());
+        for item in items {
+            filter.insert(item);
+        }
+        filter
+    }
+
+    /// Whether the filter holds more items than it was sized for
+    #[inline]
+    pub fn is_saturated(&self) -> bool {
+        self.len > self.capacity.max(MIN_BITS / BITS_PER_ITEM)
+    }
+
+    pub fn insert(&mut self, item: &str) {
+        for bit in self.bit_indexes(item) {
+            self.bits[bit / 64] |= 1 << (bit % 64);
+        }
+        self.len += 1;
+    }
+
+    /// False means the item was never inserted; true needs confirming
+    pub fn might_contain(&self, item: &str) -> bool {
+        self.bit_indexes(item)
+            .all(|bit| self.bits[bit / 64] & (1 << (bit % 64)) != 0)
+    }
+
+    /// Bit positions for an item (double hashing over two seeded hashes)
+    fn bit_indexes(&self, item: &str) -> impl Iterator<Item = usize> {
+        let first = seeded_hash(item, 0);
+        let second = seeded_hash(item, 1) | 1;
+        let mask = (self.bits.len() * 64 - 1) as u64;
+        (0..HASHES).map(move |i| (first.wrapping_add(i.wrapping_mul(second)) & mask) as usize)
+    }
+}
+
+#[inline]
+fn seeded_hash(item: &str, seed: u64) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    seed.hash(&mut hasher);
+    item.hash(&mut hasher);
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeSet;
+    use std::time::Instant;
+
+    #[test]
+    fn test_no_false_negatives() {
+        let domains: Vec<String> = (0..5000).map(|i| format!("ads{}.example.com", i)).collect();
+        let filter = BloomFilter::from_items(domains.iter().map(String::as_str));
+
+        assert!(domains.iter().all(|domain| filter.might_contain(domain)));
+    }
+
+    #[test]
+    fn test_false_positive_rate() {
+        let filter = BloomFilter::from_items(
+            (0..5000)
+                .map(|i| format!("ads{}.example.com", i))
+                .collect::<Vec<_>>()
+                .iter()
+                .map(String::as_str),
+        );
+
+        let false_positives = (0..10000)
+            .filter(|i| filter.might_contain(&format!("clean{}.example.org", i)))
+            .count();
+        assert!(false_positives < 300, "{} false positives", false_positives);
+    }
+
+    #[test]
+    fn test_saturation() {
+        let mut filter = BloomFilter::with_capacity(0);
+        assert!(!filter.is_saturated());
+        for i in 0..MIN_BITS {
+            filter.insert(&format!("ads{}.example.com", i));
+        }
+        assert!(filter.is_saturated());
+    }
+
+    /// Compare negative lookups against the BTreeSet alone
+    /// Run with `cargo test --release bench_membership -- --ignored --nocapture`
+    #[test]
+    #[ignore]
+    fn bench_membership() {
+        let domains: BTreeSet<String> = (0..80_000)
+            .map(|i| format!("ads{}.tracker{}.example.com", i, i % 97))
+            .collect();
+        let filter = BloomFilter::from_items(domains.iter().map(String::as_str));
+        let queries: Vec<String> = (0..200_000)
+            .map(|i| format!("www{}.news{}.example.org", i, i % 13))
+            .collect();
+
+        let started = Instant::now();
+        let set_hits = queries
+            .iter()
+            .filter(|q| domains.contains(q.as_str()))
+            .count();
+        let set_time = started.elapsed();
+
+        let started = Instant::now();
+        let filter_hits = queries
+            .iter()
+            .filter(|q| filter.might_contain(q) && domains.contains(q.as_str()))
+            .count();
+        let filter_time = started.elapsed();
+
+        assert_eq!(set_hits, filter_hits);
+        println!(
+            "{} negative lookups over {} domains: BTreeSet {:?}, bloom + BTreeSet {:?}",
+            queries.len(),
+            domains.len(),
+            set_time,
+            filter_time
+        );
+    }
+}
@@ -0,0 +1,136 @@
+use crate::utils::registrable_domain;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+/// Category of a blocked domain, for display
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Category {
+    Advertising,
+    Tracking,
+    Malware,
+    Social,
+    Unknown,
+}
+
+impl Category {
+    pub const ALL: [Category; 5] = [
+        Category::Advertising,
+        Category::Tracking,
+        Category::Malware,
+        Category::Social,
+        Category::Unknown,
+    ];
+
+    #[inline]
+    pub fn to_str(self) -> &'static str {
+        match self {
+            Category::Advertising => "advertising",
+            Category::Tracking => "tracking",
+            Category::Malware => "malware",
+            Category::Social => "social",
+            Category::Unknown => "unknown",
+        }
+    }
+}
+
+/// Bundled category mapping, keyed by registrable domain and sorted for binary search
+const CATEGORIES: &[(&str, Category)] = &[
+    ("adcolony.com", Category::Advertising),
+    ("adnxs.com", Category::Advertising),
+    ("adsrvr.org", Category::Advertising),
+    ("amazon-adsystem.com", Category::Advertising),
+    ("amplitude.com", Category::Tracking),
+    ("appsflyer.com", Category::Tracking),
+    ("chartbeat.com", Category::Tracking),
+    ("coinhive.com", Category::Malware),
+    ("criteo.com", Category::Advertising),
+    ("doubleclick.net", Category::Advertising),
+    ("facebook.com", Category::Social),
+    ("facebook.net", Category::Social),
+    ("fbcdn.net", Category::Social),
+    ("google-analytics.com", Category::Tracking),
+    ("googleadservices.com", Category::Advertising),
+    ("googlesyndication.com", Category::Advertising),
+    ("googletagmanager.com", Category::Tracking),
+    ("hotjar.com", Category::Tracking),
+    ("instagram.com", Category::Social),
+    ("linkedin.com", Category::Social),
+    ("mixpanel.com", Category::Tracking),
+    ("moatads.com", Category::Advertising),
+    ("outbrain.com", Category::Advertising),
+    ("pinterest.com", Category::Social),
+    ("pubmatic.com", Category::Advertising),
+    ("quantserve.com", Category::Tracking),
+    ("rubiconproject.com", Category::Advertising),
+    ("scorecardresearch.com", Category::Tracking),
+    ("segment.io", Category::Tracking),
+    ("snapchat.com", Category::Social),
+    ("taboola.com", Category::Advertising),
+    ("tiktok.com", Category::Social),
+    ("twitter.com", Category::Social),
+    ("wicar.org", Category::Malware),
+    ("x.com", Category::Social),
+];
+
+/// Look up the category of a hostname by its registrable domain
+#[inline]
+pub fn categorize_domain(hostname: &str) -> Category {
+    let domain = registrable_domain(hostname).to_ascii_lowercase();
+    CATEGORIES
+        .binary_search_by(|(candidate, _)| (*candidate).cmp(domain.as_str()))
+        .map(|index| CATEGORIES[index].1)
+        .unwrap_or(Category::Unknown)
+}
+
+/// Count blocked domains per category (every category is present, possibly zero)
+pub fn category_breakdown<'a>(
+    hostnames: impl IntoIterator<Item = &'a Arc<str>>,
+) -> BTreeMap<Category, usize> {
+    let mut counts: BTreeMap<Category, usize> = Category::ALL
+        .iter()
+        .map(|category| (*category, 0))
+        .collect();
+    for hostname in hostnames {
+        *counts.entry(categorize_domain(hostname)).or_default() += 1;
+    }
+    counts
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dataset_is_sorted() {
+        assert!(CATEGORIES.windows(2).all(|pair| pair[0].0 < pair[1].0));
+    }
+
+    #[test]
+    fn test_categorize_domain() {
+        assert_eq!(
+            categorize_domain("stats.g.doubleclick.net"),
+            Category::Advertising
+        );
+        assert_eq!(
+            categorize_domain("www.google-analytics.com"),
+            Category::Tracking
+        );
+        assert_eq!(categorize_domain("Connect.Facebook.NET"), Category::Social);
+        assert_eq!(categorize_domain("malware.wicar.org"), Category::Malware);
+        assert_eq!(categorize_domain("example.com"), Category::Unknown);
+    }
+
+    #[test]
+    fn test_category_breakdown() {
+        let hostnames: Vec<Arc<str>> = vec![
+            "ads.doubleclick.net".into(),
+            "criteo.com".into(),
+            "example.com".into(),
+        ];
+        let counts = category_breakdown(&hostnames);
+
+        assert_eq!(counts[&Category::Advertising], 2);
+        assert_eq!(counts[&Category::Unknown], 1);
+        assert_eq!(counts[&Category::Social], 0);
+    }
+}
@@ -1,12 +1,40 @@
-use crate::commit::commit_changes;
-use crate::history::{list_history_entries, rollback_to_history};
-use crate::parser::parse_hosts;
-use crate::platform::{default_hosts_file_path, is_elevated};
-use crate::state::AppState;
-use std::collections::BTreeSet;
-use std::path::PathBuf;
+use crate::backup::BackupManifest;
+use crate::blockcheck::BlockCheck;
+use crate::categories::category_breakdown;
+use crate::commit::{
+    commit_changes, find_recovery_candidate, recover_from_temp, RecoveryCandidate,
+};
+use crate::config::{Config, SafeListPolicy};
+use crate::diagnostics::Diagnostics;
+use crate::history::{
+    cleanup_old_history, is_snapshot_filename, is_valid_history_prefix, list_history_entries,
+    read_snapshot, rollback_to_history, trash_dir, verify_hosts_content, verify_localhost_mapping,
+    write_snapshot, HistoryEntry,
+};
+use crate::import::{
+    check_safe_list, fetch_source, fetch_sources, find_import_conflicts, override_sink,
+    parse_pihole_export, parse_sink_for, parse_source, parse_source_metadata, read_pihole_export,
+    read_source_file, strip_source_comments, ImportOptions, PendingImport, Resolution,
+    SourceFormat,
+};
+use crate::parser::{
+    is_valid_disabled_marker, parse_hosts_with, ExportFormat, LayoutStyle, LineClass, ParseOptions,
+    ParsedHosts, PreservedLine, PARSER_CAPABILITIES,
+};
+use crate::platform::{default_hosts_file_path, dns_flush_capability, is_elevated, system_theme};
+use crate::schedule::{run_at, unix_now};
+use crate::sources::{bundle_json, merge_sources, parse_bundle, sources_to_fetch};
+use crate::stale::{
+    find_stale_with, query_dns, sample_domains, DEFAULT_STALE_RESOLVER, STALE_QUERY_INTERVAL,
+};
+use crate::state::{AppState, RemoveMode, SetOp, SyncStatus, MAX_PAUSE_MINUTES};
+use crate::utils::{domains_from_text, normalize_domain, registrable_domain, to_ascii_domain};
+use crate::watcher::{restart_watcher, HostsWatcher};
+use std::collections::{BTreeSet, HashMap};
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
-use tauri::State;
+use std::time::{Duration, Instant};
+use tauri::{async_runtime, AppHandle, Emitter, Manager, State};
 
 #[tauri::command]
 pub async fn get_blocked_domains(
@@ -15,40 +43,594 @@ pub async fn get_blocked_domains(
     Ok(state.get_all_blocks())
 }
 
+/// Domains added on or after the day of `unix_secs`, newest first
+/// Only domains added with annotate_additions on carry an add date
 #[tauri::command]
-pub async fn remove_domain(state: State<'_, Arc<AppState>>, hostname: &str) -> Result<(), ()> {
-    state.remove_block(hostname);
+pub fn export_added_since(state: State<'_, Arc<AppState>>, unix_secs: u64) -> Vec<Arc<str>> {
+    state.added_since(unix_secs)
+}
+
+/// Blocked and disabled domains for the domain list, each with the address
+/// it's written under, whether it's enabled, when it was added and where it
+/// came from ("manual" and source URLs)
+#[tauri::command]
+pub fn get_blocked_domains_detailed(state: State<'_, Arc<AppState>>) -> Vec<serde_json::Value> {
+    state
+        .get_blocked_detailed()
+        .into_iter()
+        .map(|domain| {
+            serde_json::json!({
+                "hostname": domain.hostname,
+                "display": domain.display,
+                "sink": domain.sink.to_string(),
+                "enabled": domain.enabled,
+                "added_on": domain.added_on,
+                "source": domain.source,
+            })
+        })
+        .collect()
+}
+
+/// Unblock a domain; `mode` "disable" keeps it in the hosts file commented
+/// out so it can be enabled again, "delete" (the default) drops it
+#[tauri::command]
+pub async fn remove_domain(
+    state: State<'_, Arc<AppState>>,
+    hostname: &str,
+    mode: Option<String>,
+) -> Result<(), String> {
+    let mode = mode
+        .as_deref()
+        .map(RemoveMode::from_str)
+        .transpose()
+        .map_err(|e| e.to_string())?
+        .unwrap_or_default();
+    let hostname = to_ascii_domain(hostname.trim()).unwrap_or_else(|_| hostname.to_string());
+    match mode {
+        RemoveMode::Delete => {
+            state.remove_block(&hostname);
+            state.audit("remove", &hostname);
+        }
+        RemoveMode::Disable => {
+            if state.disable_block(&hostname) {
+                state.audit("disable", &hostname);
+            }
+        }
+    }
     Ok(())
 }
 
+/// Block a disabled domain again
 #[tauri::command]
-pub async fn add_domain(state: State<'_, Arc<AppState>>, hostname: &str) -> Result<(), String> {
-    state.add_block(hostname);
+pub async fn enable_domain(state: State<'_, Arc<AppState>>, hostname: &str) -> Result<(), String> {
+    let hostname = to_ascii_domain(hostname.trim()).unwrap_or_else(|_| hostname.to_string());
+    if !state.enable_block(&hostname) {
+        return Err(format!("{} is not disabled", hostname));
+    }
+    state.audit("enable", &hostname);
     Ok(())
 }
 
 #[tauri::command]
-pub async fn save_changes(state: State<'_, Arc<AppState>>) -> Result<(), String> {
+pub fn get_disabled_domains(state: State<'_, Arc<AppState>>) -> BTreeSet<Arc<str>> {
+    state.get_disabled()
+}
+
+/// Block a domain; returns false if it was already blocked or is allowlisted
+#[tauri::command]
+pub async fn add_domain(
+    state: State<'_, Arc<AppState>>,
+    hostname: &str,
+    allow_over_cap: Option<bool>,
+) -> Result<bool, String> {
+    // Internationalized names are stored as punycode
+    let hostname = to_ascii_domain(hostname.trim()).map_err(|e| e.to_string())?;
+    let new_total = state.get_total_blocked() + state.count_unblocked([hostname.as_str()]);
+    state
+        .check_domain_cap(new_total, allow_over_cap.unwrap_or(false))
+        .map_err(|e| e.to_string())?;
+    let added = state.add_block(&hostname);
+    if added {
+        state.audit("add", &hostname);
+    }
+    Ok(added)
+}
+
+#[tauri::command]
+pub fn get_allowed_domains(state: State<'_, Arc<AppState>>) -> BTreeSet<Arc<str>> {
+    state.get_allowlist()
+}
+
+#[tauri::command]
+pub fn add_allow(state: State<'_, Arc<AppState>>, hostname: &str) -> Result<bool, String> {
+    let hostname = to_ascii_domain(hostname.trim()).map_err(|e| e.to_string())?;
+    let added = state.add_allow(&hostname);
+    if added {
+        state.audit("allow", &hostname);
+    }
+    Ok(added)
+}
+
+#[tauri::command]
+pub fn remove_allow(state: State<'_, Arc<AppState>>, hostname: &str) -> bool {
+    let hostname = to_ascii_domain(hostname.trim()).unwrap_or_else(|_| hostname.to_string());
+    let removed = state.remove_allow(&hostname);
+    if removed {
+        state.audit("disallow", &hostname);
+    }
+    removed
+}
+
+/// Write the allowlist to the configured allow file
+/// Returns the number of domains written
+#[tauri::command]
+pub fn export_allow(state: State<'_, Arc<AppState>>) -> Result<usize, String> {
+    let allow_path = state
+        .get_config()
+        .allow_file_path
+        .ok_or_else(|| "No allow file is configured (set allow_file_path)".to_string())?;
+    state
+        .export_allowlist(&allow_path)
+        .map_err(|e| format!("Failed to write {}: {}", allow_path.display(), e))
+}
+
+/// Block every domain found in pasted text (URLs, bare domains, hosts lines)
+#[tauri::command]
+pub async fn add_from_text(
+    state: State<'_, Arc<AppState>>,
+    text: String,
+    allow_over_cap: Option<bool>,
+) -> Result<serde_json::Value, String> {
+    let (domains, skipped) = domains_from_text(&text);
+    let new_total =
+        state.get_total_blocked() + state.count_unblocked(domains.iter().map(String::as_str));
+    state
+        .check_domain_cap(new_total, allow_over_cap.unwrap_or(false))
+        .map_err(|e| e.to_string())?;
+
+    let mut added = Vec::new();
+    for domain in domains {
+        if state.add_block(&domain) {
+            added.push(domain);
+        }
+    }
+    if !added.is_empty() {
+        state.audit("add", &added.join(","));
+    }
+
+    Ok(serde_json::json!({
+        "added": added.len(),
+        "skipped": skipped,
+    }))
+}
+
+/// Unblock a registrable domain and all of its blocked subdomains
+#[tauri::command]
+pub async fn remove_base_domain(
+    state: State<'_, Arc<AppState>>,
+    base: String,
+) -> Result<usize, String> {
+    let base = normalize_domain(&base).ok_or_else(|| format!("Invalid domain: {}", base))?;
+    let removed = state.remove_base_domain(&base);
+    if !removed.is_empty() {
+        state.audit("remove", &removed.join(","));
+    }
+    Ok(removed.len())
+}
+
+/// Combine the blocked domains with a list: "union" blocks the listed domains,
+/// "difference" unblocks them and "intersection" unblocks everything not listed
+/// Returns how many domains are blocked afterwards
+#[tauri::command]
+pub async fn apply_set_operation(
+    state: State<'_, Arc<AppState>>,
+    op: String,
+    domains: Vec<String>,
+    allow_over_cap: Option<bool>,
+) -> Result<usize, String> {
+    let op = SetOp::from_str(&op).map_err(|e| e.to_string())?;
+    let domains = domains
+        .iter()
+        .map(|hostname| to_ascii_domain(hostname.trim()))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| e.to_string())?;
+    let before = state.get_total_blocked();
+    if op == SetOp::Union {
+        let new_total = before + state.count_unblocked(domains.iter().map(String::as_str));
+        state
+            .check_domain_cap(new_total, allow_over_cap.unwrap_or(false))
+            .map_err(|e| e.to_string())?;
+    }
+    let total = state.apply_set_operation(op, &domains);
+    state.audit(
+        "set-operation",
+        &format!(
+            "{} with {} domains ({} -> {} blocked)",
+            op.to_str(),
+            domains.len(),
+            before,
+            total
+        ),
+    );
+    Ok(total)
+}
+
+/// Whether this is the app's first launch, so the UI can offer the starter list
+#[tauri::command]
+pub fn is_first_run(state: State<'_, Arc<AppState>>) -> bool {
+    *state.first_run.lock()
+}
+
+/// Block the bundled starter list, returning how many domains were added
+/// Only called once the user accepts the first-run offer
+#[tauri::command]
+pub async fn seed_starter_list(
+    state: State<'_, Arc<AppState>>,
+    allow_over_cap: Option<bool>,
+) -> Result<usize, String> {
+    let added = state
+        .seed_starter_list(allow_over_cap.unwrap_or(false))
+        .map_err(|e| e.to_string())?;
+    state.audit("seed", &format!("starter list ({} domains added)", added));
+    Ok(added)
+}
+
+/// Block a registrable domain itself (its subdomains are listed separately)
+/// Returns 1 if it was added, 0 if it was already blocked, allowlisted or local
+#[tauri::command]
+pub async fn block_base_domain(
+    state: State<'_, Arc<AppState>>,
+    base: String,
+    allow_over_cap: Option<bool>,
+) -> Result<usize, String> {
+    let hostname = normalize_domain(&base).ok_or_else(|| format!("Invalid domain: {}", base))?;
+    let base = registrable_domain(&hostname);
+    // Nothing to do for a base that's already blocked, allowlisted or local
+    if state.count_unblocked([base]) == 0 {
+        return Ok(0);
+    }
+    state
+        .check_domain_cap(
+            state.get_total_blocked() + 1,
+            allow_over_cap.unwrap_or(false),
+        )
+        .map_err(|e| e.to_string())?;
+    if !state.add_block(base) {
+        return Ok(0);
+    }
+    state.audit("add", base);
+    Ok(1)
+}
+
+/// Why a domain is or isn't blocked: whether it was added by hand, which
+/// sources list it, and whether the allowlist or a local name keeps it unblocked
+#[tauri::command]
+pub fn explain_domain(state: State<'_, Arc<AppState>>, hostname: String) -> serde_json::Value {
+    let explanation = state.explain(&hostname);
+    serde_json::json!({
+        "hostname": explanation.hostname,
+        "display": explanation.display,
+        "blocked": explanation.blocked,
+        "local": explanation.local,
+        "preserved": explanation.preserved,
+        "manual": explanation.manual,
+        "sources": explanation.sources,
+        "allowlisted": explanation.allowlisted,
+        "wildcard": explanation.wildcard,
+    })
+}
+
+#[tauri::command]
+pub fn request_clear_token(state: State<'_, Arc<AppState>>) -> String {
+    state.issue_clear_token().to_string()
+}
+
+#[tauri::command]
+pub async fn clear_all_blocks(
+    state: State<'_, Arc<AppState>>,
+    confirm_token: String,
+) -> Result<usize, String> {
+    state
+        .consume_clear_token(&confirm_token)
+        .map_err(|e| format!("Refusing to clear blocked domains: {}", e))?;
+
+    // Snapshot current state so the wipe can be rolled back
+    if let Some(history) = state.get_config().history_settings() {
+        write_snapshot(&history, &state.serialize())
+            .map_err(|e| format!("Failed to write history snapshot: {}", e))?;
+        cleanup_old_history(&history.dir, &history.prefix, history.max_entries)
+            .map_err(|e| format!("Failed to clean up history: {}", e))?;
+    }
+
+    let cleared = state.clear_blocks();
+    state.audit("clear", &format!("{} domains", cleared));
+    Ok(cleared)
+}
+
+/// Write the hosts file; a state with no preserved lines is only written
+/// with `allow_empty`
+#[tauri::command]
+pub async fn save_changes(
+    state: State<'_, Arc<AppState>>,
+    allow_empty: Option<bool>,
+) -> Result<(), String> {
+    let debounce = state.get_config().save_debounce();
+    commit_state(
+        state.inner().clone(),
+        debounce,
+        allow_empty.unwrap_or(false),
+    )
+    .await
+}
+
+/// Write the current state to the hosts file, coalescing commits requested
+/// within `debounce` of each other
+async fn commit_state(
+    state: Arc<AppState>,
+    debounce: Duration,
+    allow_empty: bool,
+) -> Result<(), String> {
     let config = state.get_config();
+    let lock_timeout = config.lock_timeout();
+    let history = config.history_settings();
     let hosts_path = config
         .host_file_path
         .unwrap_or_else(default_hosts_file_path);
 
-    let history_dir = config.history_dir.clone();
-    let max_history = config.max_history_entries;
+    let history_policy = config.history_failure_policy;
 
-    commit_changes(state.inner().clone(), hosts_path, history_dir, max_history)
+    // Saves landing within the debounce window share a single write and DNS flush
+    state
+        .commit_coalescer
+        .run(debounce, || async {
+            commit_changes(
+                state.clone(),
+                hosts_path,
+                history,
+                history_policy,
+                lock_timeout,
+                allow_empty,
+            )
+            .await?;
+            state
+                .persist_db()
+                .map_err(|e| anyhow::anyhow!("Failed to save state database: {}", e))?;
+            state.mark_clean();
+            Ok(())
+        })
         .await
         .map_err(|e| {
             // Provide more detailed error message
             let error_msg = format!("Failed to save changes: {}", e);
-            eprintln!("{}", error_msg);
+            state.log_error("commit", error_msg.as_str());
             error_msg
         })?;
 
     Ok(())
 }
 
+/// Write the hosts file with every managed entry commented out for `minutes`,
+/// at most `MAX_PAUSE_MINUTES`
+/// Blocking resumes on its own when the time is up; if the write fails, the
+/// pause is rolled back
+#[tauri::command]
+pub async fn pause_blocking(state: State<'_, Arc<AppState>>, minutes: u64) -> Result<(), String> {
+    if minutes == 0 {
+        return Err("Pause duration must be at least one minute".to_string());
+    }
+    if minutes > MAX_PAUSE_MINUTES {
+        return Err(format!(
+            "Pause duration must be at most {} minutes",
+            MAX_PAUSE_MINUTES
+        ));
+    }
+    let duration = minutes
+        .checked_mul(60)
+        .map(Duration::from_secs)
+        .ok_or_else(|| "Pause duration is out of range".to_string())?;
+    let state = state.inner().clone();
+    let previous = *state.paused_until.lock();
+    let until = state.pause_blocking(duration).map_err(|e| e.to_string())?;
+    if let Err(e) = commit_state(state.clone(), Duration::ZERO, false).await {
+        state.restore_pause(previous);
+        return Err(e);
+    }
+    state.audit("pause", &format!("{} minutes", minutes));
+    spawn_pause_expiry(state, until);
+    Ok(())
+}
+
+/// Resume blocking in the background once the pause ending at `until` is over
+pub fn spawn_pause_expiry(state: Arc<AppState>, until: Instant) {
+    async_runtime::spawn(async move {
+        tokio::time::sleep_until(until.into()).await;
+        // A resume or a newer pause in the meantime takes precedence
+        if state.resume_blocking_if(until) {
+            match commit_state(state.clone(), Duration::ZERO, false).await {
+                Ok(()) => state.audit("resume", "pause expired"),
+                Err(e) => state.log_error("pause", format!("Failed to resume blocking: {}", e)),
+            }
+        }
+    });
+}
+
+/// Commit the current changes at `at_unix_secs` instead of now, e.g. at night
+/// so the DNS flush doesn't interrupt anything; replaces any earlier schedule
+#[tauri::command]
+pub fn schedule_commit(
+    app: AppHandle,
+    state: State<'_, Arc<AppState>>,
+    at_unix_secs: u64,
+) -> Result<(), String> {
+    if at_unix_secs <= unix_now() {
+        return Err("The scheduled time has already passed".to_string());
+    }
+    state
+        .schedule_commit(at_unix_secs)
+        .map_err(|e| format!("Failed to schedule commit: {}", e))?;
+    state.audit("schedule-commit", &at_unix_secs.to_string());
+    spawn_scheduled_commit(app, state.inner().clone(), at_unix_secs);
+    Ok(())
+}
+
+/// Drop the scheduled commit, leaving the changes unsaved; returns whether
+/// one was scheduled
+#[tauri::command]
+pub fn cancel_scheduled_commit(state: State<'_, Arc<AppState>>) -> Result<bool, String> {
+    let cancelled = state
+        .cancel_scheduled_commit()
+        .map_err(|e| format!("Failed to cancel scheduled commit: {}", e))?;
+    if cancelled {
+        state.audit("cancel-scheduled-commit", "");
+    }
+    Ok(cancelled)
+}
+
+/// Commit at `at` in the background, emitting "scheduled-commit-done" once
+/// written; a cancelled or replaced schedule does nothing
+pub fn spawn_scheduled_commit(app: AppHandle, state: Arc<AppState>, at: u64) {
+    async_runtime::spawn(async move {
+        let commit = || commit_state(state.clone(), Duration::ZERO, false);
+        let Some(result) = run_at(&state, at, commit).await else {
+            return;
+        };
+        if result.is_ok() {
+            state.audit("scheduled-commit", &at.to_string());
+        }
+        let _ = app.emit(
+            "scheduled-commit-done",
+            serde_json::json!({
+                "at": at,
+                "ok": result.is_ok(),
+                "error": result.err(),
+            }),
+        );
+    });
+}
+
+/// End a pause early, writing the managed entries back
+#[tauri::command]
+pub async fn resume_blocking(state: State<'_, Arc<AppState>>) -> Result<(), String> {
+    let state = state.inner().clone();
+    if !state.resume_blocking() {
+        return Ok(());
+    }
+    commit_state(state.clone(), Duration::ZERO, false).await?;
+    state.audit("resume", "resumed manually");
+    Ok(())
+}
+
+/// Whether blocking is paused and for how much longer
+#[tauri::command]
+pub fn get_pause_status(state: State<'_, Arc<AppState>>) -> serde_json::Value {
+    let remaining = state.pause_remaining();
+    serde_json::json!({
+        "paused": remaining.is_some(),
+        "remaining_secs": remaining.map(|remaining| remaining.as_secs()),
+    })
+}
+
+/// Time a save of the current state against a scratch file in the temp
+/// directory, to gauge save latency on large lists
+/// Neither the hosts file nor the DNS cache is touched
+#[tauri::command]
+pub async fn benchmark_commit(
+    state: State<'_, Arc<AppState>>,
+) -> Result<serde_json::Value, String> {
+    let state = state.inner().clone();
+    let benchmark = async_runtime::spawn_blocking(move || {
+        // A directory of its own, so overlapping runs don't share scratch files
+        let scratch_dir = tempfile::Builder::new()
+            .prefix("adguard-benchmark-")
+            .tempdir()?;
+        crate::commit::benchmark_commit(&state, scratch_dir.path())
+    })
+    .await
+    .map_err(|e| format!("Benchmark task failed: {}", e))?
+    .map_err(|e| format!("Benchmark failed: {}", e))?;
+
+    Ok(serde_json::json!({
+        "entries": benchmark.entries,
+        "bytes": benchmark.bytes,
+        "serialize_us": benchmark.serialize_us,
+        "write_us": benchmark.write_us,
+        "last_dns_flush_ms": benchmark.last_dns_flush_ms,
+        "dns_flush": dns_flush_capability().map(|method| method.to_str()),
+        "total_us": benchmark.total_us,
+    }))
+}
+
+#[tauri::command]
+pub fn get_last_commit_timings(state: State<'_, Arc<AppState>>) -> Option<serde_json::Value> {
+    let timings = (*state.last_commit_timings.lock())?;
+    Some(serde_json::json!({
+        "serialize_ms": timings.serialize_ms,
+        "history_ms": timings.history_ms,
+        "write_ms": timings.write_ms,
+        "dns_flush_ms": timings.dns_flush_ms,
+        "total_ms": timings.total_ms,
+    }))
+}
+
+#[tauri::command]
+pub async fn pending_changes(state: State<'_, Arc<AppState>>) -> Result<serde_json::Value, String> {
+    let config = state.get_config();
+    let hosts_path = config
+        .host_file_path
+        .unwrap_or_else(default_hosts_file_path);
+
+    // A missing hosts file compares as empty
+    let disk_content = std::fs::read_to_string(&hosts_path).unwrap_or_default();
+    let pending = state
+        .pending_changes(&disk_content)
+        .map_err(|e| format!("Failed to parse hosts file: {}", e))?;
+
+    Ok(serde_json::json!({
+        "dirty": pending.dirty,
+        "differs_from_disk": pending.differs_from_disk,
+        "added": pending.added.iter().map(|h| h.to_string()).collect::<Vec<_>>(),
+        "removed": pending.removed.iter().map(|h| h.to_string()).collect::<Vec<_>>(),
+    }))
+}
+
+/// Check whether the hosts file on disk still matches what the app last
+/// loaded or saved, catching external edits the watcher missed
+#[tauri::command]
+pub async fn verify_sync(state: State<'_, Arc<AppState>>) -> Result<serde_json::Value, String> {
+    let config = state.get_config();
+    let hosts_path = config
+        .host_file_path
+        .unwrap_or_else(default_hosts_file_path);
+
+    // A missing hosts file compares as empty
+    let disk_content = std::fs::read_to_string(&hosts_path).unwrap_or_default();
+    let status = state
+        .verify_sync(&disk_content)
+        .map_err(|e| format!("Failed to parse hosts file: {}", e))?;
+
+    Ok(match status {
+        SyncStatus::InSync => serde_json::json!({ "status": "in_sync" }),
+        SyncStatus::Drifted(drift) => serde_json::json!({
+            "status": "drifted",
+            "blocked_on_disk_only": drift.blocked_on_disk_only.iter().map(|h| h.to_string()).collect::<Vec<_>>(),
+            "blocked_in_app_only": drift.blocked_in_app_only.iter().map(|h| h.to_string()).collect::<Vec<_>>(),
+            "preserved_on_disk_only": drift.preserved_on_disk_only,
+            "preserved_in_app_only": drift.preserved_in_app_only,
+        }),
+    })
+}
+
+/// Number of domains blocked and unblocked since the last save
+/// Cheaper than `pending_changes`, which compares against the file on disk
+#[tauri::command]
+pub fn get_pending_counts(state: State<'_, Arc<AppState>>) -> serde_json::Value {
+    let (added, removed) = state.pending_counts();
+    serde_json::json!({
+        "added": added,
+        "removed": removed,
+    })
+}
+
 #[tauri::command]
 pub async fn get_history_list(
     state: State<'_, Arc<AppState>>,
@@ -58,146 +640,1780 @@ pub async fn get_history_list(
         .history_dir
         .ok_or_else(|| "History directory not configured".to_string())?;
 
-    let entries =
-        list_history_entries(&history_dir).map_err(|e| format!("Failed to list history: {}", e))?;
+    let entries = list_history_entries(&history_dir, &config.history_prefix)
+        .map_err(|e| format!("Failed to list history: {}", e))?;
 
-    Ok(entries
-        .into_iter()
-        .map(|entry| {
-            serde_json::json!({
-                "filename": entry.filename,
-                "path": entry.path.to_string_lossy(),
-                "entry_count": entry.entry_count,
-                "file_size": entry.file_size,
-                "timestamp": entry.timestamp.duration_since(std::time::UNIX_EPOCH)
-                    .unwrap()
-                    .as_secs(),
-            })
-        })
-        .collect())
+    Ok(entries.into_iter().map(history_entry_json).collect())
 }
 
+/// Snapshots deleted from history that can still be restored
 #[tauri::command]
-pub async fn rollback_to(state: State<'_, Arc<AppState>>, filename: &str) -> Result<(), String> {
+pub async fn get_trash_list(
+    state: State<'_, Arc<AppState>>,
+) -> Result<Vec<serde_json::Value>, String> {
     let config = state.get_config();
     let history_dir = config
         .history_dir
         .ok_or_else(|| "History directory not configured".to_string())?;
 
-    let entries =
-        list_history_entries(&history_dir).map_err(|e| format!("Failed to list history: {}", e))?;
+    let entries = list_history_entries(&trash_dir(&history_dir), &config.history_prefix)
+        .map_err(|e| format!("Failed to list trash: {}", e))?;
+
+    Ok(entries.into_iter().map(history_entry_json).collect())
+}
+
+fn history_entry_json(entry: HistoryEntry) -> serde_json::Value {
+    serde_json::json!({
+        "filename": entry.filename,
+        "path": entry.path.to_string_lossy(),
+        "entry_count": entry.entry_count,
+        "file_size": entry.file_size,
+        "timestamp": entry.timestamp.duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs(),
+    })
+}
+
+/// Find a history entry by filename in the configured history directory
+/// Files not named like a snapshot are refused, even if they're in the directory
+fn find_history_entry(config: &Config, filename: &str) -> Result<HistoryEntry, String> {
+    let history_dir = config
+        .history_dir
+        .as_ref()
+        .ok_or_else(|| "History directory not configured".to_string())?;
+    if !is_snapshot_filename(&config.history_prefix, filename) {
+        return Err(format!("{} is not a history snapshot", filename));
+    }
+
+    let entries = list_history_entries(history_dir, &config.history_prefix)
+        .map_err(|e| format!("Failed to list history: {}", e))?;
 
-    let entry = entries
+    entries
         .into_iter()
         .find(|e| e.filename == filename)
-        .ok_or_else(|| "History entry not found".to_string())?;
+        .ok_or_else(|| "History entry not found".to_string())
+}
+
+#[tauri::command]
+pub async fn rollback_to(state: State<'_, Arc<AppState>>, filename: &str) -> Result<(), String> {
+    let config = state.get_config();
+    let entry = find_history_entry(&config, filename)?;
 
     let hosts_path = config
         .host_file_path
         .unwrap_or_else(default_hosts_file_path);
 
     rollback_to_history(&entry, &hosts_path).map_err(|e| format!("Failed to rollback: {}", e))?;
+    state.audit("rollback", filename);
 
     Ok(())
 }
 
+/// Restore the blocked domains of a history snapshot and save, keeping the
+/// current preserved lines; returns how many domains are blocked
 #[tauri::command]
-pub async fn delete_history_files(
+pub async fn rollback_blocking_only(
     state: State<'_, Arc<AppState>>,
-    filenames: Vec<String>,
-) -> Result<(), String> {
+    filename: &str,
+) -> Result<usize, String> {
     let config = state.get_config();
-    let history_dir = config
-        .history_dir
-        .ok_or_else(|| "History directory not configured".to_string())?;
+    let entry = find_history_entry(&config, filename)?;
 
-    crate::history::delete_history_files(&history_dir, &filenames)
-        .map_err(|e| format!("Failed to delete history files: {}", e))?;
+    let content =
+        read_snapshot(&entry.path).map_err(|e| format!("Failed to read history entry: {}", e))?;
+    verify_hosts_content(&content).map_err(|e| format!("Invalid history entry: {}", e))?;
+    let snapshot = parse_hosts_with(&content, &config.parse_options())
+        .map_err(|e| format!("Failed to parse history entry: {}", e))?;
 
-    Ok(())
-}
+    let total = state.replace_blocking(snapshot);
+    state.audit(
+        "rollback-blocking",
+        &format!("{} ({} domains)", filename, total),
+    );
+    commit_state(state.inner().clone(), Duration::ZERO, false).await?;
 
-#[tauri::command]
-pub fn get_config(state: State<'_, Arc<AppState>>) -> Result<serde_json::Value, String> {
-    let config = state.get_config();
-    Ok(serde_json::json!({
-        "host_file_path": config.host_file_path.as_ref().map(|p| p.to_string_lossy().to_string()),
-        "history_dir": config.history_dir.as_ref().map(|p| p.to_string_lossy().to_string()),
-        "max_history_entries": config.max_history_entries,
-        "theme": config.theme.to_str(),
-    }))
+    Ok(total)
 }
 
+/// Re-add domains blocked in a history snapshot, keeping everything currently blocked
 #[tauri::command]
-pub fn update_config(
+pub async fn rollback_merge(
     state: State<'_, Arc<AppState>>,
-    config_json: serde_json::Value,
-) -> Result<(), String> {
-    let mut config = state.get_config();
+    filename: &str,
+    allow_over_cap: Option<bool>,
+) -> Result<serde_json::Value, String> {
+    let config = state.get_config();
+    let entry = find_history_entry(&config, filename)?;
 
-    if let Some(host_path) = config_json.get("host_file_path").and_then(|v| v.as_str()) {
-        if host_path.is_empty() {
-            config.host_file_path = None;
-        } else {
-            config.host_file_path = Some(PathBuf::from(host_path).as_path().into());
-        }
-    }
+    let content =
+        read_snapshot(&entry.path).map_err(|e| format!("Failed to read history entry: {}", e))?;
+    let snapshot = parse_hosts_with(&content, &config.parse_options())
+        .map_err(|e| format!("Failed to parse history entry: {}", e))?;
+    let in_snapshot = snapshot.blocking.len();
 
-    if let Some(history_path) = config_json.get("history_dir").and_then(|v| v.as_str()) {
-        if history_path.is_empty() {
-            config.history_dir = None;
-        } else {
-            config.history_dir = Some(PathBuf::from(history_path).as_path().into());
-        }
-    }
+    check_merge_cap(&state, &snapshot, allow_over_cap)
+        .map_err(|e| format!("Refusing to roll back: {}", e))?;
+    let re_added = state.merge_blocking(snapshot);
+    state.audit(
+        "rollback-merge",
+        &format!("{} ({} domains re-added)", filename, re_added),
+    );
 
-    if let Some(max_entries) = config_json
-        .get("max_history_entries")
-        .and_then(|v| v.as_u64())
-    {
-        config.max_history_entries = max_entries as usize;
-    }
+    Ok(serde_json::json!({
+        "re_added": re_added,
+        "already_blocked": in_snapshot - re_added,
+        "total_blocked": state.get_total_blocked(),
+    }))
+}
 
-    if let Some(theme_str) = config_json.get("theme").and_then(|v| v.as_str()) {
-        config.theme = crate::config::Theme::from_str(theme_str);
-    }
+/// Refuse a merge that would take the blocked set past `max_total_domains`,
+/// unless overridden
+fn check_merge_cap(
+    state: &AppState,
+    parsed: &ParsedHosts,
+    allow_over_cap: Option<bool>,
+) -> Result<(), anyhow::Error> {
+    let new_total = state.get_total_blocked()
+        + state.count_unblocked(parsed.blocking.iter().map(|hostname| &**hostname));
+    state.check_domain_cap(new_total, allow_over_cap.unwrap_or(false))
+}
 
-    state.update_config(config);
-    Ok(())
+/// Parse another hosts file for merging into the current state
+fn parse_merge_source(state: &AppState, path: &str) -> Result<ParsedHosts, String> {
+    let content =
+        std::fs::read_to_string(path).map_err(|e| format!("Failed to read {}: {}", path, e))?;
+    parse_hosts_with(&content, &state.get_config().parse_options())
+        .map_err(|e| format!("Failed to parse {}: {}", path, e))
 }
 
+/// Domains merging another hosts file would add, without changing anything
 #[tauri::command]
-pub fn get_host_file_path(state: State<'_, Arc<AppState>>) -> String {
-    let config = state.get_config();
-    config
-        .host_file_path
-        .unwrap_or_else(default_hosts_file_path)
-        .to_string_lossy()
-        .to_string()
+pub fn merge_file_preview(
+    state: State<'_, Arc<AppState>>,
+    path: String,
+) -> Result<Vec<String>, String> {
+    let other = parse_merge_source(&state, &path)?;
+    Ok(state
+        .merge_preview(&other)
+        .iter()
+        .map(|h| h.to_string())
+        .collect())
+}
+
+/// Check imported domains against the bundled safe list
+/// Safe-listed domains are logged, and dropped if the config says to skip them
+fn screen_safe_list(state: &AppState, parsed: &mut ParsedHosts, source: &str) -> Vec<String> {
+    let policy = state.get_config().safe_list_policy;
+    let safe: Vec<String> = check_safe_list(parsed, policy)
+        .iter()
+        .map(|h| h.to_string())
+        .collect();
+    if !safe.is_empty() {
+        state.log_error(
+            "import",
+            format!(
+                "{} blocks safe-listed domains ({}): {}",
+                source,
+                match policy {
+                    SafeListPolicy::Warn => "imported anyway",
+                    SafeListPolicy::Skip => "skipped",
+                },
+                safe.join(", ")
+            ),
+        );
+    }
+    safe
 }
 
+/// Add the domains blocked in another hosts file, keeping everything currently blocked
 #[tauri::command]
-pub fn get_statistics(state: State<'_, Arc<AppState>>) -> Result<serde_json::Value, String> {
-    let total_blocked = state.get_total_blocked();
+pub fn merge_file_apply(
+    state: State<'_, Arc<AppState>>,
+    path: String,
+    sink: Option<String>,
+    allow_over_cap: Option<bool>,
+) -> Result<serde_json::Value, String> {
+    let mut other = parse_merge_source(&state, &path)?;
+    if let Some(sink) = sink {
+        let sink = parse_sink_for(&sink, &state.get_config().parse_options())
+            .map_err(|e| e.to_string())?;
+        override_sink(&mut other, sink);
+    }
+    let safe_listed = screen_safe_list(&state, &mut other, &path);
+    check_merge_cap(&state, &other, allow_over_cap)
+        .map_err(|e| format!("Refusing to merge: {}", e))?;
+    let added = state.merge_blocking(other);
+    state.audit("merge", &format!("{} ({} domains added)", path, added));
+
     Ok(serde_json::json!({
-        "total_blocked": total_blocked,
+        "added": added,
+        "safe_listed": safe_listed,
+        "total_blocked": state.get_total_blocked(),
     }))
 }
 
+/// Add the domains of a Pi-hole export (gravity or adlist text), keeping
+/// everything currently blocked
 #[tauri::command]
-pub fn check_admin_privileges() -> bool {
+pub fn import_pihole(
+    state: State<'_, Arc<AppState>>,
+    path: String,
+    allow_over_cap: Option<bool>,
+) -> Result<serde_json::Value, String> {
+    let content = read_pihole_export(Path::new(&path))
+        .map_err(|e| format!("Failed to read {}: {}", path, e))?;
+    let (blocking, skipped) = parse_pihole_export(&content, &state.get_config().parse_options());
+    let mut parsed = ParsedHosts {
+        blocking,
+        ..ParsedHosts::default()
+    };
+    let safe_listed = screen_safe_list(&state, &mut parsed, &path);
+
+    check_merge_cap(&state, &parsed, allow_over_cap)
+        .map_err(|e| format!("Refusing to import: {}", e))?;
+    let added = state.merge_blocking(parsed);
+    state.audit(
+        "import-pihole",
+        &format!("{} ({} domains added)", path, added),
+    );
+
+    Ok(serde_json::json!({
+        "added": added,
+        "skipped": skipped,
+        "safe_listed": safe_listed,
+        "total_blocked": state.get_total_blocked(),
+    }))
+}
+
+/// Compare the blocked domains of two hosts files (e.g. a friend's export
+/// against mine): `added` are only in `path_b`, `removed` only in `path_a`
+#[tauri::command]
+pub fn diff_files(
+    state: State<'_, Arc<AppState>>,
+    path_a: String,
+    path_b: String,
+) -> Result<serde_json::Value, String> {
+    let diff = crate::history::diff_files(
+        Path::new(&path_a),
+        Path::new(&path_b),
+        &state.get_config().parse_options(),
+    )
+    .map_err(|e| e.to_string())?;
+
+    Ok(serde_json::json!({
+        "added": diff.added,
+        "removed": diff.removed,
+        "common": diff.common,
+    }))
+}
+
+/// Recovery candidate as sent to the frontend
+pub fn recovery_json(candidate: &RecoveryCandidate) -> serde_json::Value {
+    serde_json::json!({
+        "temp_path": candidate.temp_path.to_string_lossy(),
+        "hosts_path": candidate.hosts_path.to_string_lossy(),
+        "reason": candidate.reason.to_string(),
+    })
+}
+
+#[tauri::command]
+pub fn get_recovery_candidate(
+    state: State<'_, Arc<AppState>>,
+) -> Result<Option<serde_json::Value>, String> {
+    let hosts_path = state
+        .get_config()
+        .host_file_path
+        .unwrap_or_else(default_hosts_file_path);
+
+    find_recovery_candidate(&hosts_path)
+        .map(|candidate| candidate.as_ref().map(recovery_json))
+        .map_err(|e| format!("Failed to check for a leftover temp file: {}", e))
+}
+
+#[tauri::command]
+pub fn recover_hosts_file(state: State<'_, Arc<AppState>>) -> Result<(), String> {
+    let hosts_path = state
+        .get_config()
+        .host_file_path
+        .unwrap_or_else(default_hosts_file_path);
+
+    recover_from_temp(&hosts_path).map_err(|e| format!("Failed to recover hosts file: {}", e))?;
+    state
+        .load_from_file(&hosts_path)
+        .map_err(|e| format!("Failed to load recovered hosts file: {}", e))?;
+
+    Ok(())
+}
+
+#[tauri::command]
+pub async fn delete_history_files(
+    state: State<'_, Arc<AppState>>,
+    filenames: Vec<String>,
+) -> Result<(), String> {
+    let config = state.get_config();
+    let history_dir = config
+        .history_dir
+        .ok_or_else(|| "History directory not configured".to_string())?;
+
+    crate::history::delete_history_files(&history_dir, &config.history_prefix, &filenames)
+        .map_err(|e| format!("Failed to delete history files: {}", e))?;
+
+    Ok(())
+}
+
+/// Move a deleted snapshot back into history
+#[tauri::command]
+pub async fn restore_from_trash(
+    state: State<'_, Arc<AppState>>,
+    filename: String,
+) -> Result<(), String> {
+    let config = state.get_config();
+    let history_dir = config
+        .history_dir
+        .ok_or_else(|| "History directory not configured".to_string())?;
+
+    crate::history::restore_from_trash(&history_dir, &filename)
+        .map_err(|e| format!("Failed to restore {}: {}", filename, e))
+}
+
+/// Permanently remove every deleted snapshot
+#[tauri::command]
+pub async fn empty_trash(state: State<'_, Arc<AppState>>) -> Result<usize, String> {
+    let config = state.get_config();
+    let history_dir = config
+        .history_dir
+        .ok_or_else(|| "History directory not configured".to_string())?;
+
+    crate::history::empty_trash(&history_dir).map_err(|e| format!("Failed to empty trash: {}", e))
+}
+
+#[tauri::command]
+pub fn get_config(state: State<'_, Arc<AppState>>) -> Result<serde_json::Value, String> {
+    let config = state.get_config();
+    Ok(serde_json::json!({
+        "host_file_path": config.host_file_path.as_ref().map(|p| p.to_string_lossy().to_string()),
+        "history_dir": config.history_dir.as_ref().map(|p| p.to_string_lossy().to_string()),
+        "allow_file_path": config.allow_file_path.as_ref().map(|p| p.to_string_lossy().to_string()),
+        "max_history_entries": config.max_history_entries,
+        "history_prefix": config.history_prefix,
+        "history_keyframe_interval": config.history_keyframe_interval,
+        "theme": config.theme.to_str(),
+        "block_ipv6": config.block_ipv6,
+        "separator": config.separator.to_str(),
+        "ensure_trailing_newline": config.ensure_trailing_newline,
+        "disabled_comment_prefix": config.disabled_comment_prefix,
+        "preserve_whitespace": config.preserve_whitespace,
+        "annotate_additions": config.annotate_additions,
+        "blocking_sink": config.blocking_sink.to_string(),
+        "sink_ips": config.sink_ips.iter().map(|ip| ip.to_string()).collect::<Vec<_>>(),
+        "preserve_ips": config.preserve_ips.iter().map(|ip| ip.to_string()).collect::<Vec<_>>(),
+        "safe_list_policy": config.safe_list_policy.to_str(),
+        "block_single_label": config.block_single_label,
+        "save_debounce_ms": config.save_debounce_ms,
+        "history_failure_policy": config.history_failure_policy.to_str(),
+        "lock_timeout_ms": config.lock_timeout_ms,
+        "file_header": config.file_header,
+        "max_total_domains": config.max_total_domains,
+        "sources": bundle_json(&config.sources),
+        "active_profile": config.active_profile,
+    }))
+}
+
+#[tauri::command]
+pub fn update_config(
+    app: AppHandle,
+    state: State<'_, Arc<AppState>>,
+    config_json: serde_json::Value,
+) -> Result<(), String> {
+    let mut config = state.get_config();
+
+    if let Some(host_path) = config_json.get("host_file_path").and_then(|v| v.as_str()) {
+        if host_path.is_empty() {
+            config.host_file_path = None;
+        } else {
+            config.host_file_path = Some(PathBuf::from(host_path).as_path().into());
+        }
+    }
+
+    if let Some(history_path) = config_json.get("history_dir").and_then(|v| v.as_str()) {
+        if history_path.is_empty() {
+            config.history_dir = None;
+        } else {
+            config.history_dir = Some(PathBuf::from(history_path).as_path().into());
+        }
+    }
+
+    if let Some(allow_path) = config_json.get("allow_file_path").and_then(|v| v.as_str()) {
+        if allow_path.is_empty() {
+            config.allow_file_path = None;
+        } else {
+            config.allow_file_path = Some(PathBuf::from(allow_path).as_path().into());
+        }
+    }
+
+    if let Some(max_entries) = config_json
+        .get("max_history_entries")
+        .and_then(|v| v.as_u64())
+    {
+        config.max_history_entries = max_entries as usize;
+    }
+
+    if let Some(prefix) = config_json.get("history_prefix").and_then(|v| v.as_str()) {
+        let prefix = prefix.trim();
+        if !is_valid_history_prefix(prefix) {
+            return Err(format!(
+                "Invalid history_prefix (use letters, digits, '-', '_' or '.'): {}",
+                prefix
+            ));
+        }
+        config.history_prefix = prefix.to_string();
+    }
+
+    if let Some(interval) = config_json
+        .get("history_keyframe_interval")
+        .and_then(|v| v.as_u64())
+    {
+        config.history_keyframe_interval = interval as usize;
+    }
+
+    if let Some(theme_str) = config_json.get("theme").and_then(|v| v.as_str()) {
+        config.theme = crate::config::Theme::from_str(theme_str);
+    }
+
+    if let Some(block_ipv6) = config_json.get("block_ipv6").and_then(|v| v.as_bool()) {
+        config.block_ipv6 = block_ipv6;
+    }
+
+    if let Some(separator) = config_json.get("separator").and_then(|v| v.as_str()) {
+        config.separator = crate::parser::Separator::from_str(separator);
+    }
+
+    if let Some(trailing) = config_json
+        .get("ensure_trailing_newline")
+        .and_then(|v| v.as_bool())
+    {
+        config.ensure_trailing_newline = trailing;
+    }
+
+    if let Some(marker) = config_json
+        .get("disabled_comment_prefix")
+        .and_then(|v| v.as_str())
+    {
+        let marker = marker.trim();
+        if !is_valid_disabled_marker(marker) {
+            return Err(format!(
+                "Invalid disabled_comment_prefix (a single-line comment starting with '#'): {}",
+                marker
+            ));
+        }
+        config.disabled_comment_prefix = marker.to_string();
+    }
+
+    if let Some(preserve) = config_json
+        .get("preserve_whitespace")
+        .and_then(|v| v.as_bool())
+    {
+        config.preserve_whitespace = preserve;
+    }
+
+    if let Some(annotate) = config_json
+        .get("annotate_additions")
+        .and_then(|v| v.as_bool())
+    {
+        config.annotate_additions = annotate;
+    }
+
+    if let Some(sink) = config_json.get("blocking_sink").and_then(|v| v.as_str()) {
+        config.blocking_sink = sink
+            .trim()
+            .parse()
+            .map_err(|_| format!("Invalid blocking_sink address: {}", sink))?;
+    }
+
+    for (key, ips) in [
+        ("sink_ips", &mut config.sink_ips),
+        ("preserve_ips", &mut config.preserve_ips),
+    ] {
+        if let Some(list) = config_json.get(key).and_then(|v| v.as_array()) {
+            *ips = list
+                .iter()
+                .map(|ip| {
+                    ip.as_str()
+                        .and_then(|ip| ip.trim().parse().ok())
+                        .ok_or_else(|| format!("Invalid {} address: {}", key, ip))
+                })
+                .collect::<Result<_, String>>()?;
+        }
+    }
+
+    if let Some(single_label) = config_json
+        .get("block_single_label")
+        .and_then(|v| v.as_bool())
+    {
+        config.block_single_label = single_label;
+    }
+
+    if let Some(policy) = config_json.get("safe_list_policy").and_then(|v| v.as_str()) {
+        config.safe_list_policy = SafeListPolicy::from_str(policy);
+    }
+
+    if let Some(debounce) = config_json.get("save_debounce_ms").and_then(|v| v.as_u64()) {
+        config.save_debounce_ms = debounce;
+    }
+
+    if let Some(policy) = config_json
+        .get("history_failure_policy")
+        .and_then(|v| v.as_str())
+    {
+        config.history_failure_policy = crate::config::HistoryFailurePolicy::from_str(policy);
+    }
+
+    if let Some(timeout) = config_json.get("lock_timeout_ms").and_then(|v| v.as_u64()) {
+        config.lock_timeout_ms = timeout;
+    }
+
+    if let Some(max_total) = config_json
+        .get("max_total_domains")
+        .and_then(|v| v.as_u64())
+    {
+        config.max_total_domains = max_total as usize;
+    }
+
+    if let Some(header) = config_json.get("file_header").and_then(|v| v.as_str()) {
+        config.file_header = (!header.trim().is_empty()).then(|| header.to_string());
+    }
+
+    config.validate().map_err(|e| e.to_string())?;
+
+    // Switching hosts files reloads the preserved lines from the new file
+    // and moves the watcher over to it
+    if let Some(hosts_path) = state.apply_config(config).map_err(|e| e.to_string())? {
+        restart_watcher(&app, hosts_path, state.inner().clone())
+            .map_err(|e| format!("Failed to watch the new hosts file: {}", e))?;
+    }
+
+    Ok(())
+}
+
+/// Compact the hosts file, snapshotting the current file to history first
+/// The result is applied to the in-memory state and written on the next save
+#[tauri::command]
+pub async fn optimize(state: State<'_, Arc<AppState>>) -> Result<serde_json::Value, String> {
+    let config = state.get_config();
+    let hosts_path = config
+        .host_file_path
+        .clone()
+        .unwrap_or_else(default_hosts_file_path);
+    let before = std::fs::read_to_string(&hosts_path).unwrap_or_else(|_| state.serialize());
+
+    if let Some(history) = config.history_settings() {
+        write_snapshot(&history, &before)
+            .map_err(|e| format!("Failed to write history snapshot: {}", e))?;
+        cleanup_old_history(&history.dir, &history.prefix, history.max_entries)
+            .map_err(|e| format!("Failed to clean up history: {}", e))?;
+    }
+
+    let removed = state.compact_preserved_lines();
+    let after = state.serialize();
+    state.audit("optimize", &format!("{} preserved lines removed", removed));
+
+    Ok(serde_json::json!({
+        "before_lines": before.lines().count(),
+        "after_lines": after.lines().count(),
+    }))
+}
+
+/// Rearrange the hosts file's other lines in `style` ("as-is" or
+/// "comments-on-top"), snapshotting the current file to history first
+/// The result is applied to the in-memory state and written on the next save
+#[tauri::command]
+pub async fn normalize_layout(
+    state: State<'_, Arc<AppState>>,
+    style: String,
+) -> Result<bool, String> {
+    let style = LayoutStyle::from_str(&style).map_err(|e| e.to_string())?;
+    if style == LayoutStyle::AsIs {
+        return Ok(false);
+    }
+    let config = state.get_config();
+    let hosts_path = config
+        .host_file_path
+        .clone()
+        .unwrap_or_else(default_hosts_file_path);
+    let before = std::fs::read_to_string(&hosts_path).unwrap_or_else(|_| state.serialize());
+
+    if let Some(history) = config.history_settings() {
+        write_snapshot(&history, &before)
+            .map_err(|e| format!("Failed to write history snapshot: {}", e))?;
+        cleanup_old_history(&history.dir, &history.prefix, history.max_entries)
+            .map_err(|e| format!("Failed to clean up history: {}", e))?;
+    }
+
+    let changed = state.normalize_layout(style);
+    if changed {
+        state.audit("normalize-layout", style.to_str());
+    }
+    Ok(changed)
+}
+
+/// Remove blocked domains from the other lines of the hosts file, and repeated
+/// localhost entries, snapshotting the current state to history first if
+/// anything would change
+#[tauri::command]
+pub async fn dedupe_state(state: State<'_, Arc<AppState>>) -> Result<usize, String> {
+    if state.duplicate_count() == 0 {
+        return Ok(0);
+    }
+
+    if let Some(history) = state.get_config().history_settings() {
+        write_snapshot(&history, &state.serialize())
+            .map_err(|e| format!("Failed to write history snapshot: {}", e))?;
+        cleanup_old_history(&history.dir, &history.prefix, history.max_entries)
+            .map_err(|e| format!("Failed to clean up history: {}", e))?;
+    }
+
+    let removed = state.dedupe_state();
+    state.audit("dedupe", &format!("{} duplicate entries removed", removed));
+    Ok(removed)
+}
+
+/// Path of the active hosts file
+/// Deprecated: `get_hosts_file_info` returns the path along with the rest
+#[tauri::command]
+pub fn get_host_file_path(state: State<'_, Arc<AppState>>) -> String {
+    let config = state.get_config();
+    config
+        .host_file_path
+        .unwrap_or_else(default_hosts_file_path)
+        .to_string_lossy()
+        .to_string()
+}
+
+/// Path, size, modification time and writability of the hosts file, with the
+/// number of managed entries, in one call
+#[tauri::command]
+pub fn get_hosts_file_info(state: State<'_, Arc<AppState>>) -> serde_json::Value {
+    let info = state.host_file_info();
+    serde_json::json!({
+        "path": info.path.to_string_lossy(),
+        "exists": info.exists,
+        "size": info.size,
+        "last_modified": info.last_modified,
+        "writable": info.writable,
+        "entry_count": info.entry_count,
+    })
+}
+
+/// Fingerprint of the blocked domains, for checking whether two setups block
+/// the same set without sharing the list itself
+#[tauri::command]
+pub fn blocklist_fingerprint(state: State<'_, Arc<AppState>>) -> serde_json::Value {
+    serde_json::json!({
+        "algorithm": "sha256",
+        "fingerprint": state.blocklist_fingerprint(),
+        "domains": state.get_total_blocked(),
+    })
+}
+
+/// Check every blocked domain is a well-formed hostname, listing the invalid
+/// ones with the reason (up to `MAX_INVALID_REPORTED`) without removing them
+#[tauri::command]
+pub fn validate_all_domains(state: State<'_, Arc<AppState>>) -> serde_json::Value {
+    let validation = state.validate_all_domains();
+    serde_json::json!({
+        "checked": validation.checked,
+        "total_invalid": validation.total_invalid,
+        "truncated": validation.total_invalid > validation.invalid.len(),
+        "invalid": validation
+            .invalid
+            .iter()
+            .map(|(domain, reason)| serde_json::json!({ "domain": domain, "reason": reason }))
+            .collect::<Vec<_>>(),
+    })
+}
+
+#[tauri::command]
+pub fn get_statistics(state: State<'_, Arc<AppState>>) -> Result<serde_json::Value, String> {
+    let total_blocked = state.get_total_blocked();
+    Ok(serde_json::json!({
+        "total_blocked": total_blocked,
+    }))
+}
+
+/// Preserved lines with their index, as returned to the frontend
+fn preserved_lines_json(state: &AppState) -> Vec<serde_json::Value> {
+    state
+        .preserved_lines
+        .lock()
+        .iter()
+        .enumerate()
+        .map(|(index, line)| preserved_line_json(index, line))
+        .collect()
+}
+
+/// One preserved line, tagged with its variant under "type"
+/// Entries also carry their IP and hostnames so the UI needn't parse the text
+fn preserved_line_json(index: usize, line: &PreservedLine) -> serde_json::Value {
+    let mut json = serde_json::json!({
+        "index": index,
+        "kind": line.kind(),
+        "content": line.content(),
+        "editable": !matches!(line, PreservedLine::ManagedRegion),
+    });
+    let fields = match line {
+        PreservedLine::Comment(_) => serde_json::json!({ "type": "comment" }),
+        PreservedLine::NonLocalhostEntry(entry) => {
+            let mut tokens = entry.split('#').next().unwrap_or("").split_whitespace();
+            serde_json::json!({
+                "type": "entry",
+                "ip": tokens.next(),
+                "hostnames": tokens.collect::<Vec<_>>(),
+            })
+        }
+        PreservedLine::LocalhostEntry { ip, hostname } => serde_json::json!({
+            "type": "localhost_entry",
+            "ip": ip.to_string(),
+            "hostnames": [hostname],
+        }),
+        PreservedLine::LocalhostLine(_) => serde_json::json!({ "type": "localhost_line" }),
+        PreservedLine::Raw(_) => serde_json::json!({ "type": "raw" }),
+        PreservedLine::Invalid(_) => serde_json::json!({ "type": "invalid" }),
+        PreservedLine::ManagedRegion => serde_json::json!({ "type": "managed_region" }),
+    };
+    if let (Some(json), serde_json::Value::Object(fields)) = (json.as_object_mut(), fields) {
+        json.extend(fields);
+    }
+    json
+}
+
+/// Lines of the hosts file outside the managed blocklist, for display and editing
+#[tauri::command]
+pub fn get_preserved_lines(state: State<'_, Arc<AppState>>) -> Vec<serde_json::Value> {
+    preserved_lines_json(&state)
+}
+
+/// Non-managed address mappings (LAN devices, static names), one per IP
+#[tauri::command]
+pub fn get_network_entries(state: State<'_, Arc<AppState>>) -> Vec<serde_json::Value> {
+    state
+        .network_entries()
+        .into_iter()
+        .map(|entry| {
+            serde_json::json!({
+                "ip": entry.ip.to_string(),
+                "hostnames": entry.hostnames,
+            })
+        })
+        .collect()
+}
+
+#[tauri::command]
+pub fn update_preserved_line(
+    state: State<'_, Arc<AppState>>,
+    index: usize,
+    new_content: String,
+) -> Result<Vec<serde_json::Value>, String> {
+    state
+        .update_preserved_line(index, &new_content)
+        .map_err(|e| format!("Failed to update line: {}", e))?;
+    Ok(preserved_lines_json(&state))
+}
+
+#[tauri::command]
+pub fn delete_preserved_line(
+    state: State<'_, Arc<AppState>>,
+    index: usize,
+) -> Result<Vec<serde_json::Value>, String> {
+    state
+        .delete_preserved_line(index)
+        .map_err(|e| format!("Failed to delete line: {}", e))?;
+    Ok(preserved_lines_json(&state))
+}
+
+#[tauri::command]
+pub fn is_blocked(state: State<'_, Arc<AppState>>, hostname: String) -> bool {
+    state.is_blocked(&hostname)
+}
+
+/// When a domain was first blocked, in seconds since the Unix epoch, or null
+/// if it never was; unlike the added-at annotation this survives removal
+#[tauri::command]
+pub fn get_domain_first_seen(state: State<'_, Arc<AppState>>, hostname: String) -> Option<u64> {
+    state.first_seen(&hostname)
+}
+
+#[tauri::command]
+pub fn suggest_related(
+    state: State<'_, Arc<AppState>>,
+    hostname: String,
+    limit: usize,
+) -> Vec<String> {
+    state
+        .suggest_related(&hostname, limit)
+        .iter()
+        .map(|h| h.to_string())
+        .collect()
+}
+
+#[tauri::command]
+pub fn query_domains_regex(
+    state: State<'_, Arc<AppState>>,
+    pattern: String,
+    limit: usize,
+) -> Result<serde_json::Value, String> {
+    let query = state
+        .query_regex(&pattern, limit)
+        .map_err(|e| format!("Invalid pattern: {}", e))?;
+
+    Ok(serde_json::json!({
+        "matches": query.matches.iter().map(|h| h.to_string()).collect::<Vec<_>>(),
+        "truncated": query.truncated,
+        "timed_out": query.timed_out,
+    }))
+}
+
+#[tauri::command]
+pub fn categorize_domain(hostname: String) -> &'static str {
+    crate::categories::categorize_domain(hostname.trim()).to_str()
+}
+
+#[tauri::command]
+pub fn get_category_breakdown(state: State<'_, Arc<AppState>>) -> serde_json::Value {
+    let blocking = state.get_all_blocks();
+    let counts: serde_json::Map<String, serde_json::Value> = category_breakdown(&blocking)
+        .into_iter()
+        .map(|(category, count)| (category.to_str().to_string(), count.into()))
+        .collect();
+    serde_json::Value::Object(counts)
+}
+
+#[tauri::command]
+pub fn get_recent_errors(state: State<'_, Arc<AppState>>) -> Vec<serde_json::Value> {
+    state
+        .get_recent_errors()
+        .into_iter()
+        .map(|entry| {
+            serde_json::json!({
+                "timestamp": entry.timestamp.duration_since(std::time::UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_secs(),
+                "source": entry.source,
+                "message": entry.message.to_string(),
+            })
+        })
+        .collect()
+}
+
+#[tauri::command]
+pub fn get_audit_log(
+    state: State<'_, Arc<AppState>>,
+    limit: usize,
+) -> Result<Vec<serde_json::Value>, String> {
+    let audit_log = state.audit_log.lock().clone();
+    let Some(audit_log) = audit_log else {
+        return Ok(Vec::new());
+    };
+
+    let entries = audit_log
+        .tail(limit)
+        .map_err(|e| format!("Failed to read audit log: {}", e))?;
+    Ok(entries
+        .into_iter()
+        .map(|entry| {
+            serde_json::json!({
+                "timestamp": entry.timestamp,
+                "operation": entry.operation,
+                "detail": entry.detail,
+            })
+        })
+        .collect())
+}
+
+/// What importing each line of `content` would do, for reviewing an import
+#[tauri::command]
+pub fn classify_lines(state: State<'_, Arc<AppState>>, content: String) -> Vec<serde_json::Value> {
+    crate::parser::classify_lines(&content, &state.get_config().parse_options())
+        .into_iter()
+        .map(|line| {
+            let blocked = match &line.class {
+                LineClass::Blocked(hostnames) => hostnames.iter().map(|h| h.to_string()).collect(),
+                _ => Vec::new(),
+            };
+            serde_json::json!({
+                "line_number": line.line_number,
+                "class": line.class.to_str(),
+                "blocked": blocked,
+            })
+        })
+        .collect()
+}
+
+/// Hosts file features the parser supports
+#[tauri::command]
+pub fn parser_capabilities() -> serde_json::Value {
+    let caps = PARSER_CAPABILITIES;
+    serde_json::json!({
+        "ipv6": caps.ipv6,
+        "multiple_hostnames": caps.multiple_hostnames,
+        "inline_comments": caps.inline_comments,
+        "crlf_line_endings": caps.crlf_line_endings,
+        "managed_region": caps.managed_region,
+        "internationalized_names": caps.internationalized_names,
+        "per_domain_sinks": caps.per_domain_sinks,
+        "preserve_whitespace": caps.preserve_whitespace,
+        "addition_annotations": caps.addition_annotations,
+        "wildcards": caps.wildcards,
+        "adblock_syntax": caps.adblock_syntax,
+    })
+}
+
+#[tauri::command]
+pub fn check_admin_privileges() -> bool {
     is_elevated()
 }
 
+/// OS appearance ("dark" or "light"), used when the theme is "system"
+#[tauri::command]
+pub fn get_system_theme() -> &'static str {
+    system_theme().to_str()
+}
+
+/// DNS flush method available on this system, or null if saves can't flush
+/// the DNS cache and changes may only apply after a reboot
+#[tauri::command]
+pub fn get_dns_flush_capability() -> Option<&'static str> {
+    dns_flush_capability().map(|method| method.to_str())
+}
+
+/// Look up a sample of blocked domains and report those that no longer exist
+/// Nothing is removed; at most `MAX_STALE_SAMPLE` domains are queried, one
+/// every `STALE_QUERY_INTERVAL`
+#[tauri::command]
+pub async fn find_stale_domains(
+    state: State<'_, Arc<AppState>>,
+    sample_limit: usize,
+    resolver: Option<String>,
+) -> Result<serde_json::Value, String> {
+    let resolver = resolver.as_deref().unwrap_or(DEFAULT_STALE_RESOLVER);
+    let server: std::net::SocketAddr = resolver
+        .parse()
+        .or_else(|_| format!("{}:53", resolver).parse())
+        .map_err(|_| format!("Invalid resolver address: {}", resolver))?;
+    let sample = sample_domains(state.blocking.lock().iter(), sample_limit);
+    let total = state.get_total_blocked();
+
+    let report = async_runtime::spawn_blocking(move || {
+        find_stale_with(&sample, STALE_QUERY_INTERVAL, |domain| {
+            query_dns(server, domain)
+        })
+    })
+    .await
+    .map_err(|e| format!("Stale domain check failed: {}", e))?;
+
+    Ok(serde_json::json!({
+        "checked": report.checked,
+        "total_blocked": total,
+        "stale": report.stale.iter().map(|h| h.to_string()).collect::<Vec<_>>(),
+        "errors": report.errors,
+    }))
+}
+
+/// Resolve domains through the system resolver and report whether each one
+/// lands on a sink, e.g. to confirm a save took effect
+#[tauri::command]
+pub async fn check_blocking_batch(
+    state: State<'_, Arc<AppState>>,
+    domains: Vec<String>,
+) -> Result<Vec<serde_json::Value>, String> {
+    let options = state.get_config().parse_options();
+    let results = crate::blockcheck::check_blocking_batch(&domains, |ip| options.is_sink(ip)).await;
+
+    Ok(domains
+        .iter()
+        .zip(results)
+        .map(|(domain, result)| match result {
+            BlockCheck::Blocked => serde_json::json!({
+                "domain": domain,
+                "status": "blocked",
+            }),
+            BlockCheck::NotBlocked(addresses) => serde_json::json!({
+                "domain": domain,
+                "status": "not_blocked",
+                "addresses": addresses.iter().map(|ip| ip.to_string()).collect::<Vec<_>>(),
+            }),
+            BlockCheck::Error(error) => serde_json::json!({
+                "domain": domain,
+                "status": "error",
+                "error": error,
+            }),
+        })
+        .collect())
+}
+
+/// Bundle the health checks support needs into one report
+#[tauri::command]
+pub async fn diagnostics(state: State<'_, Arc<AppState>>) -> Result<serde_json::Value, String> {
+    let state = state.inner().clone();
+    async_runtime::spawn_blocking(move || Diagnostics::collect(&state).to_json())
+        .await
+        .map_err(|e| format!("Diagnostics failed: {}", e))
+}
+
 #[tauri::command]
 pub fn export_hosts(state: State<'_, Arc<AppState>>) -> String {
     state.serialize()
 }
 
+/// Export only the blocked domains ("hosts" or "domains" format), leaving out
+/// preserved lines such as LAN entries and local aliases
+#[tauri::command]
+pub fn export_managed_only(state: State<'_, Arc<AppState>>, format: Option<String>) -> String {
+    let format = format
+        .as_deref()
+        .map(ExportFormat::from_str)
+        .unwrap_or_default();
+    state.serialize_managed_only(format)
+}
+
+/// Export to a file without sending the whole content over IPC
+/// Returns the number of bytes written
+#[tauri::command]
+pub async fn export_hosts_to_path(
+    state: State<'_, Arc<AppState>>,
+    path: String,
+) -> Result<u64, String> {
+    let state = state.inner().clone();
+    async_runtime::spawn_blocking(move || {
+        state
+            .export_to_path(Path::new(&path))
+            .map_err(|e| format!("Failed to export to {}: {}", path, e))
+    })
+    .await
+    .map_err(|e| format!("Export task failed: {}", e))?
+}
+
+/// Write the blocklist across numbered hosts files of at most `max_per_file`
+/// domains each; returns the paths written
+/// Existing parts in `dest_dir` are only replaced with `overwrite`
+#[tauri::command]
+pub async fn split_export(
+    state: State<'_, Arc<AppState>>,
+    dest_dir: String,
+    max_per_file: usize,
+    overwrite: Option<bool>,
+) -> Result<Vec<String>, String> {
+    let state = state.inner().clone();
+    async_runtime::spawn_blocking(move || {
+        state
+            .split_export(
+                Path::new(&dest_dir),
+                max_per_file,
+                overwrite.unwrap_or(false),
+            )
+            .map(|files| {
+                files
+                    .iter()
+                    .map(|path| path.display().to_string())
+                    .collect()
+            })
+            .map_err(|e| format!("Failed to export to {}: {}", dest_dir, e))
+    })
+    .await
+    .map_err(|e| format!("Export task failed: {}", e))?
+}
+
+/// Parse and import hosts content, emitting "import-progress" events while
+/// parsing and a final "import-complete" event
+async fn import_content(
+    app: AppHandle,
+    state: &AppState,
+    content: String,
+    source: &str,
+    import_options: ImportOptions,
+) -> Result<serde_json::Value, String> {
+    let options = ParseOptions {
+        exclude: import_options.exclude.clone(),
+        ..state.get_config().parse_options()
+    };
+    let format = import_options
+        .format
+        .unwrap_or_else(|| SourceFormat::detect(&content));
+
+    // Parse off the async runtime so progress events reach the UI as they happen
+    let parsed = async_runtime::spawn_blocking({
+        let app = app.clone();
+        move || {
+            parse_source(&content, format, &options, |progress| {
+                let _ = app.emit(
+                    "import-progress",
+                    serde_json::json!({
+                        "bytes_processed": progress.bytes_processed,
+                        "total_bytes": progress.total_bytes,
+                        "lines_processed": progress.lines_processed,
+                        "total_lines": progress.total_lines,
+                    }),
+                );
+            })
+        }
+    })
+    .await
+    .map_err(|e| format!("Import task failed: {}", e))?
+    .map_err(|e| format!("Failed to parse hosts file: {}", e))?;
+
+    apply_import(app, state, parsed, format, source, import_options)
+}
+
+/// Screen parsed entries, apply the import options, and replace the state with them
+fn apply_import(
+    app: AppHandle,
+    state: &AppState,
+    mut parsed: ParsedHosts,
+    format: SourceFormat,
+    source: &str,
+    import_options: ImportOptions,
+) -> Result<serde_json::Value, String> {
+    // A domain list has no lines of its own, so the current ones are kept
+    if format == SourceFormat::DomainList {
+        parsed.preserved_lines = state.preserved_lines.lock().clone();
+    }
+
+    // An imported list must not redirect localhost
+    for line in &parsed.preserved_lines {
+        verify_localhost_mapping(line).map_err(|e| format!("Refusing to import: {}", e))?;
+    }
+
+    if let Some(sink) = import_options.sink {
+        override_sink(&mut parsed, sink);
+    }
+    let safe_listed = screen_safe_list(state, &mut parsed, source);
+    let report = import_options
+        .policy
+        .apply(&mut parsed)
+        .map_err(|e| format!("Import conflict: {}", e))?;
+    if !import_options.keep_source_comments && format == SourceFormat::Hosts {
+        strip_source_comments(&mut parsed, source);
+    }
+    let blocked = parsed.blocking.len();
+    state
+        .check_domain_cap(blocked, import_options.allow_over_cap)
+        .map_err(|e| format!("Refusing to import: {}", e))?;
+    let preserved = parsed.preserved_lines.len();
+    state.replace_with(parsed);
+    state.audit(
+        "import",
+        &format!(
+            "{} ({} domains, policy {})",
+            source,
+            blocked,
+            report.policy.to_str()
+        ),
+    );
+
+    let resolutions: Vec<serde_json::Value> = report
+        .resolutions()
+        .map(|(conflict, kept)| {
+            serde_json::json!({
+                "hostname": conflict.hostname.to_string(),
+                "existing": conflict.existing.to_string(),
+                "incoming": conflict.incoming.to_string(),
+                "kept": kept.to_string(),
+            })
+        })
+        .collect();
+
+    let summary = serde_json::json!({
+        "format": format.to_str(),
+        "policy": report.policy.to_str(),
+        "blocked": blocked,
+        "preserved": preserved,
+        "conflicts": report.conflicts.len(),
+        "resolutions": resolutions,
+        "safe_listed": safe_listed,
+    });
+    let _ = app.emit("import-complete", &summary);
+    Ok(summary)
+}
+
+/// Parse a file for import and list the domains needing a decision, without importing
+#[tauri::command]
+pub fn import_dry_run(
+    state: State<'_, Arc<AppState>>,
+    path: String,
+) -> Result<Vec<serde_json::Value>, String> {
+    let content =
+        std::fs::read_to_string(&path).map_err(|e| format!("Failed to read {}: {}", path, e))?;
+    let parsed = parse_hosts_with(&content, &state.get_config().parse_options())
+        .map_err(|e| format!("Failed to parse hosts file: {}", e))?;
+    for line in &parsed.preserved_lines {
+        verify_localhost_mapping(line).map_err(|e| format!("Refusing to import: {}", e))?;
+    }
+
+    let conflicts = find_import_conflicts(&state.blocking.lock(), &state.sinks.lock(), &parsed);
+    *state.pending_import.lock() = Some(PendingImport {
+        source: path,
+        parsed,
+    });
+
+    Ok(conflicts
+        .iter()
+        .map(|conflict| {
+            serde_json::json!({
+                "hostname": conflict.hostname.to_string(),
+                "reason": conflict.reason.to_str(),
+                "current": conflict.current.map(|sink| sink.to_string()),
+                "incoming": conflict.incoming.map(|sink| sink.to_string()),
+            })
+        })
+        .collect())
+}
+
+/// Import the file from the last dry run, applying the user's decisions
+/// `decisions` maps hostnames to "current" or "incoming"; undecided
+/// conflicts take the incoming side
+#[tauri::command]
+pub fn apply_import_decisions(
+    state: State<'_, Arc<AppState>>,
+    decisions: serde_json::Value,
+    allow_over_cap: Option<bool>,
+) -> Result<serde_json::Value, String> {
+    // Checked before the import is taken, so a refused one can be retried
+    if let Some(pending) = state.pending_import.lock().as_ref() {
+        state
+            .check_domain_cap(
+                pending.parsed.blocking.len(),
+                allow_over_cap.unwrap_or(false),
+            )
+            .map_err(|e| format!("Refusing to import: {}", e))?;
+    }
+
+    let decisions = decisions
+        .as_object()
+        .ok_or_else(|| "Decisions must be an object".to_string())?
+        .iter()
+        .map(|(hostname, keep)| {
+            let keep = keep.as_str().unwrap_or_default();
+            Resolution::from_str(keep)
+                .map(|resolution| (Arc::from(hostname.as_str()), resolution))
+                .map_err(|e| e.to_string())
+        })
+        .collect::<Result<HashMap<_, _>, _>>()?;
+
+    let PendingImport { source, mut parsed } = state
+        .pending_import
+        .lock()
+        .take()
+        .ok_or_else(|| "No import is awaiting decisions".to_string())?;
+
+    // Compare against the state as it is now, not as it was at the dry run
+    let conflicts = find_import_conflicts(&state.blocking.lock(), &state.sinks.lock(), &parsed);
+    crate::import::apply_import_decisions(&mut parsed, &conflicts, &decisions);
+    strip_source_comments(&mut parsed, &source);
+    let safe_listed = screen_safe_list(&state, &mut parsed, &source);
+
+    let kept_current = conflicts
+        .iter()
+        .filter(|conflict| decisions.get(&conflict.hostname) == Some(&Resolution::KeepCurrent))
+        .count();
+    let blocked = parsed.blocking.len();
+    state.replace_with(parsed);
+    state.audit(
+        "import",
+        &format!(
+            "{} ({} domains, {} of {} conflicts kept current)",
+            source,
+            blocked,
+            kept_current,
+            conflicts.len()
+        ),
+    );
+
+    Ok(serde_json::json!({
+        "blocked": blocked,
+        "conflicts": conflicts.len(),
+        "kept_current": kept_current,
+        "safe_listed": safe_listed,
+    }))
+}
+
+#[tauri::command]
+#[allow(clippy::too_many_arguments)]
+pub async fn import_hosts(
+    app: AppHandle,
+    state: State<'_, Arc<AppState>>,
+    content: String,
+    policy: Option<String>,
+    keep_source_comments: Option<bool>,
+    sink: Option<String>,
+    domain_filter: Option<Vec<String>>,
+    format: Option<String>,
+    allow_over_cap: Option<bool>,
+) -> Result<serde_json::Value, String> {
+    let options = ImportOptions::from_args(
+        policy.as_deref(),
+        format.as_deref(),
+        keep_source_comments,
+        sink.as_deref(),
+        domain_filter.as_deref(),
+        allow_over_cap,
+        &state.get_config().parse_options(),
+    )
+    .map_err(|e| e.to_string())?;
+    import_content(app, &state, content, "pasted content", options).await
+}
+
+#[tauri::command]
+#[allow(clippy::too_many_arguments)]
+pub async fn import_hosts_from_path(
+    app: AppHandle,
+    state: State<'_, Arc<AppState>>,
+    path: String,
+    policy: Option<String>,
+    keep_source_comments: Option<bool>,
+    sink: Option<String>,
+    domain_filter: Option<Vec<String>>,
+    format: Option<String>,
+    allow_over_cap: Option<bool>,
+) -> Result<serde_json::Value, String> {
+    let options = ImportOptions::from_args(
+        policy.as_deref(),
+        format.as_deref(),
+        keep_source_comments,
+        sink.as_deref(),
+        domain_filter.as_deref(),
+        allow_over_cap,
+        &state.get_config().parse_options(),
+    )
+    .map_err(|e| e.to_string())?;
+    let content = read_source_file(Path::new(&path))
+        .map_err(|e| format!("Failed to read {}: {}", path, e))?;
+    import_content(app, &state, content, &path, options).await
+}
+
+/// Download several lists at once and import the domains they block
+///
+/// Without URLs, the enabled subscribed sources are downloaded, each under
+/// its own sink if it has one. Lists are merged as domain lists: the current
+/// non-blocking lines are kept. A list that fails to download or parse is
+/// reported and left out, so the others are still imported.
+#[tauri::command]
+pub async fn import_hosts_from_urls(
+    app: AppHandle,
+    state: State<'_, Arc<AppState>>,
+    urls: Option<Vec<String>>,
+    policy: Option<String>,
+    sink: Option<String>,
+    domain_filter: Option<Vec<String>>,
+    allow_over_cap: Option<bool>,
+) -> Result<serde_json::Value, String> {
+    let import_options = ImportOptions::from_args(
+        policy.as_deref(),
+        None,
+        None,
+        sink.as_deref(),
+        domain_filter.as_deref(),
+        allow_over_cap,
+        &state.get_config().parse_options(),
+    )
+    .map_err(|e| e.to_string())?;
+    let config = state.get_config();
+    let options = ParseOptions {
+        exclude: import_options.exclude.clone(),
+        ..config.parse_options()
+    };
+    let sources =
+        sources_to_fetch(urls.unwrap_or_default(), &config.sources).map_err(|e| e.to_string())?;
+    let urls: Vec<String> = sources.iter().map(|source| source.url.clone()).collect();
+
+    let downloads = fetch_sources(&urls).await;
+    let (merged, listed, statuses) = async_runtime::spawn_blocking(move || {
+        let mut merged = ParsedHosts::default();
+        let mut listed = Vec::with_capacity(sources.len());
+        let mut statuses = Vec::with_capacity(sources.len());
+        for (source, download) in sources.iter().zip(downloads) {
+            let url = &source.url;
+            let parsed = download.and_then(|content| {
+                parse_source(&content, SourceFormat::detect(&content), &options, |_| {})
+            });
+            match parsed {
+                Ok(mut parsed) => {
+                    if let Some(sink) = source.sink {
+                        override_sink(&mut parsed, sink);
+                    }
+                    statuses.push(serde_json::json!({
+                        "url": url,
+                        "ok": true,
+                        "domains": parsed.blocking.len(),
+                    }));
+                    listed.push((url.clone(), parsed.blocking.clone()));
+                    merged.merge_source(parsed);
+                }
+                Err(e) => {
+                    statuses.push(serde_json::json!({
+                        "url": url,
+                        "ok": false,
+                        "error": e.to_string(),
+                    }));
+                }
+            }
+        }
+        (merged, listed, statuses)
+    })
+    .await
+    .map_err(|e| format!("Import task failed: {}", e))?;
+
+    if statuses.iter().all(|status| status["ok"] == false) {
+        return Err(format!(
+            "No list could be imported: {}",
+            serde_json::Value::from(statuses)
+        ));
+    }
+    let source = format!("{} lists", statuses.len());
+    let mut summary = apply_import(
+        app,
+        &state,
+        merged,
+        SourceFormat::DomainList,
+        &source,
+        import_options,
+    )?;
+    for (url, domains) in &listed {
+        state.record_source(url, domains);
+    }
+    summary["sources"] = serde_json::Value::from(statuses);
+    Ok(summary)
+}
+
+/// Add the sources listed in a JSON bundle file, skipping URLs already subscribed
+/// The bundle is an array of `{url, sink, enabled}` objects
+#[tauri::command]
+pub fn import_sources_bundle(
+    state: State<'_, Arc<AppState>>,
+    path: String,
+) -> Result<serde_json::Value, String> {
+    let content = read_source_file(Path::new(&path))
+        .map_err(|e| format!("Failed to read {}: {}", path, e))?;
+    let incoming = parse_bundle(&content).map_err(|e| format!("Invalid bundle: {}", e))?;
+    let listed = incoming.len();
+
+    let mut config = state.get_config();
+    let added = merge_sources(&mut config.sources, incoming);
+    let total = config.sources.len();
+    config
+        .validate()
+        .map_err(|e| format!("Invalid bundle: {}", e))?;
+    state.update_config(config);
+    state
+        .persist_config()
+        .map_err(|e| format!("Failed to save config: {}", e))?;
+    state.audit(
+        "import_sources_bundle",
+        &format!("{} ({} of {} sources added)", path, added, listed),
+    );
+
+    Ok(serde_json::json!({
+        "added": added,
+        "skipped": listed - added,
+        "total": total,
+    }))
+}
+
+/// Write the subscribed sources to a JSON bundle file, returning how many were written
+#[tauri::command]
+pub fn export_sources_bundle(
+    state: State<'_, Arc<AppState>>,
+    path: String,
+) -> Result<usize, String> {
+    let sources = state.get_config().sources;
+    let bundle = serde_json::to_string_pretty(&bundle_json(&sources))
+        .map_err(|e| format!("Failed to encode bundle: {}", e))?;
+    std::fs::write(&path, bundle).map_err(|e| format!("Failed to write {}: {}", path, e))?;
+    Ok(sources.len())
+}
+
+/// Report how many of the currently blocked domains each list provides and
+/// how many no other compared list or manual addition does, to spot
+/// redundant lists
+/// Without URLs, the enabled subscribed sources are compared. Counts come
+/// from the origins recorded when the lists were imported, so a list never
+/// imported provides nothing
+#[tauri::command]
+pub fn source_contribution_report(
+    state: State<'_, Arc<AppState>>,
+    urls: Option<Vec<String>>,
+) -> Result<serde_json::Value, String> {
+    let config = state.get_config();
+    let urls: Vec<String> = sources_to_fetch(urls.unwrap_or_default(), &config.sources)
+        .map_err(|e| e.to_string())?
+        .into_iter()
+        .map(|source| source.url)
+        .collect();
+    let report: Vec<serde_json::Value> = urls
+        .iter()
+        .zip(state.source_contributions(&urls))
+        .map(|(url, contribution)| {
+            serde_json::json!({
+                "url": url,
+                "total": contribution.total,
+                "unique": contribution.unique,
+            })
+        })
+        .collect();
+    Ok(serde_json::Value::from(report))
+}
+
+/// Download a list and summarize it without importing anything
+#[tauri::command]
+pub async fn inspect_source(
+    state: State<'_, Arc<AppState>>,
+    url: String,
+) -> Result<serde_json::Value, String> {
+    let content = fetch_source(&url)
+        .await
+        .map_err(|e| format!("Failed to fetch {}: {}", url, e))?;
+    let metadata = parse_source_metadata(&content);
+    let format = SourceFormat::detect(&content);
+    let options = state.config.lock().parse_options();
+    let parsed = parse_source(&content, format, &options, |_| {})
+        .map_err(|e| format!("Failed to parse {}: {}", url, e))?;
+
+    Ok(serde_json::json!({
+        "url": url,
+        "title": metadata.title,
+        "homepage": metadata.homepage,
+        "last_updated": metadata.last_updated,
+        "declared_entries": metadata.declared_entries,
+        "format": format.to_str(),
+        "domains": parsed.blocking.len(),
+        "bytes": content.len(),
+    }))
+}
+
+/// Profiles and which one is active
+#[tauri::command]
+pub fn list_profiles(state: State<'_, Arc<AppState>>) -> Result<serde_json::Value, String> {
+    let profiles = state
+        .list_profiles()
+        .map_err(|e| format!("Failed to list profiles: {}", e))?;
+    Ok(serde_json::json!({
+        "active": state.active_profile(),
+        "profiles": profiles,
+    }))
+}
+
+/// Create an empty profile writing to `host_file_path`, or to the current hosts file
+#[tauri::command]
+pub fn create_profile(
+    state: State<'_, Arc<AppState>>,
+    name: String,
+    host_file_path: Option<String>,
+) -> Result<(), String> {
+    let host_file_path = host_file_path
+        .filter(|path| !path.trim().is_empty())
+        .map(|path| PathBuf::from(path).as_path().into());
+    state
+        .create_profile(&name, host_file_path)
+        .map_err(|e| format!("Failed to create profile: {}", e))?;
+    state.audit("create-profile", &name);
+    Ok(())
+}
+
+/// Switch to another profile, moving the watcher to its hosts file and
+/// writing its blocked domains there
+#[tauri::command]
+pub async fn switch_profile(
+    app: AppHandle,
+    state: State<'_, Arc<AppState>>,
+    name: String,
+) -> Result<(), String> {
+    let previous = state.active_profile();
+    let hosts_path = state
+        .switch_profile(&name)
+        .map_err(|e| format!("Failed to switch profile: {}", e))?;
+    if previous == name {
+        return Ok(());
+    }
+    // The switch has happened by now, so a watcher that can't follow it is
+    // reported without stopping the new profile from being written
+    if let Err(e) = restart_watcher(&app, hosts_path, state.inner().clone()) {
+        state.log_error(
+            "profile",
+            format!("Failed to watch the profile's hosts file: {}", e),
+        );
+    }
+    state.audit("switch-profile", &format!("{} -> {}", previous, name));
+    commit_state(state.inner().clone(), Duration::ZERO, false).await?;
+
+    let _ = app.emit("config-changed", ());
+    Ok(())
+}
+
+/// Stop reloading the hosts file on external changes, e.g. during a long manual edit
 #[tauri::command]
-pub async fn import_hosts(state: State<'_, Arc<AppState>>, content: String) -> Result<(), String> {
-    let parsed = parse_hosts(&content).map_err(|e| format!("Failed to parse hosts file: {}", e))?;
-    *state.blocking.lock() = parsed.blocking;
-    *state.preserved_lines.lock() = parsed.preserved_lines;
+pub fn pause_watcher(app: AppHandle) -> Result<(), String> {
+    let watcher = app
+        .try_state::<HostsWatcher>()
+        .ok_or_else(|| "The hosts file watcher isn't running".to_string())?;
+    watcher.pause();
     Ok(())
 }
+
+/// Reload on external changes again, reloading once to pick up edits made
+/// while paused
+/// Returns what the reload changed, or null if there was nothing to reload
+#[tauri::command]
+pub fn resume_watcher(
+    app: AppHandle,
+    state: State<'_, Arc<AppState>>,
+) -> Result<serde_json::Value, String> {
+    let watcher = app
+        .try_state::<HostsWatcher>()
+        .ok_or_else(|| "The hosts file watcher isn't running".to_string())?;
+    let report = watcher
+        .resume(state.inner().clone())
+        .map_err(|e| format!("Failed to reload hosts file: {}", e))?;
+    Ok(match report {
+        Some(report) => serde_json::json!({
+            "added": report.added,
+            "removed": report.removed,
+            "preserved_conflict": report.preserved_conflict,
+        }),
+        None => serde_json::Value::Null,
+    })
+}
+
+/// Whether the hosts file watcher is running and paused, and the file it watches
+#[tauri::command]
+pub fn watcher_status(app: AppHandle) -> serde_json::Value {
+    let watcher = app.try_state::<HostsWatcher>();
+    serde_json::json!({
+        "running": watcher.is_some(),
+        "paused": watcher.as_ref().is_some_and(|watcher| watcher.is_paused()),
+        "path": watcher
+            .and_then(|watcher| watcher.watched_path())
+            .map(|path| path.display().to_string()),
+    })
+}
+
+/// Back up the config, state database, profiles and optionally the history
+/// to a single archive at `dest`
+#[tauri::command]
+pub async fn export_full_backup(
+    state: State<'_, Arc<AppState>>,
+    dest: String,
+    include_history: Option<bool>,
+) -> Result<serde_json::Value, String> {
+    let state = state.inner().clone();
+    let manifest = async_runtime::spawn_blocking(move || {
+        state.export_full_backup(Path::new(&dest), include_history.unwrap_or(false))
+    })
+    .await
+    .map_err(|e| format!("Backup task failed: {}", e))?
+    .map_err(|e| format!("Failed to write backup: {}", e))?;
+    Ok(backup_manifest_json(&manifest))
+}
+
+/// Restore a backup written by `export_full_backup`, refusing one from a newer
+/// version, and write the restored blocklist to its hosts file
+/// Profiles that differ from the backup's are only overwritten with `replace_profiles`
+#[tauri::command]
+pub async fn import_full_backup(
+    app: AppHandle,
+    state: State<'_, Arc<AppState>>,
+    src: String,
+    replace_profiles: Option<bool>,
+) -> Result<serde_json::Value, String> {
+    let previous = state.get_config().host_file_path;
+    let (manifest, hosts_path) = state
+        .import_full_backup(Path::new(&src), replace_profiles.unwrap_or(false))
+        .map_err(|e| format!("Failed to restore backup: {}", e))?;
+    if state.get_config().host_file_path != previous {
+        restart_watcher(&app, hosts_path, state.inner().clone())
+            .map_err(|e| format!("Failed to watch the restored hosts file: {}", e))?;
+    }
+    state.audit("import-full-backup", &src);
+    commit_state(state.inner().clone(), Duration::ZERO, false).await?;
+
+    let _ = app.emit("config-changed", ());
+    Ok(backup_manifest_json(&manifest))
+}
+
+/// A backup manifest as returned to the frontend
+fn backup_manifest_json(manifest: &BackupManifest) -> serde_json::Value {
+    serde_json::json!({
+        "version": manifest.version,
+        "app_version": manifest.app_version,
+        "db_version": manifest.db_version,
+        "created_at": manifest.created_at,
+        "includes_history": manifest.includes_history,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_preserved_line_json() {
+        let lines = [
+            PreservedLine::Comment("# router".into()),
+            PreservedLine::NonLocalhostEntry("192.168.1.1 router nas # home".into()),
+            PreservedLine::LocalhostEntry {
+                ip: "::1".parse().unwrap(),
+                hostname: "ip6-localhost".into(),
+            },
+            PreservedLine::LocalhostLine("127.0.0.1\tlocalhost".into()),
+            PreservedLine::Raw("10.0.0.5 dev.proxy".into()),
+            PreservedLine::Invalid("$$ garbage".into()),
+            PreservedLine::ManagedRegion,
+        ];
+        let json: Vec<_> = lines
+            .iter()
+            .enumerate()
+            .map(|(index, line)| preserved_line_json(index, line))
+            .collect();
+
+        assert_eq!(
+            json[0],
+            serde_json::json!({
+                "index": 0,
+                "type": "comment",
+                "kind": "comment",
+                "content": "# router",
+                "editable": true,
+            })
+        );
+        assert_eq!(
+            json[1],
+            serde_json::json!({
+                "index": 1,
+                "type": "entry",
+                "kind": "entry",
+                "content": "192.168.1.1 router nas # home",
+                "editable": true,
+                "ip": "192.168.1.1",
+                "hostnames": ["router", "nas"],
+            })
+        );
+        assert_eq!(
+            json[2],
+            serde_json::json!({
+                "index": 2,
+                "type": "localhost_entry",
+                "kind": "localhost",
+                "content": "::1 ip6-localhost",
+                "editable": true,
+                "ip": "::1",
+                "hostnames": ["ip6-localhost"],
+            })
+        );
+        let types: Vec<_> = json[3..].iter().map(|line| &line["type"]).collect();
+        assert_eq!(
+            types,
+            ["localhost_line", "raw", "invalid", "managed_region"]
+        );
+        assert_eq!(json[3]["content"], "127.0.0.1\tlocalhost");
+        assert_eq!(json[5]["content"], "$$ garbage");
+        assert_eq!(json[6]["content"], serde_json::Value::Null);
+        assert_eq!(json[6]["editable"], false);
+    }
+}
@@ -1,59 +1,993 @@
-use crate::history::{cleanup_old_history, verify_host_file, write_history_snapshot};
+use crate::config::HistoryFailurePolicy;
+use crate::history::{
+    cleanup_old_history, matches_latest_snapshot, read_snapshot, verify_host_file,
+    verify_hosts_content, write_snapshot, HistorySettings,
+};
+use crate::parser::{PreservedLine, DEFAULT_SINK};
 use crate::platform::flush_dns;
 use crate::state::AppState;
+use fs2::FileExt;
 use std::fs;
-use std::path::Path;
+use std::future::Future;
+use std::io::{self, Write};
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 use tauri::async_runtime;
+use tracing::{debug, info, info_span, warn, Instrument};
 
-/// Commit current state to hosts file
+/// Result of a save request passed through a `CommitCoalescer`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CommitOutcome {
+    /// This request performed the write
+    Written,
+    /// Another request of the same burst wrote the state, and succeeded
+    Coalesced,
+}
+
+/// How many windows a burst of saves may keep extending before it's written anyway
+pub const MAX_COALESCED_WINDOWS: u32 = 10;
+
+/// Last write of a `CommitCoalescer`: the latest request it covered and its result
+type WriteResult = (u64, Result<(), Arc<str>>);
+
+/// Debounces rapid successive commits into a single write and DNS flush
+///
+/// Every request waits out the window; the last request of a burst commits,
+/// and every request of the burst gets that write's result. A burst that keeps
+/// going for `MAX_COALESCED_WINDOWS` windows is written without waiting for
+/// the last request. Commits never overlap. Since a burst produces one write,
+/// it also produces at most one history snapshot, so `max_history_entries`
+/// counts bursts rather than clicks.
+pub struct CommitCoalescer {
+    generation: AtomicU64,
+    writing: tokio::sync::Mutex<()>,
+    /// When the burst waiting to be written started
+    burst_started: parking_lot::Mutex<Option<Instant>>,
+    written: tokio::sync::watch::Sender<WriteResult>,
+}
+
+impl Default for CommitCoalescer {
+    fn default() -> Self {
+        CommitCoalescer {
+            generation: AtomicU64::new(0),
+            writing: tokio::sync::Mutex::new(()),
+            burst_started: parking_lot::Mutex::new(None),
+            written: tokio::sync::watch::channel((0, Ok(()))).0,
+        }
+    }
+}
+
+impl CommitCoalescer {
+    pub async fn run<F, Fut>(
+        &self,
+        window: Duration,
+        commit: F,
+    ) -> Result<CommitOutcome, anyhow::Error>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<(), anyhow::Error>>,
+    {
+        let mut written = self.written.subscribe();
+        let generation = self.generation.fetch_add(1, Ordering::SeqCst) + 1;
+        let started = *self.burst_started.lock().get_or_insert_with(Instant::now);
+        if !window.is_zero() {
+            tokio::time::sleep(window).await;
+        }
+
+        let superseded = self.generation.load(Ordering::SeqCst) != generation;
+        let overdue = started.elapsed() >= window * MAX_COALESCED_WINDOWS;
+        if superseded && !overdue {
+            // A later request of the burst writes this one's changes too
+            let shared = written
+                .wait_for(|(covered, _)| *covered >= generation)
+                .await
+                .map_err(|_| anyhow::anyhow!("Commit coalescer closed"))?;
+            return shared_outcome(&shared.1);
+        }
+
+        // The state is serialized inside `commit`, after the lock is taken, so
+        // the write covers every request made before it
+        let _writing = self.writing.lock().await;
+        {
+            let shared = written.borrow();
+            if shared.0 >= generation {
+                return shared_outcome(&shared.1);
+            }
+        }
+        let covered = self.generation.load(Ordering::SeqCst);
+        *self.burst_started.lock() = None;
+        let result = commit().await;
+        let shared = result
+            .as_ref()
+            .map(|_| ())
+            .map_err(|e| e.to_string().into());
+        let _ = self.written.send_replace((covered, shared));
+        result.map(|()| CommitOutcome::Written)
+    }
+}
+
+/// Outcome of a request whose changes another request's write covered
+#[inline]
+fn shared_outcome(result: &Result<(), Arc<str>>) -> Result<CommitOutcome, anyhow::Error> {
+    match result {
+        Ok(()) => Ok(CommitOutcome::Coalesced),
+        Err(e) => Err(anyhow::anyhow!("{}", e)),
+    }
+}
+
+/// Resolve the file that should actually be written for a hosts path
+/// A symlinked hosts file resolves to its target so the link itself is kept
+#[inline]
+pub fn resolve_hosts_target(hosts_file_path: &Path) -> Result<PathBuf, anyhow::Error> {
+    match fs::symlink_metadata(hosts_file_path) {
+        Ok(metadata) if metadata.file_type().is_symlink() => Ok(fs::canonicalize(hosts_file_path)?),
+        _ => Ok(hosts_file_path.to_path_buf()),
+    }
+}
+
+/// Temp file a hosts write goes through before being renamed into place
+#[inline]
+pub fn hosts_temp_path(target: &Path) -> PathBuf {
+    target.with_extension("tmp")
+}
+
+/// Advisory lock file guarding writes to a hosts file
+#[inline]
+pub fn hosts_lock_path(target: &Path) -> PathBuf {
+    let mut name = target.file_name().unwrap_or_default().to_os_string();
+    name.push(".lock");
+    target.with_file_name(name)
+}
+
+/// The lock on a hosts file's lock file could not be acquired in time
+#[derive(Debug)]
+pub struct CommitBusy {
+    pub lock_path: PathBuf,
+}
+
+impl std::fmt::Display for CommitBusy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Busy: another process is writing the hosts file (lock held on {})",
+            self.lock_path.display()
+        )
+    }
+}
+
+impl std::error::Error for CommitBusy {}
+
+/// Exclusive advisory lock on a hosts file, released on drop
+pub struct CommitLock {
+    file: fs::File,
+}
+
+impl Drop for CommitLock {
+    fn drop(&mut self) {
+        let _ = FileExt::unlock(&self.file);
+    }
+}
+
+/// Lock a hosts file against other writers, waiting up to `timeout`
+/// Fails with `CommitBusy` if the lock is still held when the timeout expires
+pub fn acquire_commit_lock(
+    hosts_file_path: &Path,
+    timeout: Duration,
+) -> Result<CommitLock, anyhow::Error> {
+    let lock_path = hosts_lock_path(&resolve_hosts_target(hosts_file_path)?);
+    let file = fs::OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(&lock_path)?;
+
+    let deadline = Instant::now() + timeout;
+    loop {
+        match file.try_lock_exclusive() {
+            Ok(()) => return Ok(CommitLock { file }),
+            Err(e) if e.kind() != fs2::lock_contended_error().kind() => return Err(e.into()),
+            Err(_) if Instant::now() >= deadline => return Err(CommitBusy { lock_path }.into()),
+            Err(_) => std::thread::sleep(Duration::from_millis(50)),
+        }
+    }
+}
+
+/// Attempts at writing the hosts file before a transient failure is returned
+const WRITE_ATTEMPTS: u32 = 3;
+
+/// Wait before the first retry, doubled for each one after it
+const WRITE_RETRY_DELAY: Duration = Duration::from_millis(50);
+
+/// Windows error codes for a file another process has open or locked
+/// (ERROR_SHARING_VIOLATION, ERROR_LOCK_VIOLATION)
+const WINDOWS_SHARING_ERRORS: [i32; 2] = [32, 33];
+
+/// Whether a write failure is likely to clear up on its own, as when
+/// antivirus or indexing briefly holds the hosts file open
+/// Permission errors are permanent and never retried
+fn is_transient_write_error(e: &io::Error) -> bool {
+    if cfg!(windows)
+        && e.raw_os_error()
+            .is_some_and(|code| WINDOWS_SHARING_ERRORS.contains(&code))
+    {
+        return true;
+    }
+    matches!(
+        e.kind(),
+        io::ErrorKind::ResourceBusy | io::ErrorKind::WouldBlock | io::ErrorKind::Interrupted
+    )
+}
+
+/// Run `operation`, retrying transient failures with exponential backoff
+/// The last error is returned once `attempts` are used up
+fn retry_transient<T>(
+    attempts: u32,
+    delay: Duration,
+    mut operation: impl FnMut() -> io::Result<T>,
+) -> io::Result<T> {
+    let mut delay = delay;
+    for _ in 1..attempts {
+        match operation() {
+            Err(e) if is_transient_write_error(&e) => {
+                warn!("Hosts file write failed, retrying in {:?}: {}", delay, e);
+                std::thread::sleep(delay);
+                delay *= 2;
+            }
+            result => return result,
+        }
+    }
+    operation()
+}
+
+/// Atomically replace the hosts file content (temp file then rename)
+pub fn write_hosts_file(hosts_file_path: &Path, content: &str) -> Result<(), anyhow::Error> {
+    let target = resolve_hosts_target(hosts_file_path)?;
+    let temp_path = hosts_temp_path(&target);
+    retry_transient(WRITE_ATTEMPTS, WRITE_RETRY_DELAY, || {
+        fs::write(&temp_path, content)?;
+        fs::rename(&temp_path, &target)
+    })?;
+    Ok(())
+}
+
+/// Atomically replace a file the app owns, such as the state database, so a
+/// crash mid-write leaves the previous content rather than a truncated file
+pub fn write_atomic(path: &Path, content: &str) -> Result<(), anyhow::Error> {
+    let mut temp_name = path
+        .file_name()
+        .ok_or_else(|| anyhow::anyhow!("Not a file path: {}", path.display()))?
+        .to_os_string();
+    temp_name.push(".tmp");
+    let temp_path = path.with_file_name(temp_name);
+    let mut file = fs::File::create(&temp_path)?;
+    file.write_all(content.as_bytes())?;
+    file.sync_all()?;
+    drop(file);
+    fs::rename(&temp_path, path)?;
+    Ok(())
+}
+
+/// Whether this process can replace the hosts file
+/// An existing file must open for writing; a missing one needs a writable directory
+pub fn can_write_hosts(hosts_file_path: &Path) -> bool {
+    let Ok(target) = resolve_hosts_target(hosts_file_path) else {
+        return false;
+    };
+    if target.exists() {
+        return fs::OpenOptions::new().write(true).open(&target).is_ok();
+    }
+    target
+        .parent()
+        .and_then(|parent| fs::metadata(parent).ok())
+        .is_some_and(|metadata| metadata.is_dir() && !metadata.permissions().readonly())
+}
+
+/// A temp file left by an interrupted write that can replace a broken hosts file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RecoveryCandidate {
+    pub temp_path: PathBuf,
+    pub hosts_path: PathBuf,
+    /// Why the hosts file needs recovering
+    pub reason: Arc<str>,
+}
+
+/// Look for a leftover temp file that could restore a missing or invalid hosts file
+/// A valid hosts file means the temp file is stale and nothing is offered
+pub fn find_recovery_candidate(
+    hosts_file_path: &Path,
+) -> Result<Option<RecoveryCandidate>, anyhow::Error> {
+    let target = resolve_hosts_target(hosts_file_path)?;
+    let temp_path = hosts_temp_path(&target);
+    // Only a temp file that parses cleanly is worth restoring
+    if !temp_path.is_file() || !verify_host_file(&temp_path).is_ok_and(|w| w.is_empty()) {
+        return Ok(None);
+    }
+
+    let reason = if !target.exists() {
+        "Hosts file is missing".to_string()
+    } else {
+        match verify_host_file(&target) {
+            Ok(warnings) if warnings.is_empty() => return Ok(None),
+            // A partly written file tends to end in a line that isn't hosts syntax
+            Ok(warnings) => format!("Hosts file has {} invalid lines", warnings.len()),
+            Err(e) => format!("Hosts file is invalid: {}", e),
+        }
+    };
+
+    Ok(Some(RecoveryCandidate {
+        temp_path,
+        hosts_path: target,
+        reason: reason.into(),
+    }))
+}
+
+/// Replace a missing or invalid hosts file with the leftover temp file
+pub fn recover_from_temp(hosts_file_path: &Path) -> Result<RecoveryCandidate, anyhow::Error> {
+    let candidate = find_recovery_candidate(hosts_file_path)?
+        .ok_or_else(|| anyhow::anyhow!("No recoverable temp file found"))?;
+    fs::rename(&candidate.temp_path, &candidate.hosts_path)?;
+    Ok(candidate)
+}
+
+/// Wall time of each phase of the last successful commit, in milliseconds
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CommitTimings {
+    pub serialize_ms: u64,
+    /// Snapshot, verification and pruning (zero when history is disabled or skipped)
+    pub history_ms: u64,
+    pub write_ms: u64,
+    pub dns_flush_ms: u64,
+    pub total_ms: u64,
+}
+
+#[inline]
+fn elapsed_ms(since: Instant) -> u64 {
+    since.elapsed().as_millis() as u64
+}
+
+/// How long saving the current state takes, measured against a scratch file
+/// Microseconds, since small lists save in well under a millisecond
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CommitBenchmark {
+    pub entries: usize,
+    pub bytes: usize,
+    pub serialize_us: u64,
+    /// Temp file write and rename, as a save does it
+    pub write_us: u64,
+    /// DNS flush of the last real save; flushing isn't benchmarked, since it
+    /// would clear the system's cache
+    pub last_dns_flush_ms: Option<u64>,
+    pub total_us: u64,
+}
+
+/// Serialize the state and write it to a hosts file in `scratch_dir` the way
+/// a save would, timing each step; the real hosts file and DNS cache are
+/// left alone
+pub fn benchmark_commit(
+    state: &AppState,
+    scratch_dir: &Path,
+) -> Result<CommitBenchmark, anyhow::Error> {
+    let elapsed_us = |since: Instant| (since.elapsed().as_micros() as u64).max(1);
+    let started = Instant::now();
+    let content = ensure_localhost_entry(state.serialize());
+    let serialize_us = elapsed_us(started);
+
+    fs::create_dir_all(scratch_dir)?;
+    let scratch_path = scratch_dir.join("hosts");
+    let phase = Instant::now();
+    let written = write_hosts_file(&scratch_path, &content);
+    let write_us = elapsed_us(phase);
+    let _ = fs::remove_file(&scratch_path);
+    written?;
+
+    Ok(CommitBenchmark {
+        entries: state.get_total_blocked(),
+        bytes: content.len(),
+        serialize_us,
+        write_us,
+        last_dns_flush_ms: state
+            .last_commit_timings
+            .lock()
+            .map(|timings| timings.dns_flush_ms),
+        total_us: serialize_us + write_us,
+    })
+}
+
+/// Write, verify and prune the history snapshot for a commit
+async fn snapshot_history(history: HistorySettings, content: String) -> Result<(), anyhow::Error> {
+    let history_entry = async_runtime::spawn_blocking({
+        let history = history.clone();
+        move || write_snapshot(&history, &content)
+    })
+    .await??;
+
+    // Verify the history file, rebuilding it if it was stored as a delta
+    verify_hosts_content(&read_snapshot(&history_entry.path)?)?;
+
+    // Cleanup old history entries
+    async_runtime::spawn_blocking(move || {
+        cleanup_old_history(&history.dir, &history.prefix, history.max_entries)
+    })
+    .await??;
+    Ok(())
+}
+
+/// Decide whether a failed history snapshot stops the commit
+fn handle_history_failure(
+    state: &AppState,
+    policy: HistoryFailurePolicy,
+    snapshot: Result<(), anyhow::Error>,
+) -> Result<(), anyhow::Error> {
+    match (snapshot, policy) {
+        (Ok(()), _) => Ok(()),
+        (Err(e), HistoryFailurePolicy::Abort) => Err(e),
+        (Err(e), HistoryFailurePolicy::WarnAndContinue) => {
+            state.log_error(
+                "commit",
+                format!("History snapshot failed, saving anyway: {}", e),
+            );
+            Ok(())
+        }
+    }
+}
+
+/// Refuse to write a state with no preserved lines, which would leave the
+/// hosts file without localhost entries unless `allow_empty` is set
+///
+/// Such a state usually comes from a bad import or an accidental clear, and
+/// writing it can break localhost resolution system-wide.
+pub fn check_not_empty(state: &AppState, allow_empty: bool) -> Result<(), anyhow::Error> {
+    let has_preserved_lines = state.preserved_lines.lock().iter().any(|line| match line {
+        PreservedLine::ManagedRegion => false,
+        PreservedLine::Raw(text) => !text.trim().is_empty(),
+        _ => true,
+    });
+    if has_preserved_lines || allow_empty {
+        return Ok(());
+    }
+    Err(anyhow::anyhow!(
+        "Refusing to write a hosts file with no localhost entries or preserved lines; \
+         save with allow_empty to write it anyway"
+    ))
+}
+
+/// Put a `127.0.0.1 localhost` line first unless the content already maps it
+pub fn ensure_localhost_entry(content: String) -> String {
+    let maps_localhost = content.lines().any(|line| {
+        let mut tokens = line
+            .split('#')
+            .next()
+            .unwrap_or_default()
+            .split_whitespace();
+        tokens.next().and_then(|ip| ip.parse::<IpAddr>().ok()) == Some(DEFAULT_SINK)
+            && tokens.any(|hostname| hostname.eq_ignore_ascii_case("localhost"))
+    });
+    if maps_localhost {
+        return content;
+    }
+    format!("127.0.0.1 localhost\n{}", content)
+}
+
+/// Write the state to the hosts file, logging each phase under a `commit` span
 pub async fn commit_changes(
     state: Arc<AppState>,
     hosts_file_path: Arc<Path>,
-    history_dir: Option<Arc<Path>>,
-    max_history_entries: usize,
+    history: Option<HistorySettings>,
+    history_failure_policy: HistoryFailurePolicy,
+    lock_timeout: Option<Duration>,
+    allow_empty: bool,
 ) -> Result<(), anyhow::Error> {
-    // Serialize current state
-    let content = state.serialize();
+    commit_with_flush(
+        state,
+        hosts_file_path,
+        history,
+        history_failure_policy,
+        lock_timeout,
+        allow_empty,
+        flush_dns,
+    )
+    .await
+}
+
+/// `commit_changes` with the DNS flush run after the write passed in
+async fn commit_with_flush(
+    state: Arc<AppState>,
+    hosts_file_path: Arc<Path>,
+    history: Option<HistorySettings>,
+    history_failure_policy: HistoryFailurePolicy,
+    lock_timeout: Option<Duration>,
+    allow_empty: bool,
+    flush: fn() -> Result<(), anyhow::Error>,
+) -> Result<(), anyhow::Error> {
+    check_not_empty(&state, allow_empty)?;
+    let span = info_span!("commit", hosts_file = %hosts_file_path.display());
+    run_commit(
+        state,
+        hosts_file_path,
+        history,
+        history_failure_policy,
+        lock_timeout,
+        flush,
+    )
+    .instrument(span)
+    .await
+}
+
+async fn run_commit(
+    state: Arc<AppState>,
+    hosts_file_path: Arc<Path>,
+    history: Option<HistorySettings>,
+    history_failure_policy: HistoryFailurePolicy,
+    lock_timeout: Option<Duration>,
+    flush: fn() -> Result<(), anyhow::Error>,
+) -> Result<(), anyhow::Error> {
+    let started = Instant::now();
+    let mut timings = CommitTimings::default();
 
-    // Write to history directory if enabled
-    if let Some(ref history_dir) = history_dir {
-        let history_entry = async_runtime::spawn_blocking({
-            let content = content.clone();
-            let history_dir = history_dir.clone();
-            move || write_history_snapshot(&history_dir, &content)
-        })
-        .await??;
+    // Serialize current state
+    let content = ensure_localhost_entry(state.serialize());
+    timings.serialize_ms = elapsed_ms(started);
 
-        // Verify the history file
-        verify_host_file(&history_entry.path)?;
+    // Held from before the history snapshot until after the rename
+    let lock = match lock_timeout {
+        Some(timeout) => Some(
+            async_runtime::spawn_blocking({
+                let hosts_file_path = hosts_file_path.clone();
+                move || acquire_commit_lock(&hosts_file_path, timeout)
+            })
+            .instrument(info_span!("lock"))
+            .await??,
+        ),
+        None => None,
+    };
 
-        // Cleanup old history entries
-        async_runtime::spawn_blocking({
-            let history_dir = history_dir.clone();
-            move || cleanup_old_history(&history_dir, max_history_entries)
-        })
-        .await??;
+    // Write to history directory if enabled, skipping snapshots identical to the last one
+    let history =
+        history.filter(|history| !matches_latest_snapshot(&history.dir, &history.prefix, &content));
+    if let Some(history) = history {
+        let phase = Instant::now();
+        let snapshot = snapshot_history(history, content.clone())
+            .instrument(info_span!("history"))
+            .await;
+        handle_history_failure(&state, history_failure_policy, snapshot)?;
+        timings.history_ms = elapsed_ms(phase);
+        debug!(elapsed_ms = timings.history_ms, "History snapshot written");
     }
 
     // Write to actual hosts file (atomic write)
+    let phase = Instant::now();
     async_runtime::spawn_blocking({
         let content = content.clone();
         let hosts_file_path = hosts_file_path.clone();
-        move || {
-            let temp_path = hosts_file_path.with_extension("tmp");
-            fs::write(&temp_path, content)?;
-            fs::rename(&temp_path, &hosts_file_path)?;
-            Ok::<(), anyhow::Error>(())
-        }
+        move || write_hosts_file(&hosts_file_path, &content)
     })
+    .instrument(info_span!("write"))
     .await??;
+    timings.write_ms = elapsed_ms(phase);
+    debug!(elapsed_ms = timings.write_ms, "Hosts file written");
+    drop(lock);
+    state.record_last_modified(&hosts_file_path);
 
     // Flush DNS cache
-    async_runtime::spawn_blocking(|| {
-        flush_dns().map_err(|e| anyhow::anyhow!("Failed to flush DNS: {}", e))
+    let phase = Instant::now();
+    async_runtime::spawn_blocking(move || {
+        flush().map_err(|e| anyhow::anyhow!("Failed to flush DNS: {}", e))
     })
+    .instrument(info_span!("flush_dns"))
     .await??;
+    timings.dns_flush_ms = elapsed_ms(phase);
+    debug!(elapsed_ms = timings.dns_flush_ms, "DNS cache flushed");
+
+    timings.total_ms = elapsed_ms(started);
+    info!(total_ms = timings.total_ms, "Saved hosts file");
+    *state.last_commit_timings.lock() = Some(timings);
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::history::DEFAULT_HISTORY_PREFIX;
+    use tempfile::TempDir;
+
+    /// Stands in for the DNS flush, which tests must not run on the host
+    fn skip_flush() -> Result<(), anyhow::Error> {
+        Ok(())
+    }
+
+    #[test]
+    fn test_benchmark_uses_scratch_file() {
+        let temp_dir = TempDir::new().unwrap();
+        let hosts_path = temp_dir.path().join("hosts");
+        let scratch_dir = temp_dir.path().join("scratch");
+        fs::write(&hosts_path, "127.0.0.1 localhost\n").unwrap();
+
+        let state = AppState::new(crate::config::Config {
+            host_file_path: Some(hosts_path.as_path().into()),
+            ..crate::config::Config::default()
+        });
+        for i in 0..1000 {
+            state.add_block(&format!("ads{}.example.com", i));
+        }
+
+        let benchmark = benchmark_commit(&state, &scratch_dir).unwrap();
+        assert_eq!(benchmark.entries, 1000);
+        assert!(benchmark.bytes > 1000 * "ads.example.com".len());
+        assert!(benchmark.serialize_us > 0);
+        assert!(benchmark.write_us > 0);
+        assert_eq!(
+            benchmark.total_us,
+            benchmark.serialize_us + benchmark.write_us
+        );
+        assert_eq!(benchmark.last_dns_flush_ms, None);
+
+        // The real hosts file is untouched and the scratch file is cleaned up
+        assert_eq!(
+            fs::read_to_string(&hosts_path).unwrap(),
+            "127.0.0.1 localhost\n"
+        );
+        assert_eq!(fs::read_dir(&scratch_dir).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn test_coalescer_writes_once_per_burst() {
+        let coalescer = Arc::new(CommitCoalescer::default());
+        let writes = Arc::new(AtomicU64::new(0));
+
+        let outcomes = async_runtime::block_on(async {
+            let handles: Vec<_> = (0..3)
+                .map(|_| {
+                    let coalescer = coalescer.clone();
+                    let writes = writes.clone();
+                    async_runtime::spawn(async move {
+                        coalescer
+                            .run(Duration::from_millis(50), || async move {
+                                writes.fetch_add(1, Ordering::SeqCst);
+                                Ok(())
+                            })
+                            .await
+                            .unwrap()
+                    })
+                })
+                .collect();
+
+            let mut outcomes = Vec::new();
+            for handle in handles {
+                outcomes.push(handle.await.unwrap());
+            }
+            outcomes
+        });
+
+        assert_eq!(writes.load(Ordering::SeqCst), 1);
+        assert_eq!(
+            outcomes
+                .iter()
+                .filter(|outcome| **outcome == CommitOutcome::Written)
+                .count(),
+            1
+        );
+    }
+
+    #[test]
+    fn test_coalesced_requests_share_the_write_result() {
+        let coalescer = Arc::new(CommitCoalescer::default());
+        let results = async_runtime::block_on(async {
+            let handles: Vec<_> = (0..3)
+                .map(|_| {
+                    let coalescer = coalescer.clone();
+                    async_runtime::spawn(async move {
+                        coalescer
+                            .run(Duration::from_millis(50), || async {
+                                Err(anyhow::anyhow!("permission denied"))
+                            })
+                            .await
+                    })
+                })
+                .collect();
+            let mut results = Vec::new();
+            for handle in handles {
+                results.push(handle.await.unwrap());
+            }
+            results
+        });
+
+        for result in results {
+            assert!(result
+                .unwrap_err()
+                .to_string()
+                .contains("permission denied"));
+        }
+    }
+
+    #[test]
+    fn test_steady_saves_still_write() {
+        let coalescer = Arc::new(CommitCoalescer::default());
+        let writes = Arc::new(AtomicU64::new(0));
+        let window = Duration::from_millis(20);
+
+        async_runtime::block_on(async {
+            // A new save every half window, for longer than the cap
+            let mut handles = Vec::new();
+            for _ in 0..(MAX_COALESCED_WINDOWS * 3) {
+                let coalescer = coalescer.clone();
+                let writes = writes.clone();
+                handles.push(async_runtime::spawn(async move {
+                    coalescer
+                        .run(window, || async move {
+                            writes.fetch_add(1, Ordering::SeqCst);
+                            Ok(())
+                        })
+                        .await
+                }));
+                tokio::time::sleep(window / 2).await;
+            }
+            for handle in handles {
+                handle.await.unwrap().unwrap();
+            }
+        });
+
+        // Written before the stream stopped, then once more for its tail
+        assert!(writes.load(Ordering::SeqCst) >= 2);
+    }
+
+    #[test]
+    fn test_held_lock_blocks_second_commit() {
+        let temp_dir = TempDir::new().unwrap();
+        let hosts_path = temp_dir.path().join("hosts");
+        fs::write(&hosts_path, "127.0.0.1 localhost\n").unwrap();
+
+        let held = acquire_commit_lock(&hosts_path, Duration::from_millis(100)).unwrap();
+        assert!(temp_dir.path().join("hosts.lock").exists());
+
+        let err = acquire_commit_lock(&hosts_path, Duration::from_millis(100))
+            .err()
+            .unwrap();
+        assert!(err.downcast_ref::<CommitBusy>().is_some());
+
+        // A commit waiting on the lock proceeds once it's released
+        let waiter = std::thread::spawn({
+            let hosts_path = hosts_path.clone();
+            move || acquire_commit_lock(&hosts_path, Duration::from_secs(5)).is_ok()
+        });
+        std::thread::sleep(Duration::from_millis(100));
+        drop(held);
+        assert!(waiter.join().unwrap());
+    }
+
+    #[test]
+    fn test_commit_updates_last_modified() {
+        let temp_dir = TempDir::new().unwrap();
+        let hosts_path = temp_dir.path().join("hosts");
+        fs::write(&hosts_path, "127.0.0.1 localhost\n").unwrap();
+
+        let state = Arc::new(AppState::new(crate::config::Config::default()));
+        state.load_from_file(&hosts_path).unwrap();
+        let loaded = state.last_modified.lock().unwrap();
+
+        // Backdate the file so the commit's write is guaranteed a newer time
+        let backdated = loaded - Duration::from_secs(60);
+        fs::File::options()
+            .write(true)
+            .open(&hosts_path)
+            .unwrap()
+            .set_modified(backdated)
+            .unwrap();
+        state.load_from_file(&hosts_path).unwrap();
+        assert_eq!(*state.last_modified.lock(), Some(backdated));
+
+        state.add_block("ads.example.com");
+        async_runtime::block_on(commit_with_flush(
+            state.clone(),
+            hosts_path.as_path().into(),
+            None,
+            HistoryFailurePolicy::Abort,
+            None,
+            false,
+            skip_flush,
+        ))
+        .unwrap();
+
+        let committed = state.last_modified.lock().unwrap();
+        assert!(committed > backdated);
+        assert_eq!(
+            Some(committed),
+            fs::metadata(&hosts_path).unwrap().modified().ok()
+        );
+        assert!(can_write_hosts(&hosts_path));
+    }
+
+    #[test]
+    fn test_commit_refuses_empty_state() {
+        let temp_dir = TempDir::new().unwrap();
+        let hosts_path = temp_dir.path().join("hosts");
+        fs::write(&hosts_path, "127.0.0.1 localhost\n").unwrap();
+
+        // A state that never loaded the hosts file has no preserved lines
+        let state = Arc::new(AppState::new(crate::config::Config::default()));
+        state.add_block("ads.example.com");
+        let commit = |allow_empty| {
+            async_runtime::block_on(commit_with_flush(
+                state.clone(),
+                hosts_path.as_path().into(),
+                None,
+                HistoryFailurePolicy::Abort,
+                None,
+                allow_empty,
+                skip_flush,
+            ))
+        };
+
+        let error = commit(false).unwrap_err().to_string();
+        assert!(error.contains("no localhost entries"));
+        assert_eq!(
+            fs::read_to_string(&hosts_path).unwrap(),
+            "127.0.0.1 localhost\n"
+        );
+
+        // Allowed explicitly, the write still keeps localhost resolving
+        commit(true).unwrap();
+        let content = fs::read_to_string(&hosts_path).unwrap();
+        assert!(content.starts_with("127.0.0.1 localhost\n"));
+        assert!(content.contains("ads.example.com"));
+    }
+
+    #[test]
+    fn test_ensure_localhost_entry() {
+        let content = "127.0.0.1\tlocalhost # loopback\n".to_string();
+        assert_eq!(ensure_localhost_entry(content.clone()), content);
+
+        let content = "::1 localhost\n192.168.1.1 router\n".to_string();
+        assert_eq!(
+            ensure_localhost_entry(content),
+            "127.0.0.1 localhost\n::1 localhost\n192.168.1.1 router\n"
+        );
+    }
+
+    #[test]
+    fn test_history_failure_policy() {
+        let temp_dir = TempDir::new().unwrap();
+        // A file where the history directory should be makes the snapshot fail
+        let history_dir = temp_dir.path().join("history");
+        fs::write(&history_dir, "").unwrap();
+
+        let snapshot = || {
+            async_runtime::block_on(snapshot_history(
+                HistorySettings {
+                    dir: history_dir.as_path().into(),
+                    prefix: DEFAULT_HISTORY_PREFIX.into(),
+                    max_entries: 5,
+                    keyframe_interval: 0,
+                },
+                "127.0.0.1 localhost\n".to_string(),
+            ))
+        };
+        let state = AppState::new(crate::config::Config::default());
+
+        assert!(handle_history_failure(&state, HistoryFailurePolicy::Abort, snapshot()).is_err());
+        assert!(state.get_recent_errors().is_empty());
+
+        assert!(
+            handle_history_failure(&state, HistoryFailurePolicy::WarnAndContinue, snapshot())
+                .is_ok()
+        );
+        let errors = state.get_recent_errors();
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].message.contains("History snapshot failed"));
+    }
+
+    #[test]
+    fn test_find_recovery_candidate() {
+        let temp_dir = TempDir::new().unwrap();
+        let hosts_path = temp_dir.path().join("hosts");
+        let temp_path = hosts_temp_path(&hosts_path);
+
+        // No temp file
+        assert_eq!(find_recovery_candidate(&hosts_path).unwrap(), None);
+
+        // Temp file left behind and the hosts file is missing
+        fs::write(&temp_path, "127.0.0.1 localhost example.com\n").unwrap();
+        let candidate = find_recovery_candidate(&hosts_path).unwrap().unwrap();
+        assert_eq!(candidate.temp_path, temp_path);
+        assert_eq!(candidate.hosts_path, hosts_path);
+
+        // The hosts file is invalid
+        fs::write(&hosts_path, "").unwrap();
+        assert!(find_recovery_candidate(&hosts_path).unwrap().is_some());
+
+        // A valid hosts file makes the temp file stale
+        fs::write(&hosts_path, "127.0.0.1 localhost\n").unwrap();
+        assert_eq!(find_recovery_candidate(&hosts_path).unwrap(), None);
+
+        // An invalid temp file is never offered
+        fs::remove_file(&hosts_path).unwrap();
+        fs::write(&temp_path, "not a hosts file @@@\n").unwrap();
+        assert_eq!(find_recovery_candidate(&hosts_path).unwrap(), None);
+    }
+
+    #[test]
+    fn test_recover_from_temp() {
+        let temp_dir = TempDir::new().unwrap();
+        let hosts_path = temp_dir.path().join("hosts");
+        fs::write(
+            hosts_temp_path(&hosts_path),
+            "127.0.0.1 localhost example.com\n",
+        )
+        .unwrap();
+
+        recover_from_temp(&hosts_path).unwrap();
+
+        let content = fs::read_to_string(&hosts_path).unwrap();
+        assert_eq!(content, "127.0.0.1 localhost example.com\n");
+        assert!(!hosts_temp_path(&hosts_path).exists());
+        assert!(recover_from_temp(&hosts_path).is_err());
+    }
+
+    #[test]
+    fn test_retry_transient() {
+        let busy = || io::Error::from(io::ErrorKind::ResourceBusy);
+
+        // Transient failures are retried until the operation succeeds
+        let mut calls = 0;
+        let result = retry_transient(3, Duration::from_millis(1), || {
+            calls += 1;
+            if calls < 3 {
+                Err(busy())
+            } else {
+                Ok(calls)
+            }
+        });
+        assert_eq!(result.unwrap(), 3);
+
+        // The underlying error is returned once the attempts run out
+        let mut calls = 0;
+        let result: io::Result<()> = retry_transient(3, Duration::from_millis(1), || {
+            calls += 1;
+            Err(busy())
+        });
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::ResourceBusy);
+        assert_eq!(calls, 3);
+
+        // Permission errors fail straight away
+        let mut calls = 0;
+        let result: io::Result<()> = retry_transient(3, Duration::from_millis(1), || {
+            calls += 1;
+            Err(io::Error::from(io::ErrorKind::PermissionDenied))
+        });
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::PermissionDenied);
+        assert_eq!(calls, 1);
+    }
+
+    #[test]
+    fn test_write_hosts_file() {
+        let temp_dir = TempDir::new().unwrap();
+        let hosts_path = temp_dir.path().join("hosts");
+        fs::write(&hosts_path, "127.0.0.1 localhost\n").unwrap();
+
+        write_hosts_file(&hosts_path, "127.0.0.1 localhost example.com\n").unwrap();
+
+        let content = fs::read_to_string(&hosts_path).unwrap();
+        assert_eq!(content, "127.0.0.1 localhost example.com\n");
+        assert!(!hosts_path.with_extension("tmp").exists());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_write_hosts_file_preserves_symlink() {
+        let temp_dir = TempDir::new().unwrap();
+        let target_path = temp_dir.path().join("hosts.real");
+        let link_path = temp_dir.path().join("hosts");
+        fs::write(&target_path, "127.0.0.1 localhost\n").unwrap();
+        std::os::unix::fs::symlink(&target_path, &link_path).unwrap();
+
+        write_hosts_file(&link_path, "127.0.0.1 localhost example.com\n").unwrap();
+
+        let metadata = fs::symlink_metadata(&link_path).unwrap();
+        assert!(metadata.file_type().is_symlink());
+        let content = fs::read_to_string(&target_path).unwrap();
+        assert_eq!(content, "127.0.0.1 localhost example.com\n");
+    }
+}
@@ -1,8 +1,18 @@
+use crate::history::{is_valid_history_prefix, HistorySettings, DEFAULT_HISTORY_PREFIX};
+use crate::parser::{
+    is_valid_disabled_marker, ParseOptions, Separator, SerializeOptions, DEFAULT_DISABLED_MARKER,
+    DEFAULT_SINK,
+};
+use crate::profiles::validate_profile_name;
+use crate::sources::Source;
+use crate::utils::is_localhost_ip;
 use pest_derive::Parser;
 use std::collections::HashMap;
 use std::fs;
+use std::net::IpAddr;
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::time::Duration;
 
 #[derive(Parser)]
 #[grammar = "grammar/config.pest"]
@@ -15,6 +25,8 @@ pub struct ConfigParser;
 pub enum Theme {
     Dark,
     Light,
+    /// Follow the OS appearance, resolved by the frontend via `get_system_theme`
+    System,
 }
 
 impl Theme {
@@ -22,6 +34,7 @@ impl Theme {
     pub fn from_str(s: &str) -> Self {
         match s.trim().to_lowercase().as_str() {
             "light" => Theme::Light,
+            "system" => Theme::System,
             _ => Theme::Dark, // Default to dark
         }
     }
@@ -31,16 +44,126 @@ impl Theme {
         match self {
             Theme::Dark => "dark",
             Theme::Light => "light",
+            Theme::System => "system",
         }
     }
 }
 
+/// What a commit does when the history snapshot can't be written
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum HistoryFailurePolicy {
+    /// Abort the save without touching the hosts file
+    #[default]
+    Abort,
+    /// Log the failure and write the hosts file anyway
+    WarnAndContinue,
+}
+
+impl HistoryFailurePolicy {
+    #[inline]
+    pub fn from_str(s: &str) -> Self {
+        match s.trim().to_lowercase().as_str() {
+            "warn-and-continue" => HistoryFailurePolicy::WarnAndContinue,
+            _ => HistoryFailurePolicy::Abort, // Default to the safe behavior
+        }
+    }
+
+    #[inline]
+    pub fn to_str(self) -> &'static str {
+        match self {
+            HistoryFailurePolicy::Abort => "abort",
+            HistoryFailurePolicy::WarnAndContinue => "warn-and-continue",
+        }
+    }
+}
+
+/// What an import does with domains on the bundled safe list
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum SafeListPolicy {
+    /// Import them, reporting each one
+    #[default]
+    Warn,
+    /// Leave them out of the import, reporting each one
+    Skip,
+}
+
+impl SafeListPolicy {
+    #[inline]
+    pub fn from_str(s: &str) -> Self {
+        match s.trim().to_lowercase().as_str() {
+            "skip" => SafeListPolicy::Skip,
+            _ => SafeListPolicy::Warn,
+        }
+    }
+
+    #[inline]
+    pub fn to_str(self) -> &'static str {
+        match self {
+            SafeListPolicy::Warn => "warn",
+            SafeListPolicy::Skip => "skip",
+        }
+    }
+}
+
+/// Default for `max_total_domains`, well above any list a system handles comfortably
+pub const DEFAULT_MAX_TOTAL_DOMAINS: usize = 500_000;
+
 #[derive(Clone, Debug)]
 pub struct Config {
     pub host_file_path: Option<Arc<Path>>,
     pub history_dir: Option<Arc<Path>>,
+    /// File the allowlist is exported to, one domain per line
+    pub allow_file_path: Option<Arc<Path>>,
     pub max_history_entries: usize,
+    /// Start of history snapshot filenames, so a shared folder can hold several apps' snapshots
+    pub history_prefix: String,
+    /// Store history snapshots as deltas against the previous one, with a
+    /// full copy every this many deltas (0 stores full copies only)
+    pub history_keyframe_interval: usize,
     pub theme: Theme,
+    /// Also write blocked domains under the IPv6 loopback sink
+    pub block_ipv6: bool,
+    /// Separator used in lines written to the managed region
+    pub separator: Separator,
+    /// End the hosts file with exactly one newline (false: no final newline)
+    pub ensure_trailing_newline: bool,
+    /// Comment marker written before disabled entries, e.g. `#` or `#@`
+    /// Only this marker and the default one are read back: entries still under
+    /// an earlier custom marker are kept as comments, so the file should be
+    /// saved after a change to rewrite the loaded entries under the new one
+    pub disabled_comment_prefix: String,
+    /// Keep localhost lines with their original whitespace
+    pub preserve_whitespace: bool,
+    /// Mark domains added through the app with an inline "added on" comment
+    pub annotate_additions: bool,
+    /// Sink domains added through the app are written under
+    pub blocking_sink: IpAddr,
+    /// Loopback addresses whose hosts entries count as blocked; empty means
+    /// every loopback or 0.0.0.0/:: address
+    pub sink_ips: Vec<IpAddr>,
+    /// Loopback addresses whose hosts entries are kept as written
+    /// (e.g. Debian's 127.0.1.1 hostname line)
+    pub preserve_ips: Vec<IpAddr>,
+    /// Whether imports keep or drop domains on the bundled safe list
+    pub safe_list_policy: SafeListPolicy,
+    /// Allow single-label names (e.g. "adserver") to be blocked instead of
+    /// treating them as local
+    pub block_single_label: bool,
+    /// Saves requested within this many milliseconds are coalesced into one write
+    pub save_debounce_ms: u64,
+    /// Whether a failed history snapshot aborts the save
+    pub history_failure_policy: HistoryFailurePolicy,
+    /// How long a save waits for another writer's lock (0 disables locking)
+    pub lock_timeout_ms: u64,
+    /// Comment block written at the top of the hosts file, if any
+    pub file_header: Option<String>,
+    /// Blocklists subscribed to by URL
+    pub sources: Vec<Source>,
+    /// Imports and additions may not block more domains than this without an
+    /// explicit override (0 disables the cap)
+    pub max_total_domains: usize,
+    /// Profile whose blocklist and hosts file are in use (None is the default profile)
+    pub active_profile: Option<String>,
 }
 
 impl Default for Config {
@@ -49,12 +172,40 @@ impl Default for Config {
         Config {
             host_file_path: None,
             history_dir: None,
-            max_history_entries: 5,
+            allow_file_path: None,
+            max_history_entries: 50,
+            history_prefix: DEFAULT_HISTORY_PREFIX.to_string(),
+            history_keyframe_interval: 0,
             theme: Theme::Dark,
+            block_ipv6: false,
+            separator: Separator::Space,
+            ensure_trailing_newline: true,
+            disabled_comment_prefix: DEFAULT_DISABLED_MARKER.to_string(),
+            preserve_whitespace: false,
+            annotate_additions: false,
+            blocking_sink: DEFAULT_SINK,
+            sink_ips: Vec::new(),
+            preserve_ips: Vec::new(),
+            safe_list_policy: SafeListPolicy::Warn,
+            block_single_label: false,
+            save_debounce_ms: 250,
+            history_failure_policy: HistoryFailurePolicy::Abort,
+            lock_timeout_ms: 5000,
+            file_header: None,
+            sources: Vec::new(),
+            max_total_domains: DEFAULT_MAX_TOTAL_DOMAINS,
+            active_profile: None,
         }
     }
 }
 
+/// Whether INI content has the `[paths]` section every saved config starts
+/// with; a file read while it's being rewritten may not
+#[inline]
+pub fn is_complete_ini(content: &str) -> bool {
+    content.lines().any(|line| line.trim() == "[paths]")
+}
+
 impl Config {
     /// Load config from INI file
     #[inline]
@@ -67,7 +218,7 @@ impl Config {
     }
 
     /// Parse INI content into Config
-    fn parse_ini(content: &str) -> Result<Self, anyhow::Error> {
+    pub fn parse_ini(content: &str) -> Result<Self, anyhow::Error> {
         use pest::Parser;
         // Rule enum is auto-generated by pest_derive - use it directly
         // The generated Rule is available in this module scope
@@ -88,7 +239,7 @@ impl Config {
                 Rule::property => {
                     let mut inner = line.into_inner();
                     let key = inner.next().unwrap().as_str();
-                    let value = inner.next().unwrap().as_str();
+                    let value = inner.next().unwrap().as_str().trim_end();
 
                     let section = properties.entry(current_section).or_default();
                     section.insert(key, value);
@@ -111,11 +262,34 @@ impl Config {
                     config.history_dir = Some(history_path.as_path().into());
                 }
             }
-            if let Some(max_entries) = paths.get("max_history_entries") {
+            if let Some(&allow_path) = paths.get("allow_file_path") {
+                if !allow_path.is_empty() {
+                    let allow_path = PathBuf::from(allow_path);
+                    config.allow_file_path = Some(allow_path.as_path().into());
+                }
+            }
+        }
+
+        // Parse [history] section; older configs keep these keys under [paths]
+        for section in ["paths", "history"] {
+            let Some(history) = properties.get(section) else {
+                continue;
+            };
+            if let Some(max_entries) = history.get("max_history_entries") {
                 if let Ok(max) = max_entries.parse::<usize>() {
                     config.max_history_entries = max;
                 }
             }
+            if let Some(&prefix) = history.get("history_prefix") {
+                if is_valid_history_prefix(prefix) {
+                    config.history_prefix = prefix.to_string();
+                }
+            }
+            if let Some(interval) = history.get("history_keyframe_interval") {
+                if let Ok(interval) = interval.parse::<usize>() {
+                    config.history_keyframe_interval = interval;
+                }
+            }
         }
 
         // Parse [appearance] section
@@ -125,9 +299,222 @@ impl Config {
             }
         }
 
+        // Parse [blocking] section
+        if let Some(blocking) = properties.get("blocking") {
+            if let Some(block_ipv6) = blocking.get("block_ipv6") {
+                if let Ok(block_ipv6) = block_ipv6.parse::<bool>() {
+                    config.block_ipv6 = block_ipv6;
+                }
+            }
+            if let Some(separator) = blocking.get("separator") {
+                config.separator = Separator::from_str(separator);
+            }
+            if let Some(trailing) = blocking.get("ensure_trailing_newline") {
+                if let Ok(trailing) = trailing.parse::<bool>() {
+                    config.ensure_trailing_newline = trailing;
+                }
+            }
+            if let Some(&marker) = blocking.get("disabled_comment_prefix") {
+                if is_valid_disabled_marker(marker) {
+                    config.disabled_comment_prefix = marker.to_string();
+                }
+            }
+            if let Some(preserve) = blocking.get("preserve_whitespace") {
+                if let Ok(preserve) = preserve.parse::<bool>() {
+                    config.preserve_whitespace = preserve;
+                }
+            }
+            if let Some(annotate) = blocking.get("annotate_additions") {
+                if let Ok(annotate) = annotate.parse::<bool>() {
+                    config.annotate_additions = annotate;
+                }
+            }
+            if let Some(single_label) = blocking.get("block_single_label") {
+                if let Ok(single_label) = single_label.parse::<bool>() {
+                    config.block_single_label = single_label;
+                }
+            }
+            if let Some(header) = blocking.get("file_header") {
+                let header = unescape_ini_value(header);
+                config.file_header = (!header.trim().is_empty()).then_some(header);
+            }
+            if let Some(max_total) = blocking.get("max_total_domains") {
+                if let Ok(max_total) = max_total.parse::<usize>() {
+                    config.max_total_domains = max_total;
+                }
+            }
+            if let Some(policy) = blocking.get("safe_list_policy") {
+                config.safe_list_policy = SafeListPolicy::from_str(policy);
+            }
+            // A routable sink is ignored like any other invalid value
+            if let Some(sink) = blocking.get("blocking_sink") {
+                if let Ok(sink) = sink.parse::<IpAddr>() {
+                    if is_localhost_ip(&sink) {
+                        config.blocking_sink = sink;
+                    }
+                }
+            }
+            if let Some(sinks) = blocking.get("sink_ips") {
+                config.sink_ips = parse_loopback_list(sinks);
+            }
+            if let Some(preserved) = blocking.get("preserve_ips") {
+                config.preserve_ips = parse_loopback_list(preserved);
+            }
+        }
+
+        // Parse [saving] section
+        if let Some(saving) = properties.get("saving") {
+            if let Some(debounce) = saving.get("save_debounce_ms") {
+                if let Ok(debounce) = debounce.parse::<u64>() {
+                    config.save_debounce_ms = debounce;
+                }
+            }
+            if let Some(policy) = saving.get("history_failure_policy") {
+                config.history_failure_policy = HistoryFailurePolicy::from_str(policy);
+            }
+            if let Some(timeout) = saving.get("lock_timeout_ms") {
+                if let Ok(timeout) = timeout.parse::<u64>() {
+                    config.lock_timeout_ms = timeout;
+                }
+            }
+        }
+
+        // Parse [sources] section: source.1, source.2, ... in numeric order
+        if let Some(sources) = properties.get("sources") {
+            let mut numbered: Vec<(usize, &str)> = sources
+                .iter()
+                .filter_map(|(key, value)| {
+                    let number = key.strip_prefix("source.")?.parse().ok()?;
+                    Some((number, *value))
+                })
+                .collect();
+            numbered.sort_unstable();
+            config.sources = numbered
+                .into_iter()
+                .filter_map(|(_, value)| Source::from_ini(value).ok())
+                .collect();
+        }
+
+        // Parse [profiles] section
+        if let Some(profiles) = properties.get("profiles") {
+            if let Some(&active) = profiles.get("active") {
+                if validate_profile_name(active).is_ok() {
+                    config.active_profile = Some(active.to_string());
+                }
+            }
+        }
+
         Ok(config)
     }
 
+    /// Check values that would be dangerous to apply
+    /// A sink must be a loopback or unspecified address: a routable one would
+    /// send every blocked domain to that host instead of nowhere
+    pub fn validate(&self) -> Result<(), anyhow::Error> {
+        if !is_localhost_ip(&self.blocking_sink) {
+            return Err(anyhow::anyhow!(
+                "blocking_sink {} is a routable address; blocked domains would resolve to it \
+                 (use a loopback address such as 127.0.0.1 or ::1, or 0.0.0.0/::)",
+                self.blocking_sink
+            ));
+        }
+        if let Some(ip) = self
+            .sink_ips
+            .iter()
+            .chain(&self.preserve_ips)
+            .find(|ip| !is_localhost_ip(ip))
+        {
+            return Err(anyhow::anyhow!(
+                "{} is a routable address; sink_ips and preserve_ips only take loopback \
+                 or 0.0.0.0/:: addresses",
+                ip
+            ));
+        }
+        // Domains added in the app must read back as blocked
+        if !self.parse_options().is_sink(&self.blocking_sink) {
+            return Err(anyhow::anyhow!(
+                "blocking_sink {} must be one of sink_ips and not one of preserve_ips",
+                self.blocking_sink
+            ));
+        }
+        if let Some(source) = self.sources.iter().find(|source| {
+            source
+                .sink
+                .is_some_and(|sink| !self.parse_options().is_sink(&sink))
+        }) {
+            return Err(anyhow::anyhow!(
+                "Source {} is imported under a sink that isn't blocked (not one of sink_ips, \
+                 or one of preserve_ips)",
+                source.url
+            ));
+        }
+        Ok(())
+    }
+
+    /// Fall back to the default sink settings, which always pass `validate`
+    /// Used at startup, where a config that fails validation can't be refused
+    pub fn reset_sinks(&mut self) {
+        self.blocking_sink = DEFAULT_SINK;
+        self.sink_ips.clear();
+        self.preserve_ips.clear();
+    }
+
+    /// Delay used to coalesce rapid successive saves
+    #[inline]
+    pub fn save_debounce(&self) -> Duration {
+        Duration::from_millis(self.save_debounce_ms)
+    }
+
+    /// How long to wait for the hosts file lock, or None if locking is disabled
+    #[inline]
+    pub fn lock_timeout(&self) -> Option<Duration> {
+        (self.lock_timeout_ms > 0).then(|| Duration::from_millis(self.lock_timeout_ms))
+    }
+
+    /// Where and how history snapshots are kept, or None if history is disabled
+    #[inline]
+    pub fn history_settings(&self) -> Option<HistorySettings> {
+        self.history_dir.clone().map(|dir| HistorySettings {
+            dir,
+            prefix: self.history_prefix.as_str().into(),
+            max_entries: self.max_history_entries,
+            keyframe_interval: self.history_keyframe_interval,
+        })
+    }
+
+    /// Options controlling how hosts file content is written
+    #[inline]
+    pub fn serialize_options(&self) -> SerializeOptions {
+        SerializeOptions {
+            block_ipv6: self.block_ipv6,
+            separator: self.separator,
+            omit_trailing_newline: !self.ensure_trailing_newline,
+            paused: false,
+            header: self.file_header.as_deref().map(Arc::from),
+            disabled_marker: self.disabled_marker(),
+        }
+    }
+
+    /// Options controlling how hosts file content is read
+    #[inline]
+    pub fn parse_options(&self) -> ParseOptions {
+        ParseOptions {
+            preserve_whitespace: self.preserve_whitespace,
+            block_single_label: self.block_single_label,
+            sink_ips: self.sink_ips.clone(),
+            preserve_ips: self.preserve_ips.clone(),
+            disabled_marker: self.disabled_marker(),
+            ..ParseOptions::default()
+        }
+    }
+
+    /// The configured disabled entry marker, or None for the default one
+    #[inline]
+    fn disabled_marker(&self) -> Option<Arc<str>> {
+        (self.disabled_comment_prefix != DEFAULT_DISABLED_MARKER)
+            .then(|| self.disabled_comment_prefix.as_str().into())
+    }
+
     /// Serialize config to INI format
     pub fn to_ini(&self) -> String {
         let mut result = String::from("# AdBlock Manager Configuration\n\n");
@@ -140,15 +527,94 @@ impl Config {
         if let Some(ref history_path) = self.history_dir {
             result.push_str(&format!("history_dir = {}\n", history_path.display()));
         }
+        if let Some(ref allow_path) = self.allow_file_path {
+            result.push_str(&format!("allow_file_path = {}\n", allow_path.display()));
+        }
+        result.push('\n');
+
+        // [history] section
+        result.push_str("[history]\n");
         result.push_str(&format!(
-            "max_history_entries = {}\n\n",
+            "max_history_entries = {}\n",
             self.max_history_entries
         ));
+        result.push_str(&format!("history_prefix = {}\n", self.history_prefix));
+        result.push_str(&format!(
+            "history_keyframe_interval = {}\n\n",
+            self.history_keyframe_interval
+        ));
 
         // [appearance] section
         result.push_str("[appearance]\n");
         result.push_str(&format!("theme = {}\n\n", self.theme.to_str()));
 
+        // [blocking] section
+        result.push_str("[blocking]\n");
+        result.push_str(&format!("block_ipv6 = {}\n", self.block_ipv6));
+        result.push_str(&format!("separator = {}\n", self.separator.to_str()));
+        result.push_str(&format!(
+            "ensure_trailing_newline = {}\n",
+            self.ensure_trailing_newline
+        ));
+        result.push_str(&format!(
+            "disabled_comment_prefix = {}\n",
+            self.disabled_comment_prefix
+        ));
+        result.push_str(&format!(
+            "preserve_whitespace = {}\n",
+            self.preserve_whitespace
+        ));
+        result.push_str(&format!(
+            "annotate_additions = {}\n",
+            self.annotate_additions
+        ));
+        result.push_str(&format!("blocking_sink = {}\n", self.blocking_sink));
+        if !self.sink_ips.is_empty() {
+            result.push_str(&format!("sink_ips = {}\n", join_ips(&self.sink_ips)));
+        }
+        if !self.preserve_ips.is_empty() {
+            result.push_str(&format!(
+                "preserve_ips = {}\n",
+                join_ips(&self.preserve_ips)
+            ));
+        }
+        result.push_str(&format!(
+            "block_single_label = {}\n",
+            self.block_single_label
+        ));
+        if let Some(ref header) = self.file_header {
+            result.push_str(&format!("file_header = {}\n", escape_ini_value(header)));
+        }
+        result.push_str(&format!("max_total_domains = {}\n", self.max_total_domains));
+        result.push_str(&format!(
+            "safe_list_policy = {}\n\n",
+            self.safe_list_policy.to_str()
+        ));
+
+        // [saving] section
+        result.push_str("[saving]\n");
+        result.push_str(&format!("save_debounce_ms = {}\n", self.save_debounce_ms));
+        result.push_str(&format!(
+            "history_failure_policy = {}\n",
+            self.history_failure_policy.to_str()
+        ));
+        result.push_str(&format!("lock_timeout_ms = {}\n\n", self.lock_timeout_ms));
+
+        // [sources] section
+        if !self.sources.is_empty() {
+            result.push_str("[sources]\n");
+            for (index, source) in self.sources.iter().enumerate() {
+                result.push_str(&format!("source.{} = {}\n", index + 1, source.to_ini()));
+            }
+            result.push('\n');
+        }
+
+        // [profiles] section
+        if let Some(ref active) = self.active_profile {
+            result.push_str("[profiles]\n");
+            result.push_str(&format!("active = {}\n\n", active));
+        }
+
         result
     }
 
@@ -165,6 +631,57 @@ impl Config {
     }
 }
 
+/// Escape line breaks and backslashes so a value fits on one INI line
+fn escape_ini_value(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace("\r\n", "\n")
+        .replace('\n', "\\n")
+}
+
+/// Reverse `escape_ini_value`
+fn unescape_ini_value(value: &str) -> String {
+    let mut result = String::with_capacity(value.len());
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        match (c, chars.clone().next()) {
+            ('\\', Some('n')) => {
+                result.push('\n');
+                chars.next();
+            }
+            ('\\', Some('\\')) => {
+                result.push('\\');
+                chars.next();
+            }
+            _ => result.push(c),
+        }
+    }
+    result
+}
+
+/// Read a comma or space separated list of loopback addresses, skipping
+/// anything else like any other invalid value
+fn parse_loopback_list(value: &str) -> Vec<IpAddr> {
+    let mut ips: Vec<IpAddr> = Vec::new();
+    for ip in value
+        .split(|c: char| c == ',' || c.is_whitespace())
+        .filter_map(|token| token.parse::<IpAddr>().ok())
+    {
+        if is_localhost_ip(&ip) && !ips.contains(&ip) {
+            ips.push(ip);
+        }
+    }
+    ips
+}
+
+/// Addresses as written by `to_ini`
+fn join_ips(ips: &[IpAddr]) -> String {
+    ips.iter()
+        .map(IpAddr::to_string)
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -191,6 +708,40 @@ mod tests {
         assert_eq!(config.max_history_entries, 100);
     }
 
+    #[test]
+    fn test_parse_history_section() {
+        let content = "[history]\nmax_history_entries = 20\nhistory_prefix = laptop\n\
+                       history_keyframe_interval = 5\n";
+        let config = Config::parse_ini(content).unwrap();
+        assert_eq!(config.max_history_entries, 20);
+        assert_eq!(config.history_prefix, "laptop");
+        assert_eq!(config.history_keyframe_interval, 5);
+        assert!(config
+            .to_ini()
+            .contains("[history]\nmax_history_entries = 20\nhistory_prefix = laptop\nhistory_keyframe_interval = 5\n"));
+
+        // [history] wins over the keys older configs kept in [paths]
+        let content = "[paths]\nmax_history_entries = 100\n\n[history]\nmax_history_entries = 20\n";
+        assert_eq!(Config::parse_ini(content).unwrap().max_history_entries, 20);
+        let content = "[paths]\nhistory_keyframe_interval = 8\n";
+        assert_eq!(
+            Config::parse_ini(content)
+                .unwrap()
+                .history_keyframe_interval,
+            8
+        );
+    }
+
+    #[test]
+    fn test_parse_disabled_comment_prefix_alone() {
+        let config = Config::parse_ini("[blocking]\ndisabled_comment_prefix = #@\n").unwrap();
+        assert_eq!(config.disabled_comment_prefix, "#@");
+        assert!(config.ensure_trailing_newline);
+
+        let config = Config::parse_ini("[blocking]\ndisabled_comment_prefix = ads\n").unwrap();
+        assert_eq!(config.disabled_comment_prefix, DEFAULT_DISABLED_MARKER);
+    }
+
     #[test]
     fn test_parse_appearance_section() {
         let content = "[appearance]\ntheme = light\n";
@@ -198,6 +749,15 @@ mod tests {
         assert_eq!(config.theme, Theme::Light);
     }
 
+    #[test]
+    fn test_theme_names() {
+        for theme in [Theme::Dark, Theme::Light, Theme::System] {
+            assert_eq!(Theme::from_str(theme.to_str()), theme);
+        }
+        assert_eq!(Theme::from_str(" System "), Theme::System);
+        assert_eq!(Theme::System.to_str(), "system");
+    }
+
     #[test]
     fn test_parse_with_comments() {
         let content = "# This is a comment\n[paths]\nhost_file_path = /etc/hosts\n";
@@ -210,9 +770,11 @@ mod tests {
 
     #[test]
     fn test_serialize_config() {
-        let mut config = Config::default();
-        config.host_file_path = Some(PathBuf::from("/etc/hosts").as_path().into());
-        config.theme = Theme::Light;
+        let config = Config {
+            host_file_path: Some(PathBuf::from("/etc/hosts").as_path().into()),
+            theme: Theme::Light,
+            ..Config::default()
+        };
 
         let ini = config.to_ini();
         assert!(ini.contains("host_file_path = /etc/hosts"));
@@ -224,8 +786,37 @@ mod tests {
         let original = Config {
             host_file_path: Some(PathBuf::from("/etc/hosts").as_path().into()),
             history_dir: Some(PathBuf::from("/tmp/history").as_path().into()),
+            allow_file_path: Some(
+                PathBuf::from("/etc/dnsmasq.d/allowlist.txt")
+                    .as_path()
+                    .into(),
+            ),
             max_history_entries: 75,
+            history_prefix: "laptop".to_string(),
+            history_keyframe_interval: 20,
             theme: Theme::Light,
+            block_ipv6: true,
+            separator: Separator::Tab,
+            ensure_trailing_newline: false,
+            disabled_comment_prefix: "# !".to_string(),
+            preserve_whitespace: true,
+            annotate_additions: true,
+            blocking_sink: "0.0.0.0".parse().unwrap(),
+            sink_ips: vec!["0.0.0.0".parse().unwrap(), "::".parse().unwrap()],
+            preserve_ips: vec!["127.0.1.1".parse().unwrap()],
+            safe_list_policy: SafeListPolicy::Skip,
+            block_single_label: true,
+            save_debounce_ms: 1000,
+            history_failure_policy: HistoryFailurePolicy::WarnAndContinue,
+            lock_timeout_ms: 0,
+            max_total_domains: 1000,
+            sources: vec![
+                Source::from_ini("https://example.com/hosts").unwrap(),
+                Source::from_ini("https://example.org/ads.txt?format=hosts&v=2 0.0.0.0 disabled")
+                    .unwrap(),
+            ],
+            file_header: Some("Managed by adguard\nDo not edit C:\\new by hand".to_string()),
+            active_profile: Some("work".to_string()),
         };
 
         let ini = original.to_ini();
@@ -233,7 +824,109 @@ mod tests {
 
         assert_eq!(original.host_file_path, parsed.host_file_path);
         assert_eq!(original.history_dir, parsed.history_dir);
+        assert_eq!(original.allow_file_path, parsed.allow_file_path);
         assert_eq!(original.max_history_entries, parsed.max_history_entries);
+        assert_eq!(original.history_prefix, parsed.history_prefix);
+        assert_eq!(
+            original.history_keyframe_interval,
+            parsed.history_keyframe_interval
+        );
         assert_eq!(original.theme, parsed.theme);
+        assert_eq!(original.block_ipv6, parsed.block_ipv6);
+        assert_eq!(original.separator, parsed.separator);
+        assert_eq!(
+            original.ensure_trailing_newline,
+            parsed.ensure_trailing_newline
+        );
+        assert_eq!(
+            original.disabled_comment_prefix,
+            parsed.disabled_comment_prefix
+        );
+        assert_eq!(original.preserve_whitespace, parsed.preserve_whitespace);
+        assert_eq!(original.annotate_additions, parsed.annotate_additions);
+        assert_eq!(original.blocking_sink, parsed.blocking_sink);
+        assert_eq!(original.sink_ips, parsed.sink_ips);
+        assert_eq!(original.preserve_ips, parsed.preserve_ips);
+        assert_eq!(original.safe_list_policy, parsed.safe_list_policy);
+        assert_eq!(original.block_single_label, parsed.block_single_label);
+        assert_eq!(original.save_debounce_ms, parsed.save_debounce_ms);
+        assert_eq!(
+            original.history_failure_policy,
+            parsed.history_failure_policy
+        );
+        assert_eq!(original.lock_timeout_ms, parsed.lock_timeout_ms);
+        assert_eq!(original.file_header, parsed.file_header);
+        assert_eq!(original.sources, parsed.sources);
+        assert_eq!(original.max_total_domains, parsed.max_total_domains);
+        assert_eq!(original.active_profile, parsed.active_profile);
+        assert_eq!(parsed.lock_timeout(), None);
+    }
+
+    #[test]
+    fn test_parse_blocking_section() {
+        let config = Config::parse_ini("[blocking]\nblock_ipv6 = true\n").unwrap();
+        assert!(config.block_ipv6);
+
+        let config = Config::parse_ini("").unwrap();
+        assert!(!config.block_ipv6);
+    }
+
+    #[test]
+    fn test_validate_blocking_sink() {
+        for sink in ["127.0.0.1", "0.0.0.0", "::1", "::"] {
+            let config = Config {
+                blocking_sink: sink.parse().unwrap(),
+                ..Config::default()
+            };
+            assert!(config.validate().is_ok(), "{} rejected", sink);
+        }
+
+        let config = Config {
+            blocking_sink: "8.8.8.8".parse().unwrap(),
+            ..Config::default()
+        };
+        let err = config.validate().unwrap_err();
+        assert!(err.to_string().contains("routable"));
+
+        // A routable sink in the file falls back to the default
+        let config = Config::parse_ini("[blocking]\nblocking_sink = 8.8.8.8\n").unwrap();
+        assert_eq!(config.blocking_sink, DEFAULT_SINK);
+        assert!(config.validate().is_ok());
+
+        // The sink must read back as blocked; routable addresses are dropped from the lists
+        let config = Config::parse_ini(
+            "[blocking]\nblocking_sink = 127.0.0.1\nsink_ips = 0.0.0.0, 8.8.8.8 ::\n",
+        )
+        .unwrap();
+        assert_eq!(
+            config.sink_ips,
+            ["0.0.0.0".parse::<IpAddr>().unwrap(), "::".parse().unwrap()]
+        );
+        assert!(config.validate().is_err());
+        let config = Config {
+            preserve_ips: vec![DEFAULT_SINK],
+            ..Config::default()
+        };
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_reset_sinks() {
+        let mut config = Config::parse_ini(
+            "[blocking]\nblocking_sink = 127.0.0.1\npreserve_ips = 127.0.0.1\n\
+             [sources]\nsource.1 = https://example.com/hosts ::1\n",
+        )
+        .unwrap();
+        assert!(config.validate().is_err());
+
+        config.sink_ips = vec![DEFAULT_SINK];
+        config.preserve_ips.clear();
+        let err = config.validate().unwrap_err();
+        assert!(err.to_string().contains("https://example.com/hosts"));
+
+        config.reset_sinks();
+        assert!(config.validate().is_ok());
+        assert_eq!(config.blocking_sink, DEFAULT_SINK);
+        assert_eq!(config.sources[0].sink, Some("::1".parse().unwrap()));
     }
 }
@@ -0,0 +1,171 @@
+use crate::commit::can_write_hosts;
+use crate::platform::{default_hosts_file_path, dns_flush_capability, is_elevated};
+use crate::state::{AppState, SyncStatus};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Hosts files past this size slow down name resolution on some systems
+pub const LARGE_HOSTS_FILE_BYTES: u64 = 16 * 1024 * 1024;
+
+/// Snapshot of the app's health, for attaching to bug reports
+#[derive(Debug, Clone)]
+pub struct Diagnostics {
+    pub elevated: bool,
+    pub hosts_path: PathBuf,
+    pub hosts_exists: bool,
+    pub hosts_writable: bool,
+    /// Size of the hosts file on disk, if it could be read
+    pub hosts_size: Option<u64>,
+    /// Set when the hosts file is large enough to slow down lookups
+    pub size_warning: Option<String>,
+    /// None when history is disabled
+    pub history_dir: Option<PathBuf>,
+    pub history_writable: Option<bool>,
+    /// Command used to flush the DNS cache, if one is available
+    pub dns_flush: Option<&'static str>,
+    pub blocked: usize,
+    /// Whether the state matches the hosts file, or None if the check failed
+    pub in_sync: Option<bool>,
+    /// Why the sync check failed
+    pub sync_error: Option<String>,
+}
+
+impl Diagnostics {
+    /// Run every check against the current state and config
+    pub fn collect(state: &AppState) -> Self {
+        let config = state.get_config();
+        let hosts_path = config
+            .host_file_path
+            .clone()
+            .unwrap_or_else(default_hosts_file_path);
+        let hosts_size = fs::metadata(&hosts_path)
+            .map(|metadata| metadata.len())
+            .ok();
+        let size_warning = hosts_size
+            .filter(|&size| size > LARGE_HOSTS_FILE_BYTES)
+            .map(|size| {
+                format!(
+                    "Hosts file is {} MiB; name lookups may slow down past {} MiB",
+                    size / (1024 * 1024),
+                    LARGE_HOSTS_FILE_BYTES / (1024 * 1024)
+                )
+            });
+        let history_dir = config
+            .history_settings()
+            .map(|history| history.dir.to_path_buf());
+
+        // A missing hosts file compares as empty
+        let disk_content = fs::read_to_string(&hosts_path).unwrap_or_default();
+        let (in_sync, sync_error) = match state.verify_sync(&disk_content) {
+            Ok(status) => (Some(status == SyncStatus::InSync), None),
+            Err(e) => (None, Some(e.to_string())),
+        };
+
+        Diagnostics {
+            elevated: is_elevated(),
+            hosts_exists: hosts_size.is_some(),
+            hosts_writable: can_write_hosts(&hosts_path),
+            hosts_path: hosts_path.to_path_buf(),
+            hosts_size,
+            size_warning,
+            history_writable: history_dir.as_deref().map(dir_is_writable),
+            history_dir,
+            dns_flush: dns_flush_capability().map(|method| method.to_str()),
+            blocked: state.get_total_blocked(),
+            in_sync,
+            sync_error,
+        }
+    }
+
+    /// Report as returned to the frontend
+    pub fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "elevated": self.elevated,
+            "hosts_path": self.hosts_path.to_string_lossy(),
+            "hosts_exists": self.hosts_exists,
+            "hosts_writable": self.hosts_writable,
+            "hosts_size": self.hosts_size,
+            "size_warning": self.size_warning,
+            "history_dir": self.history_dir.as_ref().map(|dir| dir.to_string_lossy()),
+            "history_writable": self.history_writable,
+            "dns_flush": self.dns_flush,
+            "blocked": self.blocked,
+            "in_sync": self.in_sync,
+            "sync_error": self.sync_error,
+        })
+    }
+}
+
+/// Whether a file can be created in the directory, creating the directory as
+/// history snapshots would
+fn dir_is_writable(dir: &Path) -> bool {
+    if fs::create_dir_all(dir).is_err() {
+        return false;
+    }
+    let probe = dir.join(format!(".adguard-write-test-{}", std::process::id()));
+    let writable = fs::write(&probe, b"").is_ok();
+    let _ = fs::remove_file(&probe);
+    writable
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_healthy_setup() {
+        let temp_dir = TempDir::new().unwrap();
+        let hosts_path = temp_dir.path().join("hosts");
+        let history_dir = temp_dir.path().join("history");
+        fs::write(
+            &hosts_path,
+            "127.0.0.1 localhost\n0.0.0.0 ads.example.com\n",
+        )
+        .unwrap();
+
+        let state = AppState::new(Config {
+            host_file_path: Some(hosts_path.as_path().into()),
+            history_dir: Some(history_dir.as_path().into()),
+            ..Config::default()
+        });
+        state.load_from_file(&hosts_path).unwrap();
+
+        let report = Diagnostics::collect(&state);
+        assert_eq!(report.hosts_path, hosts_path);
+        assert!(report.hosts_exists);
+        assert!(report.hosts_writable);
+        assert!(report.hosts_size.is_some_and(|size| size > 0));
+        assert_eq!(report.size_warning, None);
+        assert_eq!(report.history_dir.as_deref(), Some(history_dir.as_path()));
+        assert_eq!(report.history_writable, Some(true));
+        assert_eq!(report.blocked, 1);
+        assert_eq!(report.in_sync, Some(true));
+        assert_eq!(report.sync_error, None);
+        // The probe file isn't left behind
+        assert_eq!(fs::read_dir(&history_dir).unwrap().count(), 0);
+
+        let json = report.to_json();
+        for field in [
+            "elevated",
+            "hosts_path",
+            "hosts_exists",
+            "hosts_writable",
+            "hosts_size",
+            "size_warning",
+            "history_dir",
+            "history_writable",
+            "dns_flush",
+            "blocked",
+            "in_sync",
+            "sync_error",
+        ] {
+            assert!(json.get(field).is_some(), "{} missing", field);
+        }
+
+        // An edit the app doesn't know about shows up as drift
+        fs::write(&hosts_path, "0.0.0.0 other.example.com\n").unwrap();
+        assert_eq!(Diagnostics::collect(&state).in_sync, Some(false));
+    }
+}
@@ -1,9 +1,14 @@
-use crate::parser::parse_hosts;
-use std::collections::HashSet;
+use crate::commit::{write_atomic, write_hosts_file};
+use crate::parser::{parse_hosts, parse_hosts_with, ParseOptions, PreservedLine};
+use crate::utils::{is_local_domain, is_localhost_name};
+use std::collections::{BTreeSet, HashMap, HashSet};
 use std::fs;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::IpAddr;
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
-use std::time::SystemTime;
+use std::time::{Duration, SystemTime};
 
 #[derive(Debug, Clone)]
 pub struct HistoryEntry {
@@ -14,18 +19,70 @@ pub struct HistoryEntry {
     pub file_size: u64,
 }
 
-/// Verify a hosts file is valid
+/// How the blocked domains of two hosts files compare
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct BlockingDiff {
+    /// Blocked only in the second file
+    pub added: Vec<Arc<str>>,
+    /// Blocked only in the first file
+    pub removed: Vec<Arc<str>>,
+    /// Blocked in both
+    pub common: Vec<Arc<str>>,
+}
+
+/// Compare two sets of blocked domains
+pub fn diff_blocking(before: &BTreeSet<Arc<str>>, after: &BTreeSet<Arc<str>>) -> BlockingDiff {
+    BlockingDiff {
+        added: after.difference(before).cloned().collect(),
+        removed: before.difference(after).cloned().collect(),
+        common: before.intersection(after).cloned().collect(),
+    }
+}
+
+/// Blocked domains of a hosts file, with the path in any error
+fn read_blocked_domains(
+    path: &Path,
+    options: &ParseOptions,
+) -> Result<BTreeSet<Arc<str>>, anyhow::Error> {
+    let content = fs::read_to_string(path)
+        .map_err(|e| anyhow::anyhow!("Failed to read {}: {}", path.display(), e))?;
+    let parsed = parse_hosts_with(&content, options)
+        .map_err(|e| anyhow::anyhow!("Failed to parse {}: {}", path.display(), e))?;
+    Ok(parsed
+        .blocking
+        .into_iter()
+        .filter(|hostname| !is_local_domain(hostname))
+        .collect())
+}
+
+/// Compare the blocked domains of two hosts files
+pub fn diff_files(
+    path_a: &Path,
+    path_b: &Path,
+    options: &ParseOptions,
+) -> Result<BlockingDiff, anyhow::Error> {
+    let before = read_blocked_domains(path_a, options)?;
+    let after = read_blocked_domains(path_b, options)?;
+    Ok(diff_blocking(&before, &after))
+}
+
+/// Verify a hosts file is valid, returning warnings about lines kept as is
 #[inline]
-pub fn verify_host_file(path: &Path) -> Result<(), anyhow::Error> {
+pub fn verify_host_file(path: &Path) -> Result<Vec<String>, anyhow::Error> {
     // Check if file exists and is readable
     let content = fs::read_to_string(path)?;
+    verify_hosts_content(&content)
+}
 
+/// Verify hosts content is valid and doesn't hijack localhost
+/// Lines that aren't hosts syntax don't fail verification; each is returned as a warning
+pub fn verify_hosts_content(content: &str) -> Result<Vec<String>, anyhow::Error> {
     if content.trim().is_empty() {
         return Err(anyhow::anyhow!("Host file is empty"));
     }
 
     // Try to parse the file
-    let parsed = parse_hosts(&content)?;
+    let parsed = parse_hosts(content)?;
 
     // Validate all IP addresses are valid
     let mut seen_entries: HashSet<Arc<str>> = HashSet::new();
@@ -44,80 +101,604 @@ pub fn verify_host_file(path: &Path) -> Result<(), anyhow::Error> {
         if hostname.len() > 253 {
             return Err(anyhow::anyhow!("Hostname too long: {}", hostname));
         }
+
+        // Local names must keep resolving
+        if is_local_domain(&hostname) {
+            return Err(anyhow::anyhow!("Local name would be blocked: {}", hostname));
+        }
     }
 
-    Ok(())
+    let mut warnings = Vec::new();
+    for line in &parsed.preserved_lines {
+        verify_localhost_mapping(line)?;
+        if let PreservedLine::Invalid(text) = line {
+            warnings.push(format!("Not a valid hosts entry, kept as is: {}", text));
+        }
+    }
+
+    Ok(warnings)
 }
 
-/// Write a history snapshot
-pub fn write_history_snapshot(
-    history_dir: &Path,
-    content: &str,
-) -> Result<HistoryEntry, anyhow::Error> {
-    // Create history directory if it doesn't exist
-    fs::create_dir_all(history_dir)?;
+/// Reject lines sending localhost anywhere but loopback
+/// (a remote address hijacks it, a sink address breaks it)
+pub fn verify_localhost_mapping(line: &PreservedLine) -> Result<(), anyhow::Error> {
+    let (ip, hostnames): (IpAddr, Vec<&str>) = match line {
+        PreservedLine::LocalhostEntry { ip, hostname } => (*ip, vec![hostname.as_ref()]),
+        PreservedLine::NonLocalhostEntry(entry)
+        | PreservedLine::LocalhostLine(entry)
+        | PreservedLine::Raw(entry)
+        | PreservedLine::Invalid(entry) => {
+            let mut fields = entry
+                .split('#')
+                .next()
+                .unwrap_or_default()
+                .split_whitespace();
+            match fields.next().and_then(|ip| ip.parse::<IpAddr>().ok()) {
+                Some(ip) => (ip, fields.collect()),
+                None => return Ok(()),
+            }
+        }
+        PreservedLine::Comment(_) | PreservedLine::ManagedRegion => return Ok(()),
+    };
+
+    if ip.is_loopback() {
+        return Ok(());
+    }
+    match hostnames.into_iter().find(|h| is_localhost_name(h)) {
+        Some(hostname) => Err(anyhow::anyhow!(
+            "{} must map to a loopback address, not {}",
+            hostname,
+            ip
+        )),
+        None => Ok(()),
+    }
+}
 
-    // Generate filename with timestamp (include nanoseconds and counter for uniqueness)
-    let now = SystemTime::now();
-    let duration = now.duration_since(std::time::UNIX_EPOCH).unwrap();
+/// Default prefix of history snapshot filenames
+pub const DEFAULT_HISTORY_PREFIX: &str = "hosts-backup";
 
-    // Use local timezone instead of UTC
-    // Convert UTC timestamp to local timezone
-    let utc_datetime = chrono::DateTime::<chrono::Utc>::from_timestamp(
-        duration.as_secs() as i64,
-        duration.subsec_nanos(),
+/// Whether a prefix is safe to use at the start of a snapshot filename
+/// Letters, digits, `-`, `_` and `.` only, so it can't reach outside the history directory
+pub fn is_valid_history_prefix(prefix: &str) -> bool {
+    !prefix.is_empty()
+        && !prefix.starts_with('.')
+        && prefix
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+}
+
+/// Where history snapshots are written, how they're named and how many are kept
+#[derive(Debug, Clone)]
+pub struct HistorySettings {
+    pub dir: Arc<Path>,
+    /// Filename prefix, so snapshots can share a directory with other files
+    pub prefix: Arc<str>,
+    pub max_entries: usize,
+    /// Store snapshots as deltas against the one before, with a full copy
+    /// after this many deltas (0 stores full copies only)
+    pub keyframe_interval: usize,
+}
+
+/// UTC timestamp format in snapshot filenames (fixed width, so names sort by time)
+const SNAPSHOT_TIME_FORMAT: &str = "%Y%m%dT%H%M%S%.9fZ";
+
+/// Nanoseconds since the epoch of the last snapshot name handed out
+static LAST_SNAPSHOT_NANOS: AtomicU64 = AtomicU64::new(0);
+
+/// Current time in nanoseconds, strictly greater than any earlier call's
+/// Keeps snapshot names increasing even if the clock stalls or steps back
+fn next_snapshot_nanos() -> u64 {
+    let now = SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos() as u64;
+    let mut last = LAST_SNAPSHOT_NANOS.load(Ordering::SeqCst);
+    loop {
+        let next = now.max(last + 1);
+        match LAST_SNAPSHOT_NANOS.compare_exchange(last, next, Ordering::SeqCst, Ordering::SeqCst) {
+            Ok(_) => return next,
+            Err(current) => last = current,
+        }
+    }
+}
+
+/// Snapshot filename for a time, e.g. `hosts-backup-20240501T093000.123456789Z-1f2a.txt`
+/// The UTC time sorts chronologically as a string; the process id keeps two
+/// app instances from ever producing the same name
+fn snapshot_filename(prefix: &str, nanos: u64) -> String {
+    let datetime = chrono::DateTime::<chrono::Utc>::from_timestamp_nanos(nanos as i64);
+    format!(
+        "{}-{}-{:x}.txt",
+        prefix,
+        datetime.format(SNAPSHOT_TIME_FORMAT),
+        std::process::id()
     )
-    .unwrap_or_else(chrono::Utc::now);
-
-    // Convert to local timezone
-    let datetime = utc_datetime.with_timezone(&chrono::Local);
-
-    // Include nanoseconds and a random component to ensure uniqueness
-    // Use a simple counter based on existing files to avoid collisions
-    let mut counter = 0u32;
-    let base_filename = format!(
-        "hosts-backup-{}-{}",
-        datetime.format("%Y-%m-%d-%H-%M-%S"),
-        duration.subsec_nanos()
-    );
+}
+
+/// Local time format of snapshot names written before they were named by UTC time,
+/// e.g. `hosts-backup-2024-05-01-09-30-00-123456789-0.txt`
+const LEGACY_SNAPSHOT_TIME_FORMAT: &str = "%Y-%m-%d-%H-%M-%S";
+
+/// Whether a filename is a snapshot written with this prefix, under the
+/// current naming scheme or the legacy one
+/// Other files in the history directory are never listed, pruned or rolled back to
+pub fn is_snapshot_filename(prefix: &str, filename: &str) -> bool {
+    snapshot_time(prefix, filename).is_some() || is_legacy_snapshot_filename(prefix, filename)
+}
+
+/// Matches `<prefix>-<local time>-<nanoseconds>-<counter>.txt`
+fn is_legacy_snapshot_filename(prefix: &str, filename: &str) -> bool {
+    let Some(rest) = filename
+        .strip_prefix(prefix)
+        .and_then(|rest| rest.strip_prefix('-'))
+        .and_then(|rest| rest.strip_suffix(".txt"))
+    else {
+        return false;
+    };
+    // The time is six dash-separated fields, followed by two numeric ones
+    let fields: Vec<&str> = rest.rsplitn(3, '-').collect();
+    let [counter, nanos, time] = fields[..] else {
+        return false;
+    };
+    let is_number = |field: &str| !field.is_empty() && field.bytes().all(|b| b.is_ascii_digit());
+    is_number(counter)
+        && is_number(nanos)
+        && chrono::NaiveDateTime::parse_from_str(time, LEGACY_SNAPSHOT_TIME_FORMAT).is_ok()
+}
 
-    let mut filename = format!("{}-{}.txt", base_filename, counter);
-    let mut file_path = history_dir.join(&filename);
+/// Creation time encoded in a snapshot filename, if it uses the current scheme
+fn snapshot_time(prefix: &str, filename: &str) -> Option<SystemTime> {
+    let (time, pid) = filename
+        .strip_prefix(prefix)?
+        .strip_prefix('-')?
+        .strip_suffix(".txt")?
+        .split_once('-')?;
+    if pid.is_empty() || !pid.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+    let datetime = chrono::NaiveDateTime::parse_from_str(time, SNAPSHOT_TIME_FORMAT).ok()?;
+    Some(datetime.and_utc().into())
+}
+
+/// Write a history snapshot as a full copy of the content
+#[inline]
+pub fn write_history_snapshot(
+    history_dir: &Path,
+    prefix: &str,
+    content: &str,
+) -> Result<HistoryEntry, anyhow::Error> {
+    create_snapshot(history_dir, prefix, content, count_entries(content))
+}
 
-    // If file exists, increment counter until we find a unique name
-    while file_path.exists() {
-        counter += 1;
-        filename = format!("{}-{}.txt", base_filename, counter);
-        file_path = history_dir.join(&filename);
+/// Write a history snapshot, as a delta against the latest one when the
+/// settings ask for incremental history
+///
+/// A full copy is written instead every `keyframe_interval` deltas, when the
+/// delta wouldn't be under half the size of the content, or when the latest
+/// snapshot can't be read.
+pub fn write_snapshot(
+    history: &HistorySettings,
+    content: &str,
+) -> Result<HistoryEntry, anyhow::Error> {
+    if history.keyframe_interval > 0 {
+        match delta_against_latest(history, content) {
+            Ok(Some(delta)) => {
+                let stored = delta.to_content();
+                if stored.len() * 2 < content.len() {
+                    return create_snapshot(
+                        &history.dir,
+                        &history.prefix,
+                        &stored,
+                        delta.entry_count,
+                    );
+                }
+            }
+            Ok(None) => {}
+            Err(e) => tracing::warn!("Writing a full history snapshot: {}", e),
+        }
     }
+    write_history_snapshot(&history.dir, &history.prefix, content)
+}
+
+/// Create a snapshot file holding `stored`, a full copy or an encoded delta
+fn create_snapshot(
+    history_dir: &Path,
+    prefix: &str,
+    stored: &str,
+    entry_count: usize,
+) -> Result<HistoryEntry, anyhow::Error> {
+    // Create history directory if it doesn't exist
+    fs::create_dir_all(history_dir)?;
 
-    // Write content to file
-    fs::write(&file_path, content)?;
+    // The name is unique by construction, so an existing file is an error
+    // rather than something to probe for and step around
+    let nanos = next_snapshot_nanos();
+    let filename = snapshot_filename(prefix, nanos);
+    let file_path = history_dir.join(&filename);
+    fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&file_path)?
+        .write_all(stored.as_bytes())?;
 
     // Get file metadata
     let metadata = fs::metadata(&file_path)?;
     let file_size = metadata.len();
 
-    // Count entries (approximate)
-    let entry_count = content
+    Ok(HistoryEntry {
+        filename,
+        path: file_path,
+        timestamp: std::time::UNIX_EPOCH + Duration::from_nanos(nanos),
+        entry_count,
+        file_size,
+    })
+}
+
+/// Count entries (approximate): lines that are neither blank nor comments
+fn count_entries(content: &str) -> usize {
+    content
         .lines()
         .filter(|line| {
             let trimmed = line.trim();
             !trimmed.is_empty() && !trimmed.starts_with('#')
         })
+        .count()
+}
+
+/// First line of a snapshot stored as a delta
+const DELTA_MARKER: &str = "# adguard history delta";
+
+/// Most deltas followed back to a full copy, guarding against a cycle of bases
+const MAX_DELTA_CHAIN: usize = 1000;
+
+/// A snapshot stored as the edits turning the snapshot before it into this one
+///
+/// The edits work on tokens of a word and the whitespace after it, so each
+/// blocked domain added or removed is a small edit even when a sink line
+/// lists thousands of domains.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Delta {
+    /// Filename of the snapshot the edits apply to, in the same directory
+    base: String,
+    /// Deltas since the last full copy, this one included
+    depth: usize,
+    /// Entries in the rebuilt content, so listing needn't rebuild it
+    entry_count: usize,
+    /// Length of the rebuilt content, checked after applying the edits
+    length: usize,
+    ops: Vec<DeltaOp>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum DeltaOp {
+    /// Copy this many tokens of the base
+    Keep(usize),
+    /// Skip this many tokens of the base
+    Delete(usize),
+    /// Text not in the base
+    Insert(String),
+}
+
+impl Delta {
+    /// File content: the marker line, then the delta as JSON with each edit
+    /// a kept count, a negated deleted count or inserted text
+    fn to_content(&self) -> String {
+        let ops: Vec<serde_json::Value> = self
+            .ops
+            .iter()
+            .map(|op| match op {
+                DeltaOp::Keep(count) => serde_json::json!(count),
+                DeltaOp::Delete(count) => serde_json::json!(-(*count as i64)),
+                DeltaOp::Insert(text) => serde_json::json!(text),
+            })
+            .collect();
+        let delta = serde_json::json!({
+            "base": self.base,
+            "depth": self.depth,
+            "entries": self.entry_count,
+            "length": self.length,
+            "ops": ops,
+        });
+        format!("{}\n{}\n", DELTA_MARKER, delta)
+    }
+
+    fn from_json(json: &str) -> Result<Self, anyhow::Error> {
+        let delta: serde_json::Value = serde_json::from_str(json)?;
+        let field = |name: &str| {
+            delta
+                .get(name)
+                .and_then(|v| v.as_u64())
+                .map(|v| v as usize)
+                .ok_or_else(|| anyhow::anyhow!("History delta has no {}", name))
+        };
+        let base = delta
+            .get("base")
+            .and_then(|v| v.as_str())
+            .ok_or_else(|| anyhow::anyhow!("History delta has no base"))?;
+        check_snapshot_name(base)?;
+        let ops = delta
+            .get("ops")
+            .and_then(|v| v.as_array())
+            .ok_or_else(|| anyhow::anyhow!("History delta has no edits"))?
+            .iter()
+            .map(|op| match (op.as_u64(), op.as_i64(), op.as_str()) {
+                (Some(count), _, _) => Ok(DeltaOp::Keep(count as usize)),
+                (None, Some(count), _) => Ok(DeltaOp::Delete(count.unsigned_abs() as usize)),
+                (_, _, Some(text)) => Ok(DeltaOp::Insert(text.to_string())),
+                _ => Err(anyhow::anyhow!("Invalid history delta edit: {}", op)),
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Delta {
+            base: base.to_string(),
+            depth: field("depth")?,
+            entry_count: field("entries")?,
+            length: field("length")?,
+            ops,
+        })
+    }
+}
+
+/// The delta stored in a snapshot file, or None if it's a full copy
+/// Only the first line of a full copy is read
+fn read_delta(path: &Path) -> Result<Option<Delta>, anyhow::Error> {
+    let mut reader = BufReader::new(fs::File::open(path)?);
+    let mut first_line = String::new();
+    reader.read_line(&mut first_line)?;
+    if first_line.trim_end() != DELTA_MARKER {
+        return Ok(None);
+    }
+    let mut json = String::new();
+    reader.read_to_string(&mut json)?;
+    Delta::from_json(&json)
+        .map(Some)
+        .map_err(|e| anyhow::anyhow!("Invalid history delta {}: {}", path.display(), e))
+}
+
+/// Content of a snapshot, rebuilding one stored as a delta by replaying the
+/// deltas since the last full copy
+pub fn read_snapshot(path: &Path) -> Result<String, anyhow::Error> {
+    let Some(delta) = read_delta(path)? else {
+        return Ok(fs::read_to_string(path)?);
+    };
+    let dir = path.parent().unwrap_or(Path::new("."));
+    let mut chain = vec![delta];
+    let mut content = loop {
+        if chain.len() > MAX_DELTA_CHAIN {
+            return Err(anyhow::anyhow!(
+                "History snapshot {} has no full copy within {} deltas",
+                path.display(),
+                MAX_DELTA_CHAIN
+            ));
+        }
+        let base_path = dir.join(&chain[chain.len() - 1].base);
+        if !base_path.is_file() {
+            return Err(anyhow::anyhow!(
+                "History snapshot {} is missing its base {}",
+                path.display(),
+                base_path.display()
+            ));
+        }
+        match read_delta(&base_path)? {
+            Some(delta) => chain.push(delta),
+            None => break fs::read_to_string(&base_path)?,
+        }
+    };
+    for delta in chain.iter().rev() {
+        content = apply_delta(&content, &delta.ops)?;
+        if content.len() != delta.length {
+            return Err(anyhow::anyhow!(
+                "History snapshot {} doesn't rebuild to its recorded length",
+                path.display()
+            ));
+        }
+    }
+    Ok(content)
+}
+
+/// The delta from the latest snapshot to `content`, or None when a full
+/// copy is due
+fn delta_against_latest(
+    history: &HistorySettings,
+    content: &str,
+) -> Result<Option<Delta>, anyhow::Error> {
+    let Some(latest) = list_history_entries(&history.dir, &history.prefix)?
+        .into_iter()
+        .next()
+    else {
+        return Ok(None);
+    };
+    let depth = read_delta(&latest.path)?.map_or(1, |delta| delta.depth + 1);
+    if depth > history.keyframe_interval {
+        return Ok(None);
+    }
+    let base = read_snapshot(&latest.path)?;
+    Ok(Some(Delta {
+        base: latest.filename,
+        depth,
+        entry_count: count_entries(content),
+        length: content.len(),
+        ops: diff_tokens(&tokenize(&base), &tokenize(content)),
+    }))
+}
+
+/// Split content into tokens of a word and the whitespace after it
+/// The tokens concatenate back to the content
+fn tokenize(content: &str) -> Vec<&str> {
+    let mut tokens = Vec::new();
+    let mut start = 0;
+    let mut in_space = false;
+    for (index, c) in content.char_indices() {
+        let space = c.is_whitespace();
+        if in_space && !space {
+            tokens.push(&content[start..index]);
+            start = index;
+        }
+        in_space = space;
+    }
+    if start < content.len() {
+        tokens.push(&content[start..]);
+    }
+    tokens
+}
+
+/// Edits turning the `old` tokens into the `new` ones
+///
+/// Tokens found once on each side (mostly blocked domains) anchor the diff,
+/// keeping the longest run of them in the same order; the tokens between two
+/// anchors are compared by their common start and end.
+fn diff_tokens(old: &[&str], new: &[&str]) -> Vec<DeltaOp> {
+    // Occurrences in old, in new, and the position in old
+    let mut counts: HashMap<&str, (usize, usize, usize)> = HashMap::with_capacity(old.len());
+    for (index, token) in old.iter().enumerate() {
+        counts.entry(token).or_insert((0, 0, index)).0 += 1;
+    }
+    for token in new {
+        if let Some(count) = counts.get_mut(token) {
+            count.1 += 1;
+        }
+    }
+    let unique: Vec<(usize, usize)> = new
+        .iter()
+        .enumerate()
+        .filter_map(|(new_index, token)| {
+            counts
+                .get(token)
+                .filter(|&&(in_old, in_new, _)| in_old == 1 && in_new == 1)
+                .map(|&(_, _, old_index)| (old_index, new_index))
+        })
+        .collect();
+
+    let mut ops = Vec::new();
+    let (mut old_start, mut new_start) = (0, 0);
+    for (old_index, new_index) in longest_increasing(&unique) {
+        diff_between(
+            &old[old_start..old_index],
+            &new[new_start..new_index],
+            &mut ops,
+        );
+        push_op(&mut ops, DeltaOp::Keep(1));
+        old_start = old_index + 1;
+        new_start = new_index + 1;
+    }
+    diff_between(&old[old_start..], &new[new_start..], &mut ops);
+    ops
+}
+
+/// Longest run of pairs whose first elements increase, taken in order
+/// (the pairs come sorted by their second element)
+fn longest_increasing(pairs: &[(usize, usize)]) -> Vec<(usize, usize)> {
+    // tails[k] is the pair ending the best run of length k + 1 found so far
+    let mut tails: Vec<usize> = Vec::new();
+    let mut previous: Vec<Option<usize>> = vec![None; pairs.len()];
+    for (index, &(first, _)) in pairs.iter().enumerate() {
+        let length = tails.partition_point(|&tail| pairs[tail].0 < first);
+        previous[index] = length.checked_sub(1).map(|shorter| tails[shorter]);
+        if length == tails.len() {
+            tails.push(index);
+        } else {
+            tails[length] = index;
+        }
+    }
+    let mut run = Vec::with_capacity(tails.len());
+    let mut next = tails.last().copied();
+    while let Some(index) = next {
+        run.push(pairs[index]);
+        next = previous[index];
+    }
+    run.reverse();
+    run
+}
+
+/// Edits for the tokens between two anchors: keep what they start and end
+/// with, replace the rest
+fn diff_between(old: &[&str], new: &[&str], ops: &mut Vec<DeltaOp>) {
+    let prefix = old.iter().zip(new).take_while(|(a, b)| a == b).count();
+    let suffix = old[prefix..]
+        .iter()
+        .rev()
+        .zip(new[prefix..].iter().rev())
+        .take_while(|(a, b)| a == b)
         .count();
+    push_op(ops, DeltaOp::Keep(prefix));
+    push_op(ops, DeltaOp::Delete(old.len() - prefix - suffix));
+    push_op(
+        ops,
+        DeltaOp::Insert(new[prefix..new.len() - suffix].concat()),
+    );
+    push_op(ops, DeltaOp::Keep(suffix));
+}
 
-    Ok(HistoryEntry {
-        filename,
-        path: file_path,
-        timestamp: now,
-        entry_count,
-        file_size,
-    })
+/// Append an edit, merging it into the last one of the same kind
+fn push_op(ops: &mut Vec<DeltaOp>, op: DeltaOp) {
+    match (ops.last_mut(), op) {
+        (_, DeltaOp::Keep(0) | DeltaOp::Delete(0)) => {}
+        (_, DeltaOp::Insert(text)) if text.is_empty() => {}
+        (Some(DeltaOp::Keep(last)), DeltaOp::Keep(count))
+        | (Some(DeltaOp::Delete(last)), DeltaOp::Delete(count)) => *last += count,
+        (Some(DeltaOp::Insert(last)), DeltaOp::Insert(text)) => last.push_str(&text),
+        (_, op) => ops.push(op),
+    }
 }
 
-/// List all history entries
-pub fn list_history_entries(history_dir: &Path) -> Result<Vec<HistoryEntry>, anyhow::Error> {
+/// Apply edits to the content of the base snapshot
+fn apply_delta(base: &str, ops: &[DeltaOp]) -> Result<String, anyhow::Error> {
+    let tokens = tokenize(base);
+    let mut position = 0;
+    let mut content = String::with_capacity(base.len());
+    for op in ops {
+        match op {
+            DeltaOp::Keep(count) => {
+                let kept = tokens
+                    .get(position..position + count)
+                    .ok_or_else(|| anyhow::anyhow!("History delta runs past its base"))?;
+                kept.iter().for_each(|token| content.push_str(token));
+                position += count;
+            }
+            DeltaOp::Delete(count) => position += count,
+            DeltaOp::Insert(text) => content.push_str(text),
+        }
+    }
+    if position != tokens.len() {
+        return Err(anyhow::anyhow!("History delta doesn't match its base"));
+    }
+    Ok(content)
+}
+
+/// Rewrite as full copies the deltas among `paths` whose base is one of
+/// `removing`, so those can go without breaking the snapshots after them
+fn detach_dependents(paths: &[PathBuf], removing: &HashSet<&str>) -> Result<(), anyhow::Error> {
+    for path in paths {
+        if read_delta(path)?.is_some_and(|delta| removing.contains(delta.base.as_str())) {
+            materialize(path)?;
+        }
+    }
+    Ok(())
+}
+
+/// Rewrite a snapshot stored as a delta as a full copy of its content
+fn materialize(path: &Path) -> Result<(), anyhow::Error> {
+    if read_delta(path)?.is_some() {
+        let content = read_snapshot(path)?;
+        write_atomic(path, &content)?;
+    }
+    Ok(())
+}
+
+/// Check whether the most recent history snapshot already has this content
+pub fn matches_latest_snapshot(history_dir: &Path, prefix: &str, content: &str) -> bool {
+    list_history_entries(history_dir, prefix)
+        .ok()
+        .and_then(|entries| entries.into_iter().next())
+        .and_then(|entry| read_snapshot(&entry.path).ok())
+        .is_some_and(|latest| latest == content)
+}
+
+/// List the snapshots in a directory, newest first
+/// Only `<prefix>-*.txt` files count, so other files sharing the directory are left alone
+pub fn list_history_entries(
+    history_dir: &Path,
+    prefix: &str,
+) -> Result<Vec<HistoryEntry>, anyhow::Error> {
     if !history_dir.exists() {
         return Ok(Vec::new());
     }
@@ -128,52 +709,78 @@ pub fn list_history_entries(history_dir: &Path) -> Result<Vec<HistoryEntry>, any
         let entry = entry?;
         let path = entry.path();
 
-        if path.is_file() && path.extension().and_then(|s| s.to_str()) == Some("txt") {
+        let filename = entry.file_name().to_string_lossy().to_string();
+        if path.is_file() && is_snapshot_filename(prefix, &filename) {
             let metadata = fs::metadata(&path)?;
             let file_size = metadata.len();
-            let modified = metadata.modified().unwrap_or(SystemTime::now());
-
-            let content = fs::read_to_string(&path).unwrap_or_default();
-            let entry_count = content
-                .lines()
-                .filter(|line| {
-                    let trimmed = line.trim();
-                    !trimmed.is_empty() && !trimmed.starts_with('#')
-                })
-                .count();
+            // Older snapshot names don't encode their time; fall back to the mtime
+            let timestamp = snapshot_time(prefix, &filename)
+                .or_else(|| metadata.modified().ok())
+                .unwrap_or(SystemTime::now());
+
+            let entry_count = match read_delta(&path) {
+                Ok(Some(delta)) => delta.entry_count,
+                _ => count_entries(&fs::read_to_string(&path).unwrap_or_default()),
+            };
 
             entries.push(HistoryEntry {
-                filename: path.file_name().unwrap().to_string_lossy().to_string(),
+                filename,
                 path,
-                timestamp: modified,
+                timestamp,
                 entry_count,
                 file_size,
             });
         }
     }
 
-    // Sort by timestamp (newest first)
-    entries.sort_by(|a, b| b.timestamp.cmp(&a.timestamp));
+    // Sort by timestamp (newest first), then by name for equal mtimes
+    entries.sort_by(|a, b| {
+        b.timestamp
+            .cmp(&a.timestamp)
+            .then_with(|| b.filename.cmp(&a.filename))
+    });
 
     Ok(entries)
 }
 
 /// Clean up old history entries, keeping only the most recent N
+/// Trashed snapshots past `TRASH_RETENTION` are removed as well
 #[inline]
-pub fn cleanup_old_history(history_dir: &Path, max_entries: usize) -> Result<(), anyhow::Error> {
-    let mut entries = list_history_entries(history_dir)?;
+pub fn cleanup_old_history(
+    history_dir: &Path,
+    prefix: &str,
+    max_entries: usize,
+) -> Result<(), anyhow::Error> {
+    // A trash that can't be cleaned up shouldn't stop the save
+    if let Err(e) = expire_trash(history_dir, TRASH_RETENTION) {
+        tracing::warn!("Failed to expire trashed history files: {}", e);
+    }
+
+    let mut entries = list_history_entries(history_dir, prefix)?;
 
     if entries.len() <= max_entries {
         return Ok(());
     }
 
-    // Remove oldest entries
-    entries.sort_by(|a, b| a.timestamp.cmp(&b.timestamp));
-    let to_remove = entries.len() - max_entries;
-
-    for entry in entries.into_iter().take(to_remove) {
+    // Remove oldest entries (the list is newest first)
+    let removed: Vec<HistoryEntry> = entries.drain(max_entries..).collect();
+    let removing: HashSet<&str> = removed.iter().map(|e| e.filename.as_str()).collect();
+    // A delta that can't be rewritten keeps its base rather than failing the save
+    let mut still_needed: HashSet<String> = HashSet::new();
+    for entry in &entries {
+        if let Err(e) = detach_dependents(std::slice::from_ref(&entry.path), &removing) {
+            tracing::warn!("Failed to detach history file {:?}: {}", entry.path, e);
+            if let Ok(Some(delta)) = read_delta(&entry.path) {
+                still_needed.insert(delta.base);
+            }
+        }
+    }
+    for entry in removed {
+        if still_needed.contains(&entry.filename) {
+            continue;
+        }
         if let Err(e) = fs::remove_file(&entry.path) {
-            eprintln!("Failed to remove history file {:?}: {}", entry.path, e);
+            tracing::warn!("Failed to remove history file {:?}: {}", entry.path, e);
         }
     }
 
@@ -186,32 +793,137 @@ pub fn rollback_to_history(
     history_entry: &HistoryEntry,
     hosts_file_path: &Path,
 ) -> Result<(), anyhow::Error> {
-    // Verify the history file first
-    verify_host_file(&history_entry.path)?;
-
-    // Read history file content
-    let content = fs::read_to_string(&history_entry.path)?;
+    // Read history file content, rebuilding a delta, and verify it first
+    let content = read_snapshot(&history_entry.path)?;
+    verify_hosts_content(&content)?;
 
     // Write to hosts file (atomic write: temp file then rename)
-    let temp_path = hosts_file_path.with_extension("tmp");
-    fs::write(&temp_path, content)?;
-    fs::rename(&temp_path, hosts_file_path)?;
+    write_hosts_file(hosts_file_path, &content)?;
+
+    Ok(())
+}
+
+/// Subdirectory of the history directory holding deleted snapshots
+const TRASH_DIR: &str = "trash";
+
+/// How long a deleted snapshot stays in the trash
+pub const TRASH_RETENTION: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
+#[inline]
+pub fn trash_dir(history_dir: &Path) -> PathBuf {
+    history_dir.join(TRASH_DIR)
+}
 
+/// Reject names that would reach outside the directory they're joined to
+pub(crate) fn check_snapshot_name(filename: &str) -> Result<(), anyhow::Error> {
+    if Path::new(filename).file_name() != Some(filename.as_ref()) {
+        return Err(anyhow::anyhow!("Invalid history filename: {}", filename));
+    }
     Ok(())
 }
 
-/// Delete history files by filenames
+/// Delete history files by filenames, moving them to the trash
+/// Only `<prefix>` snapshot names are accepted, so other files sharing the
+/// directory can't be trashed. The move time is recorded as the file's mtime,
+/// so the trash expires by deletion time rather than snapshot time. A name
+/// already in the trash is refused rather than overwritten
 #[inline]
-pub fn delete_history_files(history_dir: &Path, filenames: &[String]) -> Result<(), anyhow::Error> {
+pub fn delete_history_files(
+    history_dir: &Path,
+    prefix: &str,
+    filenames: &[String],
+) -> Result<(), anyhow::Error> {
+    let trash_dir = trash_dir(history_dir);
+    for filename in filenames {
+        check_snapshot_name(filename)?;
+        if !is_snapshot_filename(prefix, filename) {
+            return Err(anyhow::anyhow!("Not a history snapshot: {}", filename));
+        }
+        if history_dir.join(filename).is_file() && trash_dir.join(filename).exists() {
+            return Err(anyhow::anyhow!("{} is already in the trash", filename));
+        }
+    }
+    // Snapshots are trashed as full copies so each can be restored on its own,
+    // and deltas against them become full copies before they go
+    let removing: HashSet<&str> = filenames.iter().map(String::as_str).collect();
+    let mut snapshots = Vec::new();
+    if history_dir.is_dir() {
+        for entry in fs::read_dir(history_dir)? {
+            let path = entry?.path();
+            let is_snapshot = path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .is_some_and(|name| is_snapshot_filename(prefix, name));
+            if is_snapshot && path.is_file() {
+                snapshots.push(path);
+            }
+        }
+    }
+    detach_dependents(&snapshots, &removing)?;
+    for filename in filenames {
+        let path = history_dir.join(filename);
+        if path.is_file() {
+            materialize(&path)?;
+        }
+    }
+
     for filename in filenames {
         let file_path = history_dir.join(filename);
         if file_path.exists() && file_path.is_file() {
-            fs::remove_file(&file_path)?;
+            fs::create_dir_all(&trash_dir)?;
+            let trashed = trash_dir.join(filename);
+            fs::rename(&file_path, &trashed)?;
+            fs::File::options()
+                .write(true)
+                .open(&trashed)?
+                .set_modified(SystemTime::now())?;
         }
     }
     Ok(())
 }
 
+/// Move a deleted snapshot from the trash back into the history directory
+pub fn restore_from_trash(history_dir: &Path, filename: &str) -> Result<(), anyhow::Error> {
+    check_snapshot_name(filename)?;
+    let trashed = trash_dir(history_dir).join(filename);
+    if !trashed.is_file() {
+        return Err(anyhow::anyhow!("{} is not in the trash", filename));
+    }
+    let restored = history_dir.join(filename);
+    if restored.exists() {
+        return Err(anyhow::anyhow!("{} already exists in history", filename));
+    }
+    fs::rename(&trashed, &restored)?;
+    Ok(())
+}
+
+/// Permanently remove every trashed snapshot, returning how many were removed
+pub fn empty_trash(history_dir: &Path) -> Result<usize, anyhow::Error> {
+    expire_trash(history_dir, Duration::ZERO)
+}
+
+/// Permanently remove trashed snapshots deleted more than `max_age` ago
+fn expire_trash(history_dir: &Path, max_age: Duration) -> Result<usize, anyhow::Error> {
+    let trash_dir = trash_dir(history_dir);
+    if !trash_dir.exists() {
+        return Ok(0);
+    }
+
+    let mut removed = 0;
+    for entry in fs::read_dir(&trash_dir)? {
+        let path = entry?.path();
+        let deleted_at = fs::metadata(&path)?.modified()?;
+        let age = deleted_at.elapsed().unwrap_or_default();
+        if path.is_file() && age >= max_age {
+            match fs::remove_file(&path) {
+                Ok(()) => removed += 1,
+                Err(e) => tracing::warn!("Failed to remove trashed file {:?}: {}", path, e),
+            }
+        }
+    }
+    Ok(removed)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -238,13 +950,43 @@ mod tests {
         assert!(verify_host_file(&file_path).is_err());
     }
 
+    #[test]
+    fn test_verify_rejects_localhost_hijack() {
+        // Blocking alongside localhost is fine
+        assert!(verify_hosts_content("127.0.0.1 localhost evil.com\n").is_ok());
+        assert!(verify_hosts_content("::1 localhost ip6-localhost\n").is_ok());
+
+        // Sending localhost to a remote host or a sink is not
+        assert!(verify_hosts_content("10.0.0.5 localhost\n").is_err());
+        assert!(verify_hosts_content("0.0.0.0 localhost evil.com\n").is_err());
+        assert!(verify_hosts_content("192.168.1.1 router app.localhost\n").is_err());
+
+        // Single-label LAN names aren't localhost
+        assert!(verify_hosts_content("192.168.1.1 router\n").is_ok());
+    }
+
+    #[test]
+    fn test_verify_warns_about_invalid_lines() {
+        let warnings = verify_hosts_content(
+            "127.0.0.1 localhost\nnot a hosts line!\n0.0.0.0 ads.example.com\n",
+        )
+        .unwrap();
+        assert_eq!(
+            warnings,
+            ["Not a valid hosts entry, kept as is: not a hosts line!"]
+        );
+        assert!(verify_hosts_content("127.0.0.1 localhost\n")
+            .unwrap()
+            .is_empty());
+    }
+
     #[test]
     fn test_write_history_snapshot() {
         let temp_dir = TempDir::new().unwrap();
         let content = "127.0.0.1 localhost\n";
         let history_dir = temp_dir.path().to_path_buf();
 
-        let entry = write_history_snapshot(&history_dir, content).unwrap();
+        let entry = write_history_snapshot(&history_dir, DEFAULT_HISTORY_PREFIX, content).unwrap();
 
         assert!(entry.path.exists());
         assert!(entry.entry_count > 0);
@@ -256,13 +998,226 @@ mod tests {
         let content = "127.0.0.1 localhost\n";
         let history_dir = temp_dir.path().to_path_buf();
 
-        write_history_snapshot(&history_dir, content).unwrap();
-        write_history_snapshot(&history_dir, content).unwrap();
+        write_history_snapshot(&history_dir, DEFAULT_HISTORY_PREFIX, content).unwrap();
+        write_history_snapshot(&history_dir, DEFAULT_HISTORY_PREFIX, content).unwrap();
 
-        let entries = list_history_entries(&history_dir).unwrap();
+        let entries = list_history_entries(&history_dir, DEFAULT_HISTORY_PREFIX).unwrap();
         assert_eq!(entries.len(), 2);
     }
 
+    #[test]
+    fn test_list_only_prefixed_snapshots() {
+        let temp_dir = TempDir::new().unwrap();
+        let history_dir = temp_dir.path();
+        let content = "127.0.0.1 localhost\n";
+
+        let ours = write_history_snapshot(history_dir, "laptop", content).unwrap();
+        assert!(ours.filename.starts_with("laptop-"));
+        write_history_snapshot(history_dir, DEFAULT_HISTORY_PREFIX, content).unwrap();
+        fs::write(history_dir.join("notes.txt"), "unrelated\n").unwrap();
+        fs::write(history_dir.join("laptop.txt"), "unrelated\n").unwrap();
+        fs::write(history_dir.join("laptop-settings.json"), "{}").unwrap();
+
+        let entries = list_history_entries(history_dir, "laptop").unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].filename, ours.filename);
+        assert_eq!(entries[0].timestamp, ours.timestamp);
+        assert_eq!(
+            list_history_entries(history_dir, DEFAULT_HISTORY_PREFIX)
+                .unwrap()
+                .len(),
+            1
+        );
+
+        // Pruning leaves other prefixes and unrelated files in place
+        cleanup_old_history(history_dir, "laptop", 0).unwrap();
+        assert!(list_history_entries(history_dir, "laptop")
+            .unwrap()
+            .is_empty());
+        assert!(history_dir.join("notes.txt").exists());
+        assert_eq!(fs::read_dir(history_dir).unwrap().count(), 4);
+    }
+
+    #[test]
+    fn test_stray_files_are_not_snapshots() {
+        let temp_dir = TempDir::new().unwrap();
+        let history_dir = temp_dir.path();
+        let entry =
+            write_history_snapshot(history_dir, DEFAULT_HISTORY_PREFIX, "127.0.0.1 localhost\n")
+                .unwrap();
+        for stray in [
+            "notes.txt",
+            "hosts-backup-notes.txt",
+            "hosts-backup-20240501T093000.123456789Z-zz.txt",
+            "hosts-backup-2024-05-01-09-30-00-123-x.txt",
+        ] {
+            fs::write(history_dir.join(stray), "0.0.0.0 example.com\n").unwrap();
+            assert!(
+                !is_snapshot_filename(DEFAULT_HISTORY_PREFIX, stray),
+                "{}",
+                stray
+            );
+        }
+        assert!(is_snapshot_filename(
+            DEFAULT_HISTORY_PREFIX,
+            "hosts-backup-2024-05-01-09-30-00-123-0.txt"
+        ));
+
+        let entries = list_history_entries(history_dir, DEFAULT_HISTORY_PREFIX).unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].filename, entry.filename);
+
+        cleanup_old_history(history_dir, DEFAULT_HISTORY_PREFIX, 0).unwrap();
+        assert!(history_dir.join("notes.txt").exists());
+    }
+
+    #[test]
+    fn test_history_prefix_validation() {
+        assert!(is_valid_history_prefix(DEFAULT_HISTORY_PREFIX));
+        assert!(is_valid_history_prefix("work_laptop.v2"));
+        assert!(!is_valid_history_prefix(""));
+        assert!(!is_valid_history_prefix("../hosts"));
+        assert!(!is_valid_history_prefix(".hidden"));
+        assert!(!is_valid_history_prefix("my backups"));
+    }
+
+    #[test]
+    fn test_matches_latest_snapshot() {
+        let temp_dir = TempDir::new().unwrap();
+        let history_dir = temp_dir.path().to_path_buf();
+
+        assert!(!matches_latest_snapshot(
+            &history_dir,
+            DEFAULT_HISTORY_PREFIX,
+            "127.0.0.1 localhost\n"
+        ));
+        write_history_snapshot(
+            &history_dir,
+            DEFAULT_HISTORY_PREFIX,
+            "127.0.0.1 localhost\n",
+        )
+        .unwrap();
+        assert!(matches_latest_snapshot(
+            &history_dir,
+            DEFAULT_HISTORY_PREFIX,
+            "127.0.0.1 localhost\n"
+        ));
+        assert!(!matches_latest_snapshot(
+            &history_dir,
+            DEFAULT_HISTORY_PREFIX,
+            "::1 localhost\n"
+        ));
+    }
+
+    #[test]
+    fn test_snapshot_names_unique_and_ordered() {
+        let temp_dir = TempDir::new().unwrap();
+        let history_dir = temp_dir.path().to_path_buf();
+
+        let mut written: Vec<String> = (0..200)
+            .map(|i| {
+                write_history_snapshot(
+                    &history_dir,
+                    DEFAULT_HISTORY_PREFIX,
+                    &format!("127.0.0.1 localhost # {}\n", i),
+                )
+                .unwrap()
+                .filename
+            })
+            .collect();
+        // Writes from other threads interleave without colliding
+        let handles: Vec<_> = (0..4)
+            .map(|_| {
+                let history_dir = history_dir.clone();
+                std::thread::spawn(move || {
+                    (0..50)
+                        .map(|_| {
+                            write_history_snapshot(
+                                &history_dir,
+                                DEFAULT_HISTORY_PREFIX,
+                                "127.0.0.1 localhost\n",
+                            )
+                            .unwrap()
+                            .filename
+                        })
+                        .collect::<Vec<_>>()
+                })
+            })
+            .collect();
+        let threaded: Vec<String> = handles
+            .into_iter()
+            .flat_map(|handle| handle.join().unwrap())
+            .collect();
+
+        // Sequential names sort in write order
+        let mut sorted = written.clone();
+        sorted.sort();
+        assert_eq!(sorted, written);
+
+        written.extend(threaded);
+        let unique: HashSet<&String> = written.iter().collect();
+        assert_eq!(unique.len(), 400);
+
+        // Newest first, matching the names
+        let entries = list_history_entries(&history_dir, DEFAULT_HISTORY_PREFIX).unwrap();
+        assert_eq!(entries.len(), 400);
+        let listed: Vec<&str> = entries.iter().map(|e| e.filename.as_str()).collect();
+        let mut expected: Vec<&str> = written.iter().map(String::as_str).collect();
+        expected.sort_by(|a, b| b.cmp(a));
+        assert_eq!(listed, expected);
+        assert_eq!(
+            entries[0].timestamp,
+            snapshot_time(DEFAULT_HISTORY_PREFIX, &entries[0].filename).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_legacy_snapshot_names_use_mtime() {
+        let temp_dir = TempDir::new().unwrap();
+        let history_dir = temp_dir.path().to_path_buf();
+        let legacy = history_dir.join("hosts-backup-2024-05-01-09-30-00-123-0.txt");
+        fs::write(&legacy, "127.0.0.1 localhost\n").unwrap();
+
+        let entries = list_history_entries(&history_dir, DEFAULT_HISTORY_PREFIX).unwrap();
+        assert_eq!(
+            entries[0].timestamp,
+            fs::metadata(&legacy).unwrap().modified().unwrap()
+        );
+    }
+
+    #[test]
+    fn test_diff_files() {
+        let temp_dir = TempDir::new().unwrap();
+        let mine = temp_dir.path().join("mine");
+        let theirs = temp_dir.path().join("theirs");
+        fs::write(
+            &mine,
+            "127.0.0.1 localhost\n0.0.0.0 ads.example.com\n0.0.0.0 tracker.example.net\n",
+        )
+        .unwrap();
+        fs::write(
+            &theirs,
+            "# Shared list\n0.0.0.0 ads.example.com pixel.example.org\n127.0.0.1 metrics.example.com\n",
+        )
+        .unwrap();
+
+        let diff = diff_files(&mine, &theirs, &ParseOptions::default()).unwrap();
+        let names = |hostnames: &[Arc<str>]| -> Vec<String> {
+            hostnames.iter().map(|h| h.to_string()).collect()
+        };
+        assert_eq!(
+            names(&diff.added),
+            vec!["metrics.example.com", "pixel.example.org"]
+        );
+        assert_eq!(names(&diff.removed), vec!["tracker.example.net"]);
+        assert_eq!(names(&diff.common), vec!["ads.example.com"]);
+
+        let missing = temp_dir.path().join("missing");
+        let err = diff_files(&mine, &missing, &ParseOptions::default()).unwrap_err();
+        assert!(err.to_string().starts_with("Failed to read"));
+        assert!(err.to_string().contains("missing"));
+    }
+
     #[test]
     fn test_cleanup_old_history() {
         let temp_dir = TempDir::new().unwrap();
@@ -270,12 +1225,245 @@ mod tests {
         let history_dir = temp_dir.path().to_path_buf();
 
         for _ in 0..5 {
-            write_history_snapshot(&history_dir, content).unwrap();
+            write_history_snapshot(&history_dir, DEFAULT_HISTORY_PREFIX, content).unwrap();
         }
 
-        cleanup_old_history(&history_dir, 3).unwrap();
+        cleanup_old_history(&history_dir, DEFAULT_HISTORY_PREFIX, 3).unwrap();
 
-        let entries = list_history_entries(&history_dir).unwrap();
+        let entries = list_history_entries(&history_dir, DEFAULT_HISTORY_PREFIX).unwrap();
         assert_eq!(entries.len(), 3);
     }
+
+    /// Hosts content blocking `count` domains from `first` on one sink line
+    fn blocklist(first: usize, count: usize) -> String {
+        let domains: Vec<String> = (first..first + count)
+            .map(|i| format!("ads{}.example.com", i))
+            .collect();
+        format!(
+            "# Local\n127.0.0.1 localhost\n192.168.1.1 router\n0.0.0.0 {}\n",
+            domains.join(" ")
+        )
+    }
+
+    #[test]
+    fn test_incremental_history_rebuilds() {
+        let temp_dir = TempDir::new().unwrap();
+        let history = HistorySettings {
+            dir: temp_dir.path().into(),
+            prefix: DEFAULT_HISTORY_PREFIX.into(),
+            max_entries: 10,
+            keyframe_interval: 3,
+        };
+        // A domain removed and another added each time, then a LAN entry edited
+        let mut contents: Vec<String> = (0..5).map(|i| blocklist(i, 500)).collect();
+        contents.push(contents[4].replace("192.168.1.1 router", "192.168.1.2 nas"));
+        let written: Vec<HistoryEntry> = contents
+            .iter()
+            .map(|content| write_snapshot(&history, content).unwrap())
+            .collect();
+
+        // A keyframe, three deltas, then a keyframe again
+        let deltas: Vec<bool> = written
+            .iter()
+            .map(|entry| read_delta(&entry.path).unwrap().is_some())
+            .collect();
+        assert_eq!(deltas, [false, true, true, true, false, true]);
+        assert!(written[1].file_size * 20 < written[0].file_size);
+
+        for (entry, content) in written.iter().zip(&contents) {
+            assert_eq!(&read_snapshot(&entry.path).unwrap(), content);
+        }
+        let listed = list_history_entries(temp_dir.path(), DEFAULT_HISTORY_PREFIX).unwrap();
+        assert!(listed.iter().all(|entry| entry.entry_count == 3));
+        assert!(matches_latest_snapshot(
+            temp_dir.path(),
+            DEFAULT_HISTORY_PREFIX,
+            &contents[5]
+        ));
+
+        // Rolling back to a delta writes the rebuilt content
+        let hosts_path = temp_dir.path().join("hosts");
+        rollback_to_history(&written[3], &hosts_path).unwrap();
+        assert_eq!(fs::read_to_string(&hosts_path).unwrap(), contents[3]);
+    }
+
+    #[test]
+    fn test_removing_a_base_keeps_deltas_readable() {
+        let temp_dir = TempDir::new().unwrap();
+        let history_dir = temp_dir.path();
+        let history = HistorySettings {
+            dir: history_dir.into(),
+            prefix: DEFAULT_HISTORY_PREFIX.into(),
+            max_entries: 10,
+            keyframe_interval: 20,
+        };
+        let contents: Vec<String> = (0..4).map(|i| blocklist(i, 500)).collect();
+        let written: Vec<HistoryEntry> = contents
+            .iter()
+            .map(|content| write_snapshot(&history, content).unwrap())
+            .collect();
+
+        // Trashing a delta in the middle: the next one becomes a full copy
+        // and the trashed one can be restored on its own
+        delete_history_files(
+            history_dir,
+            DEFAULT_HISTORY_PREFIX,
+            std::slice::from_ref(&written[2].filename),
+        )
+        .unwrap();
+        assert!(read_delta(&written[3].path).unwrap().is_none());
+        assert_eq!(read_snapshot(&written[3].path).unwrap(), contents[3]);
+        let trashed = trash_dir(history_dir).join(&written[2].filename);
+        assert_eq!(fs::read_to_string(trashed).unwrap(), contents[2]);
+
+        // Pruning the keyframe turns the oldest kept delta into one
+        cleanup_old_history(history_dir, DEFAULT_HISTORY_PREFIX, 2).unwrap();
+        assert!(!written[0].path.exists());
+        assert!(read_delta(&written[1].path).unwrap().is_none());
+        assert_eq!(read_snapshot(&written[1].path).unwrap(), contents[1]);
+    }
+
+    #[test]
+    fn test_broken_delta_does_not_fail_cleanup() {
+        let temp_dir = TempDir::new().unwrap();
+        let history_dir = temp_dir.path();
+        let history = HistorySettings {
+            dir: history_dir.into(),
+            prefix: DEFAULT_HISTORY_PREFIX.into(),
+            max_entries: 10,
+            keyframe_interval: 20,
+        };
+        let written: Vec<HistoryEntry> = (0..3)
+            .map(|i| write_snapshot(&history, &blocklist(i, 500)).unwrap())
+            .collect();
+        fs::write(&written[1].path, format!("{}\nnot json", DELTA_MARKER)).unwrap();
+
+        // The newest can't be rebuilt, so the delta it's based on stays
+        cleanup_old_history(history_dir, DEFAULT_HISTORY_PREFIX, 1).unwrap();
+        assert!(!written[0].path.exists());
+        assert!(written[1].path.exists());
+        assert!(read_delta(&written[2].path).unwrap().is_some());
+    }
+
+    #[test]
+    fn test_delete_and_restore_from_trash() {
+        let temp_dir = TempDir::new().unwrap();
+        let history_dir = temp_dir.path();
+        let content = "127.0.0.1 localhost\n0.0.0.0 ads.example.com\n";
+        let entry = write_history_snapshot(history_dir, DEFAULT_HISTORY_PREFIX, content).unwrap();
+        let filenames = vec![entry.filename.clone()];
+
+        delete_history_files(history_dir, DEFAULT_HISTORY_PREFIX, &filenames).unwrap();
+        assert!(list_history_entries(history_dir, DEFAULT_HISTORY_PREFIX)
+            .unwrap()
+            .is_empty());
+        let trashed =
+            list_history_entries(&trash_dir(history_dir), DEFAULT_HISTORY_PREFIX).unwrap();
+        assert_eq!(trashed.len(), 1);
+        assert_eq!(trashed[0].filename, entry.filename);
+
+        restore_from_trash(history_dir, &entry.filename).unwrap();
+        let entries = list_history_entries(history_dir, DEFAULT_HISTORY_PREFIX).unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].filename, entry.filename);
+        assert_eq!(entries[0].timestamp, entry.timestamp);
+        assert_eq!(fs::read_to_string(&entries[0].path).unwrap(), content);
+
+        assert!(restore_from_trash(history_dir, &entry.filename).is_err());
+        assert!(restore_from_trash(history_dir, "../hosts").is_err());
+        assert!(delete_history_files(
+            history_dir,
+            DEFAULT_HISTORY_PREFIX,
+            &["../hosts".to_string()]
+        )
+        .is_err());
+
+        // Files that aren't snapshots stay put, and deltas aren't looked for in them
+        fs::write(history_dir.join("notes.txt"), "keep me\n").unwrap();
+        fs::write(history_dir.join("other-notes.txt"), DELTA_MARKER).unwrap();
+        let entry = write_history_snapshot(history_dir, DEFAULT_HISTORY_PREFIX, content).unwrap();
+        assert!(delete_history_files(
+            history_dir,
+            DEFAULT_HISTORY_PREFIX,
+            &["notes.txt".to_string()]
+        )
+        .is_err());
+        assert!(history_dir.join("notes.txt").exists());
+        delete_history_files(history_dir, DEFAULT_HISTORY_PREFIX, &[entry.filename]).unwrap();
+        assert_eq!(
+            fs::read_to_string(history_dir.join("other-notes.txt")).unwrap(),
+            DELTA_MARKER
+        );
+    }
+
+    #[test]
+    fn test_trash_keeps_earlier_copy() {
+        let temp_dir = TempDir::new().unwrap();
+        let history_dir = temp_dir.path();
+        let entry =
+            write_history_snapshot(history_dir, DEFAULT_HISTORY_PREFIX, "127.0.0.1 localhost\n")
+                .unwrap();
+        let filenames = vec![entry.filename.clone()];
+        delete_history_files(history_dir, DEFAULT_HISTORY_PREFIX, &filenames).unwrap();
+
+        // A snapshot of the same name can't replace the trashed one
+        fs::write(&entry.path, "::1 localhost\n").unwrap();
+        assert!(delete_history_files(history_dir, DEFAULT_HISTORY_PREFIX, &filenames).is_err());
+        assert!(entry.path.exists());
+        let trashed = trash_dir(history_dir).join(&entry.filename);
+        assert_eq!(
+            fs::read_to_string(trashed).unwrap(),
+            "127.0.0.1 localhost\n"
+        );
+    }
+
+    #[test]
+    fn test_unreadable_trash_does_not_fail_cleanup() {
+        let temp_dir = TempDir::new().unwrap();
+        let history_dir = temp_dir.path();
+        fs::write(trash_dir(history_dir), "not a directory").unwrap();
+        write_history_snapshot(history_dir, DEFAULT_HISTORY_PREFIX, "127.0.0.1 localhost\n")
+            .unwrap();
+        cleanup_old_history(history_dir, DEFAULT_HISTORY_PREFIX, 10).unwrap();
+    }
+
+    #[test]
+    fn test_trash_expiry() {
+        let temp_dir = TempDir::new().unwrap();
+        let history_dir = temp_dir.path();
+        let old =
+            write_history_snapshot(history_dir, DEFAULT_HISTORY_PREFIX, "127.0.0.1 localhost\n")
+                .unwrap();
+        let recent =
+            write_history_snapshot(history_dir, DEFAULT_HISTORY_PREFIX, "::1 localhost\n").unwrap();
+        delete_history_files(
+            history_dir,
+            DEFAULT_HISTORY_PREFIX,
+            &[old.filename.clone(), recent.filename],
+        )
+        .unwrap();
+
+        // Backdate one deletion past the retention period
+        fs::File::options()
+            .write(true)
+            .open(trash_dir(history_dir).join(&old.filename))
+            .unwrap()
+            .set_modified(SystemTime::now() - TRASH_RETENTION - Duration::from_secs(60))
+            .unwrap();
+
+        cleanup_old_history(history_dir, DEFAULT_HISTORY_PREFIX, 10).unwrap();
+        assert_eq!(
+            list_history_entries(&trash_dir(history_dir), DEFAULT_HISTORY_PREFIX)
+                .unwrap()
+                .len(),
+            1
+        );
+
+        assert_eq!(empty_trash(history_dir).unwrap(), 1);
+        assert!(
+            list_history_entries(&trash_dir(history_dir), DEFAULT_HISTORY_PREFIX)
+                .unwrap()
+                .is_empty()
+        );
+    }
 }
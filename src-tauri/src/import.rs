@@ -0,0 +1,1275 @@
+use crate::config::SafeListPolicy;
+use crate::parser::{
+    parse_domain_list, parse_hosts_with, DomainFilter, ParseOptions, ParsedHosts, PreservedLine,
+    SinkConflict, SinkMap, DEFAULT_SINK, MANAGED_START,
+};
+use crate::safelist::is_safe_listed;
+use crate::state::{OriginMap, MANUAL_ORIGIN};
+use crate::utils::is_localhost_ip;
+use futures_util::stream::{self, StreamExt};
+use std::collections::{BTreeSet, HashMap};
+use std::fs;
+use std::future::Future;
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Number of progress reports for a parse (one every 5%)
+const PROGRESS_STEPS: usize = 20;
+
+/// How far a chunked parse has got
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ImportProgress {
+    pub bytes_processed: usize,
+    pub total_bytes: usize,
+    pub lines_processed: usize,
+    pub total_lines: usize,
+}
+
+/// Parse hosts content in chunks of lines, reporting progress after each chunk
+///
+/// Content with a managed region is parsed in one go, since the region
+/// markers must be seen together.
+pub fn parse_with_progress(
+    content: &str,
+    options: &ParseOptions,
+    mut on_progress: impl FnMut(ImportProgress),
+) -> Result<ParsedHosts, anyhow::Error> {
+    let total_bytes = content.len();
+    let total_lines = content.lines().count();
+
+    if content.lines().any(|line| line.trim() == MANAGED_START) {
+        let parsed = parse_hosts_with(content, options)?;
+        on_progress(ImportProgress {
+            bytes_processed: total_bytes,
+            total_bytes,
+            lines_processed: total_lines,
+            total_lines,
+        });
+        return Ok(parsed);
+    }
+
+    let lines_per_chunk = total_lines.div_ceil(PROGRESS_STEPS).max(1);
+    let mut parsed: Option<ParsedHosts> = None;
+    let mut chunk_start = 0;
+    let mut bytes_processed = 0;
+
+    for (index, line) in content.split_inclusive('\n').enumerate() {
+        bytes_processed += line.len();
+        let is_last = bytes_processed == total_bytes;
+        if (index + 1) % lines_per_chunk != 0 && !is_last {
+            continue;
+        }
+
+        let chunk = parse_hosts_with(&content[chunk_start..bytes_processed], options)?;
+        match parsed {
+            Some(ref mut parsed) => parsed.merge(chunk),
+            None => parsed = Some(chunk),
+        }
+        chunk_start = bytes_processed;
+
+        on_progress(ImportProgress {
+            bytes_processed,
+            total_bytes,
+            lines_processed: index + 1,
+            total_lines,
+        });
+    }
+
+    match parsed {
+        Some(parsed) => Ok(parsed),
+        // Empty content: no chunk was parsed
+        None => Ok(parse_hosts_with(content, options)?),
+    }
+}
+
+/// Layout of imported content
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SourceFormat {
+    /// Hosts file lines (`0.0.0.0 ads.example.com`)
+    #[default]
+    Hosts,
+    /// One bare domain per line
+    DomainList,
+}
+
+impl SourceFormat {
+    pub fn from_str(value: &str) -> Result<Self, anyhow::Error> {
+        match value {
+            "hosts" => Ok(SourceFormat::Hosts),
+            "domain-list" => Ok(SourceFormat::DomainList),
+            _ => Err(anyhow::anyhow!("Unknown source format: {}", value)),
+        }
+    }
+
+    pub fn to_str(self) -> &'static str {
+        match self {
+            SourceFormat::Hosts => "hosts",
+            SourceFormat::DomainList => "domain-list",
+        }
+    }
+
+    /// Guess the format from the first line that isn't blank or a comment:
+    /// a line starting with an IP address means hosts content
+    pub fn detect(content: &str) -> Self {
+        let first_entry = content
+            .lines()
+            .map(str::trim)
+            .find(|line| !line.is_empty() && !line.starts_with('#'));
+        match first_entry.and_then(|line| line.split_whitespace().next()) {
+            Some(token) if token.parse::<IpAddr>().is_err() => SourceFormat::DomainList,
+            _ => SourceFormat::Hosts,
+        }
+    }
+}
+
+/// Parse imported content in the given format
+/// A domain list yields only blocked domains, under the default sink
+pub fn parse_source(
+    content: &str,
+    format: SourceFormat,
+    options: &ParseOptions,
+    mut on_progress: impl FnMut(ImportProgress),
+) -> Result<ParsedHosts, anyhow::Error> {
+    match format {
+        SourceFormat::Hosts => parse_with_progress(content, options, on_progress),
+        SourceFormat::DomainList => {
+            let (mut blocking, skipped) = parse_domain_list(content);
+            if skipped > 0 {
+                tracing::warn!("Skipped {} invalid domain(s) in domain list", skipped);
+            }
+            blocking.retain(|hostname| !options.exclude.excludes(&hostname.to_ascii_lowercase()));
+            let total_lines = content.lines().count();
+            on_progress(ImportProgress {
+                bytes_processed: content.len(),
+                total_bytes: content.len(),
+                lines_processed: total_lines,
+                total_lines,
+            });
+            Ok(ParsedHosts {
+                blocking,
+                ..ParsedHosts::default()
+            })
+        }
+    }
+}
+
+/// Largest source accepted for an import or inspection
+pub const MAX_SOURCE_BYTES: u64 = 32 * 1024 * 1024;
+
+/// Read an import source from disk, refusing files over `MAX_SOURCE_BYTES`
+pub fn read_source_file(path: &Path) -> Result<String, anyhow::Error> {
+    let size = fs::metadata(path)?.len();
+    if size > MAX_SOURCE_BYTES {
+        return Err(source_too_large(size));
+    }
+    Ok(fs::read_to_string(path)?)
+}
+
+/// First bytes of every SQLite database, such as Pi-hole's `gravity.db`
+pub const SQLITE_MAGIC: &[u8] = b"SQLite format 3\0";
+
+/// Read a Pi-hole export from disk, refusing files over `MAX_SOURCE_BYTES`
+///
+/// Only text exports are read: there is no SQLite support, so a `gravity.db`
+/// is recognised and refused with a pointer to exporting its domains.
+pub fn read_pihole_export(path: &Path) -> Result<String, anyhow::Error> {
+    let size = fs::metadata(path)?.len();
+    if size > MAX_SOURCE_BYTES {
+        return Err(source_too_large(size));
+    }
+    let content = fs::read(path)?;
+    if content.starts_with(SQLITE_MAGIC) {
+        return Err(anyhow::anyhow!(
+            "{} is a Pi-hole database, which can't be read directly; export its domains \
+             with: sqlite3 {} \"SELECT domain FROM gravity\" > gravity.txt",
+            path.display(),
+            path.display()
+        ));
+    }
+    String::from_utf8(content).map_err(|_| anyhow::anyhow!("{} is not UTF-8 text", path.display()))
+}
+
+/// Domains of a Pi-hole export, and how many lines were skipped
+///
+/// Accepts what Pi-hole's adlists and gravity exports contain: bare domains,
+/// hosts lines and Adblock-style `||domain^` rules. Regex entries and rules
+/// with modifiers can't be expressed in a hosts file and are skipped.
+pub fn parse_pihole_export(content: &str, options: &ParseOptions) -> (BTreeSet<Arc<str>>, usize) {
+    let mut domains = String::with_capacity(content.len());
+    let mut skipped = 0;
+    for line in content.lines() {
+        let line = line.split('#').next().unwrap_or_default().trim();
+        if line.is_empty() || line.starts_with('!') {
+            continue;
+        }
+        let tokens: Vec<&str> = match line.strip_prefix("||") {
+            Some(rule) => rule.strip_suffix('^').into_iter().collect(),
+            None => {
+                let mut tokens = line.split_whitespace().peekable();
+                // A hosts line lists its domains after the IP
+                if tokens.peek().is_some_and(|ip| ip.parse::<IpAddr>().is_ok()) {
+                    tokens.next();
+                    tokens.collect()
+                } else {
+                    vec![line]
+                }
+            }
+        };
+        let plain: Vec<&str> = tokens
+            .into_iter()
+            .filter(|token| {
+                !token.is_empty()
+                    && token
+                        .chars()
+                        .all(|c| c.is_alphanumeric() || matches!(c, '.' | '-' | '_'))
+            })
+            .collect();
+        if plain.is_empty() {
+            skipped += 1;
+        }
+        // Pi-hole matches domains regardless of case
+        for token in plain {
+            domains.push_str(&token.to_ascii_lowercase());
+            domains.push('\n');
+        }
+    }
+
+    let (mut blocking, invalid) = parse_domain_list(&domains);
+    blocking.retain(|hostname| !options.exclude.excludes(&hostname.to_ascii_lowercase()));
+    (blocking, skipped + invalid)
+}
+
+/// Download an import source, refusing responses over `MAX_SOURCE_BYTES`
+pub async fn fetch_source(url: &str) -> Result<String, anyhow::Error> {
+    let mut response = reqwest::get(url).await?.error_for_status()?;
+    if let Some(size) = response
+        .content_length()
+        .filter(|&size| size > MAX_SOURCE_BYTES)
+    {
+        return Err(source_too_large(size));
+    }
+
+    // The declared length may be missing or wrong, so the body is capped as it arrives
+    let mut body = Vec::new();
+    while let Some(chunk) = response.chunk().await? {
+        body.extend_from_slice(&chunk);
+        if body.len() as u64 > MAX_SOURCE_BYTES {
+            return Err(source_too_large(body.len() as u64));
+        }
+    }
+    String::from_utf8(body).map_err(|_| anyhow::anyhow!("{} is not UTF-8 text", url))
+}
+
+/// Sources downloaded at once by `fetch_sources`
+pub const SOURCE_FETCH_CONCURRENCY: usize = 4;
+
+/// Time allowed for each source, after which it counts as failed
+pub const SOURCE_FETCH_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Download several sources, `SOURCE_FETCH_CONCURRENCY` at a time
+/// Results are in the order of `urls`; a failed source doesn't stop the others
+pub async fn fetch_sources(urls: &[String]) -> Vec<Result<String, anyhow::Error>> {
+    fetch_sources_with(
+        urls,
+        SOURCE_FETCH_CONCURRENCY,
+        SOURCE_FETCH_TIMEOUT,
+        |url| async move { fetch_source(&url).await },
+    )
+    .await
+}
+
+/// `fetch_sources` with the limits and the download itself supplied
+pub async fn fetch_sources_with<F, Fut>(
+    urls: &[String],
+    concurrency: usize,
+    timeout: Duration,
+    fetch: F,
+) -> Vec<Result<String, anyhow::Error>>
+where
+    F: Fn(String) -> Fut,
+    Fut: Future<Output = Result<String, anyhow::Error>>,
+{
+    // Futures do nothing until polled, so creating them all up front starts no downloads
+    let downloads: Vec<_> = urls
+        .iter()
+        .enumerate()
+        .map(|(index, url)| {
+            let download = tokio::time::timeout(timeout, fetch(url.clone()));
+            async move {
+                let result = download.await.unwrap_or_else(|_| {
+                    Err(anyhow::anyhow!(
+                        "Timed out after {} seconds",
+                        timeout.as_secs_f32()
+                    ))
+                });
+                (index, result)
+            }
+        })
+        .collect();
+    let mut results: Vec<(usize, Result<String, anyhow::Error>)> = stream::iter(downloads)
+        .buffer_unordered(concurrency.max(1))
+        .collect()
+        .await;
+    results.sort_by_key(|(index, _)| *index);
+    results.into_iter().map(|(_, result)| result).collect()
+}
+
+/// How much of a list no other list covers
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SourceContribution {
+    /// Domains the list blocks
+    pub total: usize,
+    /// Domains no other list blocks
+    pub unique: usize,
+}
+
+/// Count each list's blocked domains and those blocked through no other of
+/// the lists, in input order
+/// Counts come from the origins recorded for each blocked domain, so they hold
+/// across saves; a domain also added by hand is never unique to a list. A
+/// list that contributes nothing unique is redundant with the rest.
+pub fn source_contributions(
+    urls: &[String],
+    blocking: &BTreeSet<Arc<str>>,
+    origins: &OriginMap,
+) -> Vec<SourceContribution> {
+    let mut contributions = vec![SourceContribution::default(); urls.len()];
+    for (hostname, tags) in origins {
+        if !blocking.contains(hostname) {
+            continue;
+        }
+        let listed: Vec<usize> = urls
+            .iter()
+            .enumerate()
+            .filter(|(_, url)| tags.contains(url.as_str()))
+            .map(|(index, _)| index)
+            .collect();
+        for &index in &listed {
+            contributions[index].total += 1;
+        }
+        if let [only] = listed[..] {
+            if !tags.contains(MANUAL_ORIGIN) {
+                contributions[only].unique += 1;
+            }
+        }
+    }
+    contributions
+}
+
+#[inline]
+fn source_too_large(size: u64) -> anyhow::Error {
+    anyhow::anyhow!(
+        "Source is too large ({} bytes, the limit is {} bytes)",
+        size,
+        MAX_SOURCE_BYTES
+    )
+}
+
+/// Metadata a list declares in its header comments
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SourceMetadata {
+    pub title: Option<String>,
+    pub homepage: Option<String>,
+    pub last_updated: Option<String>,
+    /// Entry count the list claims, which may differ from what parses
+    pub declared_entries: Option<usize>,
+}
+
+/// Read `# Key: value` lines from the comment block at the top of a list
+///
+/// Keys are matched case-insensitively, covering the common conventions
+/// (`Title`, `Homepage`, `Last modified`/`Last updated`/`Updated`/`Date`,
+/// `Number of entries`/`Entries`). Adblock-style `!` comments are accepted
+/// too. The block ends at the first line that is neither blank nor a comment.
+pub fn parse_source_metadata(content: &str) -> SourceMetadata {
+    let mut metadata = SourceMetadata::default();
+
+    for line in content.lines().map(str::trim) {
+        if line.is_empty() {
+            continue;
+        }
+        let Some(comment) = line.strip_prefix('#').or_else(|| line.strip_prefix('!')) else {
+            break;
+        };
+        let Some((key, value)) = comment.split_once(':') else {
+            continue;
+        };
+        let value = value.trim();
+        if value.is_empty() {
+            continue;
+        }
+
+        let field = match key.trim().to_ascii_lowercase().as_str() {
+            "title" => &mut metadata.title,
+            "homepage" => &mut metadata.homepage,
+            "last modified" | "last updated" | "updated" | "date" => &mut metadata.last_updated,
+            "number of entries" | "entries" => {
+                let count = value.replace([',', '_'], "").parse().ok();
+                metadata.declared_entries = metadata.declared_entries.or(count);
+                continue;
+            }
+            _ => continue,
+        };
+        // The first occurrence wins
+        field.get_or_insert_with(|| value.to_string());
+    }
+
+    metadata
+}
+
+/// Options for an import
+#[derive(Debug, Clone, Default)]
+pub struct ImportOptions {
+    pub policy: ImportPolicy,
+    /// Layout of the content; detected from the content when unset
+    pub format: Option<SourceFormat>,
+    /// Keep comment lines from the imported content
+    pub keep_source_comments: bool,
+    /// Sink for every imported domain, overriding the one it was listed under
+    pub sink: Option<IpAddr>,
+    /// Domains left out of the import, dropped while parsing
+    pub exclude: DomainFilter,
+    /// Import even if it takes the blocked set past `max_total_domains`
+    pub allow_over_cap: bool,
+}
+
+impl ImportOptions {
+    /// Build options from optional command arguments
+    pub fn from_args(
+        policy: Option<&str>,
+        format: Option<&str>,
+        keep_source_comments: Option<bool>,
+        sink: Option<&str>,
+        domain_filter: Option<&[String]>,
+        allow_over_cap: Option<bool>,
+        parse_options: &ParseOptions,
+    ) -> Result<Self, anyhow::Error> {
+        Ok(ImportOptions {
+            policy: policy
+                .map(ImportPolicy::from_str)
+                .transpose()?
+                .unwrap_or_default(),
+            format: format.map(SourceFormat::from_str).transpose()?,
+            keep_source_comments: keep_source_comments.unwrap_or(false),
+            sink: sink
+                .map(|sink| parse_sink_for(sink, parse_options))
+                .transpose()?,
+            exclude: DomainFilter::new(domain_filter.unwrap_or_default()),
+            allow_over_cap: allow_over_cap.unwrap_or(false),
+        })
+    }
+}
+
+/// Find imported domains on the bundled safe list, dropping them under `Skip`
+/// Returns the safe-listed domains the content blocks
+pub fn check_safe_list(parsed: &mut ParsedHosts, policy: SafeListPolicy) -> Vec<Arc<str>> {
+    let safe: Vec<Arc<str>> = parsed
+        .blocking
+        .iter()
+        .filter(|hostname| is_safe_listed(hostname))
+        .cloned()
+        .collect();
+    if policy == SafeListPolicy::Skip {
+        for hostname in &safe {
+            parsed.blocking.remove(hostname);
+            parsed.sinks.remove(hostname);
+            parsed.annotations.remove(hostname);
+        }
+    }
+    safe
+}
+
+/// Parse a sink address; only loopback and unspecified addresses are sinks
+pub fn parse_sink(value: &str) -> Result<IpAddr, anyhow::Error> {
+    let sink: IpAddr = value
+        .trim()
+        .parse()
+        .map_err(|_| anyhow::anyhow!("Invalid sink address: {}", value))?;
+    if !is_localhost_ip(&sink) {
+        return Err(anyhow::anyhow!(
+            "{} is not a sink address (use a loopback or 0.0.0.0/:: address)",
+            sink
+        ));
+    }
+    Ok(sink)
+}
+
+/// Parse a sink address whose entries read back as blocked under `options`
+/// A loopback address in `preserve_ips`, or missing from a non-empty
+/// `sink_ips`, would leave every domain imported under it unblocked
+pub fn parse_sink_for(value: &str, options: &ParseOptions) -> Result<IpAddr, anyhow::Error> {
+    let sink = parse_sink(value)?;
+    if !options.is_sink(&sink) {
+        return Err(anyhow::anyhow!(
+            "{} is not a sink under the current settings (it must be one of sink_ips \
+             and not one of preserve_ips)",
+            sink
+        ));
+    }
+    Ok(sink)
+}
+
+/// List every parsed domain under one sink
+/// Sink conflicts within the content no longer matter and are dropped
+pub fn override_sink(parsed: &mut ParsedHosts, sink: IpAddr) {
+    parsed.sinks = parsed
+        .blocking
+        .iter()
+        .map(|hostname| (hostname.clone(), sink))
+        .collect();
+    parsed.sink_conflicts.clear();
+}
+
+/// Drop comment lines from imported content, adding a single provenance comment
+pub fn strip_source_comments(parsed: &mut ParsedHosts, source: &str) {
+    parsed.preserved_lines.retain(|line| match line {
+        PreservedLine::Comment(_) => false,
+        PreservedLine::Raw(text) => !text.trim_start().starts_with('#'),
+        _ => true,
+    });
+
+    let imported_at = chrono::Local::now().format("%Y-%m-%d %H:%M");
+    parsed.preserved_lines.insert(
+        0,
+        PreservedLine::Comment(format!("# Imported from {} on {}", source, imported_at).into()),
+    );
+}
+
+/// How to resolve a domain listed under two different sinks while importing
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ImportPolicy {
+    /// Keep the sink from the first listing (importing twice is a no-op)
+    #[default]
+    KeepFirst,
+    /// Keep the sink from the last listing
+    KeepLast,
+    /// Refuse the import
+    ErrorOnConflict,
+}
+
+impl ImportPolicy {
+    pub fn from_str(value: &str) -> Result<Self, anyhow::Error> {
+        match value {
+            "keep-first" => Ok(ImportPolicy::KeepFirst),
+            "keep-last" => Ok(ImportPolicy::KeepLast),
+            "error-on-conflict" => Ok(ImportPolicy::ErrorOnConflict),
+            _ => Err(anyhow::anyhow!("Unknown import policy: {}", value)),
+        }
+    }
+
+    pub fn to_str(self) -> &'static str {
+        match self {
+            ImportPolicy::KeepFirst => "keep-first",
+            ImportPolicy::KeepLast => "keep-last",
+            ImportPolicy::ErrorOnConflict => "error-on-conflict",
+        }
+    }
+
+    /// Resolve the sink conflicts found while parsing
+    pub fn apply(self, parsed: &mut ParsedHosts) -> Result<ImportReport, anyhow::Error> {
+        let conflicts = std::mem::take(&mut parsed.sink_conflicts);
+
+        match self {
+            ImportPolicy::KeepFirst => {}
+            ImportPolicy::KeepLast => {
+                for conflict in &conflicts {
+                    parsed
+                        .sinks
+                        .insert(conflict.hostname.clone(), conflict.incoming);
+                }
+            }
+            ImportPolicy::ErrorOnConflict => {
+                if let Some(conflict) = conflicts.first() {
+                    return Err(anyhow::anyhow!(
+                        "{} is listed under both {} and {}",
+                        conflict.hostname,
+                        conflict.existing,
+                        conflict.incoming
+                    ));
+                }
+            }
+        }
+
+        Ok(ImportReport {
+            policy: self,
+            conflicts,
+        })
+    }
+}
+
+/// Conflicts resolved by an import and the policy used
+#[derive(Debug, Clone)]
+pub struct ImportReport {
+    pub policy: ImportPolicy,
+    pub conflicts: Vec<SinkConflict>,
+}
+
+impl ImportReport {
+    /// Sink kept for each conflicting domain
+    pub fn resolutions(&self) -> impl Iterator<Item = (&SinkConflict, std::net::IpAddr)> {
+        self.conflicts.iter().map(move |conflict| {
+            let kept = match self.policy {
+                ImportPolicy::KeepLast => conflict.incoming,
+                _ => conflict.existing,
+            };
+            (conflict, kept)
+        })
+    }
+}
+
+/// Parsed content held between an import dry run and the user's decisions
+#[derive(Debug)]
+pub struct PendingImport {
+    pub source: String,
+    pub parsed: ParsedHosts,
+}
+
+/// Why an imported domain needs a decision
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConflictReason {
+    /// Blocked now under a different sink than the import lists it under
+    SinkMismatch,
+    /// Blocked now but missing from the import, which would unblock it
+    WouldRemove,
+}
+
+impl ConflictReason {
+    pub fn to_str(self) -> &'static str {
+        match self {
+            ConflictReason::SinkMismatch => "sink-mismatch",
+            ConflictReason::WouldRemove => "would-remove",
+        }
+    }
+}
+
+/// A domain whose current and imported states differ
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ImportConflict {
+    pub hostname: Arc<str>,
+    pub reason: ConflictReason,
+    /// Current sink, if the domain is blocked
+    pub current: Option<IpAddr>,
+    /// Imported sink, if the import blocks the domain
+    pub incoming: Option<IpAddr>,
+}
+
+/// Which side of a conflict to keep
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Resolution {
+    KeepCurrent,
+    TakeIncoming,
+}
+
+impl Resolution {
+    pub fn from_str(value: &str) -> Result<Self, anyhow::Error> {
+        match value {
+            "current" => Ok(Resolution::KeepCurrent),
+            "incoming" => Ok(Resolution::TakeIncoming),
+            _ => Err(anyhow::anyhow!("Unknown resolution: {}", value)),
+        }
+    }
+}
+
+/// Compare an import against the current blocking set
+pub fn find_import_conflicts(
+    blocking: &BTreeSet<Arc<str>>,
+    sinks: &SinkMap,
+    parsed: &ParsedHosts,
+) -> Vec<ImportConflict> {
+    let sink_of =
+        |sinks: &SinkMap, hostname: &str| sinks.get(hostname).copied().unwrap_or(DEFAULT_SINK);
+
+    blocking
+        .iter()
+        .filter_map(|hostname| {
+            let current = sink_of(sinks, hostname);
+            if !parsed.blocking.contains(hostname) {
+                return Some(ImportConflict {
+                    hostname: hostname.clone(),
+                    reason: ConflictReason::WouldRemove,
+                    current: Some(current),
+                    incoming: None,
+                });
+            }
+            let incoming = sink_of(&parsed.sinks, hostname);
+            (incoming != current).then(|| ImportConflict {
+                hostname: hostname.clone(),
+                reason: ConflictReason::SinkMismatch,
+                current: Some(current),
+                incoming: Some(incoming),
+            })
+        })
+        .collect()
+}
+
+/// Apply per-conflict decisions to the imported content
+/// Conflicts without a decision take the incoming side, as a plain import does
+pub fn apply_import_decisions(
+    parsed: &mut ParsedHosts,
+    conflicts: &[ImportConflict],
+    decisions: &HashMap<Arc<str>, Resolution>,
+) {
+    for conflict in conflicts {
+        if decisions.get(&conflict.hostname) != Some(&Resolution::KeepCurrent) {
+            continue;
+        }
+        match conflict.current {
+            Some(sink) => {
+                parsed.blocking.insert(conflict.hostname.clone());
+                parsed.sinks.insert(conflict.hostname.clone(), sink);
+            }
+            None => {
+                parsed.blocking.remove(&conflict.hostname);
+                parsed.sinks.remove(&conflict.hostname);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse_hosts;
+    use std::net::IpAddr;
+
+    const CONFLICTING: &str = "127.0.0.1 ads.example.com\n0.0.0.0 ads.example.com\n";
+
+    #[test]
+    fn test_pihole_text_export() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let path = temp_dir.path().join("gravity.txt");
+        fs::write(
+            &path,
+            "# Pi-hole adlist\n\
+             ads.example.com\n\
+             Tracker.Example.org.\n\
+             0.0.0.0 pixel.example.net metrics.example.net\n\
+             ||banner.example.com^\n\
+             ! Adblock comment\n\
+             ||cdn.example.com^$third-party\n\
+             (\\.|^)doubleclick\\.net$\n\
+             localhost\n\
+             ads.example.com\n",
+        )
+        .unwrap();
+
+        let content = read_pihole_export(&path).unwrap();
+        let (domains, skipped) = parse_pihole_export(&content, &ParseOptions::default());
+        let domains: Vec<&str> = domains.iter().map(|domain| &**domain).collect();
+        assert_eq!(
+            domains,
+            [
+                "ads.example.com",
+                "banner.example.com",
+                "metrics.example.net",
+                "pixel.example.net",
+                "tracker.example.org",
+            ]
+        );
+        // The rule with a modifier and the regex
+        assert_eq!(skipped, 2);
+
+        let database = temp_dir.path().join("gravity.db");
+        fs::write(&database, [SQLITE_MAGIC, &[0u8; 84]].concat()).unwrap();
+        let error = read_pihole_export(&database).unwrap_err().to_string();
+        assert!(error.contains("SELECT domain FROM gravity"), "{}", error);
+    }
+
+    fn sink_of(parsed: &ParsedHosts, hostname: &str) -> IpAddr {
+        parsed.sinks[hostname]
+    }
+
+    #[test]
+    fn test_source_contributions() {
+        let urls: Vec<String> = [
+            "https://a.example/hosts",
+            "https://b.example/hosts",
+            "https://c.example/hosts",
+        ]
+        .iter()
+        .map(|url| url.to_string())
+        .collect();
+        let mut origins = OriginMap::new();
+        let mut tag = |hostname: &str, tags: &[&str]| {
+            origins.insert(
+                hostname.into(),
+                tags.iter().map(|tag| Arc::from(*tag)).collect(),
+            );
+        };
+        tag("shared.example.com", &[&urls[0], &urls[1]]);
+        tag("a-only.example.com", &[&urls[0]]);
+        tag("b-only.example.com", &[&urls[1]]);
+        tag("b-too.example.com", &[&urls[1]]);
+        tag("everywhere.example.com", &[&urls[0], &urls[1], &urls[2]]);
+        let blocking: BTreeSet<Arc<str>> = origins.keys().cloned().collect();
+
+        assert_eq!(
+            source_contributions(&urls, &blocking, &origins),
+            [
+                SourceContribution {
+                    total: 3,
+                    unique: 1
+                },
+                SourceContribution {
+                    total: 4,
+                    unique: 2
+                },
+                SourceContribution {
+                    total: 1,
+                    unique: 0
+                },
+            ]
+        );
+        assert!(source_contributions(&[], &blocking, &origins).is_empty());
+
+        // Unblocked domains don't count, and hand-added ones are nobody's unique
+        let mut blocking = blocking;
+        blocking.remove("b-too.example.com");
+        origins
+            .get_mut("a-only.example.com")
+            .unwrap()
+            .insert(MANUAL_ORIGIN.into());
+        // Only the compared lists count as others
+        assert_eq!(
+            source_contributions(&urls[1..], &blocking, &origins),
+            [
+                SourceContribution {
+                    total: 3,
+                    unique: 2
+                },
+                SourceContribution {
+                    total: 1,
+                    unique: 0
+                },
+            ]
+        );
+        assert_eq!(
+            source_contributions(&urls[..1], &blocking, &origins)[0],
+            SourceContribution {
+                total: 3,
+                unique: 2
+            }
+        );
+    }
+
+    #[test]
+    fn test_fetch_sources_isolates_failures() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let urls: Vec<String> = ["ok-1", "fails", "slow", "ok-2", "ok-3", "ok-4"]
+            .iter()
+            .map(|url| url.to_string())
+            .collect();
+        let running = Arc::new(AtomicUsize::new(0));
+        let peak = Arc::new(AtomicUsize::new(0));
+
+        let results = tauri::async_runtime::block_on(fetch_sources_with(
+            &urls,
+            2,
+            Duration::from_millis(200),
+            |url| {
+                let running = running.clone();
+                let peak = peak.clone();
+                async move {
+                    let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+                    peak.fetch_max(now, Ordering::SeqCst);
+                    let delay = if url == "slow" { 10_000 } else { 20 };
+                    tokio::time::sleep(Duration::from_millis(delay)).await;
+                    running.fetch_sub(1, Ordering::SeqCst);
+                    match url.as_str() {
+                        "fails" => Err(anyhow::anyhow!("HTTP 404")),
+                        _ => Ok(format!("0.0.0.0 {}.example.com\n", url)),
+                    }
+                }
+            },
+        ));
+
+        assert_eq!(results.len(), urls.len());
+        assert_eq!(results[0].as_ref().unwrap(), "0.0.0.0 ok-1.example.com\n");
+        assert_eq!(results[1].as_ref().unwrap_err().to_string(), "HTTP 404");
+        assert!(results[2]
+            .as_ref()
+            .unwrap_err()
+            .to_string()
+            .starts_with("Timed out"));
+        assert!(results[3..].iter().all(|result| result.is_ok()));
+        assert_eq!(peak.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn test_keep_first() {
+        let mut parsed = parse_hosts(CONFLICTING).unwrap();
+        let report = ImportPolicy::KeepFirst.apply(&mut parsed).unwrap();
+
+        assert_eq!(report.conflicts.len(), 1);
+        assert_eq!(
+            sink_of(&parsed, "ads.example.com"),
+            "127.0.0.1".parse::<IpAddr>().unwrap()
+        );
+        assert_eq!(parsed.blocking.len(), 1);
+    }
+
+    #[test]
+    fn test_keep_last() {
+        let mut parsed = parse_hosts(CONFLICTING).unwrap();
+        let report = ImportPolicy::KeepLast.apply(&mut parsed).unwrap();
+
+        assert_eq!(report.conflicts.len(), 1);
+        assert_eq!(
+            sink_of(&parsed, "ads.example.com"),
+            "0.0.0.0".parse::<IpAddr>().unwrap()
+        );
+        let (_, kept) = report.resolutions().next().unwrap();
+        assert_eq!(kept, "0.0.0.0".parse::<IpAddr>().unwrap());
+    }
+
+    #[test]
+    fn test_error_on_conflict() {
+        let mut parsed = parse_hosts(CONFLICTING).unwrap();
+        let err = ImportPolicy::ErrorOnConflict
+            .apply(&mut parsed)
+            .unwrap_err();
+
+        assert!(err.to_string().contains("ads.example.com"));
+    }
+
+    #[test]
+    fn test_parse_with_progress() {
+        let mut content = String::from("127.0.0.1 localhost\n");
+        for i in 0..999 {
+            content.push_str(&format!("0.0.0.0 ads{}.example.com\n", i));
+        }
+        // Conflicts spanning chunks are still detected
+        content.push_str("127.0.0.1 ads0.example.com");
+
+        let mut reports = Vec::new();
+        let parsed =
+            parse_with_progress(&content, &ParseOptions::default(), |p| reports.push(p)).unwrap();
+        let whole = parse_hosts(&content).unwrap();
+
+        assert_eq!(parsed.blocking, whole.blocking);
+        assert_eq!(parsed.sinks, whole.sinks);
+        assert_eq!(parsed.sink_conflicts.len(), 1);
+        assert_eq!(reports.len(), PROGRESS_STEPS);
+
+        let last = reports.last().unwrap();
+        assert_eq!(last.bytes_processed, content.len());
+        assert_eq!(last.lines_processed, 1001);
+        assert!(reports
+            .windows(2)
+            .all(|pair| pair[0].bytes_processed < pair[1].bytes_processed));
+    }
+
+    #[test]
+    fn test_strip_source_comments() {
+        let content = "# Title: Example list\n# Updated: today\n192.168.1.1 router\n0.0.0.0 ads.example.com # tracker\n";
+        let mut parsed = parse_hosts(content).unwrap();
+        strip_source_comments(&mut parsed, "example.txt");
+
+        let comments: Vec<_> = parsed
+            .preserved_lines
+            .iter()
+            .filter_map(|line| match line {
+                PreservedLine::Comment(text) => Some(text.clone()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(comments.len(), 1);
+        assert!(comments[0].starts_with("# Imported from example.txt"));
+        assert!(parsed
+            .preserved_lines
+            .iter()
+            .any(|line| matches!(line, PreservedLine::NonLocalhostEntry(_))));
+        assert_eq!(parsed.blocking.len(), 1);
+    }
+
+    #[test]
+    fn test_sources_with_conflicting_sinks() {
+        let sources = || {
+            let mut first = parse_hosts("0.0.0.0 ads.example.com first.example.com\n").unwrap();
+            override_sink(&mut first, parse_sink("0.0.0.0").unwrap());
+            let mut second = parse_hosts("0.0.0.0 ads.example.com second.example.com\n").unwrap();
+            override_sink(&mut second, parse_sink("127.0.0.1").unwrap());
+            let mut merged = ParsedHosts::default();
+            merged.merge_source(first);
+            merged.merge_source(second);
+            merged
+        };
+        let ads: Arc<str> = "ads.example.com".into();
+
+        let mut merged = sources();
+        assert_eq!(merged.blocking.len(), 3);
+        let report = ImportPolicy::KeepFirst.apply(&mut merged).unwrap();
+        assert_eq!(report.conflicts.len(), 1);
+        assert_eq!(report.conflicts[0].hostname, ads);
+        assert_eq!(merged.sinks[&ads], parse_sink("0.0.0.0").unwrap());
+
+        let mut merged = sources();
+        ImportPolicy::KeepLast.apply(&mut merged).unwrap();
+        assert_eq!(merged.sinks[&ads], parse_sink("127.0.0.1").unwrap());
+
+        assert!(ImportPolicy::ErrorOnConflict.apply(&mut sources()).is_err());
+    }
+
+    #[test]
+    fn test_sink_per_source() {
+        let state = crate::state::AppState::new(crate::config::Config::default());
+
+        let mut malware = parse_hosts("127.0.0.1 malware.example.com\n").unwrap();
+        override_sink(&mut malware, parse_sink("0.0.0.0").unwrap());
+        state.replace_with(malware);
+
+        let mut ads = parse_hosts("0.0.0.0 ads.example.com\n127.0.0.1 ads.example.com\n").unwrap();
+        override_sink(&mut ads, parse_sink("127.0.0.1").unwrap());
+        assert!(ads.sink_conflicts.is_empty());
+        state.merge_blocking(ads);
+
+        let serialized = state.serialize();
+        assert!(serialized.contains("0.0.0.0 malware.example.com\n"));
+        assert!(serialized.contains("127.0.0.1 ads.example.com\n"));
+
+        assert!(parse_sink("192.168.1.1").is_err());
+        assert!(parse_sink("not an ip").is_err());
+
+        // A preserved loopback address, or one outside sink_ips, doesn't block
+        let options = ParseOptions {
+            sink_ips: vec!["0.0.0.0".parse().unwrap()],
+            preserve_ips: vec!["127.0.0.2".parse().unwrap()],
+            ..ParseOptions::default()
+        };
+        assert!(parse_sink_for("0.0.0.0", &options).is_ok());
+        assert!(parse_sink_for("127.0.0.1", &options).is_err());
+        assert!(parse_sink_for("127.0.0.2", &options).is_err());
+        assert!(parse_sink_for("192.168.1.1", &options).is_err());
+        assert!(ImportOptions::from_args(
+            None,
+            None,
+            None,
+            Some("127.0.0.2"),
+            None,
+            None,
+            &options
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_import_conflicts() {
+        let current = parse_hosts(
+            "127.0.0.1 manual.example.com\n127.0.0.1 shared.example.com\n127.0.0.1 kept.example.com\n",
+        )
+        .unwrap();
+        let mut incoming = parse_hosts(
+            "0.0.0.0 shared.example.com\n127.0.0.1 kept.example.com\n127.0.0.1 new.example.com\n",
+        )
+        .unwrap();
+
+        let conflicts = find_import_conflicts(&current.blocking, &current.sinks, &incoming);
+        assert_eq!(conflicts.len(), 2);
+        assert_eq!(&*conflicts[0].hostname, "manual.example.com");
+        assert_eq!(conflicts[0].reason, ConflictReason::WouldRemove);
+        assert_eq!(&*conflicts[1].hostname, "shared.example.com");
+        assert_eq!(conflicts[1].reason, ConflictReason::SinkMismatch);
+
+        let decisions = HashMap::from([
+            (Arc::from("manual.example.com"), Resolution::KeepCurrent),
+            (Arc::from("shared.example.com"), Resolution::KeepCurrent),
+        ]);
+        apply_import_decisions(&mut incoming, &conflicts, &decisions);
+
+        assert!(incoming.blocking.contains("manual.example.com"));
+        assert!(incoming.blocking.contains("new.example.com"));
+        assert_eq!(sink_of(&incoming, "shared.example.com"), DEFAULT_SINK);
+    }
+
+    #[test]
+    fn test_safe_listed_domains_reported() {
+        let content = "0.0.0.0 ads.example.com\n\
+                       0.0.0.0 download.windowsupdate.com\n\
+                       0.0.0.0 www.chase.com\n";
+
+        let mut parsed = parse_hosts(content).unwrap();
+        let safe = check_safe_list(&mut parsed, SafeListPolicy::Warn);
+        assert_eq!(
+            safe,
+            vec![
+                Arc::from("download.windowsupdate.com"),
+                Arc::from("www.chase.com")
+            ]
+        );
+        // Warned about but still imported
+        assert_eq!(parsed.blocking.len(), 3);
+
+        let mut parsed = parse_hosts(content).unwrap();
+        let safe = check_safe_list(&mut parsed, SafeListPolicy::Skip);
+        assert_eq!(safe.len(), 2);
+        assert_eq!(
+            parsed.blocking,
+            BTreeSet::from([Arc::from("ads.example.com")])
+        );
+    }
+
+    #[test]
+    fn test_import_domain_list() {
+        let content = "# My list\n\
+                       ads.example.com\n\
+                       \n\
+                       tracker.example.net # analytics\n\
+                       metrics.example.org\n\
+                       localhost\n\
+                       pixel.example.com\n\
+                       bücher.de\n";
+        assert_eq!(SourceFormat::detect(content), SourceFormat::DomainList);
+
+        let parsed = parse_source(
+            content,
+            SourceFormat::DomainList,
+            &ParseOptions::default(),
+            |_| {},
+        )
+        .unwrap();
+        let blocking: Vec<&str> = parsed.blocking.iter().map(|h| h.as_ref()).collect();
+        assert_eq!(
+            blocking,
+            vec![
+                "ads.example.com",
+                "metrics.example.org",
+                "pixel.example.com",
+                "tracker.example.net",
+                "xn--bcher-kva.de",
+            ]
+        );
+        assert!(parsed.preserved_lines.is_empty());
+        assert!(parsed.sinks.is_empty());
+    }
+
+    #[test]
+    fn test_hosts_content_uses_hosts_parser() {
+        let content = "# Header\n127.0.0.1 localhost\n0.0.0.0 ads.example.com\n";
+        assert_eq!(SourceFormat::detect(content), SourceFormat::Hosts);
+        assert_eq!(SourceFormat::detect(""), SourceFormat::Hosts);
+
+        let parsed = parse_source(
+            content,
+            SourceFormat::Hosts,
+            &ParseOptions::default(),
+            |_| {},
+        )
+        .unwrap();
+        assert_eq!(parsed.blocking.len(), 1);
+        assert!(parsed.blocking.contains("ads.example.com"));
+        assert_eq!(parsed.preserved_lines.len(), 2);
+    }
+
+    #[test]
+    fn test_domain_filter_excludes_during_parse() {
+        let mut content = String::new();
+        for i in 0..100 {
+            content.push_str(&format!("0.0.0.0 ads{}.example.com\n", i));
+        }
+        content.push_str("0.0.0.0 reddit.com\n0.0.0.0 old.Reddit.com\n0.0.0.0 notreddit.com\n");
+        content.push_str("0.0.0.0 daily-fakenews.example.org\n");
+
+        let options = ParseOptions {
+            exclude: DomainFilter::new(["reddit.com", "*fakenews*"]),
+            ..ParseOptions::default()
+        };
+        let parsed = parse_with_progress(&content, &options, |_| {}).unwrap();
+
+        assert_eq!(parsed.blocking.len(), 101);
+        assert!(parsed.blocking.contains("notreddit.com"));
+        assert!(!parsed.blocking.contains("reddit.com"));
+        assert!(!parsed.blocking.contains("old.Reddit.com"));
+        assert!(!parsed.sinks.contains_key("reddit.com"));
+        assert!(!parsed.blocking.contains("daily-fakenews.example.org"));
+    }
+
+    #[test]
+    fn test_parse_source_metadata() {
+        let content = "\
+# Title: StevenBlack/hosts
+#
+# This hosts file is a merged collection of hosts from reputable sources
+# Date: 14 October 2026 21:09:12 (UTC)
+# Number of unique domains: 81,442
+# Number of entries: 81,451
+# Homepage: https://github.com/StevenBlack/hosts
+# Title: Not the title
+
+127.0.0.1 localhost
+# Homepage: https://example.com
+0.0.0.0 ads.example.com
+";
+        let metadata = parse_source_metadata(content);
+        assert_eq!(metadata.title.as_deref(), Some("StevenBlack/hosts"));
+        assert_eq!(
+            metadata.homepage.as_deref(),
+            Some("https://github.com/StevenBlack/hosts")
+        );
+        assert_eq!(
+            metadata.last_updated.as_deref(),
+            Some("14 October 2026 21:09:12 (UTC)")
+        );
+        assert_eq!(metadata.declared_entries, Some(81451));
+
+        let adblock =
+            "! Title: EasyList\n! Last modified: 15 Oct 2026 08:00 UTC\nads.example.com\n";
+        let metadata = parse_source_metadata(adblock);
+        assert_eq!(metadata.title.as_deref(), Some("EasyList"));
+        assert_eq!(
+            metadata.last_updated.as_deref(),
+            Some("15 Oct 2026 08:00 UTC")
+        );
+        assert_eq!(metadata.homepage, None);
+
+        assert_eq!(
+            parse_source_metadata("0.0.0.0 ads.example.com\n"),
+            SourceMetadata::default()
+        );
+    }
+
+    #[test]
+    fn test_read_source_file_size_cap() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let path = temp_dir.path().join("list.txt");
+        fs::write(&path, "0.0.0.0 ads.example.com\n").unwrap();
+        assert!(read_source_file(&path).is_ok());
+
+        let file = fs::File::create(&path).unwrap();
+        file.set_len(MAX_SOURCE_BYTES + 1).unwrap();
+        assert!(read_source_file(&path).is_err());
+    }
+
+    #[test]
+    fn test_https_sources_are_supported() {
+        // A client without TLS refuses https URLs before connecting; one with
+        // TLS connects and opens with a handshake record (0x16)
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("https://{}/list.txt", listener.local_addr().unwrap());
+        let (sent, received) = std::sync::mpsc::channel();
+        std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut first = [0u8; 1];
+            std::io::Read::read_exact(&mut stream, &mut first).unwrap();
+            sent.send(first[0]).unwrap();
+        });
+
+        assert!(tauri::async_runtime::block_on(fetch_source(&url)).is_err());
+        let first = received.recv_timeout(std::time::Duration::from_secs(5));
+        assert_eq!(first, Ok(0x16));
+    }
+
+    #[test]
+    fn test_policy_names() {
+        for policy in [
+            ImportPolicy::KeepFirst,
+            ImportPolicy::KeepLast,
+            ImportPolicy::ErrorOnConflict,
+        ] {
+            assert_eq!(ImportPolicy::from_str(policy.to_str()).unwrap(), policy);
+        }
+        assert!(ImportPolicy::from_str("merge").is_err());
+    }
+}
@@ -1,26 +1,58 @@
+mod audit;
+mod backup;
+mod blockcheck;
+mod bloom;
+mod categories;
 mod commands;
 mod commit;
 mod config;
+mod diagnostics;
 mod history;
+mod import;
+mod logging;
 mod parser;
 mod platform;
+mod profiles;
+mod safelist;
+mod schedule;
+mod sources;
+mod stale;
+mod starter;
 mod state;
 mod utils;
 mod watcher;
 
+use crate::audit::AuditLog;
+use crate::commands::{recovery_json, spawn_pause_expiry, spawn_scheduled_commit};
+use crate::commit::find_recovery_candidate;
 use crate::config::Config;
 use crate::platform::default_hosts_file_path;
+use crate::profiles::{profile_db_path, PROFILES_DIR};
+use crate::starter::check_first_run;
 use crate::state::AppState;
-use crate::watcher::start_watcher;
+use crate::watcher::{start_config_watcher, start_watcher};
 use std::path::PathBuf;
 use std::sync::Arc;
-use tauri::Manager;
+use tauri::{Emitter, Manager};
 
 #[cfg_attr(mobile, tauri::mobile_entry_point)]
 pub fn run() {
     tauri::Builder::default()
         .plugin(tauri_plugin_opener::init())
         .setup(|app| {
+            // Get app data directory for logs, history and the state database
+            let data_dir = app.path().app_data_dir().unwrap_or_else(|_| {
+                // Fallback to app_config_dir if app_data_dir fails
+                app.path().app_config_dir().unwrap_or_else(|_| {
+                    // Final fallback
+                    app.path()
+                        .config_dir()
+                        .unwrap_or_else(|_| PathBuf::from("."))
+                        .join("adguard")
+                })
+            });
+            logging::init(&data_dir.join("logs"));
+
             // Get config file path using Tauri PathResolver
             let config_path = app
                 .path()
@@ -41,57 +73,155 @@ pub fn run() {
                 // Try to save it, but don't fail if we can't
                 let _ = default_config
                     .save_to_file(&config_path)
-                    .inspect_err(|e| eprintln!("Failed to save config: {}", e));
+                    .inspect_err(|e| tracing::warn!("Failed to save config: {}", e));
 
                 default_config
             });
 
+            // A hand-edited config can't be refused at startup like on reload,
+            // so sink settings that wouldn't block fall back to the defaults
+            if let Err(e) = config.validate() {
+                tracing::warn!("Invalid config, using the default sinks: {}", e);
+                config.reset_sinks();
+            }
+
             // Set default history directory if not configured
             if config.history_dir.is_none() {
-                let default_history = app
-                    .path()
-                    .app_data_dir()
-                    .unwrap_or_else(|_| {
-                        // Fallback to app_config_dir if app_data_dir fails
-                        app.path().app_config_dir().unwrap_or_else(|_| {
-                            // Final fallback
-                            app.path()
-                                .config_dir()
-                                .unwrap_or_else(|_| PathBuf::from("."))
-                                .join("adguard")
-                        })
-                    })
-                    .join("history");
+                let default_history = data_dir.join("history");
                 config.history_dir = Some(default_history.as_path().into());
                 // Try to save updated config, but don't fail if we can't
                 let _ = config
                     .save_to_file(&config_path)
-                    .inspect_err(|e| eprintln!("Failed to save config: {}", e));
+                    .inspect_err(|e| tracing::warn!("Failed to save config: {}", e));
             }
 
             // Create app state
             let app_state = Arc::new(AppState::new(config.clone()));
 
+            // The starter list is only offered; seeding waits for the user
+            match check_first_run(&data_dir) {
+                Ok(first_run) => *app_state.first_run.lock() = first_run,
+                Err(e) => tracing::warn!("Failed to record first run: {}", e),
+            }
+
             // Get hosts file path
             let hosts_file_path = config
                 .host_file_path
                 .clone()
                 .unwrap_or_else(default_hosts_file_path);
 
+            // A crash between writing the temp file and renaming it can leave the
+            // only good copy in the temp file
+            match find_recovery_candidate(&hosts_file_path) {
+                Ok(Some(candidate)) => {
+                    app_state.log_error(
+                        "startup",
+                        format!(
+                            "{}; recovery available from {}",
+                            candidate.reason,
+                            candidate.temp_path.display()
+                        ),
+                    );
+                    let _ = app.emit("recovery-available", recovery_json(&candidate));
+                }
+                Ok(None) => {}
+                Err(e) => app_state.log_error(
+                    "startup",
+                    format!("Failed to check for a leftover temp file: {}", e),
+                ),
+            }
+
             // Load initial state from hosts file
             if hosts_file_path.exists() {
                 if let Err(e) = app_state.load_from_file(&hosts_file_path) {
-                    eprintln!("Failed to load hosts file: {}", e);
+                    app_state.log_error("startup", format!("Failed to load hosts file: {}", e));
                 }
             }
 
+            // The state database is the source of truth for blocked domains;
+            // the hosts file is only a render target once the database exists
+            // Each profile other than the original setup keeps its own database
+            let db_path = match (&config.active_profile, config_path.parent()) {
+                (Some(profile), Some(config_dir)) => {
+                    profile_db_path(&config_dir.join(PROFILES_DIR), profile)
+                }
+                _ => data_dir.join("db.json"),
+            };
+            if db_path.exists() {
+                if let Err(e) = app_state.load_db(&db_path) {
+                    app_state.log_error("startup", format!("Failed to load state database: {}", e));
+                }
+            } else if let Err(e) = app_state.save_db(&db_path) {
+                // First run with a database: seed it from the hosts file
+                app_state.log_error("startup", format!("Failed to create state database: {}", e));
+            }
+            app_state.set_db_path(db_path.as_path().into());
+
+            // A pause from before a restart still ends on time, right away if it's overdue
+            match app_state.reinstate_pause() {
+                Ok(Some(until)) => spawn_pause_expiry(app_state.clone(), until),
+                Ok(None) => {}
+                Err(e) => {
+                    app_state.log_error("startup", format!("Failed to reinstate the pause: {}", e))
+                }
+            }
+
+            // A commit scheduled before a restart still runs, right away if it's overdue
+            // Otherwise a hosts file that disagrees with the database is left as it is;
+            // the difference becomes unsaved changes for the user to review and save
+            match app_state.reinstate_scheduled_commit() {
+                Ok(Some(at)) => spawn_scheduled_commit(app.handle().clone(), app_state.clone(), at),
+                Ok(None) => match app_state.reconcile_with_hosts_file() {
+                    Ok(true) => {
+                        let (added, removed) = app_state.pending_counts();
+                        let _ = app.emit(
+                            "hosts-out-of-date",
+                            serde_json::json!({ "added": added, "removed": removed }),
+                        );
+                    }
+                    Ok(false) => {}
+                    Err(e) => app_state.log_error(
+                        "startup",
+                        format!("Failed to compare the hosts file with the database: {}", e),
+                    ),
+                },
+                Err(e) => app_state.log_error(
+                    "startup",
+                    format!("Failed to reinstate the scheduled commit: {}", e),
+                ),
+            }
+
+            // Seed an empty allowlist from an existing allow file
+            if let Some(allow_path) = config.allow_file_path.as_ref().filter(|p| p.exists()) {
+                if app_state.allowlist.lock().is_empty() {
+                    if let Err(e) = app_state.load_allowlist(allow_path) {
+                        app_state.log_error("startup", format!("Failed to load allow file: {}", e));
+                    }
+                }
+            }
+
+            // Mutating operations are logged next to the config file
+            if let Some(config_dir) = config_path.parent() {
+                app_state.set_audit_log(AuditLog::new(config_dir.join("audit.log")));
+            }
+
             // Start file watcher
             if let Err(e) = start_watcher(
                 app.handle().clone(),
                 hosts_file_path.clone(),
                 app_state.clone(),
             ) {
-                eprintln!("Failed to start file watcher: {}", e);
+                app_state.log_error("watcher", format!("Failed to start file watcher: {}", e));
+            }
+
+            // Reload the config when it's edited outside the app
+            app_state.set_config_path(config_path.as_path().into());
+            if let Err(e) = start_config_watcher(
+                app.handle().clone(),
+                config_path.as_path().into(),
+                app_state.clone(),
+            ) {
+                app_state.log_error("watcher", format!("Failed to watch config file: {}", e));
             }
 
             // Register state with Tauri
@@ -101,19 +231,101 @@ pub fn run() {
         })
         .invoke_handler(tauri::generate_handler![
             commands::get_blocked_domains,
+            commands::get_blocked_domains_detailed,
+            commands::export_added_since,
             commands::add_domain,
+            commands::get_allowed_domains,
+            commands::add_allow,
+            commands::remove_allow,
+            commands::export_allow,
             commands::remove_domain,
+            commands::enable_domain,
+            commands::get_disabled_domains,
+            commands::add_from_text,
+            commands::remove_base_domain,
+            commands::block_base_domain,
+            commands::apply_set_operation,
+            commands::is_first_run,
+            commands::seed_starter_list,
+            commands::explain_domain,
+            commands::request_clear_token,
+            commands::clear_all_blocks,
             commands::save_changes,
+            commands::pause_blocking,
+            commands::resume_blocking,
+            commands::get_pause_status,
+            commands::schedule_commit,
+            commands::cancel_scheduled_commit,
+            commands::pending_changes,
+            commands::verify_sync,
+            commands::get_pending_counts,
+            commands::get_last_commit_timings,
+            commands::benchmark_commit,
             commands::get_history_list,
             commands::rollback_to,
+            commands::rollback_blocking_only,
+            commands::rollback_merge,
+            commands::merge_file_preview,
+            commands::merge_file_apply,
+            commands::diff_files,
+            commands::get_recovery_candidate,
+            commands::recover_hosts_file,
             commands::delete_history_files,
+            commands::get_trash_list,
+            commands::restore_from_trash,
+            commands::empty_trash,
             commands::get_config,
             commands::update_config,
             commands::get_host_file_path,
+            commands::get_hosts_file_info,
+            commands::optimize,
+            commands::normalize_layout,
+            commands::dedupe_state,
             commands::get_statistics,
+            commands::validate_all_domains,
+            commands::blocklist_fingerprint,
+            commands::is_blocked,
+            commands::get_domain_first_seen,
+            commands::query_domains_regex,
+            commands::suggest_related,
+            commands::categorize_domain,
+            commands::get_category_breakdown,
             commands::check_admin_privileges,
+            commands::get_system_theme,
+            commands::get_dns_flush_capability,
+            commands::diagnostics,
+            commands::find_stale_domains,
+            commands::check_blocking_batch,
+            commands::parser_capabilities,
+            commands::classify_lines,
+            commands::get_recent_errors,
+            commands::get_audit_log,
+            commands::get_preserved_lines,
+            commands::get_network_entries,
+            commands::update_preserved_line,
+            commands::delete_preserved_line,
             commands::export_hosts,
+            commands::export_hosts_to_path,
+            commands::split_export,
+            commands::export_managed_only,
             commands::import_hosts,
+            commands::import_hosts_from_path,
+            commands::import_pihole,
+            commands::import_dry_run,
+            commands::import_hosts_from_urls,
+            commands::source_contribution_report,
+            commands::import_sources_bundle,
+            commands::export_sources_bundle,
+            commands::inspect_source,
+            commands::apply_import_decisions,
+            commands::list_profiles,
+            commands::create_profile,
+            commands::switch_profile,
+            commands::pause_watcher,
+            commands::resume_watcher,
+            commands::watcher_status,
+            commands::export_full_backup,
+            commands::import_full_backup,
         ])
         .run(tauri::generate_context!())
         .expect("error while running tauri application");
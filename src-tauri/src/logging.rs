@@ -0,0 +1,123 @@
+use std::path::Path;
+use tracing::Level;
+use tracing_appender::rolling::{RollingFileAppender, Rotation};
+use tracing_subscriber::filter::Targets;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::{fmt, Registry};
+
+/// Daily log files kept before the oldest is deleted
+pub const LOG_FILES_KEPT: usize = 7;
+
+/// Environment variable overriding the log level (error, warn, info, debug, trace)
+pub const LOG_LEVEL_ENV: &str = "ADGUARD_LOG";
+
+/// Level used when `ADGUARD_LOG` is unset or invalid
+pub const DEFAULT_LOG_LEVEL: Level = Level::INFO;
+
+/// Level from `ADGUARD_LOG`, falling back to `DEFAULT_LOG_LEVEL`
+#[inline]
+fn level_from_env() -> Level {
+    std::env::var(LOG_LEVEL_ENV)
+        .ok()
+        .and_then(|level| level.trim().parse().ok())
+        .unwrap_or(DEFAULT_LOG_LEVEL)
+}
+
+/// Only this crate's own logs, not those of dependencies
+#[inline]
+fn own_logs(max_level: Level) -> Targets {
+    Targets::new().with_target("adguard", max_level)
+}
+
+/// Log file in `log_dir` rotated daily to `adguard.<date>.log`
+fn log_file(log_dir: &Path) -> Result<RollingFileAppender, anyhow::Error> {
+    Ok(RollingFileAppender::builder()
+        .rotation(Rotation::DAILY)
+        .filename_prefix("adguard")
+        .filename_suffix("log")
+        .max_log_files(LOG_FILES_KEPT)
+        .build(log_dir)?)
+}
+
+/// Install the global logger, writing to stderr and `<log_dir>/adguard.<date>.log`
+/// Lines are `timestamp LEVEL span{fields}: target: message key=value`.
+/// If the log file can't be opened, logs still go to stderr
+pub fn init(log_dir: &Path) {
+    let max_level = level_from_env();
+    let (file, open_error) = match log_file(log_dir) {
+        Ok(file) => (Some(file), None),
+        Err(e) => (None, Some(e)),
+    };
+    let subscriber = Registry::default()
+        .with(fmt::layer().with_writer(std::io::stderr))
+        .with(file.map(|file| fmt::layer().with_ansi(false).with_writer(file)))
+        .with(own_logs(max_level));
+    if tracing::subscriber::set_global_default(subscriber).is_err() {
+        return;
+    }
+    match open_error {
+        None => tracing::info!(log_dir = %log_dir.display(), "Logging started"),
+        Some(e) => tracing::warn!("Failed to open log file in {}: {}", log_dir.display(), e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use tempfile::TempDir;
+    use tracing_subscriber::Layer;
+
+    fn file_logger(log_dir: &Path, max_level: Level) -> impl tracing::Subscriber {
+        let file = tracing_appender::rolling::never(log_dir, "adguard.log");
+        Registry::default().with(
+            fmt::layer()
+                .with_ansi(false)
+                .with_writer(file)
+                .with_filter(own_logs(max_level)),
+        )
+    }
+
+    #[test]
+    fn test_event_format_includes_spans_and_fields() {
+        let temp_dir = TempDir::new().unwrap();
+
+        tracing::subscriber::with_default(file_logger(temp_dir.path(), Level::INFO), || {
+            let span = tracing::info_span!("commit", domains = 3);
+            let _entered = span.enter();
+            tracing::warn!(path = "/etc/hosts", "Write failed");
+            tracing::debug!("Filtered out");
+            tracing::warn!(target: "hyper", "Not ours");
+        });
+
+        let content = fs::read_to_string(temp_dir.path().join("adguard.log")).unwrap();
+        let lines: Vec<&str> = content.lines().collect();
+        assert_eq!(lines.len(), 1);
+        assert!(lines[0].contains(" WARN commit{domains=3}: adguard"));
+        assert!(lines[0].ends_with(": Write failed path=\"/etc/hosts\""));
+    }
+
+    #[test]
+    fn test_log_file_is_created_in_log_dir() {
+        let temp_dir = TempDir::new().unwrap();
+        let log_dir = temp_dir.path().join("logs");
+
+        let file = log_file(&log_dir).unwrap();
+        let subscriber = Registry::default().with(
+            fmt::layer()
+                .with_ansi(false)
+                .with_writer(file)
+                .with_filter(own_logs(Level::INFO)),
+        );
+        tracing::subscriber::with_default(subscriber, || tracing::info!("Entry"));
+
+        let names: Vec<String> = fs::read_dir(&log_dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        assert_eq!(names.len(), 1);
+        assert!(names[0].starts_with("adguard.") && names[0].ends_with(".log"));
+        let content = fs::read_to_string(log_dir.join(&names[0])).unwrap();
+        assert!(content.contains("Entry"));
+    }
+}
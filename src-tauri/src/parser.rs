@@ -1,8 +1,13 @@
-use crate::utils::{is_local_domain, is_localhost_ip};
+use crate::utils::{
+    canonical_hostname, is_local_domain, is_local_domain_with, is_localhost_ip, is_localhost_name,
+    listed_domain, to_ascii_domain,
+};
 use pest::Parser;
 use pest_derive::Parser;
-use std::collections::{BTreeSet, HashMap};
-use std::net::IpAddr;
+use std::borrow::Cow;
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::io::{self, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
 use std::str::FromStr;
 use std::sync::Arc;
 
@@ -10,21 +15,693 @@ use std::sync::Arc;
 #[grammar = "grammar/hosts.pest"]
 pub struct HostsParser;
 
+/// Marker line opening the region of the hosts file managed by the app
+pub const MANAGED_START: &str = "# === ADGUARD MANAGED START ===";
+/// Marker line closing the managed region
+pub const MANAGED_END: &str = "# === ADGUARD MANAGED END ===";
+
+/// Marker line opening the header block written at the top of the file
+pub const HEADER_START: &str = "# === ADGUARD HEADER START ===";
+/// Marker line closing the header block
+pub const HEADER_END: &str = "# === ADGUARD HEADER END ===";
+
+/// Prefix commenting out managed entries while blocking is paused
+pub const PAUSED_PREFIX: &str = "# paused: ";
+
+/// Prefix of a disabled domain's line in the managed region, e.g.
+/// `# disabled: 127.0.0.1 ads.example.com`
+pub const DISABLED_PREFIX: &str = "# disabled: ";
+
+/// Marker written before disabled entries unless another is configured
+pub const DEFAULT_DISABLED_MARKER: &str = "# disabled:";
+
+/// Sink IP for blocked domains that weren't listed under a specific one
+pub const DEFAULT_SINK: IpAddr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+
+/// Sink IP each blocked domain is written under
+pub type SinkMap = BTreeMap<Arc<str>, IpAddr>;
+
 #[derive(Debug, Clone)]
 pub enum PreservedLine {
     Comment(Arc<str>),
     NonLocalhostEntry(Arc<str>),
-    LocalhostEntry { ip: IpAddr, hostname: Arc<str> },
+    LocalhostEntry {
+        ip: IpAddr,
+        hostname: Arc<str>,
+    },
+    /// Localhost line kept verbatim to preserve its alignment
+    LocalhostLine(Arc<str>),
+    /// Line outside the managed region, kept verbatim
+    Raw(Arc<str>),
+    /// Line the grammar rejects, kept verbatim so the rest of the file still loads
+    Invalid(Arc<str>),
+    /// Position of the managed region relative to the other preserved lines
+    ManagedRegion,
+}
+
+impl PreservedLine {
+    /// Check whether this line maps the given hostname (comments never do)
+    pub fn mentions(&self, hostname: &str) -> bool {
+        match self {
+            PreservedLine::Comment(_) | PreservedLine::ManagedRegion => false,
+            PreservedLine::NonLocalhostEntry(entry)
+            | PreservedLine::LocalhostLine(entry)
+            | PreservedLine::Raw(entry)
+            | PreservedLine::Invalid(entry) => entry
+                .split('#')
+                .next()
+                .unwrap_or_default()
+                .split_whitespace()
+                .skip(1)
+                .any(|h| h.eq_ignore_ascii_case(hostname)),
+            PreservedLine::LocalhostEntry { hostname: h, .. } => h.eq_ignore_ascii_case(hostname),
+        }
+    }
+
+    /// Short name of the line's kind, for display
+    pub fn kind(&self) -> &'static str {
+        match self {
+            PreservedLine::Comment(_) => "comment",
+            PreservedLine::NonLocalhostEntry(_) => "entry",
+            PreservedLine::LocalhostEntry { .. } | PreservedLine::LocalhostLine(_) => "localhost",
+            PreservedLine::Raw(_) => "raw",
+            PreservedLine::Invalid(_) => "invalid",
+            PreservedLine::ManagedRegion => "managed",
+        }
+    }
+
+    /// Text of the line as written to the hosts file (none for the region placeholder)
+    pub fn content(&self) -> Option<String> {
+        match self {
+            PreservedLine::Comment(text)
+            | PreservedLine::NonLocalhostEntry(text)
+            | PreservedLine::LocalhostLine(text)
+            | PreservedLine::Raw(text)
+            | PreservedLine::Invalid(text) => Some(text.to_string()),
+            PreservedLine::LocalhostEntry { ip, hostname } => Some(format!("{} {}", ip, hostname)),
+            PreservedLine::ManagedRegion => None,
+        }
+    }
+}
+
+/// Separator written between the IP and hostnames of managed lines
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Separator {
+    #[default]
+    Space,
+    Tab,
+}
+
+impl Separator {
+    #[inline]
+    pub fn from_str(s: &str) -> Self {
+        match s.trim().to_lowercase().as_str() {
+            "tab" => Separator::Tab,
+            _ => Separator::Space, // Default to space
+        }
+    }
+
+    #[inline]
+    pub fn to_str(self) -> &'static str {
+        match self {
+            Separator::Space => "space",
+            Separator::Tab => "tab",
+        }
+    }
+
+    #[inline]
+    pub fn as_char(self) -> char {
+        match self {
+            Separator::Space => ' ',
+            Separator::Tab => '\t',
+        }
+    }
 }
 
-#[derive(Debug)]
+/// Options controlling how hosts file content is read
+#[derive(Debug, Clone, Default)]
+pub struct ParseOptions {
+    /// Keep localhost lines verbatim instead of regrouping them by IP
+    pub preserve_whitespace: bool,
+    /// Blocked domains dropped while parsing
+    pub exclude: DomainFilter,
+    /// Treat single-label names under a sink as blocked rather than local
+    pub block_single_label: bool,
+    /// Loopback addresses whose entries are blocked; empty means all of them
+    pub sink_ips: Vec<IpAddr>,
+    /// Loopback addresses whose entries are kept as written, never blocked
+    pub preserve_ips: Vec<IpAddr>,
+    /// Marker of disabled entries besides the default one, if configured
+    pub disabled_marker: Option<Arc<str>>,
+}
+
+impl ParseOptions {
+    /// Whether a hostname is local and never blocked under these options
+    #[inline]
+    pub fn is_local(&self, hostname: &str) -> bool {
+        is_local_domain_with(hostname, self.block_single_label)
+    }
+
+    /// Whether entries under `ip` are blocked domains under these options
+    #[inline]
+    pub fn is_sink(&self, ip: &IpAddr) -> bool {
+        is_localhost_ip(ip)
+            && !self.preserve_ips.contains(ip)
+            && (self.sink_ips.is_empty() || self.sink_ips.contains(ip))
+    }
+}
+
+/// Patterns excluding domains from a parse
+///
+/// A pattern matches that domain and its subdomains ("reddit.com" matches
+/// "old.reddit.com" but not "notreddit.com"); a pattern wrapped in `*`
+/// matches anywhere in the name ("*fakenews*").
+#[derive(Debug, Clone, Default)]
+pub struct DomainFilter {
+    suffixes: Vec<Arc<str>>,
+    substrings: Vec<Arc<str>>,
+}
+
+impl DomainFilter {
+    pub fn new<S: AsRef<str>>(patterns: impl IntoIterator<Item = S>) -> Self {
+        let mut filter = DomainFilter::default();
+        for pattern in patterns {
+            let pattern = pattern.as_ref().trim().to_ascii_lowercase();
+            match pattern.strip_prefix('*').and_then(|p| p.strip_suffix('*')) {
+                Some(substring) if !substring.is_empty() => {
+                    filter.substrings.push(substring.into())
+                }
+                Some(_) => {}
+                None => {
+                    let suffix = pattern.trim_matches('.');
+                    if !suffix.is_empty() {
+                        filter.suffixes.push(suffix.into());
+                    }
+                }
+            }
+        }
+        filter
+    }
+
+    /// Whether a (lowercase) hostname is excluded
+    pub fn excludes(&self, hostname: &str) -> bool {
+        self.suffixes.iter().any(|suffix| {
+            hostname
+                .strip_suffix(&**suffix)
+                .is_some_and(|rest| rest.is_empty() || rest.ends_with('.'))
+        }) || self
+            .substrings
+            .iter()
+            .any(|substring| hostname.contains(&**substring))
+    }
+}
+
+/// Options controlling how hosts file content is written
+#[derive(Debug, Clone, Default)]
+pub struct SerializeOptions {
+    /// Pair the IPv4 blocking line with an IPv6 loopback line
+    pub block_ipv6: bool,
+    /// Separator used in managed lines
+    pub separator: Separator,
+    /// Comment out the blocking entries (localhost lines are still written)
+    pub paused: bool,
+    /// Header block written first, between the header markers
+    pub header: Option<Arc<str>>,
+    /// End the last line without a newline; otherwise the content ends with
+    /// exactly one
+    pub omit_trailing_newline: bool,
+    /// Marker written before disabled entries instead of the default one
+    pub disabled_marker: Option<Arc<str>>,
+}
+
+impl SerializeOptions {
+    /// Start of a disabled entry's line, the marker followed by a space
+    #[inline]
+    fn disabled_prefix(&self) -> Cow<'static, str> {
+        match &self.disabled_marker {
+            Some(marker) => Cow::Owned(format!("{} ", marker)),
+            None => Cow::Borrowed(DISABLED_PREFIX),
+        }
+    }
+}
+
+/// Whether a disabled entry marker can be read back: a single-line comment
+/// that can't be mistaken for a paused entry
+pub fn is_valid_disabled_marker(marker: &str) -> bool {
+    marker.starts_with('#')
+        && marker.trim_end() == marker
+        && !marker.contains(['\n', '\r'])
+        && !format!("{} ", marker).starts_with(PAUSED_PREFIX)
+}
+
+/// Hosts file features supported by the parser, for integrators gating UI
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ParserCapabilities {
+    /// IPv6 addresses, including compressed forms like `::1`
+    pub ipv6: bool,
+    /// Several hostnames on one line
+    pub multiple_hostnames: bool,
+    /// A `#` comment after an entry (accepted; only annotations are kept)
+    pub inline_comments: bool,
+    /// CRLF and CR line endings
+    pub crlf_line_endings: bool,
+    /// Managed region markers around the lines the app owns
+    pub managed_region: bool,
+    /// Internationalized names, stored as punycode
+    pub internationalized_names: bool,
+    /// Each blocked domain keeps the sink it was listed under
+    pub per_domain_sinks: bool,
+    /// Verbatim localhost lines (`preserve_whitespace`)
+    pub preserve_whitespace: bool,
+    /// "added on" annotations on entries added in the app
+    pub addition_annotations: bool,
+    /// Wildcard entries such as `*.example.com`
+    pub wildcards: bool,
+    /// Adblock filter syntax such as `||example.com^`
+    pub adblock_syntax: bool,
+}
+
+/// Features of the hosts file format this parser supports
+pub const PARSER_CAPABILITIES: ParserCapabilities = ParserCapabilities {
+    ipv6: true,
+    multiple_hostnames: true,
+    inline_comments: true,
+    crlf_line_endings: true,
+    managed_region: true,
+    internationalized_names: true,
+    per_domain_sinks: true,
+    preserve_whitespace: true,
+    addition_annotations: true,
+    wildcards: false,
+    adblock_syntax: false,
+};
+
+/// A domain listed under two different sinks of the same address family
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SinkConflict {
+    pub hostname: Arc<str>,
+    /// Sink from the first listing (the one kept while parsing)
+    pub existing: IpAddr,
+    /// Sink from the later, conflicting listing
+    pub incoming: IpAddr,
+}
+
+/// Date each annotated domain was added through the app, keyed by hostname
+pub type AnnotationMap = BTreeMap<Arc<str>, Arc<str>>;
+
+/// Inline comment written after an annotated entry
+#[inline]
+pub fn annotation_comment(added_on: &str) -> String {
+    format!("# added {} via adguard", added_on)
+}
+
+/// Date from an inline comment written by `annotation_comment`
+#[inline]
+fn parse_annotation(comment: &str) -> Option<&str> {
+    comment
+        .trim()
+        .strip_prefix("# added ")?
+        .strip_suffix(" via adguard")
+}
+
+#[derive(Debug, Default)]
 pub struct ParsedHosts {
     pub blocking: BTreeSet<Arc<str>>,
     pub preserved_lines: Vec<PreservedLine>,
+    pub sinks: SinkMap,
+    pub sink_conflicts: Vec<SinkConflict>,
+    /// Added-on dates read back from annotated entries
+    pub annotations: AnnotationMap,
+    /// Domains kept commented out in the managed region
+    pub disabled: BTreeSet<Arc<str>>,
+    /// Text between the header markers at the top of the file, as written there
+    pub header: Option<Arc<str>>,
+}
+
+impl ParsedHosts {
+    /// Append entries parsed from content that followed this one
+    /// Sinks are recorded as if both parts had been parsed together
+    pub fn merge(&mut self, later: ParsedHosts) {
+        self.blocking.extend(later.blocking);
+        self.preserved_lines.extend(later.preserved_lines);
+        self.sink_conflicts.extend(later.sink_conflicts);
+        self.annotations.extend(later.annotations);
+        self.disabled.extend(later.disabled);
+        self.header = self.header.take().or(later.header);
+        for (hostname, sink) in later.sinks {
+            record_sink(&mut self.sinks, &mut self.sink_conflicts, &hostname, sink);
+        }
+    }
+
+    /// Add the domains of another source's list, leaving its lines and header out
+    /// A domain the lists give different sinks is a sink conflict, as within one file
+    pub fn merge_source(&mut self, source: ParsedHosts) {
+        self.blocking.extend(source.blocking);
+        self.sink_conflicts.extend(source.sink_conflicts);
+        self.annotations.extend(source.annotations);
+        for (hostname, sink) in source.sinks {
+            record_sink(&mut self.sinks, &mut self.sink_conflicts, &hostname, sink);
+        }
+    }
+}
+
+/// Split a header block off the top of the content into (header, rest)
+/// The block must open on the first line; an unterminated one isn't a header
+fn split_header(content: &str) -> Option<(&str, &str)> {
+    let mut lines = content.split_inclusive('\n');
+    let first = lines.next()?;
+    if first.trim() != HEADER_START {
+        return None;
+    }
+
+    let mut offset = first.len();
+    for line in lines {
+        if line.trim() == HEADER_END {
+            let header = content[first.len()..offset].trim_end_matches(['\n', '\r']);
+            return Some((header, &content[offset + line.len()..]));
+        }
+        offset += line.len();
+    }
+    None
+}
+
+/// Split content around the managed region markers into (before, inside, after)
+/// An unterminated region extends to the end of the content
+fn split_managed_region(content: &str) -> Option<(&str, &str, &str)> {
+    let mut offset = 0;
+    let mut start: Option<(usize, usize)> = None;
+
+    for line in content.split_inclusive('\n') {
+        let trimmed = line.trim();
+        match start {
+            None if trimmed == MANAGED_START => start = Some((offset, offset + line.len())),
+            Some((region_start, inner_start)) if trimmed == MANAGED_END => {
+                return Some((
+                    &content[..region_start],
+                    &content[inner_start..offset],
+                    &content[offset + line.len()..],
+                ));
+            }
+            _ => {}
+        }
+        offset += line.len();
+    }
+
+    start.map(|(region_start, inner_start)| (&content[..region_start], &content[inner_start..], ""))
 }
 
 /// Parse a hosts file content into managed entries and preserved lines
+///
+/// When the content has managed region markers, only the region is managed:
+/// every line outside it is preserved verbatim, even localhost sink entries.
 pub fn parse_hosts(content: &str) -> Result<ParsedHosts, pest::error::Error<Rule>> {
+    parse_hosts_with(content, &ParseOptions::default())
+}
+
+/// Parse a plain list of domains, one per line, with the number of lines skipped
+/// Comments, blank lines, IP addresses and local names are ignored; anything
+/// after the first token of a line is too. Tokens that aren't valid domains
+/// are dropped and counted.
+pub fn parse_domain_list(content: &str) -> (BTreeSet<Arc<str>>, usize) {
+    let mut domains = BTreeSet::new();
+    let mut skipped = 0;
+    for token in content
+        .lines()
+        .filter_map(|line| line.split('#').next()?.split_whitespace().next())
+        .filter(|token| token.parse::<IpAddr>().is_err() && !is_local_domain(token))
+    {
+        match listed_domain(token) {
+            Some(domain) => {
+                domains.insert(domain.into());
+            }
+            None => skipped += 1,
+        }
+    }
+    (domains, skipped)
+}
+
+/// Parse a hosts file content with explicit options
+pub fn parse_hosts_with(
+    content: &str,
+    options: &ParseOptions,
+) -> Result<ParsedHosts, pest::error::Error<Rule>> {
+    // A header written on an earlier save is read back so it isn't written twice
+    if let Some((header, rest)) = split_header(content) {
+        let parsed = parse_hosts_with(rest, options)?;
+        return Ok(ParsedHosts {
+            header: Some(header.into()),
+            ..parsed
+        });
+    }
+
+    let Some((before, region, after)) = split_managed_region(content) else {
+        return parse_entries(content, options);
+    };
+
+    // Entries commented out by a pause are still managed
+    let region = if region.contains(PAUSED_PREFIX) {
+        Cow::Owned(unpause_region(region))
+    } else {
+        Cow::Borrowed(region)
+    };
+    let marker = options.disabled_marker.as_deref();
+    let (region, disabled) = if region.contains(DISABLED_PREFIX)
+        || marker.is_some_and(|marker| region.contains(marker))
+    {
+        let (region, disabled) = take_disabled_entries(&region, marker);
+        (Cow::Owned(region), disabled)
+    } else {
+        (region, Vec::new())
+    };
+    let mut parsed = parse_entries(&region, options)?;
+    for (sink, hostname) in disabled {
+        // An entry both blocked and disabled is blocked
+        if parsed.blocking.contains(&hostname)
+            || options.exclude.excludes(&hostname.to_ascii_lowercase())
+        {
+            continue;
+        }
+        parsed.sinks.entry(hostname.clone()).or_insert(sink);
+        parsed.disabled.insert(hostname);
+    }
+
+    let mut preserved_lines: Vec<PreservedLine> = before
+        .lines()
+        .map(|line| PreservedLine::Raw(line.into()))
+        .collect();
+    preserved_lines.push(PreservedLine::ManagedRegion);
+    preserved_lines.extend(parsed.preserved_lines);
+    preserved_lines.extend(after.lines().map(|line| PreservedLine::Raw(line.into())));
+
+    Ok(ParsedHosts {
+        preserved_lines,
+        ..parsed
+    })
+}
+
+/// Remove the disabled entries from a managed region, returning the rest of
+/// the region and each disabled domain with its sink
+/// Entries under a configured `marker` are only taken when an IP follows it, so
+/// a short marker like `#` leaves other comments alone; the default prefix is
+/// always recognized so changing the marker doesn't lose disabled entries
+fn take_disabled_entries(region: &str, marker: Option<&str>) -> (String, Vec<(IpAddr, Arc<str>)>) {
+    let mut disabled = Vec::new();
+    let rest = region
+        .split_inclusive('\n')
+        .filter(|line| {
+            let line = line.trim();
+            let under_marker =
+                marker
+                    .and_then(|marker| line.strip_prefix(marker))
+                    .filter(|entry| {
+                        entry
+                            .split_whitespace()
+                            .next()
+                            .is_some_and(|ip| ip.parse::<IpAddr>().is_ok())
+                    });
+            let Some(entry) = under_marker.or_else(|| line.strip_prefix(DISABLED_PREFIX)) else {
+                return true;
+            };
+            let mut tokens = entry.split_whitespace();
+            if let Some(sink) = tokens.next().and_then(|ip| ip.parse::<IpAddr>().ok()) {
+                for hostname in tokens {
+                    let hostname = canonical_hostname(hostname);
+                    if !is_local_domain(hostname) {
+                        let hostname =
+                            to_ascii_domain(hostname).unwrap_or_else(|_| hostname.to_string());
+                        disabled.push((sink, hostname.into()));
+                    }
+                }
+            }
+            false
+        })
+        .collect();
+    (rest, disabled)
+}
+
+/// Uncomment the entries of a paused managed region
+fn unpause_region(region: &str) -> String {
+    region
+        .split_inclusive('\n')
+        .map(|line| {
+            line.trim_start()
+                .strip_prefix(PAUSED_PREFIX)
+                .unwrap_or(line)
+        })
+        .collect()
+}
+
+/// Parse a single preserved line, e.g. a user edit of an existing one
+/// Lines that would block a domain belong in the blocking set instead
+pub fn parse_preserved_line(content: &str) -> Result<PreservedLine, anyhow::Error> {
+    if content.contains(['\n', '\r']) {
+        return Err(anyhow::anyhow!("Line must not contain line breaks"));
+    }
+
+    let parsed = parse_entries_strict(content, &ParseOptions::default())?;
+    if !parsed.blocking.is_empty() {
+        return Err(anyhow::anyhow!(
+            "Line blocks a domain; add it to the blocked domains instead"
+        ));
+    }
+
+    let mut lines = parsed.preserved_lines.into_iter();
+    match (lines.next(), lines.next()) {
+        (Some(line), None) => Ok(line),
+        (None, _) => Err(anyhow::anyhow!("Line is empty")),
+        (Some(_), Some(_)) => Err(anyhow::anyhow!("Line must contain a single entry")),
+    }
+}
+
+/// What parsing does with a line
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LineClass {
+    /// Sink entry; the domains it adds to the blocking set
+    Blocked(Vec<Arc<str>>),
+    /// Comment line, preserved
+    Comment,
+    /// Entry for a non-localhost address, preserved
+    NonLocalhost,
+    /// Sink entry naming only local names, preserved
+    Localhost,
+    /// Blank line
+    Blank,
+    /// Not valid hosts syntax, preserved as written
+    Invalid,
+}
+
+impl LineClass {
+    pub fn to_str(&self) -> &'static str {
+        match self {
+            LineClass::Blocked(_) => "blocked",
+            LineClass::Comment => "comment",
+            LineClass::NonLocalhost => "non-localhost",
+            LineClass::Localhost => "localhost",
+            LineClass::Blank => "blank",
+            LineClass::Invalid => "invalid",
+        }
+    }
+}
+
+/// A line of hosts content and what parsing does with it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LineClassification {
+    /// 1-based
+    pub line_number: usize,
+    pub class: LineClass,
+}
+
+/// Classify every line of hosts content, for reviewing an import line by line
+///
+/// Each line is run through the grammar on its own, so an invalid line is
+/// reported rather than failing the whole content. Managed region markers
+/// count as comments. Sinks and local names follow `options`, as in a parse.
+pub fn classify_lines(content: &str, options: &ParseOptions) -> Vec<LineClassification> {
+    content
+        .lines()
+        .enumerate()
+        .map(|(index, line)| LineClassification {
+            line_number: index + 1,
+            class: classify_line(line, options),
+        })
+        .collect()
+}
+
+fn classify_line(line: &str, options: &ParseOptions) -> LineClass {
+    let Ok(mut file) = HostsParser::parse(Rule::file, line) else {
+        return LineClass::Invalid;
+    };
+    let Some(content) = file
+        .next()
+        .and_then(|file| file.into_inner().find(|node| node.as_rule() == Rule::line))
+        .and_then(|line| line.into_inner().next())
+    else {
+        return LineClass::Blank;
+    };
+    if content.as_rule() == Rule::comment {
+        return LineClass::Comment;
+    }
+
+    let mut inner = content.into_inner();
+    let ip = inner
+        .next()
+        .and_then(|ip| IpAddr::from_str(ip.as_str()).ok());
+    match ip {
+        Some(ip) if options.is_sink(&ip) => {
+            let blocked: Vec<Arc<str>> = inner
+                .map(|hostname| hostname.as_str())
+                .filter(|hostname| !options.is_local(hostname))
+                .map(|hostname| {
+                    let hostname = canonical_hostname(hostname);
+                    to_ascii_domain(hostname)
+                        .unwrap_or_else(|_| hostname.to_string())
+                        .into()
+                })
+                .collect();
+            if blocked.is_empty() {
+                LineClass::Localhost
+            } else {
+                LineClass::Blocked(blocked)
+            }
+        }
+        // Preserved loopback addresses are kept as written
+        Some(ip) if is_localhost_ip(&ip) => LineClass::Localhost,
+        // Entries with an unparseable address are preserved like remote ones
+        _ => LineClass::NonLocalhost,
+    }
+}
+
+/// Parse hosts entries, classifying sink lines as managed blocking entries
+///
+/// If the grammar rejects the content, it's parsed again line by line and each
+/// rejected line is kept as `PreservedLine::Invalid`, so one bad line in a
+/// hand-edited file doesn't lose every other entry.
+fn parse_entries(
+    content: &str,
+    options: &ParseOptions,
+) -> Result<ParsedHosts, pest::error::Error<Rule>> {
+    match parse_entries_strict(content, options) {
+        Ok(parsed) => Ok(parsed),
+        Err(_) => {
+            let mut parsed = ParsedHosts::default();
+            for line in content.lines() {
+                match parse_entries_strict(line, options) {
+                    Ok(line_parsed) => parsed.merge(line_parsed),
+                    Err(_) => parsed
+                        .preserved_lines
+                        .push(PreservedLine::Invalid(line.trim_end().into())),
+                }
+            }
+            Ok(parsed)
+        }
+    }
+}
+
+/// `parse_entries` that fails on any line the grammar rejects
+fn parse_entries_strict(
+    content: &str,
+    options: &ParseOptions,
+) -> Result<ParsedHosts, pest::error::Error<Rule>> {
     let file = HostsParser::parse(Rule::file, content)?
         .next()
         .ok_or_else(|| {
@@ -38,12 +715,16 @@ pub fn parse_hosts(content: &str) -> Result<ParsedHosts, pest::error::Error<Rule
 
     let mut blocking: BTreeSet<Arc<str>> = BTreeSet::new();
     let mut preserved_lines: Vec<PreservedLine> = Vec::new();
+    let mut sinks = SinkMap::new();
+    let mut sink_conflicts: Vec<SinkConflict> = Vec::new();
+    let mut annotations = AnnotationMap::new();
 
     // Iterate over lines in the file
     for node in file.into_inner() {
         match node.as_rule() {
             Rule::line => {
                 // Process the line's inner content (entry, comment, or empty for NEWLINE)
+                let line_str = node.as_str().trim_end_matches(['\r', '\n']);
                 let mut line_inner = node.into_inner();
                 let line_content = line_inner.next();
 
@@ -52,15 +733,59 @@ pub fn parse_hosts(content: &str) -> Result<ParsedHosts, pest::error::Error<Rule
                         match content.as_rule() {
                             Rule::entry => {
                                 let original_line_str = content.as_str();
+                                let annotation = line_inner
+                                    .next()
+                                    .and_then(|comment| parse_annotation(comment.as_str()));
                                 let mut inner = content.into_inner();
                                 let ip_str = inner.next().unwrap().as_str();
 
                                 match IpAddr::from_str(ip_str) {
                                     Ok(ip) => {
-                                        if is_localhost_ip(&ip) {
-                                            for hostname in inner.map(|pair| pair.as_str()) {
-                                                if !is_local_domain(hostname) {
-                                                    blocking.insert(hostname.into());
+                                        let hostnames: Vec<&str> =
+                                            inner.map(|pair| pair.as_str()).collect();
+                                        let all_local =
+                                            hostnames.iter().all(|h| options.is_local(h));
+                                        if is_localhost_ip(&ip)
+                                            && options.preserve_whitespace
+                                            && all_local
+                                        {
+                                            // Only local names: keep the line as written
+                                            preserved_lines.push(PreservedLine::LocalhostLine(
+                                                line_str.into(),
+                                            ));
+                                        } else if is_localhost_ip(&ip)
+                                            && (all_local || options.is_sink(&ip))
+                                        {
+                                            for hostname in hostnames {
+                                                if !options.is_local(hostname) {
+                                                    let hostname = canonical_hostname(hostname);
+                                                    // Store internationalized names as punycode,
+                                                    // keeping the raw name if it can't be converted
+                                                    let hostname: Arc<str> =
+                                                        to_ascii_domain(hostname)
+                                                            .unwrap_or_else(|_| {
+                                                                hostname.to_string()
+                                                            })
+                                                            .into();
+                                                    if options
+                                                        .exclude
+                                                        .excludes(&hostname.to_ascii_lowercase())
+                                                    {
+                                                        continue;
+                                                    }
+                                                    record_sink(
+                                                        &mut sinks,
+                                                        &mut sink_conflicts,
+                                                        &hostname,
+                                                        ip,
+                                                    );
+                                                    if let Some(added_on) = annotation {
+                                                        annotations.insert(
+                                                            hostname.clone(),
+                                                            added_on.into(),
+                                                        );
+                                                    }
+                                                    blocking.insert(hostname);
                                                 } else {
                                                     // Localhost entry - preserve as-is
                                                     preserved_lines.push(
@@ -72,7 +797,8 @@ pub fn parse_hosts(content: &str) -> Result<ParsedHosts, pest::error::Error<Rule
                                                 }
                                             }
                                         } else {
-                                            // Non-localhost entry - preserve as-is
+                                            // Non-localhost entry, or one under an address
+                                            // that isn't a sink - preserve as-is
                                             let original_line = original_line_str.trim();
                                             preserved_lines.push(PreservedLine::NonLocalhostEntry(
                                                 original_line.into(),
@@ -110,55 +836,612 @@ pub fn parse_hosts(content: &str) -> Result<ParsedHosts, pest::error::Error<Rule
     Ok(ParsedHosts {
         blocking,
         preserved_lines,
+        sinks,
+        sink_conflicts,
+        annotations,
+        disabled: BTreeSet::new(),
+        header: None,
     })
 }
 
-#[inline]
-pub fn serialize_hosts(preserved_lines: &[PreservedLine], blocking: &BTreeSet<Arc<str>>) -> String {
-    let mut result = String::new();
-    let mut localhost_entries: HashMap<IpAddr, BTreeSet<Arc<str>>> = HashMap::new();
+/// Drop redundant preserved lines, keeping the first of each
+///
+/// Removes repeated entries (ignoring whitespace differences), sink lines
+/// with no hostname, comments with no text, a comment repeated on the next
+/// line, and runs of blank lines beyond the first.
+pub fn compact_preserved_lines(lines: Vec<PreservedLine>) -> Vec<PreservedLine> {
+    let mut seen_entries: HashSet<String> = HashSet::new();
+    let mut compacted: Vec<PreservedLine> = Vec::with_capacity(lines.len());
 
-    // First, write preserved lines
-    for line in preserved_lines {
+    for line in lines {
+        let previous = compacted.last().and_then(PreservedLine::content);
+        let keep = match &line {
+            PreservedLine::ManagedRegion => true,
+            PreservedLine::LocalhostEntry { ip, hostname } => {
+                seen_entries.insert(format!("{} {}", ip, hostname))
+            }
+            PreservedLine::Comment(text) | PreservedLine::Raw(text)
+                if text.trim_start().starts_with('#') =>
+            {
+                !text.trim().trim_start_matches('#').trim().is_empty()
+                    && previous.as_deref() != Some(text)
+            }
+            PreservedLine::Raw(text) if text.trim().is_empty() => {
+                !previous.is_some_and(|previous| previous.trim().is_empty())
+            }
+            // A sink with no hostnames
+            PreservedLine::Raw(text) if IpAddr::from_str(text.trim()).is_ok() => false,
+            PreservedLine::Comment(text)
+            | PreservedLine::NonLocalhostEntry(text)
+            | PreservedLine::LocalhostLine(text)
+            | PreservedLine::Raw(text)
+            | PreservedLine::Invalid(text) => {
+                seen_entries.insert(text.split_whitespace().collect::<Vec<_>>().join(" "))
+            }
+        };
+        if keep {
+            compacted.push(line);
+        }
+    }
+
+    compacted
+}
+
+/// An address and the hostnames the preserved lines map to it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NetworkEntry {
+    pub ip: IpAddr,
+    pub hostnames: Vec<Arc<str>>,
+}
+
+/// Mappings kept outside the blocklist (LAN devices, static names, localhost
+/// aliases), one per address sorted by IP
+///
+/// Hostnames keep the order they first appear in; lines whose address doesn't
+/// parse are skipped.
+pub fn network_entries(lines: &[PreservedLine]) -> Vec<NetworkEntry> {
+    let mut entries: BTreeMap<IpAddr, Vec<Arc<str>>> = BTreeMap::new();
+    let mut add = |ip: IpAddr, hostname: &str| {
+        let hostnames = entries.entry(ip).or_default();
+        if !hostnames.iter().any(|known| &**known == hostname) {
+            hostnames.push(hostname.into());
+        }
+    };
+    for line in lines {
         match line {
-            PreservedLine::Comment(comment) => {
-                result.push_str(comment);
-                result.push('\n');
+            PreservedLine::LocalhostEntry { ip, hostname } => add(*ip, hostname),
+            PreservedLine::NonLocalhostEntry(entry) | PreservedLine::LocalhostLine(entry) => {
+                let mut tokens = entry.split('#').next().unwrap_or("").split_whitespace();
+                let Some(ip) = tokens.next().and_then(|ip| IpAddr::from_str(ip).ok()) else {
+                    continue;
+                };
+                for hostname in tokens {
+                    add(ip, hostname);
+                }
             }
-            PreservedLine::NonLocalhostEntry(entry) => {
-                result.push_str(entry);
-                result.push('\n');
+            _ => {}
+        }
+    }
+    entries
+        .into_iter()
+        .map(|(ip, hostnames)| NetworkEntry { ip, hostnames })
+        .collect()
+}
+
+/// How `normalize_layout` arranges the preserved lines
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LayoutStyle {
+    /// Leave the lines where they are
+    AsIs,
+    /// Standalone comments first, in their original order, then the entries
+    CommentsOnTop,
+}
+
+impl LayoutStyle {
+    pub fn from_str(value: &str) -> Result<Self, anyhow::Error> {
+        match value {
+            "as-is" => Ok(LayoutStyle::AsIs),
+            "comments-on-top" => Ok(LayoutStyle::CommentsOnTop),
+            _ => Err(anyhow::anyhow!("Unknown layout style: {}", value)),
+        }
+    }
+
+    #[inline]
+    pub fn to_str(self) -> &'static str {
+        match self {
+            LayoutStyle::AsIs => "as-is",
+            LayoutStyle::CommentsOnTop => "comments-on-top",
+        }
+    }
+}
+
+/// Arrange preserved lines in the given style
+///
+/// With `CommentsOnTop`, comment lines move above everything else keeping
+/// their relative order, followed by one blank line and the entries (and the
+/// managed region) in their original order. Other blank lines are dropped;
+/// inline comments stay on their entry.
+pub fn normalize_layout(lines: Vec<PreservedLine>, style: LayoutStyle) -> Vec<PreservedLine> {
+    if style == LayoutStyle::AsIs {
+        return lines;
+    }
+
+    let (comments, entries): (Vec<_>, Vec<_>) = lines
+        .into_iter()
+        .filter(|line| !matches!(line, PreservedLine::Raw(text) if text.trim().is_empty()))
+        .partition(|line| match line {
+            PreservedLine::Comment(_) => true,
+            PreservedLine::Raw(text) => text.trim_start().starts_with('#'),
+            _ => false,
+        });
+
+    let mut normalized = comments;
+    let has_entries = entries
+        .iter()
+        .any(|line| !matches!(line, PreservedLine::ManagedRegion));
+    if !normalized.is_empty() && has_entries {
+        normalized.push(PreservedLine::Raw("".into()));
+    }
+    normalized.extend(entries);
+    normalized
+}
+
+/// Drop hostnames from preserved lines that are also blocked, and repeated localhost entries
+///
+/// The blocking representation wins: a blocked hostname is removed from any
+/// preserved line mapping it, and a line left with no hostnames is dropped.
+/// Returns the lines and the number of hostnames removed.
+pub fn dedupe_preserved_lines(
+    lines: Vec<PreservedLine>,
+    blocking: &BTreeSet<Arc<str>>,
+) -> (Vec<PreservedLine>, usize) {
+    let is_blocked = |hostname: &str| blocking.contains(hostname.to_ascii_lowercase().as_str());
+    let mut seen_localhost: HashSet<String> = HashSet::new();
+    let mut deduped = Vec::with_capacity(lines.len());
+    let mut removed = 0;
+
+    for line in lines {
+        match line {
+            PreservedLine::LocalhostEntry { ip, ref hostname } => {
+                if is_blocked(hostname) || !seen_localhost.insert(format!("{} {}", ip, hostname)) {
+                    removed += 1;
+                    continue;
+                }
+            }
+            PreservedLine::LocalhostLine(ref text) => {
+                let normalized = text.split_whitespace().collect::<Vec<_>>().join(" ");
+                if !seen_localhost.insert(normalized) {
+                    removed += 1;
+                    continue;
+                }
             }
+            PreservedLine::NonLocalhostEntry(ref text) | PreservedLine::Raw(ref text) => {
+                if let Some((rewritten, dropped)) = without_hostnames(text, is_blocked) {
+                    removed += dropped;
+                    let Some(rewritten) = rewritten else {
+                        continue;
+                    };
+                    deduped.push(match line {
+                        PreservedLine::Raw(_) => PreservedLine::Raw(rewritten.into()),
+                        _ => PreservedLine::NonLocalhostEntry(rewritten.into()),
+                    });
+                    continue;
+                }
+            }
+            PreservedLine::Comment(_)
+            | PreservedLine::Invalid(_)
+            | PreservedLine::ManagedRegion => {}
+        }
+        deduped.push(line);
+    }
+
+    (deduped, removed)
+}
+
+/// An entry line with the hostnames matching `remove` taken out, or None if none match
+/// The rewritten line is None when no hostname is left
+fn without_hostnames(
+    entry: &str,
+    remove: impl Fn(&str) -> bool,
+) -> Option<(Option<String>, usize)> {
+    let (mapping, comment) = match entry.find('#') {
+        Some(index) => entry.split_at(index),
+        None => (entry, ""),
+    };
+    let mut tokens = mapping.split_whitespace();
+    let ip = tokens.next()?;
+    let (dropped, kept): (Vec<&str>, Vec<&str>) = tokens.partition(|hostname| remove(hostname));
+    if dropped.is_empty() {
+        return None;
+    }
+    if kept.is_empty() {
+        return Some((None, dropped.len()));
+    }
+    let mut rewritten = format!("{} {}", ip, kept.join(" "));
+    if !comment.is_empty() {
+        rewritten.push(' ');
+        rewritten.push_str(comment);
+    }
+    Some((Some(rewritten), dropped.len()))
+}
+
+/// Record the sink a blocked domain is listed under
+/// The first listing wins; a later listing under another sink is a conflict,
+/// except for the IPv6 counterpart of a dual-stack entry (IPv4 is preferred)
+fn record_sink(
+    sinks: &mut SinkMap,
+    conflicts: &mut Vec<SinkConflict>,
+    hostname: &Arc<str>,
+    sink: IpAddr,
+) {
+    match sinks.get(hostname) {
+        None => {
+            sinks.insert(hostname.clone(), sink);
+        }
+        Some(existing) if *existing == sink => {}
+        Some(existing) if existing.is_ipv6() && sink.is_ipv4() => {
+            sinks.insert(hostname.clone(), sink);
+        }
+        Some(existing) if existing.is_ipv4() && sink.is_ipv6() => {}
+        Some(existing) => conflicts.push(SinkConflict {
+            hostname: hostname.clone(),
+            existing: *existing,
+            incoming: sink,
+        }),
+    }
+}
+
+/// IPv6 sink matching an IPv4 one (0.0.0.0 pairs with ::, loopback with ::1)
+#[inline]
+fn ipv6_counterpart(sink: IpAddr) -> Option<IpAddr> {
+    match sink {
+        IpAddr::V4(v4) if v4.is_unspecified() => Some(IpAddr::V6(Ipv6Addr::UNSPECIFIED)),
+        IpAddr::V4(_) => Some(IpAddr::V6(Ipv6Addr::LOCALHOST)),
+        IpAddr::V6(_) => None,
+    }
+}
+
+/// Serialize preserved lines and blocking entries into hosts file content
+///
+/// Localhost and blocking entries are written between the managed region
+/// markers, placed where the region was found or at the end of the file.
+#[inline]
+pub fn serialize_hosts(
+    preserved_lines: &[PreservedLine],
+    blocking: &BTreeSet<Arc<str>>,
+    sinks: &SinkMap,
+    annotations: &AnnotationMap,
+    disabled: &BTreeSet<Arc<str>>,
+    options: &SerializeOptions,
+) -> String {
+    let mut result = Vec::new();
+    write_hosts(
+        &mut result,
+        preserved_lines,
+        blocking,
+        sinks,
+        annotations,
+        disabled,
+        options,
+    )
+    .expect("writing to a Vec can't fail");
+    String::from_utf8(result).expect("hosts content is built from strings")
+}
+
+/// Write hosts file content to a writer, as `serialize_hosts` does
+///
+/// Blank lines at the end of the file are dropped, so the content ends with
+/// exactly one newline (or none with `omit_trailing_newline`) however the
+/// file it was read from ended.
+pub fn write_hosts(
+    out: &mut impl Write,
+    preserved_lines: &[PreservedLine],
+    blocking: &BTreeSet<Arc<str>>,
+    sinks: &SinkMap,
+    annotations: &AnnotationMap,
+    disabled: &BTreeSet<Arc<str>>,
+    options: &SerializeOptions,
+) -> io::Result<()> {
+    let mut out = TrailingNewlines::new(out);
+    write_hosts_lines(
+        &mut out,
+        preserved_lines,
+        blocking,
+        sinks,
+        annotations,
+        disabled,
+        options,
+    )?;
+    out.finish(!options.omit_trailing_newline)
+}
+
+/// Writer holding back newlines until more content follows, so trailing
+/// ones can be replaced by a single newline at the end
+struct TrailingNewlines<W> {
+    inner: W,
+    pending: usize,
+}
+
+impl<W: Write> TrailingNewlines<W> {
+    #[inline]
+    fn new(inner: W) -> Self {
+        TrailingNewlines { inner, pending: 0 }
+    }
+
+    /// Write the final newline, if wanted, in place of the held-back ones
+    fn finish(mut self, trailing_newline: bool) -> io::Result<()> {
+        if trailing_newline {
+            self.inner.write_all(b"\n")?;
+        }
+        self.inner.flush()
+    }
+}
+
+impl<W: Write> Write for TrailingNewlines<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match buf.iter().rposition(|&byte| byte != b'\n') {
+            Some(last) => {
+                for _ in 0..self.pending {
+                    self.inner.write_all(b"\n")?;
+                }
+                self.inner.write_all(&buf[..=last])?;
+                self.pending = buf.len() - last - 1;
+            }
+            None => self.pending += buf.len(),
+        }
+        Ok(buf.len())
+    }
+
+    #[inline]
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+fn write_hosts_lines(
+    out: &mut impl Write,
+    preserved_lines: &[PreservedLine],
+    blocking: &BTreeSet<Arc<str>>,
+    sinks: &SinkMap,
+    annotations: &AnnotationMap,
+    disabled: &BTreeSet<Arc<str>>,
+    options: &SerializeOptions,
+) -> io::Result<()> {
+    let managed = ManagedEntries {
+        blocking,
+        sinks,
+        annotations,
+        disabled,
+    };
+    let mut localhost_entries: BTreeMap<IpAddr, BTreeSet<Arc<str>>> = BTreeMap::new();
+    let mut localhost_lines: Vec<Arc<str>> = Vec::new();
+
+    for line in preserved_lines {
+        match line {
             PreservedLine::LocalhostEntry { ip, hostname } => {
                 localhost_entries
                     .entry(*ip)
-                    .or_insert_with(BTreeSet::new)
+                    .or_default()
                     .insert(hostname.clone());
             }
+            PreservedLine::LocalhostLine(line) => localhost_lines.push(line.clone()),
+            _ => {}
+        }
+    }
+    let localhost = LocalhostEntries {
+        lines: &localhost_lines,
+        grouped: &localhost_entries,
+    };
+
+    if let Some(header) = &options.header {
+        write_header(out, header)?;
+    }
+
+    // First, write preserved lines
+    let mut region_written = false;
+    for line in preserved_lines {
+        match line {
+            PreservedLine::Comment(comment) => writeln!(out, "{}", comment)?,
+            PreservedLine::NonLocalhostEntry(entry)
+            | PreservedLine::Raw(entry)
+            | PreservedLine::Invalid(entry) => writeln!(out, "{}", entry)?,
+            PreservedLine::LocalhostEntry { .. } | PreservedLine::LocalhostLine(_) => {}
+            PreservedLine::ManagedRegion if !region_written => {
+                write_managed_region(out, &localhost, &managed, options)?;
+                region_written = true;
+            }
+            PreservedLine::ManagedRegion => {}
+        }
+    }
+
+    // No markers yet: create the managed region at the end
+    if !region_written {
+        write_managed_region(out, &localhost, &managed, options)?;
+    }
+
+    Ok(())
+}
+
+/// Write the header block, commenting out lines that aren't comments already
+fn write_header(out: &mut impl Write, header: &str) -> io::Result<()> {
+    writeln!(out, "{}", HEADER_START)?;
+    for line in header.lines() {
+        if line.trim_start().starts_with('#') || line.trim().is_empty() {
+            writeln!(out, "{}", line)?;
+        } else {
+            writeln!(out, "# {}", line)?;
         }
     }
+    writeln!(out, "{}", HEADER_END)
+}
+
+/// Domains written in the managed region
+struct ManagedEntries<'a> {
+    blocking: &'a BTreeSet<Arc<str>>,
+    sinks: &'a SinkMap,
+    annotations: &'a AnnotationMap,
+    disabled: &'a BTreeSet<Arc<str>>,
+}
+
+/// Localhost lines written at the top of the managed region
+struct LocalhostEntries<'a> {
+    /// Lines kept verbatim, in their original order
+    lines: &'a [Arc<str>],
+    /// Entries regrouped by IP
+    grouped: &'a BTreeMap<IpAddr, BTreeSet<Arc<str>>>,
+}
+
+fn write_managed_region(
+    out: &mut impl Write,
+    localhost: &LocalhostEntries,
+    managed: &ManagedEntries,
+    options: &SerializeOptions,
+) -> io::Result<()> {
+    let ManagedEntries {
+        blocking,
+        sinks,
+        annotations,
+        disabled,
+    } = *managed;
+    writeln!(out, "{}", MANAGED_START)?;
+
+    for line in localhost.lines {
+        writeln!(out, "{}", line)?;
+    }
 
     // Write localhost entries grouped by IP
-    for (ip, hostnames) in localhost_entries {
-        result.push_str(&ip.to_string());
-        for hostname in hostnames {
-            result.push(' ');
-            result.push_str(hostname.as_ref());
+    for (ip, hostnames) in localhost.grouped {
+        write_sink_line(out, *ip, hostnames, options.separator)?;
+    }
+
+    if options.paused {
+        let mut entries = Vec::new();
+        write_blocking_entries(&mut entries, blocking, sinks, annotations, options)?;
+        for line in String::from_utf8_lossy(&entries).lines() {
+            writeln!(out, "{}{}", PAUSED_PREFIX, line)?;
         }
-        result.push('\n');
+    } else {
+        write_blocking_entries(out, blocking, sinks, annotations, options)?;
     }
 
-    // Write blocking entries (non-localhost domains)
-    if !blocking.is_empty() {
-        result.push_str("127.0.0.1");
-        for hostname in blocking {
-            result.push(' ');
-            result.push_str(hostname);
+    let disabled_prefix = options.disabled_prefix();
+    for hostname in disabled.difference(blocking) {
+        let sink = sinks.get(hostname).copied().unwrap_or(DEFAULT_SINK);
+        writeln!(
+            out,
+            "{}{}{}{}",
+            disabled_prefix,
+            sink,
+            options.separator.as_char(),
+            hostname
+        )?;
+    }
+
+    writeln!(out, "{}", MANAGED_END)
+}
+
+/// Write blocking entries (non-localhost domains) grouped by sink
+/// Annotated domains get a line of their own so the comment applies to them alone
+fn write_blocking_entries(
+    out: &mut impl Write,
+    blocking: &BTreeSet<Arc<str>>,
+    sinks: &SinkMap,
+    annotations: &AnnotationMap,
+    options: &SerializeOptions,
+) -> io::Result<()> {
+    let mut sink_lines: BTreeMap<IpAddr, BTreeSet<Arc<str>>> = BTreeMap::new();
+    let mut annotated_lines: Vec<(IpAddr, &Arc<str>, &str)> = Vec::new();
+    for hostname in blocking {
+        let sink = sinks.get(hostname).copied().unwrap_or(DEFAULT_SINK);
+        // Without an AAAA sink the domain can still resolve over IPv6
+        let ipv6_sink = ipv6_counterpart(sink).filter(|_| options.block_ipv6);
+
+        for sink in std::iter::once(sink).chain(ipv6_sink) {
+            match annotations.get(hostname) {
+                Some(added_on) => annotated_lines.push((sink, hostname, added_on)),
+                None => {
+                    sink_lines.entry(sink).or_default().insert(hostname.clone());
+                }
+            }
         }
-        result.push('\n');
     }
+    for (sink, hostnames) in &sink_lines {
+        write_sink_line(out, *sink, hostnames, options.separator)?;
+    }
+    for (sink, hostname, added_on) in annotated_lines {
+        writeln!(
+            out,
+            "{}{}{} {}",
+            sink,
+            options.separator.as_char(),
+            hostname,
+            annotation_comment(added_on)
+        )?;
+    }
+    Ok(())
+}
+
+/// Format of a managed-only export
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ExportFormat {
+    /// Hosts file lines, grouped by sink
+    #[default]
+    Hosts,
+    /// One domain per line
+    Domains,
+}
 
-    result
+impl ExportFormat {
+    #[inline]
+    pub fn from_str(s: &str) -> Self {
+        match s {
+            "domains" => ExportFormat::Domains,
+            _ => ExportFormat::Hosts,
+        }
+    }
+}
+
+/// Serialize just the blocked domains, for sharing a blocklist
+/// Preserved lines, localhost entries, region markers and annotations are left out
+pub fn serialize_managed_only(
+    blocking: &BTreeSet<Arc<str>>,
+    sinks: &SinkMap,
+    format: ExportFormat,
+    options: &SerializeOptions,
+) -> String {
+    let blocking: BTreeSet<Arc<str>> = blocking
+        .iter()
+        .filter(|hostname| !is_localhost_name(hostname))
+        .cloned()
+        .collect();
+
+    let mut out = Vec::new();
+    match format {
+        ExportFormat::Hosts => {
+            write_blocking_entries(&mut out, &blocking, sinks, &AnnotationMap::new(), options)
+                .expect("writing to a Vec can't fail");
+        }
+        ExportFormat::Domains => {
+            for hostname in &blocking {
+                out.extend_from_slice(hostname.as_bytes());
+                out.push(b'\n');
+            }
+        }
+    }
+    String::from_utf8(out).expect("hosts content is built from strings")
+}
+
+fn write_sink_line(
+    out: &mut impl Write,
+    sink: IpAddr,
+    hostnames: &BTreeSet<Arc<str>>,
+    separator: Separator,
+) -> io::Result<()> {
+    write!(out, "{}", sink)?;
+    for hostname in hostnames {
+        write!(out, "{}{}", separator.as_char(), hostname)?;
+    }
+    writeln!(out)
 }
 
 #[cfg(test)]
@@ -230,7 +1513,14 @@ mod tests {
     fn test_round_trip() {
         let original = "# Comment\n127.0.0.1 localhost example.com\n192.168.1.1 router\n\n";
         let parsed = parse_hosts(original).unwrap();
-        let serialized = serialize_hosts(&parsed.preserved_lines, &parsed.blocking);
+        let serialized = serialize_hosts(
+            &parsed.preserved_lines,
+            &parsed.blocking,
+            &parsed.sinks,
+            &parsed.annotations,
+            &parsed.disabled,
+            &SerializeOptions::default(),
+        );
 
         // Re-parse to verify
         let reparsed = parse_hosts(&serialized).unwrap();
@@ -262,4 +1552,780 @@ mod tests {
             matches!(line, PreservedLine::LocalhostEntry { hostname, .. } if hostname.as_ref() == "localhost")
         }));
     }
+
+    #[test]
+    fn test_managed_region_round_trip() {
+        let original = "# My own header\n\
+                        192.168.1.1 router\n\
+                        127.0.0.1 dev.example.test\n\
+                        \n\
+                        # === ADGUARD MANAGED START ===\n\
+                        127.0.0.1 localhost\n\
+                        127.0.0.1 ads.example.com tracker.example.com\n\
+                        # === ADGUARD MANAGED END ===\n\
+                        # Footer\n\
+                        10.0.0.1 nas.lan\n";
+        let parsed = parse_hosts(original).unwrap();
+
+        // Only domains inside the region are managed
+        assert_eq!(parsed.blocking.len(), 2);
+        assert!(parsed.blocking.contains("ads.example.com"));
+        assert!(!parsed.blocking.contains("dev.example.test"));
+        assert!(parsed.preserved_lines.iter().any(|line| {
+            matches!(line, PreservedLine::Raw(s) if s.as_ref() == "127.0.0.1 dev.example.test")
+        }));
+
+        let serialized = serialize_hosts(
+            &parsed.preserved_lines,
+            &parsed.blocking,
+            &parsed.sinks,
+            &parsed.annotations,
+            &parsed.disabled,
+            &SerializeOptions::default(),
+        );
+        assert_eq!(serialized, original);
+    }
+
+    #[test]
+    fn test_managed_region_created_on_first_save() {
+        let original = "# Comment\n127.0.0.1 localhost example.com\n";
+        let parsed = parse_hosts(original).unwrap();
+        let serialized = serialize_hosts(
+            &parsed.preserved_lines,
+            &parsed.blocking,
+            &parsed.sinks,
+            &parsed.annotations,
+            &parsed.disabled,
+            &SerializeOptions::default(),
+        );
+
+        assert!(serialized.contains(MANAGED_START));
+        assert!(serialized.contains(MANAGED_END));
+
+        let reparsed = parse_hosts(&serialized).unwrap();
+        assert_eq!(reparsed.blocking, parsed.blocking);
+        assert_eq!(
+            serialize_hosts(
+                &reparsed.preserved_lines,
+                &reparsed.blocking,
+                &reparsed.sinks,
+                &reparsed.annotations,
+                &reparsed.disabled,
+                &SerializeOptions::default()
+            ),
+            serialized
+        );
+    }
+
+    #[test]
+    fn test_ipv6_sink_round_trip() {
+        let original = "127.0.0.1 localhost\n127.0.0.1 ads.example.com\n::1 ads.example.com\n";
+        let parsed = parse_hosts(original).unwrap();
+
+        // The IPv6 sink entry is blocking, not preserved
+        assert_eq!(parsed.blocking.len(), 1);
+        assert!(parsed.blocking.contains("ads.example.com"));
+        assert!(!parsed
+            .preserved_lines
+            .iter()
+            .any(|line| line.mentions("ads.example.com")));
+
+        let options = SerializeOptions {
+            block_ipv6: true,
+            ..SerializeOptions::default()
+        };
+        let serialized = serialize_hosts(
+            &parsed.preserved_lines,
+            &parsed.blocking,
+            &parsed.sinks,
+            &parsed.annotations,
+            &parsed.disabled,
+            &options,
+        );
+        assert!(serialized.contains("127.0.0.1 ads.example.com\n"));
+        assert!(serialized.contains("::1 ads.example.com\n"));
+
+        let reparsed = parse_hosts(&serialized).unwrap();
+        assert_eq!(reparsed.blocking, parsed.blocking);
+        assert_eq!(
+            serialize_hosts(
+                &reparsed.preserved_lines,
+                &reparsed.blocking,
+                &reparsed.sinks,
+                &reparsed.annotations,
+                &reparsed.disabled,
+                &options
+            ),
+            serialized
+        );
+    }
+
+    #[test]
+    fn test_parse_idn_as_punycode() {
+        let content = "127.0.0.1 пример.рф bücher.de\n";
+        let parsed = parse_hosts(content).unwrap();
+
+        assert!(parsed.blocking.contains("xn--e1afmkfd.xn--p1ai"));
+        assert!(parsed.blocking.contains("xn--bcher-kva.de"));
+        assert!(!parsed.blocking.contains("пример.рф"));
+    }
+
+    #[test]
+    fn test_sinks_preserved_on_round_trip() {
+        let original = "0.0.0.0 ads.example.com\n127.0.0.1 tracker.example.com\n";
+        let parsed = parse_hosts(original).unwrap();
+
+        assert_eq!(
+            parsed.sinks.get("ads.example.com"),
+            Some(&IpAddr::from_str("0.0.0.0").unwrap())
+        );
+        assert!(parsed.sink_conflicts.is_empty());
+
+        let serialized = serialize_hosts(
+            &parsed.preserved_lines,
+            &parsed.blocking,
+            &parsed.sinks,
+            &parsed.annotations,
+            &parsed.disabled,
+            &SerializeOptions::default(),
+        );
+        assert!(serialized.contains("0.0.0.0 ads.example.com\n"));
+        assert!(serialized.contains("127.0.0.1 tracker.example.com\n"));
+    }
+
+    #[test]
+    fn test_sink_conflict_detected() {
+        let content = "127.0.0.1 ads.example.com\n0.0.0.0 ads.example.com\n::1 ads.example.com\n";
+        let parsed = parse_hosts(content).unwrap();
+
+        // The IPv6 listing is a dual-stack counterpart, not a conflict
+        assert_eq!(parsed.sink_conflicts.len(), 1);
+        assert_eq!(
+            parsed.sinks.get("ads.example.com"),
+            Some(&IpAddr::from_str("127.0.0.1").unwrap())
+        );
+    }
+
+    #[test]
+    fn test_classify_lines() {
+        let content = "\
+# Ad servers
+127.0.0.1 localhost
+
+0.0.0.0 ads.example.com tracker.example.org # added 2024-05-01 via adguard
+192.168.1.1 router
+::1 localhost ip6-localhost
+0.0.0.0 bad_host!
+127.0.0.1 localhost ads.example.net
+";
+        let classes: Vec<(usize, LineClass)> = classify_lines(content, &ParseOptions::default())
+            .into_iter()
+            .map(|line| (line.line_number, line.class))
+            .collect();
+        assert_eq!(
+            classes,
+            vec![
+                (1, LineClass::Comment),
+                (2, LineClass::Localhost),
+                (3, LineClass::Blank),
+                (
+                    4,
+                    LineClass::Blocked(vec![
+                        Arc::from("ads.example.com"),
+                        Arc::from("tracker.example.org")
+                    ])
+                ),
+                (5, LineClass::NonLocalhost),
+                (6, LineClass::Localhost),
+                (7, LineClass::Invalid),
+                (8, LineClass::Blocked(vec![Arc::from("ads.example.net")])),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_classify_lines_follows_options() {
+        let options = ParseOptions {
+            preserve_ips: vec!["127.0.0.1".parse().unwrap()],
+            block_single_label: true,
+            ..ParseOptions::default()
+        };
+        let classes: Vec<LineClass> = classify_lines(
+            "127.0.0.1 ads.example.com
+0.0.0.0 intranet
+",
+            &options,
+        )
+        .into_iter()
+        .map(|line| line.class)
+        .collect();
+        assert_eq!(
+            classes,
+            vec![
+                LineClass::Localhost,
+                LineClass::Blocked(vec![Arc::from("intranet")]),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_preserved_line() {
+        let line = parse_preserved_line("192.168.1.2 router").unwrap();
+        assert!(matches!(line, PreservedLine::NonLocalhostEntry(_)));
+        assert_eq!(line.content().unwrap(), "192.168.1.2 router");
+
+        assert!(parse_preserved_line("# note").is_ok());
+        assert!(parse_preserved_line("").is_err());
+        assert!(parse_preserved_line("127.0.0.1 ads.example.com").is_err());
+        assert!(parse_preserved_line("10.0.0.1 a\n10.0.0.2 b").is_err());
+    }
+
+    #[test]
+    fn test_parser_capabilities_match_behavior() {
+        let caps = PARSER_CAPABILITIES;
+
+        let parsed = parse_hosts("::1 localhost\nfe80::1 link\n").map(|p| p.preserved_lines);
+        assert_eq!(caps.ipv6, parsed.is_ok_and(|lines| !lines.is_empty()));
+
+        let parsed = parse_hosts("127.0.0.1 a.example.com b.example.com\n").unwrap();
+        assert_eq!(caps.multiple_hostnames, parsed.blocking.len() == 2);
+
+        let parsed = parse_hosts("127.0.0.1 ads.example.com # tracker\n");
+        assert_eq!(
+            caps.inline_comments,
+            parsed.is_ok_and(|p| p.blocking.contains("ads.example.com"))
+        );
+
+        let parsed = parse_hosts("127.0.0.1 a.example.com\r\n127.0.0.1 b.example.com\r\n");
+        assert_eq!(
+            caps.crlf_line_endings,
+            parsed.is_ok_and(|p| p.blocking.len() == 2)
+        );
+
+        let parsed = parse_hosts(&format!(
+            "127.0.0.1 outside.example.com\n{}\n127.0.0.1 inside.example.com\n{}\n",
+            MANAGED_START, MANAGED_END
+        ))
+        .unwrap();
+        assert_eq!(
+            caps.managed_region,
+            parsed.blocking.len() == 1 && parsed.blocking.contains("inside.example.com")
+        );
+
+        let parsed = parse_hosts("127.0.0.1 bücher.de\n").unwrap();
+        assert_eq!(
+            caps.internationalized_names,
+            parsed.blocking.contains("xn--bcher-kva.de")
+        );
+
+        let parsed = parse_hosts("0.0.0.0 ads.example.com\n").unwrap();
+        assert_eq!(
+            caps.per_domain_sinks,
+            parsed.sinks.get("ads.example.com") == Some(&IpAddr::from_str("0.0.0.0").unwrap())
+        );
+
+        let options = ParseOptions {
+            preserve_whitespace: true,
+            ..ParseOptions::default()
+        };
+        let parsed = parse_hosts_with("127.0.0.1\t\tlocalhost\n", &options).unwrap();
+        assert_eq!(
+            caps.preserve_whitespace,
+            matches!(&parsed.preserved_lines[..], [PreservedLine::LocalhostLine(line)] if line.contains("\t\t"))
+        );
+
+        let parsed =
+            parse_hosts("127.0.0.1 ads.example.com # added 2024-05-01 via adguard\n").unwrap();
+        assert_eq!(caps.addition_annotations, !parsed.annotations.is_empty());
+
+        let parsed = parse_hosts("127.0.0.1 *.example.com\n");
+        assert_eq!(caps.wildcards, parsed.is_ok_and(|p| !p.blocking.is_empty()));
+
+        let parsed = parse_hosts("||example.com^\n");
+        assert_eq!(
+            caps.adblock_syntax,
+            parsed.is_ok_and(|p| !p.blocking.is_empty())
+        );
+    }
+
+    #[test]
+    fn test_compact_preserved_lines() {
+        let original = "# Router\n\
+                        192.168.1.1 router\n\
+                        192.168.1.1\trouter\n\
+                        #\n\
+                        0.0.0.0\n\
+                        \n\
+                        \n\
+                        # === ADGUARD MANAGED START ===\n\
+                        127.0.0.1 localhost\n\
+                        127.0.0.1 localhost\n\
+                        # Ads\n\
+                        # Ads\n\
+                        10.0.0.1 nas.lan\n\
+                        10.0.0.1   nas.lan\n\
+                        # === ADGUARD MANAGED END ===\n";
+        let parsed = parse_hosts(original).unwrap();
+        let compacted = compact_preserved_lines(parsed.preserved_lines.clone());
+
+        let texts: Vec<String> = compacted
+            .iter()
+            .filter_map(PreservedLine::content)
+            .collect();
+        assert_eq!(
+            texts,
+            vec![
+                "# Router",
+                "192.168.1.1 router",
+                "",
+                "127.0.0.1 localhost",
+                "# Ads",
+                "10.0.0.1 nas.lan"
+            ]
+        );
+        assert!(compacted
+            .iter()
+            .any(|line| matches!(line, PreservedLine::ManagedRegion)));
+    }
+
+    #[test]
+    fn test_annotation_round_trip() {
+        let mut parsed =
+            parse_hosts("127.0.0.1 localhost\n0.0.0.0 ads.example.com tracker.example.com\n")
+                .unwrap();
+        parsed
+            .annotations
+            .insert("ads.example.com".into(), "2024-05-01".into());
+
+        let serialized = serialize_hosts(
+            &parsed.preserved_lines,
+            &parsed.blocking,
+            &parsed.sinks,
+            &parsed.annotations,
+            &parsed.disabled,
+            &SerializeOptions::default(),
+        );
+        assert!(serialized.contains("0.0.0.0 ads.example.com # added 2024-05-01 via adguard\n"));
+        assert!(serialized.contains("0.0.0.0 tracker.example.com\n"));
+
+        let reparsed = parse_hosts(&serialized).unwrap();
+        assert_eq!(reparsed.blocking, parsed.blocking);
+        assert_eq!(reparsed.annotations, parsed.annotations);
+        assert_eq!(
+            serialize_hosts(
+                &reparsed.preserved_lines,
+                &reparsed.blocking,
+                &reparsed.sinks,
+                &reparsed.annotations,
+                &reparsed.disabled,
+                &SerializeOptions::default()
+            ),
+            serialized
+        );
+    }
+
+    #[test]
+    fn test_trailing_dot_collapses() {
+        let content =
+            "0.0.0.0 ads.example.com.\n0.0.0.0 ads.example.com\n127.0.0.1 tracker.example.org.\n";
+        let parsed = parse_hosts(content).unwrap();
+        assert_eq!(parsed.blocking.len(), 2);
+        assert!(parsed.blocking.contains("ads.example.com"));
+        assert!(parsed.blocking.contains("tracker.example.org"));
+        assert!(parsed.sink_conflicts.is_empty());
+
+        let serialized = serialize_hosts(
+            &parsed.preserved_lines,
+            &parsed.blocking,
+            &parsed.sinks,
+            &parsed.annotations,
+            &parsed.disabled,
+            &SerializeOptions::default(),
+        );
+        assert!(!serialized.contains(".com."));
+        assert!(!serialized.contains(".org."));
+
+        let (listed, skipped) = parse_domain_list("ads.example.com.\nads.example.com\n");
+        assert_eq!(listed.len(), 1);
+        assert_eq!(skipped, 0);
+        assert!(listed.contains("ads.example.com"));
+    }
+
+    #[test]
+    fn test_domain_list_skips_invalid_tokens() {
+        let (listed, skipped) =
+            parse_domain_list("Ads.Example.com\nbad..name\n-leading.example\nexample.com/path\n");
+        assert_eq!(listed.len(), 1);
+        assert!(listed.contains("ads.example.com"));
+        assert_eq!(skipped, 3);
+    }
+
+    #[test]
+    fn test_disabled_round_trip() {
+        let original = format!(
+            "127.0.0.1 localhost\n{}\n0.0.0.0 ads.example.com\n{}0.0.0.0 tracker.example.org\n{}0.0.0.0 ads.example.com\n{}\n",
+            MANAGED_START, DISABLED_PREFIX, DISABLED_PREFIX, MANAGED_END
+        );
+        let parsed = parse_hosts(&original).unwrap();
+
+        // A domain both blocked and disabled counts as blocked
+        assert!(parsed.blocking.contains("ads.example.com"));
+        assert!(!parsed.blocking.contains("tracker.example.org"));
+        assert_eq!(
+            parsed
+                .disabled
+                .iter()
+                .map(|d| d.as_ref())
+                .collect::<Vec<_>>(),
+            vec!["tracker.example.org"]
+        );
+
+        let serialized = serialize_hosts(
+            &parsed.preserved_lines,
+            &parsed.blocking,
+            &parsed.sinks,
+            &parsed.annotations,
+            &parsed.disabled,
+            &SerializeOptions::default(),
+        );
+        assert!(serialized.contains("# disabled: 0.0.0.0 tracker.example.org\n"));
+        assert!(!serialized.contains("# disabled: 0.0.0.0 ads.example.com"));
+        assert_eq!(parse_hosts(&serialized).unwrap().disabled, parsed.disabled);
+    }
+
+    #[test]
+    fn test_custom_disabled_marker_round_trip() {
+        let marker: Arc<str> = Arc::from("#@");
+        let serialize_options = SerializeOptions {
+            disabled_marker: Some(marker.clone()),
+            ..SerializeOptions::default()
+        };
+        let parse_options = ParseOptions {
+            disabled_marker: Some(marker),
+            ..ParseOptions::default()
+        };
+        let blocking: BTreeSet<Arc<str>> = [Arc::from("ads.example.com")].into();
+        let disabled: BTreeSet<Arc<str>> = [Arc::from("tracker.example.org")].into();
+
+        let serialized = serialize_hosts(
+            &[PreservedLine::ManagedRegion],
+            &blocking,
+            &SinkMap::new(),
+            &AnnotationMap::new(),
+            &disabled,
+            &serialize_options,
+        );
+        assert!(serialized.contains("#@ 127.0.0.1 tracker.example.org\n"));
+        assert!(!serialized.contains(DISABLED_PREFIX));
+
+        let parsed = parse_hosts_with(&serialized, &parse_options).unwrap();
+        assert_eq!(parsed.disabled, disabled);
+        assert_eq!(parsed.blocking, blocking);
+
+        // Entries written under the default marker are still read
+        let legacy = format!(
+            "{}\n{}127.0.0.1 old.example.com\n#@ not an entry\n{}\n",
+            MANAGED_START, DISABLED_PREFIX, MANAGED_END
+        );
+        let parsed = parse_hosts_with(&legacy, &parse_options).unwrap();
+        assert!(parsed.disabled.contains("old.example.com"));
+        assert_eq!(parsed.disabled.len(), 1);
+
+        assert!(is_valid_disabled_marker("#"));
+        assert!(is_valid_disabled_marker("# !"));
+        assert!(!is_valid_disabled_marker("disabled"));
+        assert!(!is_valid_disabled_marker("# paused:"));
+        assert!(!is_valid_disabled_marker("#\n#"));
+    }
+
+    #[test]
+    fn test_header_round_trip() {
+        let options = SerializeOptions {
+            header: Some("Managed by adguard\n# do not edit below".into()),
+            ..SerializeOptions::default()
+        };
+        let parsed = parse_hosts("127.0.0.1 localhost\n0.0.0.0 ads.example.com\n").unwrap();
+        let serialize = |parsed: &ParsedHosts| {
+            serialize_hosts(
+                &parsed.preserved_lines,
+                &parsed.blocking,
+                &parsed.sinks,
+                &parsed.annotations,
+                &parsed.disabled,
+                &options,
+            )
+        };
+
+        let first = serialize(&parsed);
+        assert!(first.starts_with(&format!(
+            "{}\n# Managed by adguard\n# do not edit below\n{}\n",
+            HEADER_START, HEADER_END
+        )));
+
+        // The header is read back rather than kept as lines, so it isn't written twice
+        let reparsed = parse_hosts(&first).unwrap();
+        assert_eq!(
+            reparsed.header.as_deref(),
+            Some("# Managed by adguard\n# do not edit below")
+        );
+        assert!(!reparsed
+            .preserved_lines
+            .iter()
+            .any(|line| matches!(line, PreservedLine::Raw(text) | PreservedLine::Comment(text) if text.contains("adguard"))));
+        assert_eq!(serialize(&reparsed), first);
+        assert_eq!(reparsed.blocking, parsed.blocking);
+
+        // A header anywhere but the top is an ordinary comment
+        let moved = format!(
+            "0.0.0.0 ads.example.com\n{}\n{}\n",
+            HEADER_START, HEADER_END
+        );
+        assert_eq!(parse_hosts(&moved).unwrap().header, None);
+    }
+
+    #[test]
+    fn test_very_long_line_parses() {
+        // Hostname repetition is matched iteratively, so the parse tree stays
+        // flat however many names share a line
+        let hostnames: Vec<String> = (0..50_000)
+            .map(|i| format!("host{}.example.com", i))
+            .collect();
+        let line = format!("0.0.0.0 {} # generated", hostnames.join(" "));
+
+        let parsed = parse_hosts(&format!("127.0.0.1 localhost\n{}\n", line)).unwrap();
+        assert_eq!(parsed.blocking.len(), 50_000);
+
+        // Also through the line-by-line fallback taken when another line is invalid
+        let parsed = parse_hosts(&format!("{}\nnot a hosts line\n", line)).unwrap();
+        assert_eq!(parsed.blocking.len(), 50_000);
+        assert!(matches!(
+            parsed.preserved_lines.last(),
+            Some(PreservedLine::Invalid(_))
+        ));
+    }
+
+    #[test]
+    fn test_comments_move_to_top() {
+        let content = format!(
+            "# Static entries\n127.0.0.1 localhost\n\n# Printers\n192.168.1.20 printer\n{}\n127.0.0.1 ads.example.com\n{}\n# Dev\n10.0.0.5 dev.local\n",
+            MANAGED_START, MANAGED_END
+        );
+        let parsed = parse_hosts(&content).unwrap();
+
+        let as_is = normalize_layout(parsed.preserved_lines.clone(), LayoutStyle::AsIs);
+        assert_eq!(as_is.len(), parsed.preserved_lines.len());
+
+        let normalized = normalize_layout(parsed.preserved_lines, LayoutStyle::CommentsOnTop);
+        let lines: Vec<String> = normalized
+            .iter()
+            .filter_map(PreservedLine::content)
+            .collect();
+        assert_eq!(
+            lines,
+            [
+                "# Static entries",
+                "# Printers",
+                "# Dev",
+                "",
+                "127.0.0.1 localhost",
+                "192.168.1.20 printer",
+                "10.0.0.5 dev.local",
+            ]
+        );
+        // The managed region stays among the entries
+        assert!(matches!(normalized[6], PreservedLine::ManagedRegion));
+
+        assert_eq!(
+            LayoutStyle::from_str("comments-on-top").unwrap(),
+            LayoutStyle::CommentsOnTop
+        );
+        assert!(LayoutStyle::from_str("sorted").is_err());
+    }
+
+    #[test]
+    fn test_invalid_line_keeps_the_rest() {
+        let original = "127.0.0.1 localhost\n0.0.0.0 ads.example.com\n$$ garbage !!\n192.168.1.1 router\n127.0.0.1 tracker.example.org\n";
+        let parsed = parse_hosts(original).unwrap();
+
+        assert_eq!(
+            parsed
+                .blocking
+                .iter()
+                .map(|d| d.as_ref())
+                .collect::<Vec<_>>(),
+            ["ads.example.com", "tracker.example.org"]
+        );
+        assert!(parsed.preserved_lines.iter().any(
+            |line| matches!(line, PreservedLine::Invalid(text) if &**text == "$$ garbage !!")
+        ));
+        assert!(parsed
+            .preserved_lines
+            .iter()
+            .any(|line| line.mentions("router")));
+        assert_eq!(
+            parsed.sinks["ads.example.com"],
+            "0.0.0.0".parse::<IpAddr>().unwrap()
+        );
+
+        // The invalid line is written back as it was
+        let serialized = serialize_hosts(
+            &parsed.preserved_lines,
+            &parsed.blocking,
+            &parsed.sinks,
+            &parsed.annotations,
+            &parsed.disabled,
+            &SerializeOptions::default(),
+        );
+        assert!(serialized.contains("$$ garbage !!\n"));
+        assert_eq!(parse_hosts(&serialized).unwrap().blocking, parsed.blocking);
+
+        // An edit must still be valid syntax
+        assert!(parse_preserved_line("$$ garbage !!").is_err());
+    }
+
+    #[test]
+    fn test_block_single_label() {
+        let content = "127.0.0.1 localhost\n0.0.0.0 adserver\n0.0.0.0 ads.example.com\n";
+
+        // By default single-label names are local and stay preserved
+        let parsed = parse_hosts(content).unwrap();
+        assert!(!parsed.blocking.contains("adserver"));
+        assert!(parsed.blocking.contains("ads.example.com"));
+
+        let options = ParseOptions {
+            block_single_label: true,
+            ..ParseOptions::default()
+        };
+        let parsed = parse_hosts_with(content, &options).unwrap();
+        assert!(parsed.blocking.contains("adserver"));
+        assert!(parsed.blocking.contains("ads.example.com"));
+        assert!(!parsed.blocking.contains("localhost"));
+    }
+
+    #[test]
+    fn test_exactly_one_trailing_newline() {
+        let body = format!(
+            "# Local\n{}\n127.0.0.1 ads.example.com\n{}\n192.168.1.1 router",
+            MANAGED_START, MANAGED_END
+        );
+        let serialize = |content: &str, options: &SerializeOptions| {
+            let parsed = parse_hosts(content).unwrap();
+            serialize_hosts(
+                &parsed.preserved_lines,
+                &parsed.blocking,
+                &parsed.sinks,
+                &parsed.annotations,
+                &parsed.disabled,
+                options,
+            )
+        };
+
+        let canonical = format!("{}\n", body);
+        for content in [body.clone(), canonical.clone(), format!("{}\n\n\n", body)] {
+            assert_eq!(serialize(&content, &SerializeOptions::default()), canonical);
+        }
+        let options = SerializeOptions {
+            omit_trailing_newline: true,
+            ..SerializeOptions::default()
+        };
+        assert_eq!(serialize(&canonical, &options), body);
+        assert_eq!(serialize(&body, &options), body);
+    }
+
+    #[test]
+    fn test_network_entries() {
+        let content = "127.0.0.1 localhost\n::1 localhost ip6-localhost\n192.168.1.20 nas.lan nas\n# printer\n192.168.1.5 printer.lan\n192.168.1.20 nas.lan media.lan\n10.0.0.1 router # gateway\n0.0.0.0 ads.example.com\n";
+        let parsed = parse_hosts(content).unwrap();
+        let entries = network_entries(&parsed.preserved_lines);
+        let summary: Vec<String> = entries
+            .iter()
+            .map(|entry| format!("{} {}", entry.ip, entry.hostnames.join(" ")))
+            .collect();
+        // Sorted by address, IPv4 before IPv6; the blocked domain isn't listed
+        assert_eq!(
+            summary,
+            [
+                "10.0.0.1 router",
+                "127.0.0.1 localhost",
+                "192.168.1.5 printer.lan",
+                "192.168.1.20 nas.lan nas media.lan",
+                "::1 localhost ip6-localhost",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_configured_sink_and_preserved_ips() {
+        let content = "127.0.0.1 localhost\n0.0.0.0 ads.com\n127.0.1.1 myhost.lan\n";
+        let zero: IpAddr = "0.0.0.0".parse().unwrap();
+        let debian: IpAddr = "127.0.1.1".parse().unwrap();
+
+        let options = ParseOptions {
+            sink_ips: vec![zero],
+            ..ParseOptions::default()
+        };
+        let parsed = parse_hosts_with(content, &options).unwrap();
+        assert!(parsed.blocking.contains("ads.com"));
+        // Only listed sinks block; other loopback entries are kept
+        assert!(!parsed.blocking.contains("myhost.lan"));
+        assert!(parsed
+            .preserved_lines
+            .iter()
+            .any(|line| matches!(line, PreservedLine::NonLocalhostEntry(s) if s.as_ref() == "127.0.1.1 myhost.lan")));
+
+        // Preserving an address wins over the default of blocking every loopback one
+        let options = ParseOptions {
+            preserve_ips: vec![zero, debian],
+            ..ParseOptions::default()
+        };
+        let parsed = parse_hosts_with(content, &options).unwrap();
+        assert!(parsed.blocking.is_empty());
+        assert!(parsed.preserved_lines.iter().any(
+            |line| matches!(line, PreservedLine::NonLocalhostEntry(s) if s.as_ref() == "0.0.0.0 ads.com")
+        ));
+        assert!(!options.is_sink(&"8.8.8.8".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_round_trip_preserves_tab_alignment() {
+        let original = "# Local names\n127.0.0.1\tlocalhost\n::1\t\tlocalhost\tip6-localhost\n192.168.1.1\trouter\n127.0.0.1 ads.example.com\n";
+        let options = ParseOptions {
+            preserve_whitespace: true,
+            ..ParseOptions::default()
+        };
+
+        let parsed = parse_hosts_with(original, &options).unwrap();
+        assert_eq!(parsed.blocking.len(), 1);
+
+        let serialized = serialize_hosts(
+            &parsed.preserved_lines,
+            &parsed.blocking,
+            &parsed.sinks,
+            &parsed.annotations,
+            &parsed.disabled,
+            &SerializeOptions {
+                separator: Separator::Tab,
+                ..SerializeOptions::default()
+            },
+        );
+        assert!(serialized.contains("127.0.0.1\tlocalhost\n"));
+        assert!(serialized.contains("::1\t\tlocalhost\tip6-localhost\n"));
+        assert!(serialized.contains("192.168.1.1\trouter\n"));
+        assert!(serialized.contains("127.0.0.1\tads.example.com\n"));
+
+        // A second round trip is stable
+        let reparsed = parse_hosts_with(&serialized, &options).unwrap();
+        let reserialized = serialize_hosts(
+            &reparsed.preserved_lines,
+            &reparsed.blocking,
+            &reparsed.sinks,
+            &reparsed.annotations,
+            &reparsed.disabled,
+            &SerializeOptions {
+                separator: Separator::Tab,
+                ..SerializeOptions::default()
+            },
+        );
+        assert_eq!(serialized, reserialized);
+    }
 }
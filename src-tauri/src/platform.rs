@@ -1,3 +1,4 @@
+use crate::config::Theme;
 use std::path::{Path, PathBuf};
 use std::process::Command;
 use std::sync::Arc;
@@ -57,42 +58,228 @@ pub fn is_elevated() -> bool {
     }
 }
 
-/// Flush DNS cache using platform-specific command
-pub fn flush_dns() -> Result<(), anyhow::Error> {
+/// Query whether the OS is set to a dark or light appearance
+/// Falls back to dark when the setting can't be read
+pub fn system_theme() -> Theme {
+    let is_light = {
+        #[cfg(target_os = "windows")]
+        {
+            // AppsUseLightTheme is 0x0 for dark mode and 0x1 for light mode
+            command_stdout(
+                "reg",
+                &[
+                    "query",
+                    r"HKCU\Software\Microsoft\Windows\CurrentVersion\Themes\Personalize",
+                    "/v",
+                    "AppsUseLightTheme",
+                ],
+            )
+            .map(|output| output.trim_end().ends_with("0x1"))
+        }
+
+        #[cfg(target_os = "macos")]
+        {
+            // AppleInterfaceStyle is only set (to "Dark") in dark mode
+            Some(
+                command_stdout("defaults", &["read", "-g", "AppleInterfaceStyle"])
+                    .is_none_or(|style| !style.trim().eq_ignore_ascii_case("dark")),
+            )
+        }
+
+        #[cfg(target_os = "linux")]
+        {
+            // The freedesktop color scheme, or the GTK theme name on older desktops
+            command_stdout(
+                "gsettings",
+                &["get", "org.gnome.desktop.interface", "color-scheme"],
+            )
+            .filter(|scheme| scheme.contains("dark") || scheme.contains("light"))
+            .or_else(|| {
+                command_stdout(
+                    "gsettings",
+                    &["get", "org.gnome.desktop.interface", "gtk-theme"],
+                )
+            })
+            .map(|theme| !theme.to_lowercase().contains("dark"))
+        }
+
+        #[cfg(not(any(target_os = "windows", target_os = "linux", target_os = "macos")))]
+        {
+            None::<bool>
+        }
+    };
+
+    match is_light {
+        Some(true) => Theme::Light,
+        _ => Theme::Dark,
+    }
+}
+
+/// Run a command, returning its stdout if it succeeded
+#[cfg(any(target_os = "windows", target_os = "linux", target_os = "macos"))]
+fn command_stdout(program: &str, args: &[&str]) -> Option<String> {
+    let output = Command::new(program).args(args).output().ok()?;
+    output
+        .status
+        .success()
+        .then(|| String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// Command `flush_dns` uses to clear the DNS cache
+/// Each platform only has its own methods
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DnsFlushMethod {
+    /// `ipconfig /flushdns` (Windows)
     #[cfg(target_os = "windows")]
-    {
-        Command::new("ipconfig").arg("/flushdns").output()?;
+    Ipconfig,
+    /// `killall -HUP mDNSResponder` (macOS)
+    #[cfg(target_os = "macos")]
+    MdnsResponder,
+    /// `resolvectl flush-caches` (Linux with systemd-resolved)
+    #[cfg(target_os = "linux")]
+    Resolvectl,
+    /// `systemd-resolve --flush-caches` (Linux with an older systemd-resolved)
+    #[cfg(target_os = "linux")]
+    SystemdResolve,
+    /// `sudo -n service nscd restart` (Linux with nscd)
+    #[cfg(target_os = "linux")]
+    Nscd,
+}
+
+/// The methods `flush_dns` tries, in order
+const DNS_FLUSH_METHODS: &[DnsFlushMethod] = &[
+    #[cfg(target_os = "windows")]
+    DnsFlushMethod::Ipconfig,
+    #[cfg(target_os = "macos")]
+    DnsFlushMethod::MdnsResponder,
+    #[cfg(target_os = "linux")]
+    DnsFlushMethod::Resolvectl,
+    #[cfg(target_os = "linux")]
+    DnsFlushMethod::SystemdResolve,
+    #[cfg(target_os = "linux")]
+    DnsFlushMethod::Nscd,
+];
+
+impl DnsFlushMethod {
+    #[inline]
+    pub fn to_str(self) -> &'static str {
+        match self {
+            #[cfg(target_os = "windows")]
+            DnsFlushMethod::Ipconfig => "ipconfig",
+            #[cfg(target_os = "macos")]
+            DnsFlushMethod::MdnsResponder => "mdnsresponder",
+            #[cfg(target_os = "linux")]
+            DnsFlushMethod::Resolvectl => "resolvectl",
+            #[cfg(target_os = "linux")]
+            DnsFlushMethod::SystemdResolve => "systemd-resolve",
+            #[cfg(target_os = "linux")]
+            DnsFlushMethod::Nscd => "nscd",
+        }
+    }
+
+    /// Program run and its arguments
+    #[inline]
+    fn command(self) -> (&'static str, &'static [&'static str]) {
+        match self {
+            #[cfg(target_os = "windows")]
+            DnsFlushMethod::Ipconfig => ("ipconfig", &["/flushdns"]),
+            #[cfg(target_os = "macos")]
+            DnsFlushMethod::MdnsResponder => ("killall", &["-HUP", "mDNSResponder"]),
+            #[cfg(target_os = "linux")]
+            DnsFlushMethod::Resolvectl => ("resolvectl", &["flush-caches"]),
+            #[cfg(target_os = "linux")]
+            DnsFlushMethod::SystemdResolve => ("systemd-resolve", &["--flush-caches"]),
+            #[cfg(target_os = "linux")]
+            DnsFlushMethod::Nscd => ("sudo", &["-n", "service", "nscd", "restart"]),
+        }
+    }
+
+    /// Programs that must be installed for the method to work
+    #[inline]
+    fn requires(self) -> &'static [&'static str] {
+        match self {
+            #[cfg(target_os = "windows")]
+            DnsFlushMethod::Ipconfig => &["ipconfig"],
+            #[cfg(target_os = "macos")]
+            DnsFlushMethod::MdnsResponder => &["killall"],
+            #[cfg(target_os = "linux")]
+            DnsFlushMethod::Resolvectl => &["resolvectl"],
+            #[cfg(target_os = "linux")]
+            DnsFlushMethod::SystemdResolve => &["systemd-resolve"],
+            #[cfg(target_os = "linux")]
+            DnsFlushMethod::Nscd => &["sudo", "service", "nscd"],
+        }
     }
+}
 
+/// Which DNS flush `flush_dns` would use here, or None if no flush command is installed
+/// Only looks the commands up on `PATH`; nothing is run
+pub fn dns_flush_capability() -> Option<DnsFlushMethod> {
+    let path = std::env::var_os("PATH").unwrap_or_default();
+    installed_dns_flush_methods(&path).into_iter().next()
+}
+
+/// The flush methods whose commands are all in a `PATH`-style list, in the
+/// order `flush_dns` tries them
+fn installed_dns_flush_methods(path: &std::ffi::OsStr) -> Vec<DnsFlushMethod> {
+    DNS_FLUSH_METHODS
+        .iter()
+        .copied()
+        .filter(|method| {
+            method
+                .requires()
+                .iter()
+                .all(|program| find_in_path(program, path).is_some())
+        })
+        .collect()
+}
+
+/// First file named `program` in the directories of a `PATH`-style list
+fn find_in_path(program: &str, path: &std::ffi::OsStr) -> Option<PathBuf> {
+    let filename = format!("{}{}", program, std::env::consts::EXE_SUFFIX);
+    std::env::split_paths(path)
+        .map(|dir| dir.join(&filename))
+        .find(|candidate| candidate.is_file())
+}
+
+/// Flush DNS cache using platform-specific command
+/// Each installed method is tried in turn until one succeeds; fails if none
+/// is installed or all of them fail
+pub fn flush_dns() -> Result<(), anyhow::Error> {
     #[cfg(target_os = "macos")]
     {
-        // Try dscacheutil first, fallback to killall
+        // Clears the directory service cache too, if it's there
         let _ = Command::new("dscacheutil").arg("-flushcache").output();
-
-        Command::new("killall")
-            .arg("-HUP")
-            .arg("mDNSResponder")
-            .output()?;
     }
 
-    #[cfg(target_os = "linux")]
-    {
-        // Try systemd-resolve first
-        let result = Command::new("systemd-resolve")
-            .arg("--flush-caches")
-            .output();
-
-        if result.is_err() {
-            // Fallback: try restarting nscd
-            let _ = Command::new("sudo")
-                .arg("service")
-                .arg("nscd")
-                .arg("restart")
-                .output();
+    flush_dns_in(&std::env::var_os("PATH").unwrap_or_default())
+}
+
+/// `flush_dns` with the commands looked up in a `PATH`-style list
+fn flush_dns_in(path: &std::ffi::OsStr) -> Result<(), anyhow::Error> {
+    let mut failures = Vec::new();
+    for method in installed_dns_flush_methods(path) {
+        let (program, args) = method.command();
+        let Some(program) = find_in_path(program, path) else {
+            continue;
+        };
+        match Command::new(program).args(args).output() {
+            Ok(output) if output.status.success() => return Ok(()),
+            Ok(output) => {
+                failures.push(format!("{} exited with {}", method.to_str(), output.status))
+            }
+            Err(e) => failures.push(format!("{}: {}", method.to_str(), e)),
         }
     }
 
-    Ok(())
+    if failures.is_empty() {
+        Err(anyhow::anyhow!("No DNS flush command is installed"))
+    } else {
+        Err(anyhow::anyhow!(
+            "No DNS flush command succeeded ({})",
+            failures.join("; ")
+        ))
+    }
 }
 
 #[cfg(test)]
@@ -104,4 +291,72 @@ mod tests {
         let path = default_hosts_file_path();
         assert!(!path.as_os_str().is_empty());
     }
+
+    #[test]
+    fn test_find_in_path() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let bin = temp_dir.path().join("bin");
+        std::fs::create_dir(&bin).unwrap();
+        let program = format!("systemd-resolve{}", std::env::consts::EXE_SUFFIX);
+        std::fs::write(bin.join(&program), "").unwrap();
+
+        let path = std::env::join_paths([temp_dir.path().join("missing"), bin.clone()]).unwrap();
+        assert_eq!(
+            find_in_path("systemd-resolve", &path),
+            Some(bin.join(&program))
+        );
+        assert_eq!(find_in_path("nscd", &path), None);
+        // Directories don't count as commands
+        assert_eq!(find_in_path("bin", temp_dir.path().as_os_str()), None);
+    }
+
+    /// A directory of fake commands that exit with `code`
+    #[cfg(target_os = "linux")]
+    fn fake_commands(dir: &Path, programs: &[(&str, i32)]) {
+        use std::os::unix::fs::PermissionsExt;
+        for (program, code) in programs {
+            let path = dir.join(program);
+            std::fs::write(&path, format!("#!/bin/sh\nexit {}\n", code)).unwrap();
+            std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_dns_flush_methods_on_path() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let path = temp_dir.path().as_os_str();
+        assert_eq!(installed_dns_flush_methods(path), []);
+        assert!(flush_dns_in(path).is_err());
+
+        // nscd is only restarted through sudo and service
+        fake_commands(temp_dir.path(), &[("nscd", 0), ("service", 0)]);
+        assert_eq!(installed_dns_flush_methods(path), []);
+        fake_commands(temp_dir.path(), &[("sudo", 0), ("systemd-resolve", 1)]);
+        assert_eq!(
+            installed_dns_flush_methods(path),
+            [DnsFlushMethod::SystemdResolve, DnsFlushMethod::Nscd]
+        );
+
+        // A failing method falls through to the next
+        assert!(flush_dns_in(path).is_ok());
+        fake_commands(temp_dir.path(), &[("sudo", 1), ("resolvectl", 1)]);
+        assert_eq!(
+            installed_dns_flush_methods(path)[0],
+            DnsFlushMethod::Resolvectl
+        );
+        let error = flush_dns_in(path).unwrap_err().to_string();
+        assert!(error.contains("resolvectl exited"));
+        assert!(error.contains("nscd exited"));
+    }
+
+    #[test]
+    fn test_dns_flush_capability_is_a_pure_probe() {
+        // Probing twice gives the same answer, since nothing is run or changed
+        let method = dns_flush_capability();
+        assert_eq!(dns_flush_capability(), method);
+        if let Some(method) = method {
+            assert!(!method.to_str().is_empty());
+        }
+    }
 }
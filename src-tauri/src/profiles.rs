@@ -0,0 +1,157 @@
+use crate::config::Config;
+use crate::sources::Source;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// Profile holding the setup in place before any other profile was created
+pub const DEFAULT_PROFILE: &str = "default";
+
+/// Directory next to the config file holding one directory per profile
+pub const PROFILES_DIR: &str = "profiles";
+
+/// Longest profile name accepted
+const MAX_PROFILE_NAME_LEN: usize = 64;
+
+/// A named blocklist setup: its own blocked domains, sources and hosts file
+///
+/// The blocked domains live in the profile's `db.json`; the rest is kept in
+/// `profile.ini`, written in the config file's format.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Profile {
+    pub name: String,
+    /// Hosts file the profile writes to, or the platform default if None
+    pub host_file_path: Option<Arc<Path>>,
+    pub sources: Vec<Source>,
+}
+
+impl Profile {
+    /// Read a profile's settings from its directory
+    pub fn load(profiles_dir: &Path, name: &str) -> Result<Self, anyhow::Error> {
+        validate_profile_name(name)?;
+        let path = profile_dir(profiles_dir, name).join("profile.ini");
+        if !path.exists() {
+            return Err(anyhow::anyhow!("No profile named {}", name));
+        }
+        // Only the keys a profile owns are read; anything else is ignored
+        let settings = Config::load_from_file(&path)?;
+        Ok(Profile {
+            name: name.to_string(),
+            host_file_path: settings.host_file_path,
+            sources: settings.sources,
+        })
+    }
+
+    /// Write the profile's settings, creating its directory
+    pub fn save(&self, profiles_dir: &Path) -> Result<(), anyhow::Error> {
+        let dir = profile_dir(profiles_dir, &self.name);
+        fs::create_dir_all(&dir)?;
+        fs::write(dir.join("profile.ini"), self.to_ini())?;
+        Ok(())
+    }
+
+    /// Settings as written to `profile.ini`, a subset of the config file format
+    pub fn to_ini(&self) -> String {
+        let mut result = format!("# AdBlock Manager Profile: {}\n\n", self.name);
+        result.push_str("[paths]\n");
+        if let Some(ref host_path) = self.host_file_path {
+            result.push_str(&format!("host_file_path = {}\n", host_path.display()));
+        }
+        result.push('\n');
+
+        if !self.sources.is_empty() {
+            result.push_str("[sources]\n");
+            for (index, source) in self.sources.iter().enumerate() {
+                result.push_str(&format!("source.{} = {}\n", index + 1, source.to_ini()));
+            }
+            result.push('\n');
+        }
+        result
+    }
+}
+
+/// Directory of a named profile
+#[inline]
+pub fn profile_dir(profiles_dir: &Path, name: &str) -> PathBuf {
+    profiles_dir.join(name)
+}
+
+/// State database of a named profile
+#[inline]
+pub fn profile_db_path(profiles_dir: &Path, name: &str) -> PathBuf {
+    profile_dir(profiles_dir, name).join("db.json")
+}
+
+/// Check a profile name is usable as a directory name on every platform
+pub fn validate_profile_name(name: &str) -> Result<(), anyhow::Error> {
+    let valid = !name.is_empty()
+        && name.len() <= MAX_PROFILE_NAME_LEN
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    if !valid {
+        return Err(anyhow::anyhow!(
+            "Invalid profile name {:?}: use up to {} letters, digits, '-' or '_'",
+            name,
+            MAX_PROFILE_NAME_LEN
+        ));
+    }
+    Ok(())
+}
+
+/// Names of the profiles saved under `profiles_dir`, sorted
+/// A missing directory has no profiles
+pub fn list_profiles(profiles_dir: &Path) -> Result<Vec<String>, anyhow::Error> {
+    if !profiles_dir.exists() {
+        return Ok(Vec::new());
+    }
+    let mut names = Vec::new();
+    for entry in fs::read_dir(profiles_dir)? {
+        let entry = entry?;
+        let Some(name) = entry.file_name().to_str().map(str::to_string) else {
+            continue;
+        };
+        if validate_profile_name(&name).is_ok() && entry.path().join("profile.ini").exists() {
+            names.push(name);
+        }
+    }
+    names.sort_unstable();
+    Ok(names)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_profile_round_trip() {
+        let temp_dir = TempDir::new().unwrap();
+        let profile = Profile {
+            name: "work".to_string(),
+            host_file_path: Some(Path::new("/tmp/work-hosts").into()),
+            sources: vec![Source::from_ini("https://example.com/hosts disabled").unwrap()],
+        };
+        profile.save(temp_dir.path()).unwrap();
+        assert_eq!(Profile::load(temp_dir.path(), "work").unwrap(), profile);
+        assert!(Profile::load(temp_dir.path(), "home").is_err());
+
+        // Stray files and invalid names aren't profiles
+        fs::create_dir_all(temp_dir.path().join("empty")).unwrap();
+        fs::write(temp_dir.path().join("notes.txt"), "").unwrap();
+        assert_eq!(list_profiles(temp_dir.path()).unwrap(), ["work"]);
+        assert!(list_profiles(&temp_dir.path().join("missing"))
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn test_profile_names() {
+        for name in ["work", "home-2", "my_profile"] {
+            assert!(validate_profile_name(name).is_ok(), "{}", name);
+        }
+        for name in ["", "..", "a/b", "with space", &"x".repeat(65)] {
+            assert!(validate_profile_name(name).is_err(), "{}", name);
+        }
+    }
+}
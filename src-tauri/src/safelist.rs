@@ -0,0 +1,68 @@
+/// Bundled "never block" list of critical infrastructure, sorted for binary search
+///
+/// Entries cover their subdomains, so only domains whose every subdomain is
+/// critical (OS updates, certificate checks, banks) are listed; a company's
+/// whole domain is left out when it also hosts telemetry.
+const SAFE_DOMAINS: &[&str] = &[
+    "archive.ubuntu.com",
+    "bankofamerica.com",
+    "barclays.co.uk",
+    "capitalone.com",
+    "chase.com",
+    "citi.com",
+    "crl.microsoft.com",
+    "deb.debian.org",
+    "delivery.mp.microsoft.com",
+    "download.microsoft.com",
+    "hsbc.com",
+    "lencr.org",
+    "mesu.apple.com",
+    "mirrors.fedoraproject.org",
+    "ocsp.apple.com",
+    "ocsp.digicert.com",
+    "ocsp.pki.goog",
+    "paypal.com",
+    "security.debian.org",
+    "security.ubuntu.com",
+    "swcdn.apple.com",
+    "swscan.apple.com",
+    "update.microsoft.com",
+    "updates.cdn-apple.com",
+    "wellsfargo.com",
+    "windowsupdate.com",
+];
+
+/// Check whether a hostname is on the bundled safe list, or under a listed domain
+pub fn is_safe_listed(hostname: &str) -> bool {
+    let hostname = hostname.trim_end_matches('.').to_ascii_lowercase();
+    let mut suffix = hostname.as_str();
+    loop {
+        if SAFE_DOMAINS.binary_search(&suffix).is_ok() {
+            return true;
+        }
+        match suffix.split_once('.') {
+            Some((_, parent)) => suffix = parent,
+            None => return false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dataset_is_sorted() {
+        assert!(SAFE_DOMAINS.windows(2).all(|pair| pair[0] < pair[1]));
+    }
+
+    #[test]
+    fn test_is_safe_listed() {
+        assert!(is_safe_listed("windowsupdate.com"));
+        assert!(is_safe_listed("Download.WindowsUpdate.com."));
+        assert!(is_safe_listed("secure.chase.com"));
+        assert!(!is_safe_listed("notchase.com"));
+        assert!(!is_safe_listed("metrics.apple.com"));
+        assert!(!is_safe_listed("ads.example.com"));
+    }
+}
@@ -0,0 +1,148 @@
+use crate::state::AppState;
+use std::fs;
+use std::future::Future;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// File next to the state database remembering a scheduled commit
+pub const SCHEDULE_FILE: &str = "scheduled_commit.json";
+
+/// File next to the state database remembering when a pause of blocking ends
+pub const PAUSE_FILE: &str = "paused_until.json";
+
+/// Seconds since the Unix epoch
+#[inline]
+pub fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or(0)
+}
+
+/// Time left until `at` (seconds since the Unix epoch), zero if it has passed
+#[inline]
+pub fn time_until(at: u64) -> Duration {
+    (UNIX_EPOCH + Duration::from_secs(at))
+        .duration_since(SystemTime::now())
+        .unwrap_or(Duration::ZERO)
+}
+
+/// Read a saved schedule; None if there is none
+pub fn load_schedule(path: &Path) -> Result<Option<u64>, anyhow::Error> {
+    if !path.exists() {
+        return Ok(None);
+    }
+    let schedule: serde_json::Value = serde_json::from_str(&fs::read_to_string(path)?)?;
+    schedule
+        .get("at")
+        .and_then(|v| v.as_u64())
+        .map(Some)
+        .ok_or_else(|| anyhow::anyhow!("Scheduled commit has no time"))
+}
+
+/// Remember a commit scheduled for `at`
+pub fn save_schedule(path: &Path, at: u64) -> Result<(), anyhow::Error> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(path, serde_json::json!({ "at": at }).to_string())?;
+    Ok(())
+}
+
+/// Forget a saved schedule; a missing file is already forgotten
+pub fn remove_schedule(path: &Path) -> Result<(), anyhow::Error> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+/// Wait until `at`, then run `commit` if the commit scheduled for `at` is
+/// still pending; returns None when it was cancelled or replaced meanwhile
+pub async fn run_at<F, Fut, T>(state: &AppState, at: u64, commit: F) -> Option<T>
+where
+    F: FnOnce() -> Fut,
+    Fut: Future<Output = T>,
+{
+    tokio::time::sleep(time_until(at)).await;
+    if !state.take_scheduled_commit_if(at) {
+        return None;
+    }
+    Some(commit().await)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_scheduled_commit_fires() {
+        let state = AppState::new(Config::default());
+        let at = unix_now() + 1;
+        state.schedule_commit(at).unwrap();
+
+        let fired = AtomicBool::new(false);
+        let result = tauri::async_runtime::block_on(run_at(&state, at, || async {
+            fired.store(true, Ordering::SeqCst);
+        }));
+        assert!(result.is_some());
+        assert!(fired.load(Ordering::SeqCst));
+        assert!(unix_now() >= at);
+        assert_eq!(*state.scheduled_commit.lock(), None);
+    }
+
+    #[test]
+    fn test_cancel_prevents_scheduled_commit() {
+        let state = AppState::new(Config::default());
+        let at = unix_now();
+        state.schedule_commit(at).unwrap();
+        assert!(state.cancel_scheduled_commit().unwrap());
+        assert!(!state.cancel_scheduled_commit().unwrap());
+
+        let fired = AtomicBool::new(false);
+        let result = tauri::async_runtime::block_on(run_at(&state, at, || async {
+            fired.store(true, Ordering::SeqCst);
+        }));
+        assert!(result.is_none());
+        assert!(!fired.load(Ordering::SeqCst));
+
+        // A newer schedule replaces the older one's task
+        state.schedule_commit(at).unwrap();
+        state.schedule_commit(at + 60).unwrap();
+        let result = tauri::async_runtime::block_on(run_at(&state, at, || async {}));
+        assert!(result.is_none());
+    }
+
+    #[test]
+    fn test_schedule_survives_restart() {
+        let temp_dir = TempDir::new().unwrap();
+        let db_path = temp_dir.path().join("db.json");
+        let hosts_path = temp_dir.path().join("hosts");
+        fs::write(&hosts_path, "127.0.0.1 localhost\n").unwrap();
+        let config = Config {
+            host_file_path: Some(hosts_path.as_path().into()),
+            ..Config::default()
+        };
+
+        let state = AppState::new(config.clone());
+        state.set_db_path(db_path.as_path().into());
+        state.add_block("ads.example.com");
+        let at = unix_now() + 3600;
+        state.schedule_commit(at).unwrap();
+
+        // The unsaved change was stored with the schedule
+        let restarted = AppState::new(config);
+        restarted.load_db(&db_path).unwrap();
+        restarted.set_db_path(db_path.as_path().into());
+        assert_eq!(restarted.reinstate_scheduled_commit().unwrap(), Some(at));
+        assert!(restarted.get_all_blocks().contains("ads.example.com"));
+        assert_eq!(restarted.pending_counts(), (1, 0));
+
+        assert!(restarted.cancel_scheduled_commit().unwrap());
+        assert!(!temp_dir.path().join(SCHEDULE_FILE).exists());
+        assert_eq!(restarted.reinstate_scheduled_commit().unwrap(), None);
+    }
+}
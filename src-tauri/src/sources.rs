@@ -0,0 +1,258 @@
+use crate::import::parse_sink;
+use std::net::IpAddr;
+
+/// A blocklist subscribed to by URL
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Source {
+    pub url: String,
+    /// Sink the list's domains are written under instead of their own
+    pub sink: Option<IpAddr>,
+    pub enabled: bool,
+}
+
+impl Source {
+    /// Parse a `[sources]` value: the URL, then an optional sink and "disabled"
+    pub fn from_ini(value: &str) -> Result<Self, anyhow::Error> {
+        let mut tokens = value.split_whitespace();
+        let url = tokens
+            .next()
+            .ok_or_else(|| anyhow::anyhow!("Source has no URL"))?;
+        validate_source_url(url)?;
+
+        let mut source = Source {
+            url: url.to_string(),
+            sink: None,
+            enabled: true,
+        };
+        for token in tokens {
+            match token {
+                "enabled" => source.enabled = true,
+                "disabled" => source.enabled = false,
+                sink => source.sink = Some(parse_sink(sink)?),
+            }
+        }
+        Ok(source)
+    }
+
+    /// Value written under `[sources]`, read back by `from_ini`
+    pub fn to_ini(&self) -> String {
+        let mut value = self.url.clone();
+        if let Some(sink) = self.sink {
+            value.push_str(&format!(" {}", sink));
+        }
+        if !self.enabled {
+            value.push_str(" disabled");
+        }
+        value
+    }
+}
+
+/// Check a source URL is an http(s) URL with a host
+pub fn validate_source_url(url: &str) -> Result<(), anyhow::Error> {
+    let parsed =
+        reqwest::Url::parse(url).map_err(|e| anyhow::anyhow!("Invalid URL {}: {}", url, e))?;
+    if !matches!(parsed.scheme(), "http" | "https") || parsed.host_str().is_none() {
+        return Err(anyhow::anyhow!("Not an http(s) URL: {}", url));
+    }
+    Ok(())
+}
+
+/// Read a bundle: a JSON array of `{url, sink, enabled}` objects
+/// `sink` and `enabled` are optional; any invalid source rejects the bundle
+pub fn parse_bundle(content: &str) -> Result<Vec<Source>, anyhow::Error> {
+    let bundle: serde_json::Value = serde_json::from_str(content)?;
+    let entries = bundle
+        .as_array()
+        .ok_or_else(|| anyhow::anyhow!("A sources bundle must be a JSON array"))?;
+
+    entries
+        .iter()
+        .enumerate()
+        .map(|(index, entry)| {
+            let url = entry
+                .get("url")
+                .and_then(|v| v.as_str())
+                .ok_or_else(|| anyhow::anyhow!("Source {} has no url", index + 1))?
+                .trim();
+            validate_source_url(url)?;
+            let sink = match entry.get("sink").and_then(|v| v.as_str()) {
+                Some(sink) if !sink.trim().is_empty() => Some(parse_sink(sink)?),
+                _ => None,
+            };
+            Ok(Source {
+                url: url.to_string(),
+                sink,
+                enabled: entry
+                    .get("enabled")
+                    .and_then(|v| v.as_bool())
+                    .unwrap_or(true),
+            })
+        })
+        .collect()
+}
+
+/// Bundle of the given sources, as read by `parse_bundle`
+pub fn bundle_json(sources: &[Source]) -> serde_json::Value {
+    sources
+        .iter()
+        .map(|source| {
+            serde_json::json!({
+                "url": source.url,
+                "sink": source.sink.map(|sink| sink.to_string()),
+                "enabled": source.enabled,
+            })
+        })
+        .collect()
+}
+
+/// Add the incoming sources whose URL isn't listed yet, in order
+/// Returns how many were added
+pub fn merge_sources(sources: &mut Vec<Source>, incoming: Vec<Source>) -> usize {
+    let before = sources.len();
+    for source in incoming {
+        if !sources.iter().any(|existing| existing.url == source.url) {
+            sources.push(source);
+        }
+    }
+    sources.len() - before
+}
+
+/// Sources to download for the given URLs, or every enabled subscribed source
+/// when none are given
+/// A given URL that is subscribed keeps the subscription's sink.
+pub fn sources_to_fetch(
+    urls: Vec<String>,
+    subscribed: &[Source],
+) -> Result<Vec<Source>, anyhow::Error> {
+    if urls.is_empty() {
+        let enabled: Vec<Source> = subscribed
+            .iter()
+            .filter(|source| source.enabled)
+            .cloned()
+            .collect();
+        if enabled.is_empty() {
+            return Err(anyhow::anyhow!(
+                "No URLs given and no enabled sources subscribed"
+            ));
+        }
+        return Ok(enabled);
+    }
+    Ok(urls
+        .into_iter()
+        .map(|url| Source {
+            sink: subscribed
+                .iter()
+                .find(|source| source.url == url)
+                .and_then(|source| source.sink),
+            url,
+            enabled: true,
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_import_bundle_skips_duplicates() {
+        let mut sources = vec![Source {
+            url: "https://example.com/hosts".to_string(),
+            sink: None,
+            enabled: true,
+        }];
+        let bundle = r#"[
+            {"url": "https://example.com/hosts", "enabled": false},
+            {"url": "https://lists.example.org/ads.txt", "sink": "0.0.0.0"},
+            {"url": "https://lists.example.org/ads.txt"},
+            {"url": "http://mirror.example.net/trackers", "enabled": false}
+        ]"#;
+
+        let added = merge_sources(&mut sources, parse_bundle(bundle).unwrap());
+        assert_eq!(added, 2);
+        assert_eq!(
+            sources,
+            [
+                Source {
+                    url: "https://example.com/hosts".to_string(),
+                    sink: None,
+                    enabled: true,
+                },
+                Source {
+                    url: "https://lists.example.org/ads.txt".to_string(),
+                    sink: Some("0.0.0.0".parse().unwrap()),
+                    enabled: true,
+                },
+                Source {
+                    url: "http://mirror.example.net/trackers".to_string(),
+                    sink: None,
+                    enabled: false,
+                },
+            ]
+        );
+
+        // An export reads back to the same sources
+        let exported = bundle_json(&sources).to_string();
+        assert_eq!(parse_bundle(&exported).unwrap(), sources);
+    }
+
+    #[test]
+    fn test_sources_to_fetch() {
+        let subscribed = [
+            Source::from_ini("https://example.com/hosts 0.0.0.0").unwrap(),
+            Source::from_ini("https://lists.example.org/ads.txt disabled").unwrap(),
+            Source::from_ini("http://mirror.example.net/trackers").unwrap(),
+        ];
+
+        // Without URLs, the enabled subscriptions
+        let urls: Vec<String> = sources_to_fetch(Vec::new(), &subscribed)
+            .unwrap()
+            .into_iter()
+            .map(|source| source.url)
+            .collect();
+        assert_eq!(
+            urls,
+            [
+                "https://example.com/hosts",
+                "http://mirror.example.net/trackers"
+            ]
+        );
+        assert!(sources_to_fetch(Vec::new(), &subscribed[1..2]).is_err());
+
+        // Given URLs are fetched as given, with a subscription's sink
+        let fetched = sources_to_fetch(
+            vec![
+                "https://example.com/hosts".to_string(),
+                "https://other.example/list".to_string(),
+            ],
+            &subscribed,
+        )
+        .unwrap();
+        assert_eq!(fetched[0].sink, Some("0.0.0.0".parse().unwrap()));
+        assert_eq!(fetched[1].sink, None);
+    }
+
+    #[test]
+    fn test_bundle_validation() {
+        assert!(parse_bundle(r#"{"url": "https://example.com"}"#).is_err());
+        assert!(parse_bundle(r#"[{"url": "ftp://example.com/hosts"}]"#).is_err());
+        assert!(parse_bundle(r#"[{"url": "not a url"}]"#).is_err());
+        assert!(parse_bundle(r#"[{"sink": "0.0.0.0"}]"#).is_err());
+        assert!(parse_bundle(r#"[{"url": "https://example.com", "sink": "8.8.8.8"}]"#).is_err());
+        assert!(parse_bundle("[]").unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_ini_round_trip() {
+        for value in [
+            "https://example.com/hosts",
+            "https://example.com/hosts 0.0.0.0",
+            "https://example.com/hosts disabled",
+            "https://example.com/hosts ::1 disabled",
+        ] {
+            assert_eq!(Source::from_ini(value).unwrap().to_ini(), value);
+        }
+        assert!(Source::from_ini("").is_err());
+        assert!(Source::from_ini("https://example.com/hosts 10.0.0.1").is_err());
+    }
+}
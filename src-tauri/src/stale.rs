@@ -0,0 +1,205 @@
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Resolver queried for stale domains
+///
+/// A public resolver, since the system's own (e.g. systemd-resolved) answers
+/// from the hosts file and would report every blocked domain as existing.
+pub const DEFAULT_STALE_RESOLVER: &str = "1.1.1.1:53";
+
+/// Most domains checked in one run, whatever sample size is asked for
+pub const MAX_STALE_SAMPLE: usize = 500;
+
+/// Pause between queries, keeping a run to about 20 queries a second
+pub const STALE_QUERY_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Time allowed for each answer
+const STALE_QUERY_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// What the resolver said about a name
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DnsAnswer {
+    /// The name exists, even if it has no A record
+    Exists,
+    /// NXDOMAIN: the name doesn't exist at all
+    NxDomain,
+}
+
+/// Result of a stale domain check
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct StaleReport {
+    /// Domains queried
+    pub checked: usize,
+    /// Domains the resolver says don't exist
+    pub stale: Vec<Arc<str>>,
+    /// Queries that failed or timed out, which say nothing either way
+    pub errors: usize,
+}
+
+/// Pick up to `limit` domains spread evenly over the sorted list
+pub fn sample_domains<'a>(
+    domains: impl ExactSizeIterator<Item = &'a Arc<str>>,
+    limit: usize,
+) -> Vec<Arc<str>> {
+    let total = domains.len();
+    let limit = limit.min(MAX_STALE_SAMPLE);
+    if limit == 0 {
+        return Vec::new();
+    }
+    let stride = total.div_ceil(limit).max(1);
+    domains.step_by(stride).take(limit).cloned().collect()
+}
+
+/// Query each domain through `resolve`, pausing `interval` between queries
+pub fn find_stale_with<F>(domains: &[Arc<str>], interval: Duration, resolve: F) -> StaleReport
+where
+    F: Fn(&str) -> Result<DnsAnswer, anyhow::Error>,
+{
+    let mut report = StaleReport::default();
+    for (index, domain) in domains.iter().enumerate() {
+        if index > 0 && !interval.is_zero() {
+            std::thread::sleep(interval);
+        }
+        report.checked += 1;
+        match resolve(domain) {
+            Ok(DnsAnswer::NxDomain) => report.stale.push(domain.clone()),
+            Ok(DnsAnswer::Exists) => {}
+            Err(e) => {
+                tracing::debug!("Stale check for {} failed: {}", domain, e);
+                report.errors += 1;
+            }
+        }
+    }
+    report
+}
+
+/// Ask a DNS server over UDP whether a name exists
+pub fn query_dns(server: SocketAddr, hostname: &str) -> Result<DnsAnswer, anyhow::Error> {
+    let id = RandomState::new().build_hasher().finish() as u16;
+    let query = build_query(id, hostname)?;
+
+    let bind: SocketAddr = if server.is_ipv4() {
+        ([0, 0, 0, 0], 0).into()
+    } else {
+        ([0u16; 8], 0).into()
+    };
+    let socket = UdpSocket::bind(bind)?;
+    socket.set_read_timeout(Some(STALE_QUERY_TIMEOUT))?;
+    socket.connect(server)?;
+    socket.send(&query)?;
+
+    let mut response = [0u8; 512];
+    let len = socket.recv(&mut response)?;
+    parse_response(id, &response[..len])
+}
+
+/// A recursive query for the name's A record
+fn build_query(id: u16, hostname: &str) -> Result<Vec<u8>, anyhow::Error> {
+    let mut query = Vec::with_capacity(hostname.len() + 18);
+    query.extend_from_slice(&id.to_be_bytes());
+    // Flags: recursion desired; one question, no other records
+    query.extend_from_slice(&[0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0]);
+    for label in hostname.trim_end_matches('.').split('.') {
+        if label.is_empty() || label.len() > 63 || !label.is_ascii() {
+            return Err(anyhow::anyhow!("Not a valid DNS name: {}", hostname));
+        }
+        query.push(label.len() as u8);
+        query.extend_from_slice(label.as_bytes());
+    }
+    query.push(0);
+    // QTYPE A, QCLASS IN
+    query.extend_from_slice(&[0, 1, 0, 1]);
+    Ok(query)
+}
+
+/// Read the response code of the answer to query `id`
+fn parse_response(id: u16, response: &[u8]) -> Result<DnsAnswer, anyhow::Error> {
+    if response.len() < 12 {
+        return Err(anyhow::anyhow!("Truncated DNS response"));
+    }
+    if u16::from_be_bytes([response[0], response[1]]) != id || response[2] & 0x80 == 0 {
+        return Err(anyhow::anyhow!("DNS response doesn't answer the query"));
+    }
+    match response[3] & 0x0f {
+        0 => Ok(DnsAnswer::Exists),
+        3 => Ok(DnsAnswer::NxDomain),
+        rcode => Err(anyhow::anyhow!(
+            "DNS server answered with error code {}",
+            rcode
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn domains(names: &[&str]) -> Vec<Arc<str>> {
+        names.iter().map(|name| Arc::from(*name)).collect()
+    }
+
+    #[test]
+    fn test_find_stale_with_mock_resolver() {
+        let sample = domains(&[
+            "ads.example.com",
+            "gone.example.invalid",
+            "flaky.example.net",
+        ]);
+        let report = find_stale_with(&sample, Duration::ZERO, |domain| match domain {
+            "gone.example.invalid" => Ok(DnsAnswer::NxDomain),
+            "flaky.example.net" => Err(anyhow::anyhow!("timed out")),
+            _ => Ok(DnsAnswer::Exists),
+        });
+
+        assert_eq!(
+            report,
+            StaleReport {
+                checked: 3,
+                stale: domains(&["gone.example.invalid"]),
+                errors: 1,
+            }
+        );
+    }
+
+    #[test]
+    fn test_query_dns_reads_nxdomain() {
+        // A mock server answering NXDOMAIN for one name and NOERROR otherwise
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let address = server.local_addr().unwrap();
+        let handle = std::thread::spawn(move || {
+            for _ in 0..2 {
+                let mut query = [0u8; 512];
+                let (len, from) = server.recv_from(&mut query).unwrap();
+                let mut response = query[..len].to_vec();
+                let nxdomain = response.windows(4).any(|window| window == b"gone");
+                response[2] |= 0x80;
+                response[3] = if nxdomain { 0x83 } else { 0x80 };
+                server.send_to(&response, from).unwrap();
+            }
+        });
+
+        assert_eq!(
+            query_dns(address, "gone.example.invalid").unwrap(),
+            DnsAnswer::NxDomain
+        );
+        assert_eq!(
+            query_dns(address, "ads.example.com").unwrap(),
+            DnsAnswer::Exists
+        );
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn test_sample_domains() {
+        let all: Vec<Arc<str>> = (0..10).map(|i| Arc::from(format!("d{}.com", i))).collect();
+        assert_eq!(
+            sample_domains(all.iter(), 3),
+            domains(&["d0.com", "d4.com", "d8.com"])
+        );
+        assert_eq!(sample_domains(all.iter(), 50).len(), 10);
+        assert!(sample_domains(all.iter(), 0).is_empty());
+    }
+}
@@ -0,0 +1,71 @@
+use std::fs;
+use std::path::Path;
+
+/// Bundled starter blocklist offered on first run, sorted
+///
+/// Kept small and conservative: well-known ad and tracking hosts whose
+/// blocking doesn't break the sites that embed them.
+pub const STARTER_DOMAINS: &[&str] = &[
+    "ad.doubleclick.net",
+    "adnxs.com",
+    "ads.linkedin.com",
+    "ads.yahoo.com",
+    "adservice.google.com",
+    "analytics.twitter.com",
+    "app-measurement.com",
+    "bat.bing.com",
+    "cdn.taboola.com",
+    "criteo.com",
+    "doubleclick.net",
+    "googleadservices.com",
+    "googlesyndication.com",
+    "ib.adnxs.com",
+    "pagead2.googlesyndication.com",
+    "sb.scorecardresearch.com",
+    "scorecardresearch.com",
+    "securepubads.g.doubleclick.net",
+    "static.ads-twitter.com",
+    "stats.g.doubleclick.net",
+    "widgets.outbrain.com",
+];
+
+/// File in the app data directory recording that the first launch has happened
+const FIRST_RUN_MARKER: &str = "first-run-complete";
+
+/// Whether this is the first launch
+/// The marker is written on the first call, so only that call returns true
+pub fn check_first_run(data_dir: &Path) -> Result<bool, anyhow::Error> {
+    let marker = data_dir.join(FIRST_RUN_MARKER);
+    if marker.exists() {
+        return Ok(false);
+    }
+    fs::create_dir_all(data_dir)?;
+    fs::write(&marker, chrono::Local::now().to_rfc3339())?;
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::safelist::is_safe_listed;
+    use crate::utils::is_local_domain;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_dataset_is_sorted_and_blockable() {
+        assert!(STARTER_DOMAINS.windows(2).all(|pair| pair[0] < pair[1]));
+        assert!(STARTER_DOMAINS
+            .iter()
+            .all(|domain| !is_local_domain(domain) && !is_safe_listed(domain)));
+    }
+
+    #[test]
+    fn test_first_run_flag_flips_once() {
+        let temp_dir = TempDir::new().unwrap();
+        let data_dir = temp_dir.path().join("adguard");
+
+        assert!(check_first_run(&data_dir).unwrap());
+        assert!(!check_first_run(&data_dir).unwrap());
+        assert!(!check_first_run(&data_dir).unwrap());
+    }
+}
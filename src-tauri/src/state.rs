@@ -1,124 +1,3650 @@
-use crate::config::Config;
-use crate::parser::{parse_hosts, serialize_hosts, PreservedLine};
-use crate::utils::is_local_domain;
+use crate::audit::AuditLog;
+use crate::backup::{
+    read_backup, write_backup, write_restored_files, BackupEntry, BackupFiles, BackupManifest,
+};
+use crate::bloom::BloomFilter;
+use crate::commit::{can_write_hosts, write_atomic, CommitCoalescer, CommitTimings};
+use crate::config::{is_complete_ini, Config};
+use crate::history::{diff_blocking, list_history_entries, read_snapshot};
+use crate::import::{source_contributions, PendingImport, SourceContribution};
+use crate::parser::{
+    compact_preserved_lines, dedupe_preserved_lines, network_entries, normalize_layout,
+    parse_domain_list, parse_hosts_with, parse_preserved_line, serialize_hosts,
+    serialize_managed_only, write_hosts, AnnotationMap, ExportFormat, LayoutStyle, NetworkEntry,
+    ParsedHosts, PreservedLine, SerializeOptions, SinkMap, DEFAULT_SINK,
+};
+use crate::platform::default_hosts_file_path;
+use crate::profiles::{
+    list_profiles, profile_db_path, profile_dir, validate_profile_name, Profile, DEFAULT_PROFILE,
+    PROFILES_DIR,
+};
+use crate::schedule::{
+    load_schedule, remove_schedule, save_schedule, time_until, unix_now, PAUSE_FILE, SCHEDULE_FILE,
+};
+use crate::starter::STARTER_DOMAINS;
+use crate::utils::{
+    canonical_hostname, is_local_domain, is_local_domain_with, random_token, registrable_domain,
+    to_ascii_domain, to_display_domain, validate_hostname,
+};
 use parking_lot::Mutex;
-use std::collections::BTreeSet;
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
 use std::fs;
-use std::path::Path;
+use std::io::BufWriter;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+
+/// How long a clear-all confirmation token stays valid
+pub const CLEAR_TOKEN_TTL: Duration = Duration::from_secs(60);
+
+/// Version of the on-disk state database format
+pub const DB_VERSION: u64 = 1;
+
+/// Origin recorded for domains added by hand
+pub const MANUAL_ORIGIN: &str = "manual";
+
+/// Where each blocked domain came from: `MANUAL_ORIGIN` and the URLs of the
+/// sources that listed it
+pub type OriginMap = BTreeMap<Arc<str>, BTreeSet<Arc<str>>>;
+
+/// Maximum number of entries kept in the error log
+pub const ERROR_LOG_CAPACITY: usize = 200;
+
+/// Maximum compiled size of a domain search pattern
+pub const REGEX_SIZE_LIMIT: usize = 1 << 20;
+
+/// Longest a domain search may run before returning partial results
+pub const REGEX_TIME_LIMIT: Duration = Duration::from_millis(250);
+
+/// Longest `pause_blocking` will pause for (a week)
+pub const MAX_PAUSE_MINUTES: u64 = 7 * 24 * 60;
+
+/// Most invalid domains listed by `validate_all_domains`; the rest are only counted
+pub const MAX_INVALID_REPORTED: usize = 500;
+
+/// Blocked domains that fail the hostname check, as found by `validate_all_domains`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DomainValidation {
+    /// Blocked domains checked
+    pub checked: usize,
+    /// Invalid domains with the reason, sorted, at most `MAX_INVALID_REPORTED`
+    pub invalid: Vec<(Arc<str>, String)>,
+    /// Invalid domains in total, including those left out of `invalid`
+    pub total_invalid: usize,
+}
+
+/// Result of a regex search over the blocked domains
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RegexQuery {
+    pub matches: Vec<Arc<str>>,
+    /// More domains matched than the limit allowed
+    pub truncated: bool,
+    /// The search stopped at `REGEX_TIME_LIMIT` before scanning every domain
+    pub timed_out: bool,
+}
+
+/// An error from a background task, kept for display in the UI
+#[derive(Debug, Clone)]
+pub struct LogEntry {
+    pub timestamp: SystemTime,
+    /// Module the error came from (e.g. "watcher", "commit")
+    pub source: &'static str,
+    pub message: Arc<str>,
+}
+
+/// Provenance of a hostname as seen by the current state
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DomainExplanation {
+    /// Stored (punycode) form of the hostname
+    pub hostname: Arc<str>,
+    /// Unicode form of the hostname for display
+    pub display: Arc<str>,
+    /// The hostname is in the managed blocking set
+    pub blocked: bool,
+    /// The hostname is local (localhost, single-label) and can never be blocked
+    pub local: bool,
+    /// The hostname appears in a preserved (non-managed) line
+    pub preserved: bool,
+    /// The hostname was added by hand
+    pub manual: bool,
+    /// URLs of the imported sources that list the hostname
+    pub sources: Vec<Arc<str>>,
+    /// The hostname is on the allowlist, which keeps it from being blocked
+    pub allowlisted: bool,
+    /// Wildcard entry covering the hostname; always None, as the hosts file
+    /// has no wildcard entries (see `PARSER_CAPABILITIES`)
+    pub wildcard: Option<Arc<str>>,
+}
+
+/// The active hosts file and how many entries the app manages in it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HostFileInfo {
+    pub path: Arc<Path>,
+    pub exists: bool,
+    /// Size on disk, if the file could be read
+    pub size: Option<u64>,
+    /// Modification time as of the last load or commit, in seconds since the Unix epoch
+    pub last_modified: Option<u64>,
+    pub writable: bool,
+    /// Domains currently blocked, saved or not
+    pub entry_count: usize,
+}
+
+/// A managed domain as shown in the domain list
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BlockedDomain {
+    /// Stored (punycode) form of the hostname
+    pub hostname: Arc<str>,
+    /// Unicode form of the hostname for display
+    pub display: Arc<str>,
+    /// Address the hostname is written under
+    pub sink: IpAddr,
+    /// False for a disabled domain, kept commented out
+    pub enabled: bool,
+    /// Date the domain was added through the app, when annotations are on
+    pub added_on: Option<Arc<str>>,
+    /// Where the domain came from: `MANUAL_ORIGIN` and the URLs of sources
+    /// listing it, empty for a domain read from a hosts file
+    pub source: Vec<Arc<str>>,
+}
+
+/// How the in-memory state compares to the hosts file on disk
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PendingChanges {
+    /// The state was mutated since the last commit or load
+    pub dirty: bool,
+    /// Serializing the state would change the file on disk
+    pub differs_from_disk: bool,
+    /// Blocked in memory but not on disk
+    pub added: Vec<Arc<str>>,
+    /// Blocked on disk but not in memory
+    pub removed: Vec<Arc<str>>,
+}
+
+/// How the hosts file on disk compares to what the app last loaded or saved
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SyncStatus {
+    InSync,
+    Drifted(SyncDrift),
+}
+
+/// Changes made to the hosts file outside the app
+/// Lists are sorted; preserved lines are compared regardless of order
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct SyncDrift {
+    /// Blocked on disk but not in the app
+    pub blocked_on_disk_only: Vec<Arc<str>>,
+    /// Blocked in the app (and saved) but not on disk
+    pub blocked_in_app_only: Vec<Arc<str>>,
+    pub preserved_on_disk_only: Vec<String>,
+    pub preserved_in_app_only: Vec<String>,
+}
+
+/// What a reload of the hosts file changed in the state
+/// Lists are sorted
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct ReloadReport {
+    /// Blocked on disk since the last load or save, and now blocked in the app
+    pub added: Vec<Arc<str>>,
+    /// Unblocked on disk since the last load or save, and now unblocked in the app
+    pub removed: Vec<Arc<str>>,
+    /// The preserved lines changed both on disk and in the app since the last
+    /// save; the app's lines were kept and the next save overwrites the disk's
+    pub preserved_conflict: bool,
+}
+
+/// What removing a domain does with it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RemoveMode {
+    /// Drop the domain from the hosts file
+    #[default]
+    Delete,
+    /// Keep the domain in the hosts file, commented out, to re-enable later
+    Disable,
+}
+
+impl RemoveMode {
+    pub fn from_str(value: &str) -> Result<Self, anyhow::Error> {
+        match value {
+            "delete" => Ok(RemoveMode::Delete),
+            "disable" => Ok(RemoveMode::Disable),
+            _ => Err(anyhow::anyhow!("Unknown remove mode: {}", value)),
+        }
+    }
+}
+
+/// How `apply_set_operation` combines a list of domains with the blocking set
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SetOp {
+    /// Block the listed domains as well
+    Union,
+    /// Unblock the listed domains
+    Difference,
+    /// Unblock every domain not listed
+    Intersection,
+}
+
+impl SetOp {
+    pub fn from_str(value: &str) -> Result<Self, anyhow::Error> {
+        match value {
+            "union" => Ok(SetOp::Union),
+            "difference" => Ok(SetOp::Difference),
+            "intersection" => Ok(SetOp::Intersection),
+            _ => Err(anyhow::anyhow!("Unknown set operation: {}", value)),
+        }
+    }
+
+    #[inline]
+    pub fn to_str(self) -> &'static str {
+        match self {
+            SetOp::Union => "union",
+            SetOp::Difference => "difference",
+            SetOp::Intersection => "intersection",
+        }
+    }
+}
+
+/// A pending clear-all confirmation
+#[derive(Debug, Clone)]
+pub struct ClearToken {
+    pub token: Arc<str>,
+    pub expires_at: Instant,
+}
 
 #[derive(Clone)]
 pub struct AppState {
     pub blocking: Arc<Mutex<BTreeSet<Arc<str>>>>,
+    /// Domains removed with `RemoveMode::Disable`, written commented out
+    pub disabled: Arc<Mutex<BTreeSet<Arc<str>>>>,
+    /// Domains explicitly allowed, written to the allow file for a local DNS
+    pub allowlist: Arc<Mutex<BTreeSet<Arc<str>>>>,
+    /// Fast negative lookups for `blocking`; rebuilt on bulk changes
+    pub blocking_filter: Arc<Mutex<BloomFilter>>,
     pub preserved_lines: Arc<Mutex<Vec<PreservedLine>>>,
+    /// Sink each blocked domain was listed under (others use the default sink)
+    pub sinks: Arc<Mutex<SinkMap>>,
+    /// Date each domain was added, for domains added with annotations enabled
+    pub annotations: Arc<Mutex<AnnotationMap>>,
+    /// When each domain was first blocked, in seconds since the Unix epoch
+    /// Kept after the domain is removed, so blocking it again doesn't reset it
+    pub first_seen: Arc<Mutex<BTreeMap<Arc<str>, u64>>>,
+    /// Origins of blocked and disabled domains; domains from a hosts file or
+    /// an import of one have none
+    pub origins: Arc<Mutex<OriginMap>>,
+    /// Header block as last read from the hosts file, so edits made there are kept
+    pub file_header: Arc<Mutex<Option<Arc<str>>>>,
     pub config: Arc<Mutex<Config>>,
+    /// Location of the config file, if it's written back on changes
+    pub config_path: Arc<Mutex<Option<Arc<Path>>>>,
+    /// Location of the canonical state database, if persistence is enabled
+    pub db_path: Arc<Mutex<Option<Arc<Path>>>>,
+    /// Log of mutating operations, if one has been configured
+    pub audit_log: Arc<Mutex<Option<AuditLog>>>,
+    pub clear_token: Arc<Mutex<Option<ClearToken>>>,
+    /// Import awaiting the user's conflict decisions
+    pub pending_import: Arc<Mutex<Option<PendingImport>>>,
+    /// Set on every mutation, cleared on a successful commit or load
+    pub dirty: Arc<Mutex<bool>>,
+    /// Domains blocked since the last commit or load
+    pub pending_added: Arc<Mutex<BTreeSet<Arc<str>>>>,
+    /// Domains unblocked since the last commit or load
+    pub pending_removed: Arc<Mutex<BTreeSet<Arc<str>>>>,
+    /// Preserved lines as of the last commit or load, the base for telling
+    /// disk edits apart from the app's own
+    pub saved_preserved_lines: Arc<Mutex<Vec<PreservedLine>>>,
+    /// Modification time of the hosts file as of the last load or commit
+    pub last_modified: Arc<Mutex<Option<SystemTime>>>,
+    /// When a pause of blocking ends, if blocking is paused
+    pub paused_until: Arc<Mutex<Option<Instant>>>,
+    /// When a scheduled commit is due, in seconds since the Unix epoch
+    pub scheduled_commit: Arc<Mutex<Option<u64>>>,
+    pub commit_coalescer: Arc<CommitCoalescer>,
+    /// Phase timings of the last successful commit
+    pub last_commit_timings: Arc<Mutex<Option<CommitTimings>>>,
+    /// Most recent errors, oldest first, bounded by `ERROR_LOG_CAPACITY`
+    pub error_log: Arc<Mutex<VecDeque<LogEntry>>>,
+    /// This is the app's first launch, so the starter list can be offered
+    pub first_run: Arc<Mutex<bool>>,
 }
 
-impl AppState {
-    #[inline]
-    pub fn new(config: Config) -> Self {
-        AppState {
-            blocking: Arc::new(Mutex::new(BTreeSet::new())),
-            preserved_lines: Arc::new(Mutex::new(Vec::new())),
-            config: Arc::new(Mutex::new(config)),
-        }
+impl AppState {
+    #[inline]
+    pub fn new(config: Config) -> Self {
+        AppState {
+            blocking: Arc::new(Mutex::new(BTreeSet::new())),
+            disabled: Arc::new(Mutex::new(BTreeSet::new())),
+            allowlist: Arc::new(Mutex::new(BTreeSet::new())),
+            blocking_filter: Arc::new(Mutex::new(BloomFilter::with_capacity(0))),
+            preserved_lines: Arc::new(Mutex::new(Vec::new())),
+            sinks: Arc::new(Mutex::new(SinkMap::new())),
+            annotations: Arc::new(Mutex::new(AnnotationMap::new())),
+            first_seen: Arc::new(Mutex::new(BTreeMap::new())),
+            origins: Arc::new(Mutex::new(OriginMap::new())),
+            file_header: Arc::new(Mutex::new(None)),
+            config: Arc::new(Mutex::new(config)),
+            config_path: Arc::new(Mutex::new(None)),
+            db_path: Arc::new(Mutex::new(None)),
+            audit_log: Arc::new(Mutex::new(None)),
+            clear_token: Arc::new(Mutex::new(None)),
+            pending_import: Arc::new(Mutex::new(None)),
+            dirty: Arc::new(Mutex::new(false)),
+            pending_added: Arc::new(Mutex::new(BTreeSet::new())),
+            pending_removed: Arc::new(Mutex::new(BTreeSet::new())),
+            saved_preserved_lines: Arc::new(Mutex::new(Vec::new())),
+            last_modified: Arc::new(Mutex::new(None)),
+            paused_until: Arc::new(Mutex::new(None)),
+            scheduled_commit: Arc::new(Mutex::new(None)),
+            commit_coalescer: Arc::new(CommitCoalescer::default()),
+            last_commit_timings: Arc::new(Mutex::new(None)),
+            error_log: Arc::new(Mutex::new(VecDeque::new())),
+            first_run: Arc::new(Mutex::new(false)),
+        }
+    }
+
+    /// Load state from hosts file
+    ///
+    /// The file is merged into the current state against what the app last
+    /// loaded or saved: domains blocked or unblocked on disk since then are
+    /// applied, while unsaved changes (and the dirty flag) survive a reload
+    /// triggered by an external edit.
+    pub fn load_from_file(&self, path: &Path) -> Result<ReloadReport, anyhow::Error> {
+        let content = fs::read_to_string(path)?;
+        let options = self.config.lock().parse_options();
+        let parsed = parse_hosts_with(&content, &options)?;
+        let invalid = parsed
+            .preserved_lines
+            .iter()
+            .filter(|line| matches!(line, PreservedLine::Invalid(_)))
+            .count();
+        if invalid > 0 {
+            tracing::warn!(
+                "{} lines of {} aren't valid hosts entries and are kept as is",
+                invalid,
+                path.display()
+            );
+        }
+
+        let on_disk = self.blockable(parsed.blocking, &self.get_allowlist());
+        let external = diff_blocking(&self.last_saved_blocking(), &on_disk);
+        let preserved_conflict = {
+            let mut blocking = self.blocking.lock();
+            let mut pending_added = self.pending_added.lock();
+            let mut pending_removed = self.pending_removed.lock();
+            for hostname in &external.added {
+                blocking.insert(hostname.clone());
+            }
+            for hostname in &external.removed {
+                blocking.remove(hostname);
+            }
+            // Unsaved changes the disk now agrees with are no longer pending
+            pending_added.retain(|hostname| !on_disk.contains(hostname));
+            pending_removed.retain(|hostname| on_disk.contains(hostname));
+
+            let mut disabled = self.disabled.lock();
+            disabled.extend(parsed.disabled);
+            disabled.retain(|hostname| !blocking.contains(hostname));
+
+            // Preserved lines are compared with those last saved: the disk's
+            // are taken unless the app edited them too
+            let mut preserved_lines = self.preserved_lines.lock();
+            let mut saved_preserved_lines = self.saved_preserved_lines.lock();
+            let edited_on_disk =
+                preserved_lines_differ(&parsed.preserved_lines, &saved_preserved_lines);
+            let edited_in_app = preserved_lines_differ(&preserved_lines, &saved_preserved_lines);
+            if edited_on_disk && edited_in_app && *self.dirty.lock() {
+                true
+            } else {
+                if edited_on_disk || !*self.dirty.lock() {
+                    *saved_preserved_lines = parsed.preserved_lines.clone();
+                    *preserved_lines = parsed.preserved_lines;
+                }
+                false
+            }
+        };
+        if preserved_conflict {
+            tracing::warn!(
+                "Preserved lines of {} changed both on disk and in the app; keeping the app's",
+                path.display()
+            );
+        }
+        self.forget_origins(external.removed.iter().cloned());
+        self.sinks.lock().extend(parsed.sinks);
+        self.annotations.lock().extend(parsed.annotations);
+        if parsed.header.is_some() {
+            *self.file_header.lock() = parsed.header;
+        }
+        self.rebuild_filter();
+        self.record_last_modified(path);
+
+        Ok(ReloadReport {
+            added: external.added,
+            removed: external.removed,
+            preserved_conflict,
+        })
+    }
+
+    /// Remember the hosts file's modification time (None if it can't be read)
+    #[inline]
+    pub fn record_last_modified(&self, path: &Path) {
+        *self.last_modified.lock() = fs::metadata(path)
+            .and_then(|metadata| metadata.modified())
+            .ok();
+    }
+
+    /// Make another hosts file the active one
+    /// Preserved lines are replaced by the new file's; managed domains are kept
+    /// and merged with any the new file blocks
+    pub fn switch_hosts_file(&self, path: &Path) -> Result<(), anyhow::Error> {
+        let content = if path.exists() {
+            fs::read_to_string(path)?
+        } else {
+            String::new()
+        };
+        let options = self.config.lock().parse_options();
+        let parsed = parse_hosts_with(&content, &options)?;
+
+        let blocked_in_file = self.blockable(parsed.blocking, &self.get_allowlist());
+        *self.preserved_lines.lock() = parsed.preserved_lines;
+        *self.file_header.lock() = parsed.header;
+        let mut blocking = self.blocking.lock();
+        blocking.extend(blocked_in_file);
+        let mut disabled = self.disabled.lock();
+        disabled.extend(parsed.disabled);
+        disabled.retain(|hostname| !blocking.contains(hostname));
+        drop((blocking, disabled));
+        let mut sinks = self.sinks.lock();
+        for (hostname, sink) in parsed.sinks {
+            sinks.entry(hostname).or_insert(sink);
+        }
+        drop(sinks);
+        let mut annotations = self.annotations.lock();
+        for (hostname, added_on) in parsed.annotations {
+            annotations.entry(hostname).or_insert(added_on);
+        }
+        drop(annotations);
+        self.rebuild_filter();
+        self.prune_pending();
+        self.record_last_modified(path);
+
+        Ok(())
+    }
+
+    /// Replace the whole state with parsed hosts content (e.g. an import)
+    pub fn replace_with(&self, parsed: ParsedHosts) {
+        let previous = std::mem::replace(&mut *self.blocking.lock(), parsed.blocking.clone());
+        self.record_removed(previous.difference(&parsed.blocking).cloned());
+        self.record_added(parsed.blocking.difference(&previous).cloned());
+        *self.preserved_lines.lock() = parsed.preserved_lines;
+        *self.sinks.lock() = parsed.sinks;
+        *self.annotations.lock() = parsed.annotations;
+        *self.disabled.lock() = parsed.disabled;
+        *self.file_header.lock() = parsed.header;
+        self.prune_origins();
+        self.rebuild_filter();
+        self.mark_dirty();
+    }
+
+    /// Take the managed domains from parsed content, keeping the current
+    /// preserved lines and header; returns how many domains are blocked after
+    pub fn replace_blocking(&self, parsed: ParsedHosts) -> usize {
+        let blocking = self.blockable(parsed.blocking, &self.get_allowlist());
+        let total = blocking.len();
+        let previous = std::mem::replace(&mut *self.blocking.lock(), blocking.clone());
+        self.record_removed(previous.difference(&blocking).cloned());
+        self.record_added(blocking.difference(&previous).cloned());
+        *self.sinks.lock() = parsed.sinks;
+        *self.annotations.lock() = parsed.annotations;
+        *self.disabled.lock() = parsed.disabled;
+        self.prune_origins();
+        self.rebuild_filter();
+        self.mark_dirty();
+        total
+    }
+
+    /// Add back blocked domains from parsed content without removing any
+    /// Returns how many domains were added
+    pub fn merge_blocking(&self, parsed: ParsedHosts) -> usize {
+        let block_single_label = self.config.lock().block_single_label;
+        let allowlist = self.get_allowlist();
+        let mut blocking = self.blocking.lock();
+        let mut sinks = self.sinks.lock();
+        let mut annotations = self.annotations.lock();
+        let mut added = Vec::new();
+
+        for hostname in parsed.blocking {
+            if !is_mergeable(&blocking, &allowlist, &hostname, block_single_label) {
+                continue;
+            }
+            if let Some(sink) = parsed.sinks.get(&hostname) {
+                sinks.insert(hostname.clone(), *sink);
+            }
+            if let Some(added_on) = parsed.annotations.get(&hostname) {
+                annotations.insert(hostname.clone(), added_on.clone());
+            }
+            blocking.insert(hostname.clone());
+            added.push(hostname);
+        }
+        drop((blocking, sinks, annotations));
+
+        let count = added.len();
+        if count > 0 {
+            self.record_added(added);
+            self.rebuild_filter();
+            self.mark_dirty();
+        }
+        count
+    }
+
+    /// Domains `merge_blocking` would add from parsed content, without merging
+    pub fn merge_preview(&self, parsed: &ParsedHosts) -> Vec<Arc<str>> {
+        let block_single_label = self.config.lock().block_single_label;
+        let allowlist = self.get_allowlist();
+        let blocking = self.blocking.lock();
+        parsed
+            .blocking
+            .iter()
+            .filter(|hostname| is_mergeable(&blocking, &allowlist, hostname, block_single_label))
+            .cloned()
+            .collect()
+    }
+
+    /// Load the blocking set from the state database, replacing the current one
+    ///
+    /// The database is the source of truth for managed domains; the hosts file
+    /// only contributes preserved lines once a database exists.
+    pub fn load_db(&self, path: &Path) -> Result<(), anyhow::Error> {
+        let content = fs::read_to_string(path)?;
+        let db: serde_json::Value = serde_json::from_str(&content)?;
+
+        let version = db.get("version").and_then(|v| v.as_u64()).unwrap_or(0);
+        if version > DB_VERSION {
+            return Err(anyhow::anyhow!(
+                "Unsupported database version {} (expected at most {})",
+                version,
+                DB_VERSION
+            ));
+        }
+
+        // Databases from before the allowlist have none
+        let allowlist: BTreeSet<Arc<str>> = db
+            .get("allowed")
+            .and_then(|v| v.as_array())
+            .map(|allowed| {
+                allowed
+                    .iter()
+                    .filter_map(|v| v.as_str())
+                    .filter(|hostname| !self.is_local(hostname))
+                    .map(Arc::from)
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        let blocking = self.blockable(
+            db.get("blocking")
+                .and_then(|v| v.as_array())
+                .ok_or_else(|| anyhow::anyhow!("Database is missing the blocking list"))?
+                .iter()
+                .filter_map(|v| v.as_str())
+                .map(Arc::from),
+            &allowlist,
+        );
+
+        // Databases from before disabling domains have none
+        let disabled = db
+            .get("disabled")
+            .and_then(|v| v.as_array())
+            .map(|disabled| {
+                disabled
+                    .iter()
+                    .filter_map(|v| v.as_str())
+                    .filter(|hostname| !self.is_local(hostname))
+                    .map(Arc::from)
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        // Only domains listed under a non-default sink are stored with one
+        let sinks = db
+            .get("sinks")
+            .and_then(|v| v.as_object())
+            .map(|sinks| {
+                sinks
+                    .iter()
+                    .filter_map(|(hostname, sink)| {
+                        let sink = sink.as_str()?.parse::<IpAddr>().ok()?;
+                        Some((Arc::from(hostname.as_str()), sink))
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        let annotations = db
+            .get("added_at")
+            .and_then(|v| v.as_object())
+            .map(|added_at| {
+                added_at
+                    .iter()
+                    .filter_map(|(hostname, added_on)| {
+                        Some((Arc::from(hostname.as_str()), Arc::from(added_on.as_str()?)))
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        *self.blocking.lock() = blocking;
+        *self.allowlist.lock() = allowlist;
+        *self.disabled.lock() = disabled;
+        *self.sinks.lock() = sinks;
+        // Databases from before first-seen tracking have none
+        let first_seen = db
+            .get("first_seen")
+            .and_then(|v| v.as_object())
+            .map(|first_seen| {
+                first_seen
+                    .iter()
+                    .filter_map(|(hostname, at)| Some((Arc::from(hostname.as_str()), at.as_u64()?)))
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        // Databases from before origin tracking have none
+        let origins = db
+            .get("origins")
+            .and_then(|v| v.as_object())
+            .map(|origins| {
+                let blocking = self.blocking.lock();
+                let disabled = self.disabled.lock();
+                origins
+                    .iter()
+                    .filter(|(hostname, _)| {
+                        blocking.contains(hostname.as_str()) || disabled.contains(hostname.as_str())
+                    })
+                    .filter_map(|(hostname, tags)| {
+                        let tags: BTreeSet<Arc<str>> = tags
+                            .as_array()?
+                            .iter()
+                            .filter_map(|tag| tag.as_str().map(Arc::from))
+                            .collect();
+                        Some((Arc::from(hostname.as_str()), tags))
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        *self.annotations.lock() = annotations;
+        *self.first_seen.lock() = first_seen;
+        *self.origins.lock() = origins;
+        self.rebuild_filter();
+        self.mark_clean();
+        Ok(())
+    }
+
+    /// Save the blocking set to the state database
+    pub fn save_db(&self, path: &Path) -> Result<(), anyhow::Error> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        write_atomic(path, &serde_json::to_string_pretty(&self.db_json())?)
+    }
+
+    /// The state database as saved by `save_db`
+    fn db_json(&self) -> serde_json::Value {
+        let sinks: serde_json::Map<String, serde_json::Value> = self
+            .sinks
+            .lock()
+            .iter()
+            .filter(|(_, sink)| **sink != DEFAULT_SINK)
+            .map(|(hostname, sink)| (hostname.to_string(), sink.to_string().into()))
+            .collect();
+
+        serde_json::json!({
+            "version": DB_VERSION,
+            "blocking": *self.blocking.lock(),
+            "sinks": sinks,
+            "added_at": *self.annotations.lock(),
+            "first_seen": *self.first_seen.lock(),
+            "origins": *self.origins.lock(),
+            "allowed": *self.allowlist.lock(),
+            "disabled": *self.disabled.lock(),
+        })
+    }
+
+    /// Set where the state database lives
+    #[inline]
+    pub fn set_db_path(&self, path: Arc<Path>) {
+        *self.db_path.lock() = Some(path);
+    }
+
+    /// Save the state database if a location has been configured
+    #[inline]
+    pub fn persist_db(&self) -> Result<(), anyhow::Error> {
+        let db_path = self.db_path.lock().clone();
+        match db_path {
+            Some(path) => self.save_db(&path),
+            None => Ok(()),
+        }
+    }
+
+    /// Set where mutating operations are logged
+    #[inline]
+    pub fn set_audit_log(&self, audit_log: AuditLog) {
+        *self.audit_log.lock() = Some(audit_log);
+    }
+
+    /// Record a mutating operation in the audit log, if one is configured
+    /// A failed write is logged but never fails the operation itself
+    pub fn audit(&self, operation: &str, detail: &str) {
+        let audit_log = self.audit_log.lock().clone();
+        if let Some(audit_log) = audit_log {
+            if let Err(e) = audit_log.append(operation, detail) {
+                self.log_error("audit", format!("Failed to write audit log: {}", e));
+            }
+        }
+    }
+
+    /// Add a domain to blocking
+    /// Returns false for local or already blocked names
+    #[inline]
+    pub fn add_block(&self, hostname: &str) -> bool {
+        let hostname = canonical_hostname(hostname);
+        if self.is_allowed(hostname) {
+            return false;
+        }
+        // Adding a domain a source already blocks still records it as manual
+        let manual: Arc<str> = MANUAL_ORIGIN.into();
+        if self.enable_block(hostname) {
+            self.tag_origins([hostname.into()], &manual);
+            return true;
+        }
+        let added = !self.is_local(hostname) && self.blocking.lock().insert(hostname.into());
+        if added {
+            let (annotate, sink) = {
+                let config = self.config.lock();
+                (config.annotate_additions, config.blocking_sink)
+            };
+            if annotate {
+                let added_on = chrono::Local::now().format("%Y-%m-%d").to_string();
+                self.annotations
+                    .lock()
+                    .insert(hostname.into(), added_on.into());
+            }
+            if sink != DEFAULT_SINK {
+                self.sinks.lock().insert(hostname.into(), sink);
+            }
+            self.record_added([hostname.into()]);
+            let mut filter = self.blocking_filter.lock();
+            filter.insert(hostname);
+            if filter.is_saturated() {
+                drop(filter);
+                self.rebuild_filter();
+            }
+            self.mark_dirty();
+        }
+        self.tag_origins([hostname.into()], &manual);
+        added
+    }
+
+    /// Record `origin` for those of `hostnames` that are blocked
+    fn tag_origins(&self, hostnames: impl IntoIterator<Item = Arc<str>>, origin: &Arc<str>) {
+        let blocking = self.blocking.lock();
+        let mut origins = self.origins.lock();
+        for hostname in hostnames {
+            if blocking.contains(&hostname) {
+                origins.entry(hostname).or_default().insert(origin.clone());
+            }
+        }
+    }
+
+    /// Record that the source at `url` lists `domains`, for those blocked
+    pub fn record_source(&self, url: &str, domains: &BTreeSet<Arc<str>>) {
+        self.tag_origins(domains.iter().cloned(), &Arc::from(url));
+    }
+
+    /// Drop the origins of those of `hostnames` neither blocked nor disabled
+    fn forget_origins(&self, hostnames: impl IntoIterator<Item = Arc<str>>) {
+        let blocking = self.blocking.lock();
+        let disabled = self.disabled.lock();
+        let mut origins = self.origins.lock();
+        for hostname in hostnames {
+            if !blocking.contains(&hostname) && !disabled.contains(&hostname) {
+                origins.remove(&hostname);
+            }
+        }
+    }
+
+    /// Drop the origins of every domain neither blocked nor disabled
+    fn prune_origins(&self) {
+        let blocking = self.blocking.lock();
+        let disabled = self.disabled.lock();
+        self.origins
+            .lock()
+            .retain(|hostname, _| blocking.contains(hostname) || disabled.contains(hostname));
+    }
+
+    /// Add a domain to the allowlist; returns false for local or already allowed names
+    pub fn add_allow(&self, hostname: &str) -> bool {
+        let added = !self.is_local(hostname) && self.allowlist.lock().insert(hostname.into());
+        if added {
+            self.mark_dirty();
+        }
+        added
+    }
+
+    /// Remove a domain from the allowlist; returns whether it was allowed
+    pub fn remove_allow(&self, hostname: &str) -> bool {
+        let removed = self.allowlist.lock().remove(hostname);
+        if removed {
+            self.mark_dirty();
+        }
+        removed
+    }
+
+    /// Check if a domain is on the allowlist, which keeps it from being blocked
+    #[inline]
+    pub fn is_allowed(&self, hostname: &str) -> bool {
+        self.allowlist.lock().contains(canonical_hostname(hostname))
+    }
+
+    /// Get all allowed domains
+    #[inline]
+    pub fn get_allowlist(&self) -> BTreeSet<Arc<str>> {
+        self.allowlist.lock().clone()
+    }
+
+    /// Allow file content: one domain per line
+    pub fn serialize_allowlist(&self) -> String {
+        serialize_managed_only(
+            &self.allowlist.lock(),
+            &SinkMap::new(),
+            ExportFormat::Domains,
+            &SerializeOptions::default(),
+        )
+    }
+
+    /// Write the allowlist to an allow file, returning how many domains it lists
+    pub fn export_allowlist(&self, path: &Path) -> Result<usize, anyhow::Error> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let content = self.serialize_allowlist();
+        fs::write(path, &content)?;
+        Ok(content.lines().count())
+    }
+
+    /// Replace the allowlist with the domains of an allow file
+    pub fn load_allowlist(&self, path: &Path) -> Result<usize, anyhow::Error> {
+        let (mut allowlist, _) = parse_domain_list(&fs::read_to_string(path)?);
+        allowlist.retain(|hostname| !self.is_local(hostname));
+        let count = allowlist.len();
+        *self.allowlist.lock() = allowlist;
+        Ok(count)
+    }
+
+    /// Remove a domain from blocking
+    #[inline]
+    pub fn remove_block(&self, hostname: &str) {
+        let hostname = canonical_hostname(hostname);
+        if self.is_local(hostname) {
+            return;
+        }
+        let blocked = self.blocking.lock().remove(hostname);
+        let disabled = self.disabled.lock().remove(hostname);
+        if blocked {
+            self.record_removed([hostname.into()]);
+        }
+        if blocked || disabled {
+            self.sinks.lock().remove(hostname);
+            self.annotations.lock().remove(hostname);
+            self.forget_origins([hostname.into()]);
+            self.mark_dirty();
+        }
+    }
+
+    /// Unblock a domain but keep it in the hosts file, commented out
+    /// Its sink is kept for when it's enabled again; returns whether it was blocked
+    pub fn disable_block(&self, hostname: &str) -> bool {
+        let hostname = canonical_hostname(hostname);
+        if !self.blocking.lock().remove(hostname) {
+            return false;
+        }
+        self.disabled.lock().insert(hostname.into());
+        self.annotations.lock().remove(hostname);
+        self.record_removed([hostname.into()]);
+        self.mark_dirty();
+        true
+    }
+
+    /// Block a disabled domain again under its previous sink
+    /// Returns whether it was disabled
+    pub fn enable_block(&self, hostname: &str) -> bool {
+        let hostname = canonical_hostname(hostname);
+        if !self.disabled.lock().remove(hostname) {
+            return false;
+        }
+        self.blocking.lock().insert(hostname.into());
+        self.record_added([hostname.into()]);
+        let mut filter = self.blocking_filter.lock();
+        filter.insert(hostname);
+        if filter.is_saturated() {
+            drop(filter);
+            self.rebuild_filter();
+        }
+        self.mark_dirty();
+        true
+    }
+
+    /// Domains disabled rather than deleted, sorted
+    #[inline]
+    pub fn get_disabled(&self) -> BTreeSet<Arc<str>> {
+        self.disabled.lock().clone()
+    }
+
+    /// Unblock every hostname whose registrable domain is `base`
+    /// Returns the hostnames removed
+    pub fn remove_base_domain(&self, base: &str) -> Vec<Arc<str>> {
+        let mut blocking = self.blocking.lock();
+        let removed: Vec<Arc<str>> = blocking
+            .iter()
+            .filter(|hostname| registrable_domain(hostname).eq_ignore_ascii_case(base))
+            .cloned()
+            .collect();
+        if removed.is_empty() {
+            return removed;
+        }
+
+        let mut sinks = self.sinks.lock();
+        let mut annotations = self.annotations.lock();
+        for hostname in &removed {
+            blocking.remove(hostname);
+            sinks.remove(hostname);
+            annotations.remove(hostname);
+        }
+        drop((blocking, sinks, annotations));
+
+        self.record_removed(removed.iter().cloned());
+        self.mark_dirty();
+        removed
+    }
+
+    /// Combine the blocking set with a list of domains (see `SetOp`)
+    /// Returns how many domains are blocked afterwards
+    pub fn apply_set_operation(&self, op: SetOp, domains: &[String]) -> usize {
+        match op {
+            SetOp::Union => domains.iter().for_each(|hostname| {
+                self.add_block(hostname);
+            }),
+            SetOp::Difference => domains
+                .iter()
+                .for_each(|hostname| self.remove_block(hostname)),
+            SetOp::Intersection => {
+                let keep: BTreeSet<&str> = domains
+                    .iter()
+                    .map(|hostname| canonical_hostname(hostname))
+                    .collect();
+                let unblocked: Vec<Arc<str>> = self
+                    .blocking
+                    .lock()
+                    .iter()
+                    .filter(|hostname| !keep.contains(hostname.as_ref()))
+                    .cloned()
+                    .collect();
+                for hostname in unblocked {
+                    self.remove_block(&hostname);
+                }
+            }
+        }
+        self.get_total_blocked()
+    }
+
+    /// Block the bundled starter list, returning how many domains were added
+    /// Refused past `max_total_domains` unless `allow_over_cap` is set, like any
+    /// other addition. Seeding answers the first-run offer, so it isn't made again
+    pub fn seed_starter_list(&self, allow_over_cap: bool) -> Result<usize, anyhow::Error> {
+        let before = self.get_total_blocked();
+        let new_total = before + self.count_unblocked(STARTER_DOMAINS.iter().copied());
+        self.check_domain_cap(new_total, allow_over_cap)?;
+        for hostname in STARTER_DOMAINS {
+            self.add_block(hostname);
+        }
+        *self.first_run.lock() = false;
+        Ok(self.get_total_blocked() - before)
+    }
+
+    /// Issue a confirmation token required by `clear_blocks`
+    /// Any previously issued token is invalidated
+    pub fn issue_clear_token(&self) -> Arc<str> {
+        let token: Arc<str> = random_token().into();
+        *self.clear_token.lock() = Some(ClearToken {
+            token: token.clone(),
+            expires_at: Instant::now() + CLEAR_TOKEN_TTL,
+        });
+        token
+    }
+
+    /// Validate and consume a clear-all confirmation token
+    /// The pending token is consumed even when validation fails
+    pub fn consume_clear_token(&self, token: &str) -> Result<(), anyhow::Error> {
+        let pending = self
+            .clear_token
+            .lock()
+            .take()
+            .ok_or_else(|| anyhow::anyhow!("No clear was requested"))?;
+
+        if Instant::now() >= pending.expires_at {
+            return Err(anyhow::anyhow!("Confirmation token expired"));
+        }
+        if pending.token.as_ref() != token {
+            return Err(anyhow::anyhow!("Invalid confirmation token"));
+        }
+        Ok(())
+    }
+
+    /// Remove every blocked domain, returning how many were removed
+    #[inline]
+    pub fn clear_blocks(&self) -> usize {
+        let previous = std::mem::take(&mut *self.blocking.lock());
+        let removed = previous.len();
+        self.record_removed(previous);
+        self.sinks.lock().clear();
+        self.annotations.lock().clear();
+        self.disabled.lock().clear();
+        self.origins.lock().clear();
+        *self.blocking_filter.lock() = BloomFilter::with_capacity(0);
+        if removed > 0 {
+            self.mark_dirty();
+        }
+        removed
+    }
+
+    /// Rebuild the bloom filter from the blocking set
+    /// Removed domains stay in the filter until the next rebuild
+    pub fn rebuild_filter(&self) {
+        let blocking = self.blocking.lock();
+        *self.blocking_filter.lock() =
+            BloomFilter::from_items(blocking.iter().map(|hostname| hostname.as_ref()));
+    }
+
+    /// Check whether a hostname is blocked
+    /// The bloom filter answers most negatives without touching the set
+    pub fn is_blocked(&self, hostname: &str) -> bool {
+        let hostname = hostname.trim();
+        let hostname = to_ascii_domain(hostname).unwrap_or_else(|_| hostname.to_string());
+        if !self.blocking_filter.lock().might_contain(&hostname) {
+            return false;
+        }
+        self.blocking.lock().contains(hostname.as_str())
+    }
+
+    /// Check every blocked domain with `validate_hostname`, without changing anything
+    pub fn validate_all_domains(&self) -> DomainValidation {
+        let blocking = self.blocking.lock();
+        let mut invalid = Vec::new();
+        let mut total_invalid = 0;
+        for hostname in blocking.iter() {
+            if let Err(e) = validate_hostname(hostname) {
+                total_invalid += 1;
+                if invalid.len() < MAX_INVALID_REPORTED {
+                    invalid.push((hostname.clone(), e.to_string()));
+                }
+            }
+        }
+        DomainValidation {
+            checked: blocking.len(),
+            invalid,
+            total_invalid,
+        }
+    }
+
+    /// When a domain was first blocked, in seconds since the Unix epoch, even if
+    /// it has been removed since
+    /// None for domains never blocked through the app
+    pub fn first_seen(&self, hostname: &str) -> Option<u64> {
+        let hostname = hostname.trim();
+        let hostname = to_ascii_domain(hostname).unwrap_or_else(|_| hostname.to_string());
+        self.first_seen.lock().get(hostname.as_str()).copied()
+    }
+
+    /// Get all blocked domains (only returns localhost entries)
+    /// Returns domains sorted alphabetically by hostname
+    #[inline]
+    pub fn get_all_blocks(&self) -> BTreeSet<Arc<str>> {
+        self.blocking.lock().clone()
+    }
+
+    /// Blocked and disabled domains with their sink and annotation, sorted by hostname
+    pub fn get_blocked_detailed(&self) -> Vec<BlockedDomain> {
+        let blocking = self.blocking.lock();
+        let disabled = self.disabled.lock();
+        let sinks = self.sinks.lock();
+        let annotations = self.annotations.lock();
+        let origins = self.origins.lock();
+
+        let mut domains: Vec<BlockedDomain> = blocking
+            .iter()
+            .map(|hostname| (hostname, true))
+            .chain(
+                disabled
+                    .difference(&blocking)
+                    .map(|hostname| (hostname, false)),
+            )
+            .map(|(hostname, enabled)| BlockedDomain {
+                hostname: hostname.clone(),
+                display: to_display_domain(hostname).into(),
+                sink: sinks.get(hostname).copied().unwrap_or(DEFAULT_SINK),
+                enabled,
+                added_on: annotations.get(hostname).cloned(),
+                source: origins
+                    .get(hostname)
+                    .map(|tags| tags.iter().cloned().collect())
+                    .unwrap_or_default(),
+            })
+            .collect();
+        domains.sort_by(|a, b| a.hostname.cmp(&b.hostname));
+        domains
+    }
+
+    /// Blocked domains added on or after the local day of `unix_secs`, newest first
+    ///
+    /// Annotations only record the day a domain was added, so the whole day of
+    /// the cutoff counts. Domains without an annotation are left out.
+    pub fn added_since(&self, unix_secs: u64) -> Vec<Arc<str>> {
+        let Some(cutoff) = i64::try_from(unix_secs)
+            .ok()
+            .and_then(|secs| chrono::DateTime::from_timestamp(secs, 0))
+            .map(|time| time.with_timezone(&chrono::Local).date_naive())
+        else {
+            return Vec::new();
+        };
+        let blocking = self.blocking.lock();
+        let mut added: Vec<(chrono::NaiveDate, Arc<str>)> = self
+            .annotations
+            .lock()
+            .iter()
+            .filter(|(hostname, _)| blocking.contains(*hostname))
+            .filter_map(|(hostname, added_on)| {
+                let added_on = chrono::NaiveDate::parse_from_str(added_on, "%Y-%m-%d").ok()?;
+                (added_on >= cutoff).then(|| (added_on, hostname.clone()))
+            })
+            .collect();
+        added.sort_by(|a, b| b.0.cmp(&a.0).then_with(|| a.1.cmp(&b.1)));
+        added.into_iter().map(|(_, hostname)| hostname).collect()
+    }
+
+    /// Find blocked domains matching a regex, returning at most `limit` of them
+    ///
+    /// The `regex` crate matches in linear time; the compiled size and the
+    /// search time are capped as well.
+    pub fn query_regex(&self, pattern: &str, limit: usize) -> Result<RegexQuery, anyhow::Error> {
+        let regex = regex::RegexBuilder::new(pattern)
+            .case_insensitive(true)
+            .size_limit(REGEX_SIZE_LIMIT)
+            .dfa_size_limit(REGEX_SIZE_LIMIT)
+            .build()?;
+
+        let started = Instant::now();
+        let blocking = self.blocking.lock();
+        let mut query = RegexQuery {
+            matches: Vec::new(),
+            truncated: false,
+            timed_out: false,
+        };
+
+        for (index, hostname) in blocking.iter().enumerate() {
+            // Checking the clock on every domain would dominate short searches
+            if index % 1024 == 0 && started.elapsed() >= REGEX_TIME_LIMIT {
+                query.timed_out = true;
+                break;
+            }
+            if regex.is_match(hostname) {
+                if query.matches.len() == limit {
+                    query.truncated = true;
+                    break;
+                }
+                query.matches.push(hostname.clone());
+            }
+        }
+
+        Ok(query)
+    }
+
+    /// Blocked domains sharing the hostname's registrable domain, most specific first
+    ///
+    /// Domains sharing more trailing labels with the hostname come first, then
+    /// deeper subdomains, then alphabetical order.
+    pub fn suggest_related(&self, hostname: &str, limit: usize) -> Vec<Arc<str>> {
+        let hostname = hostname.trim().trim_end_matches('.');
+        let hostname = to_ascii_domain(hostname)
+            .unwrap_or_else(|_| hostname.to_string())
+            .to_ascii_lowercase();
+        let base = registrable_domain(&hostname);
+        if is_local_domain(base) {
+            return Vec::new();
+        }
+        let suffix = format!(".{}", base);
+
+        let mut related: Vec<(usize, usize, Arc<str>)> = self
+            .blocking
+            .lock()
+            .iter()
+            .filter(|candidate| {
+                candidate.as_ref() != hostname
+                    && (candidate.as_ref() == base || candidate.ends_with(&suffix))
+            })
+            .map(|candidate| {
+                let shared = candidate
+                    .rsplit('.')
+                    .zip(hostname.rsplit('.'))
+                    .take_while(|(a, b)| a == b)
+                    .count();
+                (shared, candidate.split('.').count(), candidate.clone())
+            })
+            .collect();
+
+        related.sort_by(|a, b| b.0.cmp(&a.0).then(b.1.cmp(&a.1)).then(a.2.cmp(&b.2)));
+        related
+            .into_iter()
+            .take(limit)
+            .map(|(_, _, candidate)| candidate)
+            .collect()
+    }
+
+    /// Explain why a hostname is or isn't blocked
+    pub fn explain(&self, hostname: &str) -> DomainExplanation {
+        let hostname = hostname.trim();
+        let hostname = to_ascii_domain(hostname).unwrap_or_else(|_| hostname.to_string());
+        let hostname = hostname.as_str();
+        let blocked = self.blocking.lock().contains(hostname);
+        let preserved = self
+            .preserved_lines
+            .lock()
+            .iter()
+            .any(|line| line.mentions(hostname));
+        let origins = self
+            .origins
+            .lock()
+            .get(hostname)
+            .cloned()
+            .unwrap_or_default();
+
+        DomainExplanation {
+            hostname: hostname.into(),
+            display: to_display_domain(hostname).into(),
+            blocked,
+            local: self.is_local(hostname),
+            preserved,
+            manual: origins.contains(MANUAL_ORIGIN),
+            sources: origins
+                .into_iter()
+                .filter(|origin| origin.as_ref() != MANUAL_ORIGIN)
+                .collect(),
+            allowlisted: self.is_allowed(hostname),
+            wildcard: None,
+        }
+    }
+
+    /// Pause blocking for `duration`, at most `MAX_PAUSE_MINUTES`; returns when the pause ends
+    /// The end is saved next to the state database so a restart can resume
+    pub fn pause_blocking(&self, duration: Duration) -> Result<Instant, anyhow::Error> {
+        if duration > Duration::from_secs(MAX_PAUSE_MINUTES * 60) {
+            return Err(anyhow::anyhow!(
+                "Blocking can be paused for at most {} minutes",
+                MAX_PAUSE_MINUTES
+            ));
+        }
+        let until = Instant::now()
+            .checked_add(duration)
+            .ok_or_else(|| anyhow::anyhow!("Pause duration is out of range"))?;
+        let until_unix = unix_now()
+            .checked_add(duration.as_secs())
+            .ok_or_else(|| anyhow::anyhow!("Pause duration is out of range"))?;
+        *self.paused_until.lock() = Some(until);
+        self.save_pause(until_unix);
+        Ok(until)
+    }
+
+    /// Put back the pause in effect before `pause_blocking`, e.g. when the
+    /// paused hosts file couldn't be written
+    pub fn restore_pause(&self, previous: Option<Instant>) {
+        *self.paused_until.lock() = previous;
+        match previous {
+            Some(until) => {
+                let remaining = until.saturating_duration_since(Instant::now());
+                self.save_pause(unix_now().saturating_add(remaining.as_secs()));
+            }
+            None => self.forget_pause(),
+        }
+    }
+
+    /// Save the end of the pause, logging a failure
+    fn save_pause(&self, until_unix: u64) {
+        if let Some(path) = self.pause_path() {
+            if let Err(e) = save_schedule(&path, until_unix) {
+                self.log_error(
+                    "pause",
+                    format!("Failed to write {}: {}", path.display(), e),
+                );
+            }
+        }
+    }
+
+    /// End a pause; returns whether blocking was paused
+    pub fn resume_blocking(&self) -> bool {
+        let resumed = self.paused_until.lock().take().is_some();
+        self.forget_pause();
+        resumed
+    }
+
+    /// End the pause ending at `until`, unless it was resumed or replaced
+    pub fn resume_blocking_if(&self, until: Instant) -> bool {
+        let mut paused_until = self.paused_until.lock();
+        if *paused_until != Some(until) {
+            return false;
+        }
+        *paused_until = None;
+        drop(paused_until);
+        self.forget_pause();
+        true
+    }
+
+    /// Pick up a pause saved before a restart; returns when it ends
+    /// A pause that ended while the app wasn't running ends now, so the
+    /// entries left commented out in the hosts file get written back
+    pub fn reinstate_pause(&self) -> Result<Option<Instant>, anyhow::Error> {
+        let Some(path) = self.pause_path() else {
+            return Ok(None);
+        };
+        let Some(at) = load_schedule(&path)? else {
+            return Ok(None);
+        };
+        let until = Instant::now() + time_until(at);
+        *self.paused_until.lock() = Some(until);
+        Ok(Some(until))
+    }
+
+    /// Path of the saved pause, next to the state database
+    #[inline]
+    fn pause_path(&self) -> Option<PathBuf> {
+        self.db_path
+            .lock()
+            .as_deref()
+            .map(|db_path| db_path.with_file_name(PAUSE_FILE))
+    }
+
+    /// Remove the saved pause
+    fn forget_pause(&self) {
+        if let Some(path) = self.pause_path() {
+            if let Err(e) = remove_schedule(&path) {
+                self.log_error(
+                    "pause",
+                    format!("Failed to remove {}: {}", path.display(), e),
+                );
+            }
+        }
+    }
+
+    /// Time left before blocking resumes, or None if it isn't paused
+    pub fn pause_remaining(&self) -> Option<Duration> {
+        let until = (*self.paused_until.lock())?;
+        let remaining = until.checked_duration_since(Instant::now())?;
+        (!remaining.is_zero()).then_some(remaining)
+    }
+
+    /// Options for writing the hosts file, with entries commented out while paused
+    /// The header read from the file, with any edits, replaces the configured one
+    #[inline]
+    fn hosts_serialize_options(&self) -> SerializeOptions {
+        let options = self.config.lock().serialize_options();
+        let header = options
+            .header
+            .map(|configured| self.file_header.lock().clone().unwrap_or(configured));
+        SerializeOptions {
+            paused: self.pause_remaining().is_some(),
+            header,
+            ..options
+        }
+    }
+
+    /// Serialize state to hosts file content
+    #[inline]
+    pub fn serialize(&self) -> String {
+        let options = self.hosts_serialize_options();
+        let preserved_lines = self.preserved_lines.lock();
+        let blocking = self.blocking.lock();
+        let sinks = self.sinks.lock();
+        let annotations = self.annotations.lock();
+        let disabled = self.disabled.lock();
+        serialize_hosts(
+            &preserved_lines,
+            &blocking,
+            &sinks,
+            &annotations,
+            &disabled,
+            &options,
+        )
+    }
+
+    /// Serialize only the managed blocklist, without preserved lines
+    pub fn serialize_managed_only(&self, format: ExportFormat) -> String {
+        let options = self.config.lock().serialize_options();
+        let blocking = self.blocking.lock();
+        let sinks = self.sinks.lock();
+        serialize_managed_only(&blocking, &sinks, format, &options)
+    }
+
+    /// Serialize state straight to a file through a buffered writer
+    /// Returns the number of bytes written
+    pub fn export_to_path(&self, path: &Path) -> Result<u64, anyhow::Error> {
+        let mut writer = BufWriter::new(fs::File::create(path)?);
+        {
+            let options = self.hosts_serialize_options();
+            let preserved_lines = self.preserved_lines.lock();
+            let blocking = self.blocking.lock();
+            let sinks = self.sinks.lock();
+            let annotations = self.annotations.lock();
+            let disabled = self.disabled.lock();
+            write_hosts(
+                &mut writer,
+                &preserved_lines,
+                &blocking,
+                &sinks,
+                &annotations,
+                &disabled,
+                &options,
+            )?;
+        }
+        let file = writer.into_inner().map_err(|e| e.into_error())?;
+        Ok(file.metadata()?.len())
+    }
+
+    /// Write the blocked domains across `hosts.part1`, `hosts.part2`, ... in
+    /// `dest_dir`, at most `max_per_file` domains each, for systems that load
+    /// several included hosts files
+    ///
+    /// The first part also maps localhost. A directory that already has parts
+    /// is refused unless `overwrite` is set, in which case they're replaced and
+    /// parts left from an earlier, longer split are removed. Returns the files
+    /// written, in order.
+    pub fn split_export(
+        &self,
+        dest_dir: &Path,
+        max_per_file: usize,
+        overwrite: bool,
+    ) -> Result<Vec<PathBuf>, anyhow::Error> {
+        if max_per_file == 0 {
+            return Err(anyhow::anyhow!("max_per_file must be at least 1"));
+        }
+        if !overwrite && dest_dir.is_dir() {
+            for entry in fs::read_dir(dest_dir)? {
+                let name = entry?.file_name();
+                let is_part = name
+                    .to_str()
+                    .and_then(|name| name.strip_prefix("hosts.part"))
+                    .is_some_and(|n| !n.is_empty() && n.bytes().all(|b| b.is_ascii_digit()));
+                if is_part {
+                    return Err(anyhow::anyhow!(
+                        "{} already has split hosts files",
+                        dest_dir.display()
+                    ));
+                }
+            }
+        }
+        fs::create_dir_all(dest_dir)?;
+        let options = self.config.lock().serialize_options();
+        let blocking = self.blocking.lock().clone();
+        let sinks = self.sinks.lock().clone();
+
+        let domains: Vec<Arc<str>> = blocking.into_iter().collect();
+        let mut chunks: Vec<&[Arc<str>]> = domains.chunks(max_per_file).collect();
+        if chunks.is_empty() {
+            chunks.push(&[]);
+        }
+        let mut files = Vec::with_capacity(chunks.len());
+        for (index, chunk) in chunks.into_iter().enumerate() {
+            let mut content = String::new();
+            if index == 0 {
+                content.push_str(&format!(
+                    "{}{}localhost\n",
+                    DEFAULT_SINK,
+                    options.separator.as_char()
+                ));
+            }
+            let chunk: BTreeSet<Arc<str>> = chunk.iter().cloned().collect();
+            content.push_str(&serialize_managed_only(
+                &chunk,
+                &sinks,
+                ExportFormat::Hosts,
+                &options,
+            ));
+            let path = dest_dir.join(format!("hosts.part{}", index + 1));
+            fs::write(&path, content)?;
+            files.push(path);
+        }
+
+        let mut stale = files.len() + 1;
+        while dest_dir.join(format!("hosts.part{}", stale)).exists() {
+            fs::remove_file(dest_dir.join(format!("hosts.part{}", stale)))?;
+            stale += 1;
+        }
+        Ok(files)
+    }
+
+    /// Replace a preserved line with new content (validated as a single line)
+    pub fn update_preserved_line(&self, index: usize, content: &str) -> Result<(), anyhow::Error> {
+        let mut preserved_lines = self.preserved_lines.lock();
+        let line = preserved_line_mut(&mut preserved_lines, index)?;
+
+        let content = content.trim();
+        let updated = parse_preserved_line(content)?;
+        // Lines outside the managed region stay verbatim
+        *line = match line {
+            PreservedLine::Raw(_) => PreservedLine::Raw(content.into()),
+            _ => updated,
+        };
+        drop(preserved_lines);
+
+        self.mark_dirty();
+        Ok(())
+    }
+
+    /// Delete a preserved line
+    pub fn delete_preserved_line(&self, index: usize) -> Result<(), anyhow::Error> {
+        let mut preserved_lines = self.preserved_lines.lock();
+        preserved_line_mut(&mut preserved_lines, index)?;
+        preserved_lines.remove(index);
+        drop(preserved_lines);
+
+        self.mark_dirty();
+        Ok(())
+    }
+
+    /// Addresses mapped outside the blocklist with their hostnames, sorted by IP
+    pub fn network_entries(&self) -> Vec<NetworkEntry> {
+        network_entries(&self.preserved_lines.lock())
+    }
+
+    /// Drop redundant preserved lines (see `compact_preserved_lines`)
+    /// Returns how many lines were removed
+    pub fn compact_preserved_lines(&self) -> usize {
+        let mut preserved_lines = self.preserved_lines.lock();
+        let before = preserved_lines.len();
+        *preserved_lines = compact_preserved_lines(std::mem::take(&mut *preserved_lines));
+        let removed = before - preserved_lines.len();
+        drop(preserved_lines);
+
+        if removed > 0 {
+            self.mark_dirty();
+        }
+        removed
+    }
+
+    /// Rearrange the preserved lines (see `normalize_layout`)
+    /// Returns whether their order or content changed
+    pub fn normalize_layout(&self, style: LayoutStyle) -> bool {
+        let mut preserved_lines = self.preserved_lines.lock();
+        let before: Vec<Option<String>> =
+            preserved_lines.iter().map(PreservedLine::content).collect();
+        *preserved_lines = normalize_layout(std::mem::take(&mut *preserved_lines), style);
+        let changed = preserved_lines
+            .iter()
+            .map(PreservedLine::content)
+            .ne(before);
+        drop(preserved_lines);
+
+        if changed {
+            self.mark_dirty();
+        }
+        changed
+    }
+
+    /// Number of entries `dedupe_state` would remove, leaving the state as it is
+    pub fn duplicate_count(&self) -> usize {
+        let blocking = self.blocking.lock();
+        let preserved_lines = self.preserved_lines.lock().clone();
+        dedupe_preserved_lines(preserved_lines, &blocking).1
+    }
+
+    /// Remove blocked hostnames from preserved lines and repeated localhost
+    /// entries (see `dedupe_preserved_lines`), returning how many were removed
+    pub fn dedupe_state(&self) -> usize {
+        let blocking = self.blocking.lock();
+        let mut preserved_lines = self.preserved_lines.lock();
+        let (deduped, removed) =
+            dedupe_preserved_lines(std::mem::take(&mut *preserved_lines), &blocking);
+        *preserved_lines = deduped;
+        drop((blocking, preserved_lines));
+
+        if removed > 0 {
+            self.mark_dirty();
+        }
+        removed
+    }
+
+    /// Record an error in the error log (and the application log)
+    pub fn log_error(&self, source: &'static str, message: impl Into<Arc<str>>) {
+        let message = message.into();
+        tracing::error!(source, "{}", message);
+
+        let mut error_log = self.error_log.lock();
+        if error_log.len() >= ERROR_LOG_CAPACITY {
+            error_log.pop_front();
+        }
+        error_log.push_back(LogEntry {
+            timestamp: SystemTime::now(),
+            source,
+            message,
+        });
+    }
+
+    /// Get logged errors, newest first
+    #[inline]
+    pub fn get_recent_errors(&self) -> Vec<LogEntry> {
+        self.error_log.lock().iter().rev().cloned().collect()
+    }
+
+    /// Record that the state has unsaved changes
+    #[inline]
+    pub fn mark_dirty(&self) {
+        *self.dirty.lock() = true;
+    }
+
+    /// Record that the state matches what was last committed or loaded
+    #[inline]
+    pub fn mark_clean(&self) {
+        *self.dirty.lock() = false;
+        *self.saved_preserved_lines.lock() = self.preserved_lines.lock().clone();
+        self.pending_added.lock().clear();
+        self.pending_removed.lock().clear();
+    }
+
+    /// Note domains blocked since the last commit
+    /// A domain unblocked earlier and blocked again cancels out
+    /// The first time a domain is blocked is also remembered
+    fn record_added(&self, hostnames: impl IntoIterator<Item = Arc<str>>) {
+        let now = unix_now();
+        let mut pending_added = self.pending_added.lock();
+        let mut pending_removed = self.pending_removed.lock();
+        let mut first_seen = self.first_seen.lock();
+        for hostname in hostnames {
+            first_seen.entry(hostname.clone()).or_insert(now);
+            if !pending_removed.remove(&hostname) {
+                pending_added.insert(hostname);
+            }
+        }
+    }
+
+    /// Note domains unblocked since the last commit
+    /// A domain blocked earlier and unblocked again cancels out
+    fn record_removed(&self, hostnames: impl IntoIterator<Item = Arc<str>>) {
+        let hostnames: Vec<Arc<str>> = hostnames.into_iter().collect();
+        let mut pending_added = self.pending_added.lock();
+        let mut pending_removed = self.pending_removed.lock();
+        for hostname in &hostnames {
+            if !pending_added.remove(hostname) {
+                pending_removed.insert(hostname.clone());
+            }
+        }
+        drop((pending_added, pending_removed));
+        self.forget_origins(hostnames);
+    }
+
+    /// Blocked domains as of the last save or load: the state without the
+    /// changes made since
+    fn last_saved_blocking(&self) -> BTreeSet<Arc<str>> {
+        let mut saved = self.get_all_blocks();
+        for hostname in self.pending_added.lock().iter() {
+            saved.remove(hostname);
+        }
+        saved.extend(self.pending_removed.lock().iter().cloned());
+        saved
+    }
+
+    /// Drop pending removals a reload brought back
+    fn prune_pending(&self) {
+        let blocking = self.blocking.lock();
+        self.pending_removed
+            .lock()
+            .retain(|hostname| !blocking.contains(hostname));
+    }
+
+    /// How many blocked domains each source at `urls` lists, and how many no
+    /// other of them does, from the recorded origins (see `source_contributions`)
+    pub fn source_contributions(&self, urls: &[String]) -> Vec<SourceContribution> {
+        let blocking = self.blocking.lock();
+        let origins = self.origins.lock();
+        source_contributions(urls, &blocking, &origins)
+    }
+
+    /// Number of domains blocked and unblocked since the last commit
+    pub fn pending_counts(&self) -> (usize, usize) {
+        (
+            self.pending_added.lock().len(),
+            self.pending_removed.lock().len(),
+        )
+    }
+
+    /// Compare the state against the current hosts file content
+    pub fn pending_changes(&self, disk_content: &str) -> Result<PendingChanges, anyhow::Error> {
+        let options = self.config.lock().parse_options();
+        let on_disk: BTreeSet<Arc<str>> = parse_hosts_with(disk_content, &options)?
+            .blocking
+            .into_iter()
+            .filter(|hostname| !options.is_local(hostname))
+            .collect();
+        let diff = diff_blocking(&on_disk, &self.get_all_blocks());
+
+        Ok(PendingChanges {
+            dirty: *self.dirty.lock(),
+            differs_from_disk: self.serialize() != disk_content,
+            added: diff.added,
+            removed: diff.removed,
+        })
+    }
+
+    /// Check the hosts file on disk for changes the app doesn't know about
+    ///
+    /// Unlike `pending_changes`, unsaved edits don't count: domains added or
+    /// removed since the last save are expected to differ from the disk.
+    pub fn verify_sync(&self, disk_content: &str) -> Result<SyncStatus, anyhow::Error> {
+        let options = self.config.lock().parse_options();
+        let parsed = parse_hosts_with(disk_content, &options)?;
+        let on_disk: BTreeSet<Arc<str>> = parsed
+            .blocking
+            .into_iter()
+            .filter(|hostname| !options.is_local(hostname))
+            .collect();
+
+        let blocking = diff_blocking(&self.last_saved_blocking(), &on_disk);
+
+        let (preserved_on_disk_only, preserved_in_app_only) =
+            diff_preserved_lines(&parsed.preserved_lines, &self.preserved_lines.lock());
+
+        let drift = SyncDrift {
+            blocked_on_disk_only: blocking.added,
+            blocked_in_app_only: blocking.removed,
+            preserved_on_disk_only,
+            preserved_in_app_only,
+        };
+        if drift == SyncDrift::default() {
+            return Ok(SyncStatus::InSync);
+        }
+        Ok(SyncStatus::Drifted(drift))
+    }
+
+    /// Get statistics (only counts localhost entries)
+    #[inline]
+    pub fn get_total_blocked(&self) -> usize {
+        self.blocking.lock().len()
+    }
+
+    /// SHA-256 of the blocked domains, as lowercase hex
+    ///
+    /// Computed over the sorted domains one per line, so sinks, comments and
+    /// the file's layout don't affect it: two identical sets of blocked domains
+    /// always have the same fingerprint.
+    pub fn blocklist_fingerprint(&self) -> String {
+        let mut hasher = Sha256::new();
+        for hostname in self.blocking.lock().iter() {
+            hasher.update(hostname.as_bytes());
+            hasher.update(b"\n");
+        }
+        hasher
+            .finalize()
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect()
+    }
+
+    /// Path, size and writability of the active hosts file, with the entry count
+    pub fn host_file_info(&self) -> HostFileInfo {
+        let path = self
+            .get_config()
+            .host_file_path
+            .unwrap_or_else(default_hosts_file_path);
+        let size = fs::metadata(&path).map(|metadata| metadata.len()).ok();
+        let last_modified = self.last_modified.lock().map(|modified| {
+            modified
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs()
+        });
+
+        HostFileInfo {
+            exists: size.is_some(),
+            size,
+            last_modified,
+            writable: can_write_hosts(&path),
+            entry_count: self.get_total_blocked(),
+            path,
+        }
+    }
+
+    /// Number of the given hostnames that aren't blocked yet, counting each once
+    pub fn count_unblocked<'a>(&self, hostnames: impl IntoIterator<Item = &'a str>) -> usize {
+        let block_single_label = self.config.lock().block_single_label;
+        let allowlist = self.get_allowlist();
+        let blocking = self.blocking.lock();
+        hostnames
+            .into_iter()
+            .map(canonical_hostname)
+            .filter(|hostname| is_mergeable(&blocking, &allowlist, hostname, block_single_label))
+            .collect::<BTreeSet<_>>()
+            .len()
+    }
+
+    /// Refuse to grow the blocked set past `max_total_domains` unless overridden
+    /// Staying level or shrinking is always allowed, even over the cap
+    pub fn check_domain_cap(
+        &self,
+        new_total: usize,
+        allow_over_cap: bool,
+    ) -> Result<(), anyhow::Error> {
+        let cap = self.config.lock().max_total_domains;
+        let current = self.get_total_blocked();
+        if allow_over_cap || cap == 0 || new_total <= cap || new_total <= current {
+            return Ok(());
+        }
+        Err(anyhow::anyhow!(
+            "Blocking {} domains would exceed the limit of {} ({} would have been added); allow going over the limit to continue",
+            new_total,
+            cap,
+            new_total - current
+        ))
+    }
+
+    /// Get config (read-only)
+    #[inline]
+    pub fn get_config(&self) -> Config {
+        self.config.lock().clone()
+    }
+
+    /// Update config
+    #[inline]
+    pub fn update_config(&self, config: Config) {
+        let previous = std::mem::replace(&mut *self.config.lock(), config);
+        self.forget_stale_header(&previous);
+    }
+
+    /// Replace the config and save it, switching to its hosts file if that changed
+    /// If saving or switching fails, the previous config is put back in memory
+    /// and on disk. Returns the new hosts file path when it changed.
+    pub fn apply_config(&self, config: Config) -> Result<Option<Arc<Path>>, anyhow::Error> {
+        let previous = self.get_config();
+        let hosts_path = config
+            .host_file_path
+            .clone()
+            .unwrap_or_else(default_hosts_file_path);
+        let previous_hosts_path = previous
+            .host_file_path
+            .clone()
+            .unwrap_or_else(default_hosts_file_path);
+
+        // The new config is in place while the new file is parsed, so its
+        // parse options apply; the config watcher sees the write as matching
+        self.update_config(config);
+        let applied = self
+            .persist_config()
+            .map_err(|e| anyhow::anyhow!("Failed to save config: {}", e))
+            .and_then(|()| {
+                if hosts_path == previous_hosts_path {
+                    return Ok(());
+                }
+                self.switch_hosts_file(&hosts_path)
+                    .map_err(|e| anyhow::anyhow!("Failed to load {}: {}", hosts_path.display(), e))
+            });
+        if let Err(e) = applied {
+            self.update_config(previous);
+            if let Err(restore) = self.persist_config() {
+                self.log_error("config", format!("Failed to restore config: {}", restore));
+            }
+            return Err(e);
+        }
+        Ok((hosts_path != previous_hosts_path).then_some(hosts_path))
+    }
+
+    /// Drop the header read from the file once the configured header changes
+    /// so the new one is written in its place
+    fn forget_stale_header(&self, previous: &Config) {
+        if self.config.lock().file_header != previous.file_header {
+            *self.file_header.lock() = None;
+        }
+    }
+
+    /// Set where the config file lives
+    #[inline]
+    pub fn set_config_path(&self, path: Arc<Path>) {
+        *self.config_path.lock() = Some(path);
+    }
+
+    /// Save the config file if a location has been configured
+    #[inline]
+    pub fn persist_config(&self) -> Result<(), anyhow::Error> {
+        let config_path = self.config_path.lock().clone();
+        match config_path {
+            Some(path) => self.get_config().save_to_file(&path),
+            None => Ok(()),
+        }
+    }
+
+    /// Reload the config after the file changed on disk
+    /// Returns the previous config, or None when the file matches the config
+    /// in memory (as it does after the app's own writes) or looks incomplete
+    pub fn reload_config(&self, path: &Path) -> Result<Option<Config>, anyhow::Error> {
+        // A file caught mid-rewrite can be briefly empty or cut short, which
+        // would read as defaults for everything missing
+        let content = fs::read_to_string(path)?;
+        if !is_complete_ini(&content) {
+            return Ok(None);
+        }
+        let mut loaded = Config::parse_ini(&content)?;
+        loaded.validate()?;
+
+        let mut config = self.config.lock();
+        // The history directory is filled in at startup when unset
+        if loaded.history_dir.is_none() {
+            loaded.history_dir = config.history_dir.clone();
+        }
+        if loaded.to_ini() == config.to_ini() {
+            return Ok(None);
+        }
+        let previous = std::mem::replace(&mut *config, loaded);
+        drop(config);
+        self.forget_stale_header(&previous);
+        Ok(Some(previous))
+    }
+
+    /// Directory holding the profiles, next to the config file
+    pub fn profiles_dir(&self) -> Result<PathBuf, anyhow::Error> {
+        self.config_path
+            .lock()
+            .as_deref()
+            .and_then(Path::parent)
+            .map(|dir| dir.join(PROFILES_DIR))
+            .ok_or_else(|| anyhow::anyhow!("Config file location not set"))
+    }
+
+    /// Name of the profile in use
+    #[inline]
+    pub fn active_profile(&self) -> String {
+        self.config
+            .lock()
+            .active_profile
+            .clone()
+            .unwrap_or_else(|| DEFAULT_PROFILE.to_string())
+    }
+
+    /// Names of all profiles, always including the default one
+    pub fn list_profiles(&self) -> Result<Vec<String>, anyhow::Error> {
+        let mut names = list_profiles(&self.profiles_dir()?)?;
+        if !names.iter().any(|name| name == DEFAULT_PROFILE) {
+            names.insert(0, DEFAULT_PROFILE.to_string());
+        }
+        Ok(names)
+    }
+
+    /// Create an empty profile writing to `host_file_path`, or to the current
+    /// hosts file if None
+    pub fn create_profile(
+        &self,
+        name: &str,
+        host_file_path: Option<Arc<Path>>,
+    ) -> Result<Profile, anyhow::Error> {
+        validate_profile_name(name)?;
+        let profiles_dir = self.profiles_dir()?;
+        if name == DEFAULT_PROFILE || profile_dir(&profiles_dir, name).exists() {
+            return Err(anyhow::anyhow!("A profile named {} already exists", name));
+        }
+
+        let profile = Profile {
+            name: name.to_string(),
+            host_file_path: host_file_path.or_else(|| self.config.lock().host_file_path.clone()),
+            sources: Vec::new(),
+        };
+        profile.save(&profiles_dir)?;
+        let db = serde_json::json!({ "version": DB_VERSION, "blocking": [] });
+        write_atomic(
+            &profile_db_path(&profiles_dir, name),
+            &serde_json::to_string_pretty(&db)?,
+        )?;
+        Ok(profile)
+    }
+
+    /// Make another profile the active one
+    ///
+    /// The current profile's settings and blocked domains are saved to its
+    /// directory first. The new profile's blocked domains replace the state and
+    /// its hosts file becomes the target; the state is left dirty so the next
+    /// commit renders them. Returns the new hosts file path.
+    pub fn switch_profile(&self, name: &str) -> Result<Arc<Path>, anyhow::Error> {
+        let profiles_dir = self.profiles_dir()?;
+        let current = self.active_profile();
+        if name == current {
+            return Ok(self
+                .config
+                .lock()
+                .host_file_path
+                .clone()
+                .unwrap_or_else(default_hosts_file_path));
+        }
+        if *self.dirty.lock() {
+            return Err(anyhow::anyhow!(
+                "Save or discard the unsaved changes before switching profiles"
+            ));
+        }
+
+        let profile = Profile::load(&profiles_dir, name)?;
+        let hosts_path = profile
+            .host_file_path
+            .clone()
+            .unwrap_or_else(default_hosts_file_path);
+        let content = if hosts_path.exists() {
+            fs::read_to_string(&hosts_path)?
+        } else {
+            String::new()
+        };
+        let options = self.config.lock().parse_options();
+        let parsed = parse_hosts_with(&content, &options)?;
+
+        let config = self.get_config();
+        Profile {
+            name: current.clone(),
+            host_file_path: config.host_file_path.clone(),
+            sources: config.sources.clone(),
+        }
+        .save(&profiles_dir)?;
+        let current_db_path = profile_db_path(&profiles_dir, &current);
+        self.save_db(&current_db_path)?;
+
+        let previous_db_path = self.db_path.lock().clone();
+        let db_path: Arc<Path> = profile_db_path(&profiles_dir, name).as_path().into();
+        self.load_db(&db_path)?;
+        self.set_db_path(db_path);
+        self.update_config(Config {
+            host_file_path: profile.host_file_path,
+            sources: profile.sources,
+            active_profile: Some(name.to_string()),
+            ..config.clone()
+        });
+        // Until the config is saved the switch can be undone: the current
+        // profile had no unsaved changes, so its database has all of them
+        if let Err(e) = self.persist_config() {
+            self.update_config(config);
+            if let Err(restore) = self.persist_config() {
+                self.log_error("profile", format!("Failed to restore config: {}", restore));
+            }
+            if let Err(restore) = self.load_db(&current_db_path) {
+                self.log_error(
+                    "profile",
+                    format!(
+                        "Failed to restore {}: {}",
+                        current_db_path.display(),
+                        restore
+                    ),
+                );
+            }
+            *self.db_path.lock() = previous_db_path;
+            return Err(anyhow::anyhow!("Failed to save config: {}", e));
+        }
+
+        // Only the preserved lines come from the hosts file: its domains belong
+        // to whichever profile last wrote it
+        *self.preserved_lines.lock() = parsed.preserved_lines;
+        *self.file_header.lock() = parsed.header;
+        let on_disk: BTreeSet<Arc<str>> = parsed
+            .blocking
+            .into_iter()
+            .filter(|hostname| !options.is_local(hostname))
+            .collect();
+        self.track_changes_since(&on_disk);
+        self.record_last_modified(&hosts_path);
+
+        Ok(hosts_path)
+    }
+
+    /// Write the config, state database, profiles and optionally the history
+    /// snapshots to a single backup archive at `dest`
+    ///
+    /// The state database is taken from memory, so unsaved changes are included;
+    /// history snapshots are stored as full copies. Returns the manifest written.
+    pub fn export_full_backup(
+        &self,
+        dest: &Path,
+        include_history: bool,
+    ) -> Result<BackupManifest, anyhow::Error> {
+        let config = self.get_config();
+        let mut files = BackupFiles::new();
+        files.insert("config.ini".to_string(), config.to_ini());
+        files.insert(
+            "db.json".to_string(),
+            serde_json::to_string_pretty(&self.db_json())?,
+        );
+
+        if let Ok(profiles_dir) = self.profiles_dir() {
+            for name in list_profiles(&profiles_dir)? {
+                for file in ["profile.ini", "db.json"] {
+                    let path = profile_dir(&profiles_dir, &name).join(file);
+                    if path.exists() {
+                        files.insert(
+                            format!("{}/{}/{}", PROFILES_DIR, name, file),
+                            fs::read_to_string(&path)?,
+                        );
+                    }
+                }
+            }
+        }
+
+        if include_history {
+            if let Some(history_dir) = config.history_dir.as_deref() {
+                for entry in list_history_entries(history_dir, &config.history_prefix)? {
+                    files.insert(
+                        format!("history/{}", entry.filename),
+                        read_snapshot(&entry.path)?,
+                    );
+                }
+            }
+        }
+
+        let manifest = BackupManifest::new(include_history);
+        write_backup(dest, &manifest, &files)?;
+        Ok(manifest)
+    }
+
+    /// Restore a backup written by `export_full_backup`
+    ///
+    /// Everything is validated before anything is written. The history
+    /// directory stays this machine's, and snapshots already in it are kept.
+    /// Like switching profiles, the restored blocked domains replace the state
+    /// and are left dirty for the next commit. Profiles whose files would
+    /// change, the restored database's included, are only overwritten with
+    /// `replace_profiles`; the files are written all together or not at all.
+    /// Returns the backup's manifest and the hosts file the restored config
+    /// writes to.
+    pub fn import_full_backup(
+        &self,
+        src: &Path,
+        replace_profiles: bool,
+    ) -> Result<(BackupManifest, Arc<Path>), anyhow::Error> {
+        if *self.dirty.lock() {
+            return Err(anyhow::anyhow!(
+                "Save or discard the unsaved changes before restoring a backup"
+            ));
+        }
+        let (manifest, files) = read_backup(src)?;
+        let profiles_dir = self.profiles_dir()?;
+
+        let current = self.get_config();
+        let mut config = Config::parse_ini(&files["config.ini"])?;
+        config.validate()?;
+        config.history_dir = current.history_dir.clone();
+        // Without a database location for the original setup, it's restored as
+        // the default profile
+        let db_path: Arc<Path> = match (&config.active_profile, self.db_path.lock().clone()) {
+            (None, Some(db_path)) if current.active_profile.is_none() => db_path,
+            _ => {
+                let name = config
+                    .active_profile
+                    .get_or_insert_with(|| DEFAULT_PROFILE.to_string());
+                validate_profile_name(name)?;
+                profile_db_path(&profiles_dir, name).as_path().into()
+            }
+        };
+        let db: serde_json::Value = serde_json::from_str(&files["db.json"])?;
+        let db_version = db.get("version").and_then(|v| v.as_u64()).unwrap_or(0);
+        if db_version > DB_VERSION {
+            return Err(anyhow::anyhow!(
+                "Unsupported database version {} (expected at most {})",
+                db_version,
+                DB_VERSION
+            ));
+        }
+
+        let hosts_path = config
+            .host_file_path
+            .clone()
+            .unwrap_or_else(default_hosts_file_path);
+        let content = if hosts_path.exists() {
+            fs::read_to_string(&hosts_path)?
+        } else {
+            String::new()
+        };
+        let options = config.parse_options();
+        let parsed = parse_hosts_with(&content, &options)?;
+
+        // Profiles whose files on disk the restore would change
+        let differs = |path: &Path, content: &str| {
+            path.exists() && fs::read_to_string(path).map_or(true, |existing| existing != content)
+        };
+        let mut writes: BTreeMap<PathBuf, String> = BTreeMap::new();
+        let mut replaced: BTreeSet<String> = BTreeSet::new();
+        for (path, content) in &files {
+            match BackupEntry::parse(path)? {
+                BackupEntry::Config | BackupEntry::Db => {}
+                BackupEntry::Profile { name, file } => {
+                    let path = profile_dir(&profiles_dir, name).join(file);
+                    if differs(&path, content) {
+                        replaced.insert(name.to_string());
+                    }
+                    writes.insert(path, content.clone());
+                }
+                BackupEntry::History(filename) => {
+                    let Some(history_dir) = config.history_dir.as_deref() else {
+                        continue;
+                    };
+                    let path = history_dir.join(filename);
+                    if !path.exists() {
+                        writes.insert(path, content.clone());
+                    }
+                }
+            }
+        }
+        if differs(&db_path, &files["db.json"]) {
+            replaced.insert(
+                config
+                    .active_profile
+                    .clone()
+                    .unwrap_or_else(|| DEFAULT_PROFILE.to_string()),
+            );
+        }
+        if !replaced.is_empty() && !replace_profiles {
+            return Err(anyhow::anyhow!(
+                "Restoring would overwrite the profiles {}; restore with replace_profiles to \
+                 replace them",
+                replaced.into_iter().collect::<Vec<_>>().join(", ")
+            ));
+        }
+        writes.insert(db_path.to_path_buf(), files["db.json"].clone());
+        if let Some(config_path) = self.config_path.lock().clone() {
+            writes.insert(config_path.to_path_buf(), config.to_ini());
+        }
+        write_restored_files(&writes)?;
+
+        self.update_config(config);
+        self.load_db(&db_path)?;
+        self.set_db_path(db_path);
+
+        *self.preserved_lines.lock() = parsed.preserved_lines;
+        *self.file_header.lock() = parsed.header;
+        let on_disk: BTreeSet<Arc<str>> = parsed
+            .blocking
+            .into_iter()
+            .filter(|hostname| !options.is_local(hostname))
+            .collect();
+        self.track_changes_since(&on_disk);
+        self.record_last_modified(&hosts_path);
+
+        Ok((manifest, hosts_path))
+    }
+
+    /// Where a scheduled commit is remembered, next to the state database
+    fn schedule_path(&self) -> Option<PathBuf> {
+        self.db_path
+            .lock()
+            .as_deref()
+            .map(|db_path| db_path.with_file_name(SCHEDULE_FILE))
+    }
+
+    /// Schedule a commit for `at` (seconds since the Unix epoch), replacing any
+    /// earlier schedule
+    /// The state database and the schedule are saved so a restart can pick it up
+    pub fn schedule_commit(&self, at: u64) -> Result<(), anyhow::Error> {
+        self.persist_db()?;
+        if let Some(path) = self.schedule_path() {
+            save_schedule(&path, at)?;
+        }
+        *self.scheduled_commit.lock() = Some(at);
+        Ok(())
+    }
+
+    /// Drop the scheduled commit; returns whether one was scheduled
+    pub fn cancel_scheduled_commit(&self) -> Result<bool, anyhow::Error> {
+        if let Some(path) = self.schedule_path() {
+            remove_schedule(&path)?;
+        }
+        Ok(self.scheduled_commit.lock().take().is_some())
+    }
+
+    /// Claim the commit scheduled for `at`, unless it was cancelled or replaced
+    pub fn take_scheduled_commit_if(&self, at: u64) -> bool {
+        let mut scheduled_commit = self.scheduled_commit.lock();
+        if *scheduled_commit != Some(at) {
+            return false;
+        }
+        *scheduled_commit = None;
+        drop(scheduled_commit);
+        if let Some(path) = self.schedule_path() {
+            if let Err(e) = remove_schedule(&path) {
+                self.log_error(
+                    "schedule",
+                    format!("Failed to remove {}: {}", path.display(), e),
+                );
+            }
+        }
+        true
+    }
+
+    /// Pick up a commit scheduled before the app restarted
+    /// The changes it was scheduled for were stored in the state database; they
+    /// are compared against the hosts file so they show as unsaved again
+    pub fn reinstate_scheduled_commit(&self) -> Result<Option<u64>, anyhow::Error> {
+        let Some(path) = self.schedule_path() else {
+            return Ok(None);
+        };
+        let Some(at) = load_schedule(&path)? else {
+            return Ok(None);
+        };
+
+        let on_disk = self.blocking_on_disk()?;
+        self.track_changes_since(&on_disk);
+        *self.scheduled_commit.lock() = Some(at);
+        Ok(Some(at))
+    }
+
+    /// Compare the blocked domains loaded from the state database with the
+    /// hosts file, recording any difference as unsaved changes
+    /// Returns whether the hosts file is out of date; it's only rewritten on the
+    /// next commit
+    pub fn reconcile_with_hosts_file(&self) -> Result<bool, anyhow::Error> {
+        let on_disk = self.blocking_on_disk()?;
+        if on_disk == self.get_all_blocks() {
+            return Ok(false);
+        }
+        self.track_changes_since(&on_disk);
+        Ok(true)
+    }
+
+    /// Blocked domains in the configured hosts file (none if it doesn't exist)
+    fn blocking_on_disk(&self) -> Result<BTreeSet<Arc<str>>, anyhow::Error> {
+        let config = self.get_config();
+        let hosts_path = config
+            .host_file_path
+            .clone()
+            .unwrap_or_else(default_hosts_file_path);
+        let content = fs::read_to_string(&hosts_path).unwrap_or_default();
+        let options = config.parse_options();
+        Ok(parse_hosts_with(&content, &options)?
+            .blocking
+            .into_iter()
+            .filter(|hostname| !options.is_local(hostname))
+            .collect())
+    }
+
+    /// Record how the blocked domains differ from those on disk as unsaved changes
+    fn track_changes_since(&self, on_disk: &BTreeSet<Arc<str>>) {
+        let blocking = self.get_all_blocks();
+        self.record_added(blocking.difference(on_disk).cloned());
+        self.record_removed(on_disk.difference(&blocking).cloned());
+        self.mark_dirty();
+    }
+
+    /// Whether a hostname is local and never blocked under the current config
+    #[inline]
+    pub fn is_local(&self, hostname: &str) -> bool {
+        is_local_domain_with(hostname, self.config.lock().block_single_label)
+    }
+
+    /// The hostnames that may be blocked: neither local nor on `allowlist`
+    /// Every path loading blocked domains from a file or the database filters through here
+    fn blockable(
+        &self,
+        hostnames: impl IntoIterator<Item = Arc<str>>,
+        allowlist: &BTreeSet<Arc<str>>,
+    ) -> BTreeSet<Arc<str>> {
+        let block_single_label = self.config.lock().block_single_label;
+        hostnames
+            .into_iter()
+            .filter(|hostname| {
+                !is_local_domain_with(hostname, block_single_label) && !allowlist.contains(hostname)
+            })
+            .collect()
+    }
+}
+
+/// Whether two sets of preserved lines differ other than in blank lines
+#[inline]
+fn preserved_lines_differ(a: &[PreservedLine], b: &[PreservedLine]) -> bool {
+    let (a_only, b_only) = diff_preserved_lines(a, b);
+    !a_only.is_empty() || !b_only.is_empty()
+}
+
+/// Preserved lines only in `disk` and only in `app`, counting repeated lines
+/// Blank lines and the managed region marker are ignored
+fn diff_preserved_lines(
+    disk: &[PreservedLine],
+    app: &[PreservedLine],
+) -> (Vec<String>, Vec<String>) {
+    let mut counts: BTreeMap<String, isize> = BTreeMap::new();
+    let contents = |lines: &[PreservedLine]| -> Vec<String> {
+        lines
+            .iter()
+            .filter_map(PreservedLine::content)
+            .map(|line| line.trim().to_string())
+            .filter(|line| !line.is_empty())
+            .collect()
+    };
+    for line in contents(disk) {
+        *counts.entry(line).or_default() += 1;
+    }
+    for line in contents(app) {
+        *counts.entry(line).or_default() -= 1;
+    }
+
+    let mut disk_only = Vec::new();
+    let mut app_only = Vec::new();
+    for (line, count) in counts {
+        let extra = if count > 0 {
+            &mut disk_only
+        } else {
+            &mut app_only
+        };
+        extra.extend(std::iter::repeat_n(line, count.unsigned_abs()));
+    }
+    (disk_only, app_only)
+}
+
+/// Whether a merged domain would be added to the blocking set
+/// Allowlisted domains never are, since the allowlist overrides blocking
+#[inline]
+fn is_mergeable(
+    blocking: &BTreeSet<Arc<str>>,
+    allowlist: &BTreeSet<Arc<str>>,
+    hostname: &str,
+    block_single_label: bool,
+) -> bool {
+    !is_local_domain_with(hostname, block_single_label)
+        && !blocking.contains(hostname)
+        && !allowlist.contains(hostname)
+}
+
+/// Look up an editable preserved line (the managed region placeholder isn't one)
+fn preserved_line_mut(
+    preserved_lines: &mut [PreservedLine],
+    index: usize,
+) -> Result<&mut PreservedLine, anyhow::Error> {
+    let len = preserved_lines.len();
+    match preserved_lines.get_mut(index) {
+        Some(PreservedLine::ManagedRegion) => {
+            Err(anyhow::anyhow!("The managed region can't be edited"))
+        }
+        Some(line) => Ok(line),
+        None => Err(anyhow::anyhow!(
+            "Preserved line {} out of range ({} lines)",
+            index,
+            len
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::history::DEFAULT_HISTORY_PREFIX;
+    use crate::parser::{parse_hosts, HEADER_END, HEADER_START};
+
+    #[test]
+    fn test_add_block() {
+        let state = AppState::new(Config::default());
+        assert!(state.add_block("example.com"));
+        assert!(!state.add_block("example.com"));
+        assert!(!state.add_block("localhost"));
+        let blocking = state.blocking.lock();
+        assert!(blocking.contains(&Arc::from("example.com")));
+    }
+
+    #[test]
+    fn test_remove_block() {
+        let state = AppState::new(Config::default());
+
+        state.add_block("example.com");
+        state.remove_block("example.com");
+
+        let blocking = state.blocking.lock();
+        assert!(!blocking.contains(&Arc::from("example.com")));
+    }
+
+    #[test]
+    fn test_pending_changes() {
+        let state = AppState::new(Config::default());
+        state.add_block("example.com");
+        state.mark_clean();
+
+        let on_disk = state.serialize();
+        let pending = state.pending_changes(&on_disk).unwrap();
+        assert!(!pending.dirty);
+        assert!(!pending.differs_from_disk);
+
+        state.add_block("ads.com");
+        state.remove_block("example.com");
+        let pending = state.pending_changes(&on_disk).unwrap();
+        assert!(pending.dirty);
+        assert!(pending.differs_from_disk);
+        assert_eq!(pending.added, vec![Arc::from("ads.com")]);
+        assert_eq!(pending.removed, vec![Arc::from("example.com")]);
+    }
+
+    #[test]
+    fn test_verify_sync() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let hosts_path = temp_dir.path().join("hosts");
+        let content = "127.0.0.1 localhost\n192.168.1.1 router\n0.0.0.0 ads.example.com\n";
+        fs::write(&hosts_path, content).unwrap();
+
+        let state = AppState::new(Config::default());
+        state.load_from_file(&hosts_path).unwrap();
+        assert_eq!(state.verify_sync(content).unwrap(), SyncStatus::InSync);
+
+        // Unsaved edits aren't drift
+        state.add_block("new.example.com");
+        state.remove_block("ads.example.com");
+        assert_eq!(state.verify_sync(content).unwrap(), SyncStatus::InSync);
+
+        // An edit made outside the app is
+        let edited = "127.0.0.1 localhost\n192.168.1.2 router\n0.0.0.0 ads.example.com\n0.0.0.0 tracker.example.org\n";
+        fs::write(&hosts_path, edited).unwrap();
+        let SyncStatus::Drifted(drift) = state
+            .verify_sync(&fs::read_to_string(&hosts_path).unwrap())
+            .unwrap()
+        else {
+            panic!("expected drift");
+        };
+        assert_eq!(
+            drift.blocked_on_disk_only,
+            vec![Arc::from("tracker.example.org")]
+        );
+        assert!(drift.blocked_in_app_only.is_empty());
+        assert_eq!(drift.preserved_on_disk_only, vec!["192.168.1.2 router"]);
+        assert_eq!(drift.preserved_in_app_only, vec!["192.168.1.1 router"]);
+    }
+
+    #[test]
+    fn test_reload_keeps_unsaved_changes() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let hosts_path = temp_dir.path().join("hosts");
+        fs::write(
+            &hosts_path,
+            "127.0.0.1 localhost\n0.0.0.0 ads.example.com\n0.0.0.0 tracker.example.org\n0.0.0.0 old.example.net\n",
+        )
+        .unwrap();
+
+        let state = AppState::new(Config::default());
+        state.load_from_file(&hosts_path).unwrap();
+        state.add_block("new.example.com");
+        state.remove_block("tracker.example.org");
+
+        // Edited outside the app: one domain removed, another added
+        fs::write(
+            &hosts_path,
+            "127.0.0.1 localhost\n0.0.0.0 ads.example.com\n0.0.0.0 tracker.example.org\n0.0.0.0 external.example.com\n",
+        )
+        .unwrap();
+        let report = state.load_from_file(&hosts_path).unwrap();
+        assert_eq!(report.added, vec![Arc::from("external.example.com")]);
+        assert_eq!(report.removed, vec![Arc::from("old.example.net")]);
+        assert!(!report.preserved_conflict);
+
+        // The unsaved add and removal survive; the external edit is applied
+        let blocks = state.get_all_blocks();
+        assert!(blocks.contains("new.example.com"));
+        assert!(!blocks.contains("tracker.example.org"));
+        assert!(blocks.contains("external.example.com"));
+        assert!(!blocks.contains("old.example.net"));
+        assert!(*state.dirty.lock());
+        assert_eq!(state.pending_counts(), (1, 1));
+        assert_eq!(state.preserved_lines.lock().len(), 1);
+
+        // Preserved lines edited only on disk are taken despite the unsaved domains
+        fs::write(
+            &hosts_path,
+            "127.0.0.1 localhost\n192.168.1.1 router\n0.0.0.0 ads.example.com\n0.0.0.0 tracker.example.org\n0.0.0.0 external.example.com\n",
+        )
+        .unwrap();
+        assert!(
+            !state
+                .load_from_file(&hosts_path)
+                .unwrap()
+                .preserved_conflict
+        );
+        let (disk_only, _) = diff_preserved_lines(&state.preserved_lines.lock(), &[]);
+        assert!(disk_only.contains(&"192.168.1.1 router".to_string()));
+        assert!(state.get_all_blocks().contains("new.example.com"));
+
+        // Preserved lines edited on both sides keep the app's
+        state
+            .preserved_lines
+            .lock()
+            .push(PreservedLine::Comment("# mine".into()));
+        fs::write(
+            &hosts_path,
+            "127.0.0.1 localhost\n# theirs\n0.0.0.0 ads.example.com\n0.0.0.0 tracker.example.org\n0.0.0.0 external.example.com\n",
+        )
+        .unwrap();
+        assert!(
+            state
+                .load_from_file(&hosts_path)
+                .unwrap()
+                .preserved_conflict
+        );
+        let (_, app_only) = diff_preserved_lines(&[], &state.preserved_lines.lock());
+        assert!(app_only.contains(&"# mine".to_string()));
+        assert!(!app_only.contains(&"# theirs".to_string()));
+    }
+
+    #[test]
+    fn test_db_disagreeing_with_hosts_file_is_unsaved() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let hosts = temp_dir.path().join("hosts");
+        let db_path = temp_dir.path().join("db.json");
+        fs::write(&hosts, "127.0.0.1 localhost\n0.0.0.0 old.example.com\n").unwrap();
+        let config = Config {
+            host_file_path: Some(hosts.as_path().into()),
+            ..Config::default()
+        };
+
+        let saved = AppState::new(config.clone());
+        saved.add_block("new.example.com");
+        saved.save_db(&db_path).unwrap();
+        assert!(!temp_dir.path().join("db.json.tmp").exists());
+
+        let state = AppState::new(config);
+        state.load_from_file(&hosts).unwrap();
+        state.load_db(&db_path).unwrap();
+        assert!(state.reconcile_with_hosts_file().unwrap());
+        assert!(*state.dirty.lock());
+        assert_eq!(state.pending_counts(), (1, 1));
+
+        // Once the hosts file matches, there's nothing pending
+        fs::write(&hosts, state.serialize()).unwrap();
+        state.mark_clean();
+        assert!(!state.reconcile_with_hosts_file().unwrap());
+        assert!(!*state.dirty.lock());
+    }
+
+    #[test]
+    fn test_reload_config() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let config_path = temp_dir.path().join("config.ini");
+        let config = Config::default();
+        config.save_to_file(&config_path).unwrap();
+        let state = AppState::new(config);
+        state.set_config_path(config_path.as_path().into());
+
+        // The app's own write matches the config in memory and changes nothing
+        state.persist_config().unwrap();
+        assert!(state.reload_config(&config_path).unwrap().is_none());
+
+        // Files caught mid-rewrite are ignored rather than read as defaults
+        let edited = Config {
+            max_history_entries: 42,
+            block_ipv6: true,
+            ..Config::default()
+        };
+        let ini = edited.to_ini();
+        fs::write(&config_path, "").unwrap();
+        assert!(state.reload_config(&config_path).unwrap().is_none());
+        fs::write(&config_path, &ini[..ini.find("[paths]").unwrap()]).unwrap();
+        assert!(state.reload_config(&config_path).unwrap().is_none());
+        assert_eq!(state.get_config().max_history_entries, 50);
+
+        // An invalid edit is refused and the config in memory kept
+        let preserving_sink = Config {
+            preserve_ips: vec![DEFAULT_SINK],
+            ..edited.clone()
+        };
+        fs::write(&config_path, preserving_sink.to_ini()).unwrap();
+        assert!(state.reload_config(&config_path).is_err());
+        assert!(!state.get_config().block_ipv6);
+
+        fs::write(&config_path, &ini).unwrap();
+        let previous = state.reload_config(&config_path).unwrap().unwrap();
+        assert_eq!(previous.max_history_entries, 50);
+        assert_eq!(state.get_config().max_history_entries, 42);
+        assert!(state.get_config().block_ipv6);
+    }
+
+    #[test]
+    fn test_failed_hosts_switch_restores_config() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let config_path = temp_dir.path().join("config.ini");
+        let hosts = temp_dir.path().join("hosts");
+        fs::write(&hosts, "127.0.0.1 localhost\n# router\n").unwrap();
+        let config = Config {
+            host_file_path: Some(hosts.as_path().into()),
+            ..Config::default()
+        };
+        let state = AppState::new(config.clone());
+        state.set_config_path(config_path.as_path().into());
+        state.load_from_file(&hosts).unwrap();
+
+        // A directory can't be read as a hosts file
+        let unreadable = temp_dir.path().join("not-a-file");
+        fs::create_dir(&unreadable).unwrap();
+        let switched = state.apply_config(Config {
+            host_file_path: Some(unreadable.as_path().into()),
+            ..config.clone()
+        });
+        assert!(switched.is_err());
+        assert_eq!(state.get_config().host_file_path, config.host_file_path);
+        let saved = Config::load_from_file(&config_path).unwrap();
+        assert_eq!(saved.host_file_path, config.host_file_path);
+        assert!(state.serialize().contains("# router"));
+
+        let other = temp_dir.path().join("other-hosts");
+        let switched = state
+            .apply_config(Config {
+                host_file_path: Some(other.as_path().into()),
+                ..config
+            })
+            .unwrap();
+        assert_eq!(switched.as_deref(), Some(other.as_path()));
+    }
+
+    #[test]
+    fn test_validate_all_domains() {
+        let state = AppState::new(Config::default());
+        for hostname in ["ads.example.com", "bad..example.com", "ads!.example.net"] {
+            state.blocking.lock().insert(hostname.into());
+        }
+
+        let validation = state.validate_all_domains();
+        assert_eq!(validation.checked, 3);
+        assert_eq!(validation.total_invalid, 2);
+        let invalid: Vec<&str> = validation
+            .invalid
+            .iter()
+            .map(|(hostname, _)| hostname.as_ref())
+            .collect();
+        assert_eq!(invalid, ["ads!.example.net", "bad..example.com"]);
+        assert!(validation.invalid[1].1.contains("Empty label"));
+        // Nothing is removed
+        assert_eq!(state.get_total_blocked(), 3);
+    }
+
+    #[test]
+    fn test_source_contributions_survive_saves() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let db_path = temp_dir.path().join("db.json");
+        let urls = vec![
+            "https://a.example/hosts".to_string(),
+            "https://b.example/hosts".to_string(),
+        ];
+        let state = AppState::new(Config::default());
+        state.set_db_path(db_path.as_path().into());
+        let domains = |names: &[&str]| -> BTreeSet<Arc<str>> {
+            names.iter().map(|name| Arc::from(*name)).collect()
+        };
+        let a = domains(&[
+            "shared.example.com",
+            "a-only.example.com",
+            "mine.example.com",
+        ]);
+        let b = domains(&["shared.example.com", "b-only.example.com"]);
+        state.merge_blocking(ParsedHosts {
+            blocking: a.union(&b).cloned().collect(),
+            ..ParsedHosts::default()
+        });
+        state.record_source(&urls[0], &a);
+        state.record_source(&urls[1], &b);
+        state.add_block("mine.example.com");
+        state.add_block("manual.example.com");
+
+        // Saved manual domains are still nobody's unique
+        state.persist_db().unwrap();
+        state.mark_clean();
+        let reloaded = AppState::new(Config::default());
+        reloaded.load_db(&db_path).unwrap();
+        let contributions = reloaded.source_contributions(&urls);
+        assert_eq!(
+            contributions,
+            [
+                SourceContribution {
+                    total: 3,
+                    unique: 1
+                },
+                SourceContribution {
+                    total: 2,
+                    unique: 1
+                },
+            ]
+        );
+
+        // Removing a domain forgets where it came from
+        reloaded.remove_block("a-only.example.com");
+        reloaded.add_block("a-only.example.com");
+        assert_eq!(
+            reloaded.source_contributions(&urls)[0],
+            SourceContribution {
+                total: 2,
+                unique: 0
+            }
+        );
+    }
+
+    #[test]
+    fn test_first_seen_survives_removal() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let db_path = temp_dir.path().join("db.json");
+        let state = AppState::new(Config::default());
+        assert_eq!(state.first_seen("ads.example.com"), None);
+
+        state.add_block("ads.example.com");
+        let first = state.first_seen("ads.example.com").unwrap();
+        // As if it had been blocked years ago
+        state
+            .first_seen
+            .lock()
+            .insert("ads.example.com".into(), first - 1_000_000);
+
+        state.remove_block("ads.example.com");
+        assert_eq!(state.first_seen("ads.example.com"), Some(first - 1_000_000));
+        state.add_block("ads.example.com");
+        assert_eq!(state.first_seen("ads.example.com"), Some(first - 1_000_000));
+
+        // Kept in the database across a reload, and by a re-import
+        state.save_db(&db_path).unwrap();
+        let reloaded = AppState::new(Config::default());
+        reloaded.load_db(&db_path).unwrap();
+        assert_eq!(
+            reloaded.first_seen("ads.example.com"),
+            Some(first - 1_000_000)
+        );
+        reloaded.replace_blocking(parse_hosts("0.0.0.0 ads.example.com\n").unwrap());
+        assert_eq!(
+            reloaded.first_seen("ads.example.com"),
+            Some(first - 1_000_000)
+        );
+    }
+
+    #[test]
+    fn test_full_backup_round_trip() {
+        let source_dir = tempfile::TempDir::new().unwrap();
+        let hosts = source_dir.path().join("hosts");
+        let history_dir = source_dir.path().join("history");
+        fs::write(&hosts, "127.0.0.1 localhost\n# router\n").unwrap();
+        let state = AppState::new(Config {
+            host_file_path: Some(hosts.as_path().into()),
+            history_dir: Some(history_dir.as_path().into()),
+            sources: vec![crate::sources::Source::from_ini("https://example.com/hosts").unwrap()],
+            ..Config::default()
+        });
+        state.set_config_path(source_dir.path().join("config.ini").as_path().into());
+        state.set_db_path(source_dir.path().join("db.json").as_path().into());
+        state.load_from_file(&hosts).unwrap();
+        state.add_block("ads.example.com");
+        state.add_block("tracker.example.net");
+        state.add_allow("cdn.example.com");
+        state.create_profile("work", None).unwrap();
+        crate::history::write_history_snapshot(
+            &history_dir,
+            DEFAULT_HISTORY_PREFIX,
+            "127.0.0.1 localhost\n0.0.0.0 old.example.com\n",
+        )
+        .unwrap();
+
+        let backup = source_dir.path().join("backup.gz");
+        let manifest = state.export_full_backup(&backup, true).unwrap();
+        assert!(manifest.includes_history);
+
+        // A fresh install elsewhere
+        let target_dir = tempfile::TempDir::new().unwrap();
+        let target_history = target_dir.path().join("history");
+        let restored = AppState::new(Config {
+            history_dir: Some(target_history.as_path().into()),
+            ..Config::default()
+        });
+        restored.set_config_path(target_dir.path().join("config.ini").as_path().into());
+        restored.set_db_path(target_dir.path().join("db.json").as_path().into());
+
+        // Unsaved changes block a restore
+        restored.add_block("unsaved.example.com");
+        assert!(restored.import_full_backup(&backup, false).is_err());
+        restored.remove_block("unsaved.example.com");
+        restored.mark_clean();
+
+        let (read, hosts_path) = restored.import_full_backup(&backup, false).unwrap();
+        assert_eq!(read, manifest);
+        assert_eq!(&*hosts_path, hosts.as_path());
+        assert_eq!(restored.get_all_blocks(), state.get_all_blocks());
+        assert_eq!(restored.get_allowlist(), state.get_allowlist());
+        assert_eq!(restored.list_profiles().unwrap(), ["default", "work"]);
+        assert!(restored.serialize().contains("# router"));
+
+        let saved = Config::load_from_file(&target_dir.path().join("config.ini")).unwrap();
+        assert_eq!(saved.sources, state.get_config().sources);
+        assert_eq!(saved.history_dir.as_deref(), Some(target_history.as_path()));
+        let snapshots = list_history_entries(&target_history, DEFAULT_HISTORY_PREFIX).unwrap();
+        assert_eq!(snapshots.len(), 1);
+        assert!(read_snapshot(&snapshots[0].path)
+            .unwrap()
+            .contains("old.example.com"));
+
+        // The restored database is where the next start looks for it
+        let db = AppState::new(Config::default());
+        db.load_db(&target_dir.path().join("db.json")).unwrap();
+        assert_eq!(db.get_all_blocks(), state.get_all_blocks());
+
+        // Profiles changed since are only overwritten when asked to
+        restored.mark_clean();
+        let work_profile = target_dir.path().join("profiles/work/profile.ini");
+        fs::write(&work_profile, "[profile]\nedited = true\n").unwrap();
+        let err = restored.import_full_backup(&backup, false).unwrap_err();
+        assert!(err.to_string().contains("work"));
+        assert!(fs::read_to_string(&work_profile)
+            .unwrap()
+            .contains("edited"));
+        restored.import_full_backup(&backup, true).unwrap();
+        assert!(!fs::read_to_string(&work_profile)
+            .unwrap()
+            .contains("edited"));
+    }
+
+    #[test]
+    fn test_create_and_switch_profiles() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let config_path = temp_dir.path().join("config.ini");
+        let home_hosts = temp_dir.path().join("hosts");
+        let work_hosts = temp_dir.path().join("work-hosts");
+        fs::write(
+            &home_hosts,
+            "127.0.0.1 localhost\n0.0.0.0 games.example.com\n",
+        )
+        .unwrap();
+        fs::write(
+            &work_hosts,
+            "127.0.0.1 localhost\n# work machine\n0.0.0.0 stale.example.com\n",
+        )
+        .unwrap();
+
+        let state = AppState::new(Config {
+            host_file_path: Some(home_hosts.as_path().into()),
+            ..Config::default()
+        });
+        state.set_config_path(config_path.as_path().into());
+        state.load_from_file(&home_hosts).unwrap();
+        assert_eq!(state.list_profiles().unwrap(), ["default"]);
+
+        state
+            .create_profile("work", Some(work_hosts.as_path().into()))
+            .unwrap();
+        assert!(state.create_profile("work", None).is_err());
+        assert!(state.create_profile("default", None).is_err());
+        assert_eq!(state.list_profiles().unwrap(), ["default", "work"]);
+
+        // Unsaved changes block a switch
+        state.add_block("unsaved.example.com");
+        assert!(state.switch_profile("work").is_err());
+        state.remove_block("unsaved.example.com");
+        state.mark_clean();
+
+        let hosts_path = state.switch_profile("work").unwrap();
+        assert_eq!(&*hosts_path, work_hosts.as_path());
+        assert_eq!(state.active_profile(), "work");
+        assert!(state.get_all_blocks().is_empty());
+        assert_eq!(state.pending_counts(), (0, 1));
+        assert!(state.serialize().contains("# work machine"));
+        let saved = Config::load_from_file(&config_path).unwrap();
+        assert_eq!(saved.active_profile.as_deref(), Some("work"));
+        assert_eq!(saved.host_file_path.as_deref(), Some(work_hosts.as_path()));
+
+        // As a save would after a switch
+        state.add_block("social.example.com");
+        state.persist_db().unwrap();
+        state.mark_clean();
+
+        state.switch_profile("default").unwrap();
+        assert_eq!(
+            state.get_all_blocks(),
+            BTreeSet::from([Arc::from("games.example.com")])
+        );
+        assert_eq!(
+            state.get_config().host_file_path.as_deref(),
+            Some(home_hosts.as_path())
+        );
+        state.mark_clean();
+
+        state.switch_profile("work").unwrap();
+        assert_eq!(
+            state.get_all_blocks(),
+            BTreeSet::from([Arc::from("social.example.com")])
+        );
+        assert!(state.switch_profile("missing").is_err());
+    }
+
+    #[test]
+    fn test_failed_profile_switch_stays_on_current_profile() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let config_path = temp_dir.path().join("config.ini");
+        let hosts = temp_dir.path().join("hosts");
+        fs::write(&hosts, "127.0.0.1 localhost\n0.0.0.0 games.example.com\n").unwrap();
+        let state = AppState::new(Config {
+            host_file_path: Some(hosts.as_path().into()),
+            ..Config::default()
+        });
+        state.set_config_path(config_path.as_path().into());
+        state.load_from_file(&hosts).unwrap();
+        state.mark_clean();
+        state.create_profile("work", None).unwrap();
+
+        // A directory in the config file's place can't be written
+        let _ = fs::remove_file(&config_path);
+        fs::create_dir(&config_path).unwrap();
+        assert!(state.switch_profile("work").is_err());
+        assert_eq!(state.active_profile(), "default");
+        assert_eq!(
+            state.get_config().host_file_path.as_deref(),
+            Some(hosts.as_path())
+        );
+        assert_eq!(
+            state.get_all_blocks(),
+            BTreeSet::from([Arc::from("games.example.com")])
+        );
+        assert!(state.db_path.lock().is_none());
+    }
+
+    #[test]
+    fn test_host_file_info() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let hosts_path = temp_dir.path().join("hosts");
+        let content = "127.0.0.1 localhost\n0.0.0.0 ads.example.com\n0.0.0.0 tracker.example.org\n";
+        fs::write(&hosts_path, content).unwrap();
+
+        let state = AppState::new(Config {
+            host_file_path: Some(hosts_path.as_path().into()),
+            ..Config::default()
+        });
+        state.load_from_file(&hosts_path).unwrap();
+        state.add_block("unsaved.example.com");
+
+        let info = state.host_file_info();
+        assert_eq!(&*info.path, hosts_path.as_path());
+        assert!(info.exists);
+        assert_eq!(info.size, Some(content.len() as u64));
+        assert!(info.last_modified.is_some());
+        assert!(info.writable);
+        assert_eq!(info.entry_count, 3);
+
+        fs::remove_file(&hosts_path).unwrap();
+        let info = state.host_file_info();
+        assert!(!info.exists);
+        assert_eq!(info.size, None);
+    }
+
+    #[test]
+    fn test_blocklist_fingerprint() {
+        let state = AppState::new(Config::default());
+        state.replace_with(
+            parse_hosts("# Ads\n0.0.0.0 ads.example.com\n0.0.0.0 tracker.example.org\n").unwrap(),
+        );
+        let fingerprint = state.blocklist_fingerprint();
+        assert_eq!(fingerprint.len(), 64);
+
+        // Same domains, reordered under another sink with other comments
+        let other = AppState::new(Config::default());
+        other.replace_with(
+            parse_hosts(
+                "127.0.0.1 localhost\n127.0.0.1\ttracker.example.org ads.example.com # mine\n",
+            )
+            .unwrap(),
+        );
+        assert_eq!(other.blocklist_fingerprint(), fingerprint);
+
+        other.add_block("new.example.com");
+        assert_ne!(other.blocklist_fingerprint(), fingerprint);
+        assert_eq!(
+            AppState::new(Config::default()).blocklist_fingerprint(),
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+    }
+
+    #[test]
+    fn test_disable_keeps_domain_recoverable() {
+        let state = AppState::new(Config::default());
+        state.replace_with(
+            parse_hosts(
+                "127.0.0.1 localhost\n0.0.0.0 ads.example.com\n0.0.0.0 tracker.example.org\n",
+            )
+            .unwrap(),
+        );
+
+        assert!(state.disable_block("ads.example.com"));
+        state.remove_block("tracker.example.org");
+        assert!(!state.is_blocked("ads.example.com"));
+        assert!(state.get_disabled().contains("ads.example.com"));
+        assert!(!state.get_disabled().contains("tracker.example.org"));
+
+        // Disabled domains are written commented out and survive a reload
+        let serialized = state.serialize();
+        assert!(serialized.contains("# disabled: 0.0.0.0 ads.example.com\n"));
+        assert!(!serialized.contains("tracker.example.org"));
+
+        let reloaded = AppState::new(Config::default());
+        reloaded.replace_with(parse_hosts(&serialized).unwrap());
+        assert!(!reloaded.is_blocked("ads.example.com"));
+        assert!(reloaded.enable_block("ads.example.com"));
+        assert!(reloaded.is_blocked("ads.example.com"));
+        assert!(reloaded.get_disabled().is_empty());
+
+        // Deleted domains can't be enabled again
+        assert!(!reloaded.enable_block("tracker.example.org"));
+        assert!(!reloaded.is_blocked("tracker.example.org"));
+    }
+
+    #[test]
+    fn test_dedupe_state() {
+        let state = AppState::new(Config::default());
+        state.replace_with(
+            parse_hosts(
+                "127.0.0.1 localhost\n\
+                 192.168.1.1 router ads.example.com\n\
+                 10.0.0.1 tracker.example.org\n\
+                 0.0.0.0 ads.example.com tracker.example.org\n",
+            )
+            .unwrap(),
+        );
+        // A merge can leave the same localhost entry twice
+        state
+            .preserved_lines
+            .lock()
+            .push(PreservedLine::LocalhostEntry {
+                ip: "127.0.0.1".parse().unwrap(),
+                hostname: "localhost".into(),
+            });
+        state.mark_clean();
+
+        // Counting leaves the state untouched
+        assert_eq!(state.duplicate_count(), 3);
+        assert!(!*state.dirty.lock());
+        assert_eq!(state.dedupe_state(), 3);
+        assert!(*state.dirty.lock());
+        assert!(state.is_blocked("ads.example.com"));
+        assert!(state.is_blocked("tracker.example.org"));
+
+        let preserved = state.preserved_lines.lock().clone();
+        assert!(
+            preserved
+                .iter()
+                .all(|line| !line.mentions("ads.example.com")
+                    && !line.mentions("tracker.example.org"))
+        );
+        assert!(preserved
+            .iter()
+            .any(|line| { line.content().as_deref() == Some("192.168.1.1 router") }));
+        assert_eq!(
+            preserved
+                .iter()
+                .filter(|line| line.mentions("localhost"))
+                .count(),
+            1
+        );
+
+        // Nothing left to remove
+        assert_eq!(state.duplicate_count(), 0);
+        assert_eq!(state.dedupe_state(), 0);
+    }
+
+    #[test]
+    fn test_apply_set_operation() {
+        let seeded = || {
+            let state = AppState::new(Config::default());
+            for hostname in ["a.example.com", "b.example.com", "c.example.com"] {
+                state.add_block(hostname);
+            }
+            state
+        };
+        let domains = |names: &[&str]| names.iter().map(|n| n.to_string()).collect::<Vec<_>>();
+        let blocked = |state: &AppState| {
+            state
+                .get_all_blocks()
+                .iter()
+                .map(|h| h.to_string())
+                .collect::<Vec<_>>()
+        };
+
+        let state = seeded();
+        let total = state.apply_set_operation(
+            SetOp::Union,
+            &domains(&["c.example.com", "d.example.com", "localhost"]),
+        );
+        assert_eq!(total, 4);
+        assert_eq!(
+            blocked(&state),
+            [
+                "a.example.com",
+                "b.example.com",
+                "c.example.com",
+                "d.example.com"
+            ]
+        );
+
+        let state = seeded();
+        let total = state.apply_set_operation(
+            SetOp::Difference,
+            &domains(&["a.example.com", "z.example.com"]),
+        );
+        assert_eq!(total, 2);
+        assert_eq!(blocked(&state), ["b.example.com", "c.example.com"]);
+
+        let state = seeded();
+        let total = state.apply_set_operation(
+            SetOp::Intersection,
+            &domains(&["b.example.com.", "z.example.com"]),
+        );
+        assert_eq!(total, 1);
+        assert_eq!(blocked(&state), ["b.example.com"]);
+        assert!(!state.is_blocked("z.example.com"));
+
+        for op in [SetOp::Union, SetOp::Difference, SetOp::Intersection] {
+            assert_eq!(SetOp::from_str(op.to_str()).unwrap(), op);
+        }
+        assert!(SetOp::from_str("xor").is_err());
+    }
+
+    #[test]
+    fn test_get_blocked_detailed() {
+        let state = AppState::new(Config::default());
+        state.replace_with(
+            parse_hosts(
+                "127.0.0.1 localhost\n\
+                 0.0.0.0 ads.example.com\n\
+                 127.0.0.1 tracker.example.org\n\
+                 127.0.0.1 xn--bcher-kva.example\n",
+            )
+            .unwrap(),
+        );
+        state.update_config(Config {
+            annotate_additions: true,
+            ..Config::default()
+        });
+        state.record_source(
+            "https://lists.example/hosts",
+            &["tracker.example.org".into(), "xn--bcher-kva.example".into()].into(),
+        );
+        state.disable_block("tracker.example.org");
+        state.add_block("new.example.net");
+        state.add_block("xn--bcher-kva.example");
+
+        let detailed = state.get_blocked_detailed();
+        let hostnames: Vec<&str> = detailed.iter().map(|d| d.hostname.as_ref()).collect();
+        assert_eq!(
+            hostnames,
+            [
+                "ads.example.com",
+                "new.example.net",
+                "tracker.example.org",
+                "xn--bcher-kva.example"
+            ]
+        );
+
+        let source = |index: usize| -> Vec<&str> {
+            detailed[index]
+                .source
+                .iter()
+                .map(|tag| tag.as_ref())
+                .collect()
+        };
+
+        assert_eq!(detailed[0].sink, "0.0.0.0".parse::<IpAddr>().unwrap());
+        assert!(detailed[0].enabled);
+        assert_eq!(detailed[0].added_on, None);
+        assert!(source(0).is_empty());
+
+        assert_eq!(detailed[1].sink, DEFAULT_SINK);
+        assert!(detailed[1].added_on.is_some());
+        assert_eq!(source(1), [MANUAL_ORIGIN]);
+
+        assert!(!detailed[2].enabled);
+        assert_eq!(detailed[2].sink, DEFAULT_SINK);
+        assert_eq!(source(2), ["https://lists.example/hosts"]);
+
+        assert_eq!(detailed[3].display.as_ref(), "bücher.example");
+        assert!(detailed[3].enabled);
+        assert_eq!(source(3), ["https://lists.example/hosts", MANUAL_ORIGIN]);
     }
 
-    /// Load state from hosts file
-    #[inline]
-    pub fn load_from_file(&self, path: &Path) -> Result<(), anyhow::Error> {
-        let content = fs::read_to_string(path)?;
-        let parsed = parse_hosts(&content)?;
+    #[test]
+    fn test_seed_starter_list() {
+        let state = AppState::new(Config {
+            max_total_domains: STARTER_DOMAINS.len() - 1,
+            ..Config::default()
+        });
+        *state.first_run.lock() = true;
+        state.add_block(STARTER_DOMAINS[1]);
+        state.add_block("other.example.com");
 
-        {
-            let mut blocking = self.blocking.lock();
-            for hostname in parsed.blocking {
-                if !is_local_domain(&hostname) {
-                    blocking.insert(hostname);
-                }
-            }
-        }
-        {
-            let mut preserved_lines = self.preserved_lines.lock();
-            for line in parsed.preserved_lines {
-                preserved_lines.push(line);
-            }
-        };
+        // Over the cap, nothing is added and the offer stands
+        assert!(state.seed_starter_list(false).is_err());
+        assert_eq!(state.get_total_blocked(), 2);
+        assert!(*state.first_run.lock());
 
-        Ok(())
+        assert_eq!(
+            state.seed_starter_list(true).unwrap(),
+            STARTER_DOMAINS.len() - 1
+        );
+        assert!(STARTER_DOMAINS
+            .iter()
+            .all(|hostname| state.is_blocked(hostname)));
+        assert!(!*state.first_run.lock());
+        assert_eq!(state.seed_starter_list(false).unwrap(), 0);
     }
 
-    /// Add a domain to blocking
-    #[inline]
-    pub fn add_block(&self, hostname: &str) {
-        if !is_local_domain(hostname) {
-            self.blocking.lock().insert(hostname.into());
-        }
+    #[test]
+    fn test_update_preserved_line() {
+        let state = AppState::new(Config::default());
+        state.replace_with(parse_hosts("# router\n192.168.1.1 router\n").unwrap());
+        state.mark_clean();
+
+        state
+            .update_preserved_line(1, "192.168.1.254 router")
+            .unwrap();
+        assert!(*state.dirty.lock());
+        assert!(state.serialize().contains("192.168.1.254 router\n"));
+        assert!(!state.serialize().contains("192.168.1.1 router"));
+
+        assert!(state.update_preserved_line(1, "127.0.0.1 ads.com").is_err());
+        assert!(state.update_preserved_line(5, "# out of range").is_err());
     }
 
-    /// Remove a domain from blocking
-    #[inline]
-    pub fn remove_block(&self, hostname: &str) {
-        if !is_local_domain(hostname) {
-            self.blocking.lock().remove(&Arc::from(hostname));
-        }
+    #[test]
+    fn test_delete_preserved_line() {
+        let state = AppState::new(Config::default());
+        state.replace_with(
+            parse_hosts("before\n# === ADGUARD MANAGED START ===\n# === ADGUARD MANAGED END ===\n")
+                .unwrap(),
+        );
+
+        // The managed region placeholder can't be deleted
+        assert!(state.delete_preserved_line(1).is_err());
+
+        state.delete_preserved_line(0).unwrap();
+        assert_eq!(state.preserved_lines.lock().len(), 1);
+        assert!(state.delete_preserved_line(1).is_err());
     }
 
-    /// Get all blocked domains (only returns localhost entries)
-    /// Returns domains sorted alphabetically by hostname
-    #[inline]
-    pub fn get_all_blocks(&self) -> BTreeSet<Arc<str>> {
-        self.blocking.lock().clone()
+    #[test]
+    fn test_merge_blocking_keeps_current_domains() {
+        let state = AppState::new(Config::default());
+        state.add_block("current.com");
+        state.add_block("shared.com");
+
+        let snapshot = parse_hosts("127.0.0.1 shared.com\n0.0.0.0 removed.com\n").unwrap();
+        let added = state.merge_blocking(snapshot);
+
+        assert_eq!(added, 1);
+        let blocks = state.get_all_blocks();
+        assert_eq!(blocks.len(), 3);
+        assert!(blocks.contains("current.com"));
+        assert!(blocks.contains("removed.com"));
+        assert_eq!(
+            state.sinks.lock().get("removed.com").copied(),
+            Some("0.0.0.0".parse().unwrap())
+        );
     }
 
-    /// Serialize state to hosts file content
-    #[inline]
-    pub fn serialize(&self) -> String {
-        let preserved_lines = self.preserved_lines.lock();
-        let blocking = self.blocking.lock();
-        serialize_hosts(&preserved_lines, &blocking)
+    #[test]
+    fn test_merge_preview_excludes_existing() {
+        let state = AppState::new(Config::default());
+        state.add_block("shared.com");
+
+        let other =
+            parse_hosts("127.0.0.1 localhost\n0.0.0.0 shared.com\n0.0.0.0 new.com\n").unwrap();
+        let preview = state.merge_preview(&other);
+
+        assert_eq!(preview, vec![Arc::<str>::from("new.com")]);
+        // Previewing does not mutate state
+        assert_eq!(state.get_total_blocked(), 1);
+
+        assert_eq!(state.merge_blocking(other), preview.len());
+        assert!(state.is_blocked("new.com"));
     }
 
-    /// Get statistics (only counts localhost entries)
-    #[inline]
-    pub fn get_total_blocked(&self) -> usize {
-        self.blocking.lock().len()
+    #[test]
+    fn test_error_log_is_bounded() {
+        let state = AppState::new(Config::default());
+        for i in 0..ERROR_LOG_CAPACITY + 5 {
+            state.log_error("test", format!("error {}", i));
+        }
+
+        let errors = state.get_recent_errors();
+        assert_eq!(errors.len(), ERROR_LOG_CAPACITY);
+        assert_eq!(
+            errors[0].message.as_ref(),
+            format!("error {}", ERROR_LOG_CAPACITY + 4)
+        );
+        assert_eq!(errors.last().unwrap().message.as_ref(), "error 5");
     }
 
-    /// Get config (read-only)
-    #[inline]
-    pub fn get_config(&self) -> Config {
-        self.config.lock().clone()
+    #[test]
+    fn test_query_regex() {
+        let state = AppState::new(Config::default());
+        for hostname in [
+            "img.cdn.example.com",
+            "static.cdn.test.org",
+            "ads1.example.com",
+            "ads2.example.com",
+            "tracker.example.com",
+        ] {
+            state.add_block(hostname);
+        }
+
+        let query = state.query_regex(r"\.cdn\.", 10).unwrap();
+        assert_eq!(query.matches.len(), 2);
+        assert!(!query.truncated);
+
+        let query = state.query_regex(r"^ads\d+\.", 1).unwrap();
+        assert_eq!(query.matches, vec![Arc::from("ads1.example.com")]);
+        assert!(query.truncated);
+
+        assert!(state.query_regex("(unclosed", 10).is_err());
     }
 
-    /// Update config
-    #[inline]
-    pub fn update_config(&self, config: Config) {
-        *self.config.lock() = config;
+    #[test]
+    fn test_switch_hosts_file() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let system_hosts = temp_dir.path().join("hosts");
+        let scratch_hosts = temp_dir.path().join("scratch-hosts");
+        fs::write(&system_hosts, "192.168.1.1 router\n127.0.0.1 ads.com\n").unwrap();
+        fs::write(
+            &scratch_hosts,
+            "10.0.0.5 dev.proxy\n127.0.0.1 tracker.com\n",
+        )
+        .unwrap();
+
+        let state = AppState::new(Config::default());
+        state.load_from_file(&system_hosts).unwrap();
+        state.switch_hosts_file(&scratch_hosts).unwrap();
+
+        let serialized = state.serialize();
+        assert!(serialized.contains("10.0.0.5 dev.proxy"));
+        assert!(!serialized.contains("192.168.1.1 router"));
+        assert!(state.get_all_blocks().contains("ads.com"));
+        assert!(state.get_all_blocks().contains("tracker.com"));
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    #[test]
+    fn test_file_header_keeps_edits() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let hosts_path = temp_dir.path().join("hosts");
+        fs::write(
+            &hosts_path,
+            "127.0.0.1 localhost\n0.0.0.0 ads.example.com\n",
+        )
+        .unwrap();
+        let config = Config {
+            file_header: Some("Managed by adguard".to_string()),
+            ..Config::default()
+        };
+
+        let state = AppState::new(config.clone());
+        state.load_from_file(&hosts_path).unwrap();
+        let saved = state.serialize();
+        assert!(saved.starts_with(&format!(
+            "{}\n# Managed by adguard\n{}\n",
+            HEADER_START, HEADER_END
+        )));
+
+        // An edit to the header in the file survives the next save cycle
+        let edited = saved.replace(
+            "# Managed by adguard",
+            "# Managed by adguard\n# Edited by hand",
+        );
+        fs::write(&hosts_path, &edited).unwrap();
+        let state = AppState::new(config.clone());
+        state.load_from_file(&hosts_path).unwrap();
+        assert_eq!(state.serialize(), edited);
+
+        // Changing the configured header replaces the file's
+        state.update_config(Config {
+            file_header: Some("New header".to_string()),
+            ..config
+        });
+        let saved = state.serialize();
+        assert!(saved.contains("# New header\n"));
+        assert!(!saved.contains("Edited by hand"));
+
+        // Turning the option off removes the block
+        state.update_config(Config::default());
+        assert!(!state.serialize().contains(HEADER_START));
+    }
 
     #[test]
-    fn test_add_block() {
+    fn test_domain_cap() {
+        let state = AppState::new(Config {
+            max_total_domains: 3,
+            ..Config::default()
+        });
+        state.replace_with(parse_hosts("0.0.0.0 a.example.com\n0.0.0.0 b.example.com\n").unwrap());
+
+        // An import of four domains is refused, with the count it would have added
+        let incoming = [
+            "a.example.com",
+            "c.example.com",
+            "d.example.com",
+            "e.example.com",
+        ];
+        let new_total = state.get_total_blocked() + state.count_unblocked(incoming);
+        let err = state.check_domain_cap(new_total, false).unwrap_err();
+        assert!(err.to_string().contains("3 would have been added"));
+        assert!(state.check_domain_cap(new_total, true).is_ok());
+
+        // Reaching the cap exactly is fine; local and repeated names don't count
+        assert_eq!(
+            state.count_unblocked(["c.example.com.", "c.example.com", "localhost"]),
+            1
+        );
+        assert!(state.check_domain_cap(3, false).is_ok());
+
+        // Once over the cap, nothing stops the set from shrinking
+        state.apply_set_operation(SetOp::Union, &incoming.map(String::from));
+        assert_eq!(state.get_total_blocked(), 5);
+        assert!(state.check_domain_cap(4, false).is_ok());
+        assert!(state.check_domain_cap(6, false).is_err());
+
+        state.update_config(Config {
+            max_total_domains: 0,
+            ..Config::default()
+        });
+        assert!(state.check_domain_cap(1_000_000, false).is_ok());
+    }
+
+    #[test]
+    fn test_replace_blocking_keeps_preserved_lines() {
         let state = AppState::new(Config::default());
-        state.add_block("example.com");
-        let blocking = state.blocking.lock();
-        assert!(blocking.contains(&Arc::from("example.com")));
+        state.replace_with(
+            parse_hosts(
+                "192.168.1.20 router\n# new nas\n10.0.0.9 nas\n0.0.0.0 current.example.com\n",
+            )
+            .unwrap(),
+        );
+        let preserved = state.preserved_lines.lock().clone();
+
+        let snapshot = parse_hosts(
+            "192.168.1.1 router\n0.0.0.0 old.example.com\n0.0.0.0 tracker.example.org\n",
+        )
+        .unwrap();
+        assert_eq!(state.replace_blocking(snapshot), 2);
+
+        assert_eq!(
+            state
+                .get_all_blocks()
+                .iter()
+                .map(|d| d.as_ref())
+                .collect::<Vec<_>>(),
+            ["old.example.com", "tracker.example.org"]
+        );
+        assert_eq!(
+            state
+                .preserved_lines
+                .lock()
+                .iter()
+                .map(PreservedLine::content)
+                .collect::<Vec<_>>(),
+            preserved
+                .iter()
+                .map(PreservedLine::content)
+                .collect::<Vec<_>>()
+        );
+        let serialized = state.serialize();
+        assert!(serialized.contains("192.168.1.20 router"));
+        assert!(!serialized.contains("192.168.1.1 router"));
+        assert!(!serialized.contains("current.example.com"));
+        assert!(!state.pending_added.lock().contains("current.example.com"));
+        assert!(state.pending_added.lock().contains("old.example.com"));
     }
 
     #[test]
-    fn test_remove_block() {
+    fn test_is_blocked() {
         let state = AppState::new(Config::default());
+        state.add_block("ads.example.com");
+        state.replace_with(parse_hosts("127.0.0.1 tracker.com\n").unwrap());
+        state.add_block("ads.example.com");
 
-        state.add_block("example.com");
-        state.remove_block("example.com");
+        assert!(state.is_blocked("tracker.com"));
+        assert!(state.is_blocked(" ads.example.com "));
+        assert!(!state.is_blocked("example.com"));
 
-        let blocking = state.blocking.lock();
-        assert!(!blocking.contains(&Arc::from("example.com")));
+        state.remove_block("tracker.com");
+        assert!(!state.is_blocked("tracker.com"));
+
+        state.clear_blocks();
+        assert!(!state.is_blocked("ads.example.com"));
+    }
+
+    #[test]
+    fn test_suggest_related() {
+        let state = AppState::new(Config::default());
+        for hostname in [
+            "tracker.com",
+            "px.tracker.com",
+            "a.cdn.tracker.com",
+            "b.ads.tracker.com",
+            "ads.tracker.com",
+            "nottracker.com",
+            "tracker.co.uk",
+        ] {
+            state.add_block(hostname);
+        }
+
+        let related = state.suggest_related("x.ads.tracker.com", 10);
+        let related: Vec<&str> = related.iter().map(|h| h.as_ref()).collect();
+        assert_eq!(
+            related,
+            vec![
+                "b.ads.tracker.com",
+                "ads.tracker.com",
+                "a.cdn.tracker.com",
+                "px.tracker.com",
+                "tracker.com",
+            ]
+        );
+
+        // The hostname itself is never suggested
+        let related = state.suggest_related("ads.tracker.com", 2);
+        assert_eq!(
+            related,
+            vec![
+                Arc::from("b.ads.tracker.com"),
+                Arc::from("a.cdn.tracker.com")
+            ]
+        );
     }
 
     #[test]
@@ -170,4 +3696,578 @@ mod tests {
             assert!(blocking.contains(&Arc::from("example.com")));
         }
     }
+
+    #[test]
+    fn test_explain_domain() {
+        let state = AppState::new(Config::default());
+        *state.preserved_lines.lock() =
+            parse_hosts("192.168.1.1 router.lan\n127.0.0.1 localhost\n")
+                .unwrap()
+                .preserved_lines;
+        state.add_block("ads.example.com");
+
+        let explanation = state.explain("ads.example.com");
+        assert!(explanation.blocked);
+        assert!(!explanation.local);
+        assert!(!explanation.preserved);
+
+        let explanation = state.explain("router.lan");
+        assert!(!explanation.blocked);
+        assert!(explanation.preserved);
+
+        let explanation = state.explain("localhost");
+        assert!(!explanation.blocked);
+        assert!(explanation.local);
+        assert!(explanation.preserved);
+    }
+
+    #[test]
+    fn test_explain_domain_provenance() {
+        let state = AppState::new(Config::default());
+        let listed: BTreeSet<Arc<str>> =
+            ["ads.example.com".into(), "tracker.example.org".into()].into();
+        state.merge_blocking(ParsedHosts {
+            blocking: listed.clone(),
+            ..ParsedHosts::default()
+        });
+        state.record_source("https://a.example/hosts", &listed);
+        state.record_source("https://b.example/hosts", &listed);
+        state.add_block("ads.example.com");
+        state.add_allow("cdn.example.com");
+
+        let explanation = state.explain("ads.example.com");
+        assert!(explanation.blocked);
+        assert!(explanation.manual);
+        assert_eq!(
+            explanation.sources,
+            [
+                Arc::from("https://a.example/hosts"),
+                Arc::from("https://b.example/hosts")
+            ]
+        );
+        assert!(!explanation.allowlisted);
+        assert_eq!(explanation.wildcard, None);
+
+        let explanation = state.explain("tracker.example.org");
+        assert!(!explanation.manual);
+        assert_eq!(explanation.sources.len(), 2);
+
+        let explanation = state.explain("cdn.example.com");
+        assert!(!explanation.blocked);
+        assert!(explanation.allowlisted);
+        assert!(!explanation.manual);
+        assert!(explanation.sources.is_empty());
+    }
+
+    #[test]
+    fn test_db_round_trip() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let db_path = temp_dir.path().join("db.json");
+
+        let state = AppState::new(Config::default());
+        state.replace_with(parse_hosts("0.0.0.0 ads.example.com\n").unwrap());
+        state.add_block("example.com");
+        state.save_db(&db_path).unwrap();
+
+        let restored = AppState::new(Config::default());
+        restored.add_block("stale.com");
+        restored.load_db(&db_path).unwrap();
+
+        assert_eq!(restored.get_all_blocks(), state.get_all_blocks());
+        assert_eq!(*restored.sinks.lock(), *state.sinks.lock());
+    }
+
+    #[test]
+    fn test_allow_file_round_trip() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let allow_path = temp_dir.path().join("allow").join("allowlist.txt");
+
+        let state = AppState::new(Config::default());
+        assert!(state.add_allow("intranet.example.com"));
+        assert!(state.add_allow("xn--bcher-kva.de"));
+        assert!(state.add_allow("docs.example.org"));
+        assert!(!state.add_allow("docs.example.org"));
+        assert!(!state.add_allow("localhost"));
+        assert!(state.remove_allow("intranet.example.com"));
+        assert!(!state.remove_allow("intranet.example.com"));
+
+        assert_eq!(state.export_allowlist(&allow_path).unwrap(), 2);
+        assert_eq!(
+            fs::read_to_string(&allow_path).unwrap(),
+            "docs.example.org\nxn--bcher-kva.de\n"
+        );
+
+        let restored = AppState::new(Config::default());
+        restored.add_allow("stale.example.com");
+        assert_eq!(restored.load_allowlist(&allow_path).unwrap(), 2);
+        assert_eq!(restored.get_allowlist(), state.get_allowlist());
+        assert_eq!(restored.serialize_allowlist(), state.serialize_allowlist());
+
+        // The allowlist doesn't leak into the hosts file
+        assert!(!state.serialize().contains("docs.example.org"));
+
+        // And survives the state database
+        let db_path = temp_dir.path().join("db.json");
+        state.save_db(&db_path).unwrap();
+        restored.load_db(&db_path).unwrap();
+        assert_eq!(restored.get_allowlist(), state.get_allowlist());
+    }
+
+    #[test]
+    fn test_allowlisted_domains_are_not_blocked() {
+        let state = AppState::new(Config::default());
+        assert!(state.add_allow("cdn.example.com"));
+        assert!(!state.add_block("cdn.example.com"));
+        assert_eq!(
+            state.count_unblocked(["cdn.example.com", "ads.example.com"]),
+            1
+        );
+
+        let parsed = parse_hosts("0.0.0.0 cdn.example.com\n0.0.0.0 ads.example.com\n").unwrap();
+        let preview: Vec<Arc<str>> = vec!["ads.example.com".into()];
+        assert_eq!(state.merge_preview(&parsed), preview);
+        assert_eq!(state.merge_blocking(parsed), 1);
+        assert!(!state.get_all_blocks().contains("cdn.example.com"));
+
+        let parsed = parse_hosts("0.0.0.0 cdn.example.com\n0.0.0.0 ads.example.com\n").unwrap();
+        assert_eq!(state.replace_blocking(parsed), 1);
+        assert!(!state.get_all_blocks().contains("cdn.example.com"));
+
+        // Loading a hosts file or database that blocks it doesn't either
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let hosts = temp_dir.path().join("hosts");
+        fs::write(&hosts, "0.0.0.0 cdn.example.com\n0.0.0.0 ads.example.com\n").unwrap();
+        state.load_from_file(&hosts).unwrap();
+        assert!(!state.is_blocked("cdn.example.com"));
+        let other = temp_dir.path().join("other-hosts");
+        fs::write(&other, "0.0.0.0 cdn.example.com\n").unwrap();
+        state.switch_hosts_file(&other).unwrap();
+        assert!(!state.is_blocked("cdn.example.com"));
+
+        let db_path = temp_dir.path().join("db.json");
+        fs::write(
+            &db_path,
+            r#"{"version": 1, "blocking": ["cdn.example.com", "ads.example.com"], "allowed": ["cdn.example.com"]}"#,
+        )
+        .unwrap();
+        let loaded = AppState::new(Config::default());
+        loaded.load_db(&db_path).unwrap();
+        assert!(loaded.is_allowed("cdn.example.com"));
+        assert!(!loaded.is_blocked("cdn.example.com"));
+        assert!(loaded.is_blocked("ads.example.com"));
+    }
+
+    #[test]
+    fn test_remove_base_domain() {
+        let state = AppState::new(Config::default());
+        for hostname in [
+            "doubleclick.net",
+            "ads.doubleclick.net",
+            "stats.g.doubleclick.net",
+            "notdoubleclick.net",
+            "ads.example.co.uk",
+            "example.com",
+        ] {
+            state.add_block(hostname);
+        }
+
+        let removed = state.remove_base_domain("doubleclick.net");
+        assert_eq!(removed.len(), 3);
+        assert_eq!(
+            state.get_all_blocks(),
+            ["ads.example.co.uk", "example.com", "notdoubleclick.net"]
+                .into_iter()
+                .map(Arc::from)
+                .collect()
+        );
+
+        assert_eq!(state.remove_base_domain("example.co.uk").len(), 1);
+        assert!(state.remove_base_domain("doubleclick.net").is_empty());
+    }
+
+    #[test]
+    fn test_optimize_messy_file() {
+        let messy = "# My hosts\n\
+                     # My hosts\n\
+                     127.0.0.1 localhost\n\
+                     127.0.0.1 localhost\n\
+                     0.0.0.0 ads.example.com\n\
+                     # stale\n\
+                     #\n\
+                     0.0.0.0 tracker.example.com\n\
+                     127.0.0.1 ads.example.com\n\
+                     \n\
+                     \n\
+                     0.0.0.0 metrics.example.com\n\
+                     192.168.1.1 router\n\
+                     192.168.1.1  router\n";
+        let state = AppState::new(Config::default());
+        state.replace_with(parse_hosts(messy).unwrap());
+        let blocked = state.get_all_blocks();
+
+        assert_eq!(state.compact_preserved_lines(), 4);
+        let optimized = state.serialize();
+        assert!(optimized.lines().count() < messy.lines().count());
+        assert!(optimized.contains("# My hosts\n# stale\n"));
+
+        let reparsed = parse_hosts(&optimized).unwrap();
+        assert_eq!(reparsed.blocking, blocked);
+        assert_eq!(state.compact_preserved_lines(), 0);
+    }
+
+    #[test]
+    fn test_export_to_path() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let export_path = temp_dir.path().join("hosts.export");
+
+        let state = AppState::new(Config::default());
+        let mut content = String::from("# Header\n127.0.0.1 localhost\n");
+        for i in 0..1000 {
+            content.push_str(&format!("0.0.0.0 ads{}.example.com\n", i));
+        }
+        state.replace_with(parse_hosts(&content).unwrap());
+
+        let written = state.export_to_path(&export_path).unwrap();
+        let exported = fs::read_to_string(&export_path).unwrap();
+        assert_eq!(exported, state.serialize());
+        assert_eq!(written, exported.len() as u64);
+    }
+
+    #[test]
+    fn test_split_export() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let state = AppState::new(Config::default());
+        let content: String = (0..10)
+            .map(|i| format!("0.0.0.0 ads{}.example.com\n", i))
+            .collect();
+        state.replace_with(parse_hosts(&content).unwrap());
+        // Left over from an earlier split into more parts
+        fs::write(temp_dir.path().join("hosts.part4"), "").unwrap();
+
+        // Existing parts are only replaced when asked to
+        assert!(state.split_export(temp_dir.path(), 4, false).is_err());
+        assert!(!temp_dir.path().join("hosts.part1").exists());
+        let files = state.split_export(temp_dir.path(), 4, true).unwrap();
+        let names: Vec<_> = files
+            .iter()
+            .map(|path| path.file_name().unwrap().to_str().unwrap())
+            .collect();
+        assert_eq!(names, ["hosts.part1", "hosts.part2", "hosts.part3"]);
+        assert!(!temp_dir.path().join("hosts.part4").exists());
+
+        let parts: Vec<_> = files
+            .iter()
+            .map(|path| parse_hosts(&fs::read_to_string(path).unwrap()).unwrap())
+            .collect();
+        assert_eq!(
+            parts
+                .iter()
+                .map(|part| part.blocking.len())
+                .collect::<Vec<_>>(),
+            [4, 4, 2]
+        );
+        assert!(fs::read_to_string(&files[0])
+            .unwrap()
+            .starts_with("127.0.0.1 localhost\n"));
+        let all: BTreeSet<Arc<str>> = parts.into_iter().flat_map(|part| part.blocking).collect();
+        assert_eq!(all, state.get_all_blocks());
+        assert!(state.split_export(temp_dir.path(), 0, true).is_err());
+
+        let fresh = temp_dir.path().join("fresh");
+        assert_eq!(state.split_export(&fresh, 10, false).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_pending_counts() {
+        let state = AppState::new(Config::default());
+        state.replace_with(
+            parse_hosts("0.0.0.0 old.example.com\n0.0.0.0 kept.example.com\n").unwrap(),
+        );
+        state.mark_clean();
+        assert_eq!(state.pending_counts(), (0, 0));
+
+        // Adding then removing the same domain nets to zero
+        state.add_block("ads.example.com");
+        assert_eq!(state.pending_counts(), (1, 0));
+        state.remove_block("ads.example.com");
+        assert_eq!(state.pending_counts(), (0, 0));
+
+        // And so does removing then re-adding one
+        state.remove_block("old.example.com");
+        assert_eq!(state.pending_counts(), (0, 1));
+        state.add_block("old.example.com");
+        assert_eq!(state.pending_counts(), (0, 0));
+
+        state.add_block("new.example.com");
+        state.remove_block("old.example.com");
+        state.replace_with(
+            parse_hosts(
+                "0.0.0.0 kept.example.com\n0.0.0.0 new.example.com\n0.0.0.0 other.example.com\n",
+            )
+            .unwrap(),
+        );
+        assert_eq!(
+            *state.pending_added.lock(),
+            BTreeSet::from(["new.example.com".into(), "other.example.com".into()])
+        );
+        assert_eq!(
+            *state.pending_removed.lock(),
+            BTreeSet::from(["old.example.com".into()])
+        );
+
+        state.clear_blocks();
+        assert_eq!(state.pending_counts(), (0, 2));
+
+        state.mark_clean();
+        assert_eq!(state.pending_counts(), (0, 0));
+    }
+
+    #[test]
+    fn test_pause_blocking() {
+        let state = AppState::new(Config::default());
+        state.replace_with(
+            parse_hosts("127.0.0.1 localhost\n192.168.1.1 router\n0.0.0.0 ads.example.com\n")
+                .unwrap(),
+        );
+        let active = state.serialize();
+
+        let until = state.pause_blocking(Duration::from_secs(1800)).unwrap();
+        let remaining = state.pause_remaining().unwrap();
+        assert!(remaining > Duration::from_secs(1790));
+
+        let paused = state.serialize();
+        assert!(paused.contains("# paused: 0.0.0.0 ads.example.com\n"));
+        assert!(paused.contains("\n127.0.0.1 localhost\n"));
+        assert!(paused.contains("192.168.1.1 router\n"));
+        // Reading a paused file back still finds the managed entries
+        let reparsed = parse_hosts(&paused).unwrap();
+        assert!(reparsed.blocking.contains("ads.example.com"));
+        assert_eq!(
+            reparsed.sinks.get("ads.example.com"),
+            Some(&"0.0.0.0".parse().unwrap())
+        );
+
+        // A newer pause isn't ended by the older pause's timer
+        let newer = state.pause_blocking(Duration::from_secs(3600)).unwrap();
+        assert!(!state.resume_blocking_if(until));
+        assert!(state.pause_remaining().is_some());
+        assert!(state.resume_blocking_if(newer));
+        assert_eq!(state.serialize(), active);
+
+        state.pause_blocking(Duration::from_secs(60)).unwrap();
+        assert!(state.resume_blocking());
+        assert!(!state.resume_blocking());
+        assert_eq!(state.pause_remaining(), None);
+        assert_eq!(state.serialize(), active);
+
+        // Overlong pauses are refused without pausing
+        assert!(state
+            .pause_blocking(Duration::from_secs((MAX_PAUSE_MINUTES + 1) * 60))
+            .is_err());
+        assert!(state.pause_blocking(Duration::MAX).is_err());
+        assert_eq!(state.pause_remaining(), None);
+    }
+
+    #[test]
+    fn test_pause_survives_restart() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let db_path = temp_dir.path().join("db.json");
+        let state = AppState::new(Config::default());
+        state.set_db_path(db_path.as_path().into());
+        state.pause_blocking(Duration::from_secs(1800)).unwrap();
+        assert!(temp_dir.path().join(PAUSE_FILE).exists());
+
+        // Rolling back a pause that replaced none removes the saved one
+        state.restore_pause(None);
+        assert_eq!(state.pause_remaining(), None);
+        assert!(!temp_dir.path().join(PAUSE_FILE).exists());
+        let until = state.pause_blocking(Duration::from_secs(1800)).unwrap();
+        state.pause_blocking(Duration::from_secs(60)).unwrap();
+        state.restore_pause(Some(until));
+        assert!(state.pause_remaining().unwrap() > Duration::from_secs(1790));
+
+        let restarted = AppState::new(Config::default());
+        restarted.set_db_path(db_path.as_path().into());
+        let until = restarted.reinstate_pause().unwrap().unwrap();
+        assert!(restarted.pause_remaining().unwrap() > Duration::from_secs(1790));
+        assert!(restarted.resume_blocking_if(until));
+        assert!(!temp_dir.path().join(PAUSE_FILE).exists());
+        assert_eq!(restarted.reinstate_pause().unwrap(), None);
+
+        // A pause that ran out while the app was closed ends right away
+        save_schedule(&temp_dir.path().join(PAUSE_FILE), unix_now() - 60).unwrap();
+        let until = restarted.reinstate_pause().unwrap().unwrap();
+        assert!(until <= Instant::now());
+        assert_eq!(restarted.pause_remaining(), None);
+    }
+
+    #[test]
+    fn test_export_managed_only() {
+        let state = AppState::new(Config {
+            annotate_additions: true,
+            ..Config::default()
+        });
+        state.replace_with(
+            parse_hosts(
+                "# My network\n\
+                 127.0.0.1 localhost\n\
+                 192.168.1.1 router\n\
+                 10.0.0.5 nas.home.example\n\
+                 0.0.0.0 tracker.example.net\n\
+                 0.0.0.0 ads.example.com\n",
+            )
+            .unwrap(),
+        );
+        state.add_block("metrics.example.org");
+
+        let hosts = state.serialize_managed_only(ExportFormat::Hosts);
+        assert_eq!(
+            hosts,
+            "0.0.0.0 ads.example.com tracker.example.net\n127.0.0.1 metrics.example.org\n"
+        );
+        for private in [
+            "My network",
+            "localhost",
+            "router",
+            "192.168",
+            "nas.home",
+            "adguard",
+        ] {
+            assert!(!hosts.contains(private), "{} leaked", private);
+        }
+
+        assert_eq!(
+            state.serialize_managed_only(ExportFormat::Domains),
+            "ads.example.com\nmetrics.example.org\ntracker.example.net\n"
+        );
+        // The full export still has everything
+        assert!(state.serialize().contains("192.168.1.1 router"));
+    }
+
+    #[test]
+    fn test_annotate_additions() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let db_path = temp_dir.path().join("db.json");
+        let config = Config {
+            annotate_additions: true,
+            ..Config::default()
+        };
+
+        let state = AppState::new(config.clone());
+        state.replace_with(parse_hosts("0.0.0.0 imported.example.com\n").unwrap());
+        state.add_block("ads.example.com");
+
+        let today = chrono::Local::now().format("%Y-%m-%d").to_string();
+        let serialized = state.serialize();
+        assert!(serialized.contains(&format!(
+            "127.0.0.1 ads.example.com # added {} via adguard\n",
+            today
+        )));
+        assert!(serialized.contains("0.0.0.0 imported.example.com\n"));
+
+        // The annotation survives the database and a reload of the hosts file
+        state.save_db(&db_path).unwrap();
+        let restored = AppState::new(config.clone());
+        restored.load_db(&db_path).unwrap();
+        assert_eq!(restored.serialize(), serialized);
+
+        let reloaded = AppState::new(config);
+        reloaded.replace_with(parse_hosts(&serialized).unwrap());
+        assert_eq!(reloaded.serialize(), serialized);
+
+        state.remove_block("ads.example.com");
+        assert!(state.annotations.lock().is_empty());
+    }
+
+    #[test]
+    fn test_added_since() {
+        let state = AppState::new(Config::default());
+        state.replace_with(
+            parse_hosts(
+                "127.0.0.1 old.example.com # added 2024-05-01 via adguard\n\
+                 127.0.0.1 week.example.com # added 2024-05-10 via adguard\n\
+                 127.0.0.1 new.example.com # added 2024-05-20 via adguard\n\
+                 127.0.0.1 plain.example.com\n",
+            )
+            .unwrap(),
+        );
+        let cutoff = chrono::NaiveDate::from_ymd_opt(2024, 5, 10)
+            .unwrap()
+            .and_hms_opt(12, 0, 0)
+            .unwrap()
+            .and_local_timezone(chrono::Local)
+            .unwrap()
+            .timestamp() as u64;
+
+        let added = state.added_since(cutoff);
+        assert_eq!(
+            added.iter().map(|h| &**h).collect::<Vec<_>>(),
+            ["new.example.com", "week.example.com"]
+        );
+        assert!(state.added_since(u64::MAX).is_empty());
+    }
+
+    #[test]
+    fn test_add_block_single_label() {
+        let state = AppState::new(Config::default());
+        state.add_block("adserver");
+        assert!(!state.is_blocked("adserver"));
+
+        let state = AppState::new(Config {
+            block_single_label: true,
+            ..Config::default()
+        });
+        state.add_block("adserver");
+        state.add_block("localhost");
+        assert!(state.is_blocked("adserver"));
+        assert!(!state.is_blocked("localhost"));
+    }
+
+    #[test]
+    fn test_load_db_rejects_newer_version() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let db_path = temp_dir.path().join("db.json");
+        fs::write(&db_path, r#"{"version": 999, "blocking": []}"#).unwrap();
+
+        let state = AppState::new(Config::default());
+        assert!(state.load_db(&db_path).is_err());
+    }
+
+    #[test]
+    fn test_clear_token_validation() {
+        let state = AppState::new(Config::default());
+        state.add_block("example.com");
+
+        // No token requested
+        assert!(state.consume_clear_token("anything").is_err());
+
+        // Wrong token is rejected and invalidates the pending one
+        let token = state.issue_clear_token();
+        assert!(state.consume_clear_token("wrong").is_err());
+        assert!(state.consume_clear_token(&token).is_err());
+
+        // Stale token is rejected
+        let token = state.issue_clear_token();
+        state.clear_token.lock().as_mut().unwrap().expires_at = Instant::now();
+        assert!(state.consume_clear_token(&token).is_err());
+
+        // A fresh token is accepted exactly once
+        let token = state.issue_clear_token();
+        assert!(state.consume_clear_token(&token).is_ok());
+        assert!(state.consume_clear_token(&token).is_err());
+
+        assert_eq!(state.clear_blocks(), 1);
+        assert_eq!(state.get_total_blocked(), 0);
+    }
+
+    #[test]
+    fn test_explain_idn_domain() {
+        let state = AppState::new(Config::default());
+        state.add_block("xn--e1afmkfd.xn--p1ai");
+
+        let explanation = state.explain("пример.рф");
+        assert!(explanation.blocked);
+        assert_eq!(explanation.hostname.as_ref(), "xn--e1afmkfd.xn--p1ai");
+        assert_eq!(explanation.display.as_ref(), "пример.рф");
+    }
 }
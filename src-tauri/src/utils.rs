@@ -1,4 +1,7 @@
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
 use std::net::IpAddr;
+use std::time::SystemTime;
 
 #[inline]
 pub fn is_local_domain(hostname: &str) -> bool {
@@ -9,9 +12,32 @@ pub fn is_local_domain(hostname: &str) -> bool {
         return true;
     }
 
-    let h = h.to_lowercase();
+    is_localhost_name(h)
+}
+
+/// Like `is_local_domain`, but with `block_single_label` set only localhost
+/// names count as local, so single-label names like "adserver" can be blocked
+#[inline]
+pub fn is_local_domain_with(hostname: &str, block_single_label: bool) -> bool {
+    if block_single_label {
+        is_localhost_name(hostname)
+    } else {
+        is_local_domain(hostname)
+    }
+}
 
-    return h == "localhost" || h == "localhost.localdomain" || h.ends_with(".localhost");
+/// Canonical form of a hostname: a fully-qualified `ads.example.com.` is
+/// stored as `ads.example.com`, so both spellings are one entry
+#[inline]
+pub fn canonical_hostname(hostname: &str) -> &str {
+    hostname.strip_suffix('.').unwrap_or(hostname)
+}
+
+/// Check if a hostname is localhost itself (not merely a single-label name)
+#[inline]
+pub fn is_localhost_name(hostname: &str) -> bool {
+    let h = hostname.trim_end_matches('.').to_lowercase();
+    h == "localhost" || h == "localhost.localdomain" || h.ends_with(".localhost")
 }
 
 /// Check if an IP address is a localhost address
@@ -31,3 +57,287 @@ pub fn is_localhost_ip(ip: &IpAddr) -> bool {
         }
     }
 }
+
+/// Generate a hard-to-guess hex token (randomly keyed hash of the current time)
+#[inline]
+pub fn random_token() -> String {
+    let mut hasher = RandomState::new().build_hasher();
+    let nanos = SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos();
+    hasher.write_u128(nanos);
+    format!("{:016x}", hasher.finish())
+}
+
+/// Second-level labels that act as public suffixes under a country code
+/// (e.g. "co.uk"); a small heuristic, not the full public suffix list
+const MULTI_LABEL_SUFFIXES: &[&str] = &["ac", "co", "com", "edu", "gov", "ne", "net", "or", "org"];
+
+/// Registrable domain of a hostname (e.g. "example.co.uk" for "ads.example.co.uk")
+#[inline]
+pub fn registrable_domain(hostname: &str) -> &str {
+    let hostname = hostname.trim_end_matches('.');
+    let labels: Vec<&str> = hostname.rsplitn(4, '.').collect();
+
+    let keep = match labels.as_slice() {
+        [tld, second, _, ..] if tld.len() == 2 && MULTI_LABEL_SUFFIXES.contains(second) => 3,
+        _ => 2,
+    };
+    if labels.len() <= keep {
+        return hostname;
+    }
+
+    let suffix_len: usize = labels[..keep].iter().map(|label| label.len() + 1).sum();
+    &hostname[hostname.len() + 1 - suffix_len..]
+}
+
+/// Scripts whose letters are commonly confused with each other (homograph attacks)
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum ConfusableScript {
+    Latin,
+    Greek,
+    Cyrillic,
+}
+
+#[inline]
+fn confusable_script(c: char) -> Option<ConfusableScript> {
+    match c {
+        'a'..='z' | 'A'..='Z' | '\u{00C0}'..='\u{024F}' => Some(ConfusableScript::Latin),
+        '\u{0370}'..='\u{03FF}' => Some(ConfusableScript::Greek),
+        '\u{0400}'..='\u{04FF}' => Some(ConfusableScript::Cyrillic),
+        _ => None,
+    }
+}
+
+/// Check if a label mixes Latin, Greek or Cyrillic letters (e.g. Cyrillic "а" in "pаypal")
+fn is_mixed_script(label: &str) -> bool {
+    let mut scripts = label.chars().filter_map(confusable_script);
+    match scripts.next() {
+        Some(first) => scripts.any(|script| script != first),
+        None => false,
+    }
+}
+
+/// Convert a hostname to the ASCII (punycode) form used for storage and serialization
+/// ASCII hostnames are returned unchanged
+pub fn to_ascii_domain(hostname: &str) -> Result<String, anyhow::Error> {
+    if hostname.is_ascii() {
+        return Ok(hostname.to_string());
+    }
+
+    if let Some(label) = hostname.split('.').find(|label| is_mixed_script(label)) {
+        return Err(anyhow::anyhow!("Mixed-script label not allowed: {}", label));
+    }
+
+    idna::domain_to_ascii(hostname)
+        .map_err(|e| anyhow::anyhow!("Invalid internationalized domain {}: {}", hostname, e))
+}
+
+/// Convert a stored (punycode) hostname to its Unicode display form
+#[inline]
+pub fn to_display_domain(hostname: &str) -> String {
+    match idna::domain_to_unicode(hostname) {
+        (display, Ok(())) => display,
+        _ => hostname.to_string(),
+    }
+}
+
+/// Reduce a URL or loosely written domain to a bare hostname
+/// Strips the scheme, credentials, port, path and a leading "www.", then
+/// lowercases and converts to punycode. Returns None if no domain is left.
+pub fn normalize_domain(input: &str) -> Option<String> {
+    let mut host = input.trim();
+    if let Some((_, rest)) = host.split_once("://") {
+        host = rest;
+    }
+    host = host.split(['/', '?', '#']).next()?;
+    if let Some((_, rest)) = host.rsplit_once('@') {
+        host = rest;
+    }
+    if let Some((name, port)) = host.rsplit_once(':') {
+        if !port.is_empty() && port.chars().all(|c| c.is_ascii_digit()) {
+            host = name;
+        }
+    }
+
+    let host = host.trim_end_matches('.').to_lowercase();
+    listed_domain(host.strip_prefix("www.").unwrap_or(&host))
+}
+
+/// Reduce a hostname as written in a list to its stored form
+/// Lowercases and converts to punycode; returns None for names that aren't
+/// valid domains or are local.
+pub fn listed_domain(hostname: &str) -> Option<String> {
+    let host = to_ascii_domain(&canonical_hostname(hostname).to_lowercase()).ok()?;
+    (is_domain_like(&host) && !is_local_domain(&host)).then_some(host)
+}
+
+/// Check that an ASCII hostname has valid labels and isn't an IP address
+#[inline]
+fn is_domain_like(hostname: &str) -> bool {
+    validate_hostname(hostname).is_ok()
+}
+
+/// Check that an ASCII hostname could be looked up, with the reason it can't
+/// Labels are letters, digits, '-' and '_' (seen in real DNS names), up to 63
+/// characters and not starting or ending with '-'
+pub fn validate_hostname(hostname: &str) -> Result<(), anyhow::Error> {
+    if hostname.is_empty() {
+        return Err(anyhow::anyhow!("Empty name"));
+    }
+    if hostname.len() > 253 {
+        return Err(anyhow::anyhow!("Longer than 253 characters"));
+    }
+    if hostname.parse::<IpAddr>().is_ok() {
+        return Err(anyhow::anyhow!("An IP address, not a hostname"));
+    }
+    for label in hostname.split('.') {
+        if label.is_empty() {
+            return Err(anyhow::anyhow!(
+                "Empty label (leading, trailing or repeated dot)"
+            ));
+        }
+        if label.len() > 63 {
+            return Err(anyhow::anyhow!(
+                "Label {:?} is longer than 63 characters",
+                label
+            ));
+        }
+        if label.starts_with('-') || label.ends_with('-') {
+            return Err(anyhow::anyhow!("Label {:?} starts or ends with '-'", label));
+        }
+        if let Some(c) = label
+            .chars()
+            .find(|c| !(c.is_ascii_alphanumeric() || *c == '-' || *c == '_'))
+        {
+            return Err(anyhow::anyhow!("Invalid character {:?}", c));
+        }
+    }
+    if hostname
+        .rsplit('.')
+        .next()
+        .is_some_and(|tld| tld.chars().all(|c| c.is_ascii_digit()))
+    {
+        return Err(anyhow::anyhow!("Top-level label is all digits"));
+    }
+    Ok(())
+}
+
+/// Pull every domain out of free-form text (URLs, bare domains, hosts lines)
+/// Returns the distinct domains, in order of appearance, and the number of
+/// tokens that weren't domains. Comments are ignored.
+pub fn domains_from_text(text: &str) -> (Vec<String>, usize) {
+    let mut domains = Vec::new();
+    let mut seen = std::collections::HashSet::new();
+    let mut skipped = 0;
+
+    for line in text.lines() {
+        let line = line.split('#').next().unwrap_or_default();
+        for token in line.split_whitespace() {
+            match normalize_domain(token) {
+                Some(domain) => {
+                    if seen.insert(domain.clone()) {
+                        domains.push(domain);
+                    }
+                }
+                None => skipped += 1,
+            }
+        }
+    }
+
+    (domains, skipped)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_to_ascii_domain() {
+        assert_eq!(to_ascii_domain("example.com").unwrap(), "example.com");
+        assert_eq!(
+            to_ascii_domain("пример.рф").unwrap(),
+            "xn--e1afmkfd.xn--p1ai"
+        );
+        assert_eq!(to_ascii_domain("例え.jp").unwrap(), "xn--r8jz45g.jp");
+        assert_eq!(to_ascii_domain("bücher.de").unwrap(), "xn--bcher-kva.de");
+    }
+
+    #[test]
+    fn test_to_ascii_domain_rejects_mixed_script() {
+        // Cyrillic "а" (U+0430) inside an otherwise Latin label
+        assert!(to_ascii_domain("p\u{0430}ypal.com").is_err());
+        // Greek omicron mixed with Latin
+        assert!(to_ascii_domain("g\u{03BF}ogle.com").is_err());
+        // A Cyrillic label next to a Latin label is fine
+        assert!(to_ascii_domain("пример.com").is_ok());
+    }
+
+    #[test]
+    fn test_registrable_domain() {
+        assert_eq!(registrable_domain("ads.example.com"), "example.com");
+        assert_eq!(registrable_domain("example.com"), "example.com");
+        assert_eq!(registrable_domain("a.b.ads.example.co.uk"), "example.co.uk");
+        assert_eq!(registrable_domain("example.co.uk"), "example.co.uk");
+        assert_eq!(registrable_domain("localhost"), "localhost");
+    }
+
+    #[test]
+    fn test_to_display_domain() {
+        assert_eq!(to_display_domain("xn--e1afmkfd.xn--p1ai"), "пример.рф");
+        assert_eq!(to_display_domain("example.com"), "example.com");
+    }
+
+    #[test]
+    fn test_normalize_domain() {
+        assert_eq!(
+            normalize_domain("https://www.Ads.Example.com:8443/path?q=1#top").as_deref(),
+            Some("ads.example.com")
+        );
+        assert_eq!(
+            normalize_domain("user@tracker.example.com.").as_deref(),
+            Some("tracker.example.com")
+        );
+        assert_eq!(
+            normalize_domain("bücher.de").as_deref(),
+            Some("xn--bcher-kva.de")
+        );
+        assert_eq!(normalize_domain("0.0.0.0"), None);
+        assert_eq!(normalize_domain("::1"), None);
+        assert_eq!(normalize_domain("localhost"), None);
+        assert_eq!(normalize_domain("hello"), None);
+        assert_eq!(normalize_domain("-bad-.com"), None);
+    }
+
+    #[test]
+    fn test_validate_hostname() {
+        assert!(validate_hostname("ads.example.com").is_ok());
+        assert!(validate_hostname("_tracking.example.com").is_ok());
+        for (hostname, reason) in [
+            ("", "Empty name"),
+            ("ads..example.com", "Empty label"),
+            ("-ads.example.com", "starts or ends with '-'"),
+            ("ads!.example.com", "Invalid character '!'"),
+            ("192.168.1.1", "IP address"),
+            ("example.123", "all digits"),
+        ] {
+            let error = validate_hostname(hostname).unwrap_err().to_string();
+            assert!(error.contains(reason), "{}: {}", hostname, error);
+        }
+        assert!(validate_hostname(&format!("{}.com", "a".repeat(64))).is_err());
+    }
+
+    #[test]
+    fn test_domains_from_text() {
+        let text = "Check https://www.ads.example.com/banner.js\n\
+                    # tracker.example.com is in a comment\n\
+                    0.0.0.0 metrics.example.net\n\
+                    ads.example.com\n";
+        let (domains, skipped) = domains_from_text(text);
+
+        assert_eq!(domains, vec!["ads.example.com", "metrics.example.net"]);
+        // "Check" and "0.0.0.0"
+        assert_eq!(skipped, 2);
+    }
+}
@@ -1,51 +1,542 @@
-use crate::state::AppState;
-use notify::{Event, EventKind, RecursiveMode, Watcher};
+use crate::config::Config;
+use crate::platform::default_hosts_file_path;
+use crate::state::{AppState, ReloadReport};
+use notify::{Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use parking_lot::Mutex;
 use std::path::Path;
-use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Weak};
 use tauri::async_runtime;
-use tauri::{AppHandle, Manager};
+use tauri::{AppHandle, Emitter, Manager};
 
-/// Start watching the hosts file for external changes
-pub fn start_watcher(
-    app: AppHandle,
+/// Called with what changed after the hosts file is reloaded
+pub type ReloadHandler = Arc<dyn Fn(&ReloadReport) + Send + Sync>;
+
+/// Watcher bound to the active hosts file, re-bound when the path changes
+#[derive(Default)]
+pub struct HostsWatcher {
+    active: Arc<Mutex<Option<ActiveWatch>>>,
+    on_reload: Option<ReloadHandler>,
+    /// Changes are ignored while set, e.g. during a long manual edit
+    paused: Arc<AtomicBool>,
+}
+
+struct ActiveWatch {
+    watcher: RecommendedWatcher,
+    path: Arc<Path>,
+    /// The file didn't exist, so its directory is watched until it's created
+    awaiting_creation: bool,
+}
+
+impl HostsWatcher {
+    /// Watcher calling `on_reload` after each reload of the hosts file
+    pub fn with_handler(on_reload: ReloadHandler) -> Self {
+        HostsWatcher {
+            on_reload: Some(on_reload),
+            ..HostsWatcher::default()
+        }
+    }
+
+    /// Watch a hosts file, releasing the watch on the previously watched one
+    pub fn watch(
+        &self,
+        hosts_file_path: Arc<Path>,
+        state: Arc<AppState>,
+    ) -> Result<(), anyhow::Error> {
+        bind(
+            &self.active,
+            hosts_file_path,
+            state,
+            self.on_reload.clone(),
+            self.paused.clone(),
+        )
+    }
+
+    /// Stop reloading on changes until `resume`
+    #[inline]
+    pub fn pause(&self) {
+        self.paused.store(true, Ordering::SeqCst);
+    }
+
+    /// Reload on changes again, reloading once right away to pick up whatever
+    /// changed while paused
+    /// Returns what the reload changed, or None if nothing is watched or the
+    /// watcher wasn't paused
+    pub fn resume(&self, state: Arc<AppState>) -> Result<Option<ReloadReport>, anyhow::Error> {
+        if !self.paused.swap(false, Ordering::SeqCst) {
+            return Ok(None);
+        }
+        let Some(path) = self.watched_path() else {
+            return Ok(None);
+        };
+        // A file created while paused is watched directly from now on
+        let awaiting_creation = self
+            .active
+            .lock()
+            .as_ref()
+            .is_some_and(|active| active.awaiting_creation);
+        if awaiting_creation && path.exists() {
+            self.watch(path.clone(), state.clone())?;
+        }
+        if !path.exists() {
+            return Ok(None);
+        }
+        reload(&path, &state, self.on_reload.as_ref()).map(Some)
+    }
+
+    /// Whether changes are currently ignored
+    #[inline]
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::SeqCst)
+    }
+
+    /// Path currently being watched, if any
+    pub fn watched_path(&self) -> Option<Arc<Path>> {
+        self.active
+            .lock()
+            .as_ref()
+            .map(|active| active.path.clone())
+    }
+}
+
+/// Path registered with the watcher: the file, or its directory until it exists
+#[inline]
+fn watch_target(hosts_file_path: &Path, awaiting_creation: bool) -> &Path {
+    if !awaiting_creation {
+        return hosts_file_path;
+    }
+    match hosts_file_path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    }
+}
+
+/// Replace the active watch with one on `hosts_file_path`
+/// A missing file is watched through its directory until it's created
+fn bind(
+    active: &Arc<Mutex<Option<ActiveWatch>>>,
+    hosts_file_path: Arc<Path>,
+    state: Arc<AppState>,
+    on_reload: Option<ReloadHandler>,
+    paused: Arc<AtomicBool>,
+) -> Result<(), anyhow::Error> {
+    let mut active_watch = active.lock();
+
+    // The old watcher must be dropped to release its watch
+    if let Some(mut previous) = active_watch.take() {
+        let target = watch_target(&previous.path, previous.awaiting_creation);
+        let _ = previous.watcher.unwatch(target);
+    }
+
+    let awaiting_creation = !hosts_file_path.exists();
+    let promote = awaiting_creation.then(|| Arc::downgrade(active));
+    let mut watcher = create_watcher(hosts_file_path.clone(), state, promote, on_reload, paused)?;
+    watcher.watch(
+        watch_target(&hosts_file_path, awaiting_creation),
+        RecursiveMode::NonRecursive,
+    )?;
+    *active_watch = Some(ActiveWatch {
+        watcher,
+        path: hosts_file_path,
+        awaiting_creation,
+    });
+
+    Ok(())
+}
+
+/// Switch from watching the directory to watching the newly created file
+fn promote_to_file_watch(
+    active: &Arc<Mutex<Option<ActiveWatch>>>,
     hosts_file_path: Arc<Path>,
     state: Arc<AppState>,
+    on_reload: Option<ReloadHandler>,
+    paused: Arc<AtomicBool>,
 ) -> Result<(), anyhow::Error> {
-    let path_for_watch = hosts_file_path.clone();
-    let app_for_manage = app.clone();
+    let still_awaiting = active
+        .lock()
+        .as_ref()
+        .is_some_and(|watch| watch.awaiting_creation && watch.path == hosts_file_path);
+    // The path may have been switched in the meantime
+    if still_awaiting && hosts_file_path.exists() {
+        bind(active, hosts_file_path, state, on_reload, paused)?;
+    }
+    Ok(())
+}
 
-    let mut watcher = notify::recommended_watcher(move |result: Result<Event, notify::Error>| {
+/// Re-parse the hosts file and merge it with any unsaved changes
+fn reload(
+    path: &Path,
+    state: &AppState,
+    on_reload: Option<&ReloadHandler>,
+) -> Result<ReloadReport, anyhow::Error> {
+    let report = state.load_from_file(path)?;
+    tracing::info!(
+        hosts_file = %path.display(),
+        added = report.added.len(),
+        removed = report.removed.len(),
+        "Reloaded hosts file after an external change"
+    );
+    if let Some(on_reload) = on_reload {
+        on_reload(&report);
+    }
+    Ok(report)
+}
+
+/// Reload after a change to the hosts file, unless `paused` is set
+/// Returns whether the change was handled rather than skipped
+fn reload_unless_paused(
+    path: &Path,
+    state: &AppState,
+    on_reload: Option<&ReloadHandler>,
+    paused: &AtomicBool,
+) -> bool {
+    if paused.load(Ordering::SeqCst) {
+        return false;
+    }
+    if let Err(e) = reload(path, state, on_reload) {
+        state.log_error("watcher", format!("Failed to reload hosts file: {}", e));
+    }
+    true
+}
+
+/// Create a watcher that reloads the state when the hosts file is modified,
+/// unless `paused` is set
+/// With `promote` set, the watcher is on the file's directory and re-binds to
+/// the file once it appears
+fn create_watcher(
+    hosts_file_path: Arc<Path>,
+    state: Arc<AppState>,
+    promote: Option<Weak<Mutex<Option<ActiveWatch>>>>,
+    on_reload: Option<ReloadHandler>,
+    paused: Arc<AtomicBool>,
+) -> Result<RecommendedWatcher, anyhow::Error> {
+    let watcher = notify::recommended_watcher(move |result: Result<Event, notify::Error>| {
         match result {
             Ok(event) => {
-                // Only react to modify events (not create/remove)
-                if matches!(event.kind, EventKind::Modify(_)) {
+                // A directory watch reports every file in it
+                let is_hosts_file = event
+                    .paths
+                    .iter()
+                    .any(|path| path.file_name() == hosts_file_path.file_name());
+                // Only react to modify events (and creation while awaiting the file)
+                let relevant = match event.kind {
+                    EventKind::Modify(_) => true,
+                    EventKind::Create(_) => promote.is_some(),
+                    _ => false,
+                };
+                if is_hosts_file && relevant {
                     // Debounce: spawn async task to handle the change
-                    let path_clone = path_for_watch.clone();
+                    let path_clone = hosts_file_path.clone();
                     let state_clone = state.clone();
+                    let promote = promote.clone();
+                    let on_reload = on_reload.clone();
+                    let paused = paused.clone();
 
                     async_runtime::spawn(async move {
                         // Small delay to debounce rapid changes
                         tokio::time::sleep(std::time::Duration::from_millis(500)).await;
 
-                        // Re-parse the file and update state
-                        if let Err(e) = state_clone.load_from_file(&path_clone) {
-                            eprintln!("Failed to reload hosts file: {}", e);
+                        // Checked after the delay so edits just before a pause are skipped too;
+                        // resuming reloads once instead
+                        if !reload_unless_paused(
+                            &path_clone,
+                            &state_clone,
+                            on_reload.as_ref(),
+                            &paused,
+                        ) {
+                            return;
+                        }
+
+                        if let Some(active) = promote.and_then(|active| active.upgrade()) {
+                            if let Err(e) = promote_to_file_watch(
+                                &active,
+                                path_clone,
+                                state_clone.clone(),
+                                on_reload,
+                                paused,
+                            ) {
+                                state_clone.log_error(
+                                    "watcher",
+                                    format!("Failed to watch the created hosts file: {}", e),
+                                );
+                            }
                         }
-                        // Note: Frontend can poll for updates or user can refresh manually
-                        // Event emission can be added later when needed
                     });
                 }
             }
             Err(e) => {
-                eprintln!("Watcher error: {}", e);
+                state.log_error("watcher", format!("Watcher error: {}", e));
             }
         }
     })?;
 
-    watcher.watch(&hosts_file_path, RecursiveMode::NonRecursive)?;
+    Ok(watcher)
+}
 
+/// Start watching the hosts file for external changes
+/// A reload emits "hosts-reloaded" with what changed, including whether the
+/// preserved lines conflicted with unsaved changes
+pub fn start_watcher(
+    app: AppHandle,
+    hosts_file_path: Arc<Path>,
+    state: Arc<AppState>,
+) -> Result<(), anyhow::Error> {
+    let handle = app.clone();
+    let on_reload: ReloadHandler = Arc::new(move |report: &ReloadReport| {
+        let _ = handle.emit(
+            "hosts-reloaded",
+            serde_json::json!({
+                "added": report.added,
+                "removed": report.removed,
+                "preserved_conflict": report.preserved_conflict,
+            }),
+        );
+    });
     // Store watcher in app state so it doesn't get dropped
-    app_for_manage.manage(watcher);
+    app.manage(HostsWatcher::with_handler(on_reload));
+    app.state::<HostsWatcher>().watch(hosts_file_path, state)
+}
 
-    Ok(())
+/// Re-bind the watcher to a new hosts file path
+pub fn restart_watcher(
+    app: &AppHandle,
+    hosts_file_path: Arc<Path>,
+    state: Arc<AppState>,
+) -> Result<(), anyhow::Error> {
+    match app.try_state::<HostsWatcher>() {
+        Some(watcher) if watcher.watched_path().as_deref() == Some(&*hosts_file_path) => Ok(()),
+        Some(watcher) => watcher.watch(hosts_file_path, state),
+        // The watcher failed to start at setup; start it now
+        None => start_watcher(app.clone(), hosts_file_path, state),
+    }
+}
+
+/// Called with the previous config after the config file is reloaded
+pub type ConfigChangeHandler = Box<dyn Fn(Config) + Send + Sync>;
+
+/// Watcher that reloads the config when `config.ini` is edited outside the app
+#[derive(Default)]
+pub struct ConfigWatcher {
+    active: Mutex<Option<RecommendedWatcher>>,
+}
+
+impl ConfigWatcher {
+    /// Watch the config file, calling `on_change` after each reload that changed it
+    /// The directory is watched so editors that save by renaming are followed
+    pub fn watch(
+        &self,
+        config_path: Arc<Path>,
+        state: Arc<AppState>,
+        on_change: ConfigChangeHandler,
+    ) -> Result<(), anyhow::Error> {
+        let on_change: Arc<dyn Fn(Config) + Send + Sync> = Arc::from(on_change);
+        let path = config_path.clone();
+        let mut watcher =
+            notify::recommended_watcher(move |result: Result<Event, notify::Error>| {
+                match result {
+                    Ok(event) => {
+                        let is_config_file = event
+                            .paths
+                            .iter()
+                            .any(|path| path.file_name() == config_path.file_name());
+                        let relevant =
+                            matches!(event.kind, EventKind::Modify(_) | EventKind::Create(_));
+                        if is_config_file && relevant {
+                            let path_clone = config_path.clone();
+                            let state_clone = state.clone();
+                            let on_change = on_change.clone();
+
+                            async_runtime::spawn(async move {
+                                // Small delay to debounce rapid changes
+                                tokio::time::sleep(std::time::Duration::from_millis(500)).await;
+
+                                // The app's own writes match the config in memory and
+                                // come back as None, so they don't trigger another round
+                                match state_clone.reload_config(&path_clone) {
+                                    Ok(Some(previous)) => {
+                                        tracing::info!(
+                                            config_file = %path_clone.display(),
+                                            "Reloaded config after an external change"
+                                        );
+                                        on_change(previous);
+                                    }
+                                    Ok(None) => {}
+                                    Err(e) => state_clone.log_error(
+                                        "watcher",
+                                        format!("Failed to reload config: {}", e),
+                                    ),
+                                }
+                            });
+                        }
+                    }
+                    Err(e) => {
+                        state.log_error("watcher", format!("Config watcher error: {}", e));
+                    }
+                }
+            })?;
+        // Watched through its directory, which also covers a file that doesn't exist yet
+        watcher.watch(watch_target(&path, true), RecursiveMode::NonRecursive)?;
+        *self.active.lock() = Some(watcher);
+
+        Ok(())
+    }
+}
+
+/// Start watching the config file for external changes
+/// A reload emits "config-changed" and follows a changed hosts file path
+pub fn start_config_watcher(
+    app: AppHandle,
+    config_path: Arc<Path>,
+    state: Arc<AppState>,
+) -> Result<(), anyhow::Error> {
+    let handle = app.clone();
+    let reloaded_state = state.clone();
+    let on_change = Box::new(move |previous: Config| {
+        let config = reloaded_state.get_config();
+        let hosts_path = config
+            .host_file_path
+            .unwrap_or_else(default_hosts_file_path);
+        let previous_hosts_path = previous
+            .host_file_path
+            .unwrap_or_else(default_hosts_file_path);
+        if hosts_path != previous_hosts_path {
+            let switched = reloaded_state
+                .switch_hosts_file(&hosts_path)
+                .and_then(|()| restart_watcher(&handle, hosts_path, reloaded_state.clone()));
+            if let Err(e) = switched {
+                reloaded_state.log_error(
+                    "watcher",
+                    format!("Failed to switch to the configured hosts file: {}", e),
+                );
+            }
+        }
+        let _ = handle.emit("config-changed", ());
+    });
+
+    app.manage(ConfigWatcher::default());
+    app.state::<ConfigWatcher>()
+        .watch(config_path, state, on_change)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::time::{Duration, Instant};
+    use tempfile::TempDir;
+
+    fn wait_for(condition: impl Fn() -> bool) -> bool {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while Instant::now() < deadline {
+            if condition() {
+                return true;
+            }
+            std::thread::sleep(Duration::from_millis(50));
+        }
+        false
+    }
+
+    #[test]
+    fn test_watch_follows_path_change() {
+        let temp_dir = TempDir::new().unwrap();
+        let old_path = temp_dir.path().join("hosts");
+        let new_path = temp_dir.path().join("scratch-hosts");
+        fs::write(&old_path, "127.0.0.1 localhost\n").unwrap();
+        fs::write(&new_path, "127.0.0.1 localhost\n").unwrap();
+
+        let state = Arc::new(AppState::new(Config::default()));
+        let watcher = HostsWatcher::default();
+        watcher
+            .watch(old_path.as_path().into(), state.clone())
+            .unwrap();
+        watcher
+            .watch(new_path.as_path().into(), state.clone())
+            .unwrap();
+        assert_eq!(watcher.watched_path().as_deref(), Some(new_path.as_path()));
+
+        fs::write(&old_path, "127.0.0.1 old.example.com\n").unwrap();
+        fs::write(&new_path, "127.0.0.1 new.example.com\n").unwrap();
+
+        assert!(wait_for(|| state
+            .get_all_blocks()
+            .contains("new.example.com")));
+        // The old path's reload would have landed alongside the new one
+        std::thread::sleep(Duration::from_millis(600));
+        assert!(!state.get_all_blocks().contains("old.example.com"));
+    }
+
+    #[test]
+    fn test_watch_into_existence() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("hosts");
+
+        let state = Arc::new(AppState::new(Config::default()));
+        let watcher = HostsWatcher::default();
+        watcher.watch(path.as_path().into(), state.clone()).unwrap();
+        assert!(watcher.active.lock().as_ref().unwrap().awaiting_creation);
+
+        // Other files in the directory are ignored
+        fs::write(
+            temp_dir.path().join("other"),
+            "127.0.0.1 other.example.com\n",
+        )
+        .unwrap();
+        fs::write(&path, "127.0.0.1 created.example.com\n").unwrap();
+
+        assert!(wait_for(|| state
+            .get_all_blocks()
+            .contains("created.example.com")));
+        assert!(wait_for(|| !watcher
+            .active
+            .lock()
+            .as_ref()
+            .unwrap()
+            .awaiting_creation));
+        assert!(!state.get_all_blocks().contains("other.example.com"));
+
+        // Now watching the file itself
+        fs::write(&path, "127.0.0.1 modified.example.com\n").unwrap();
+        assert!(wait_for(|| state
+            .get_all_blocks()
+            .contains("modified.example.com")));
+    }
+
+    #[test]
+    fn test_paused_changes_are_skipped() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("hosts");
+        fs::write(&path, "127.0.0.1 paused.example.com\n").unwrap();
+        let state = AppState::new(Config::default());
+
+        let paused = AtomicBool::new(true);
+        assert!(!reload_unless_paused(&path, &state, None, &paused));
+        assert!(!state.get_all_blocks().contains("paused.example.com"));
+
+        paused.store(false, Ordering::SeqCst);
+        assert!(reload_unless_paused(&path, &state, None, &paused));
+        assert!(state.get_all_blocks().contains("paused.example.com"));
+    }
+
+    #[test]
+    fn test_resume_reloads_changes_made_while_paused() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("hosts");
+        fs::write(&path, "127.0.0.1 localhost\n").unwrap();
+
+        let state = Arc::new(AppState::new(Config::default()));
+        let watcher = HostsWatcher::default();
+        // Nothing to resume before a pause
+        assert!(watcher.resume(state.clone()).unwrap().is_none());
+
+        watcher.watch(path.as_path().into(), state.clone()).unwrap();
+        watcher.pause();
+        assert!(watcher.is_paused());
+        fs::write(&path, "127.0.0.1 paused.example.com\n").unwrap();
+
+        let report = watcher.resume(state.clone()).unwrap().unwrap();
+        assert!(!watcher.is_paused());
+        assert!(report.added.iter().any(|d| &**d == "paused.example.com"));
+        assert!(state.get_all_blocks().contains("paused.example.com"));
+        assert!(watcher.resume(state.clone()).unwrap().is_none());
+    }
 }